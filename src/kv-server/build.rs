@@ -1,19 +1,54 @@
-use std::process::Command;
+use std::{fs, process::Command};
 
 fn main() {
     // 创建配置文件
     let mut config = prost_build::Config::new();
 
-    config.bytes(&["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    config.bytes(["."]);
+    // HTTP 网关使用 JSON 格式的 CommandRequest/CommandResponse
+    let derive = "#[derive(PartialOrd, serde::Serialize, serde::Deserialize)] \
+                  #[serde(rename_all = \"snake_case\")]";
+    config.type_attribute(".", derive);
 
-    config
+    // prost-build 只使用最匹配的一条 type_attribute，所以每条都要带上 derive
+    // message 在 JSON 里可以省略字段，Value 直接对应 JSON 的值（见 pb/mod.rs）
+    let proto = fs::read_to_string("abi.proto").unwrap();
+    let mut message = "";
+    for line in proto.lines() {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["message", "Value", ..] => {
+                message = "Value";
+                let value =
+                    "#[serde(into = \"serde_json::Value\", try_from = \"serde_json::Value\")]";
+                config.type_attribute(".abi.Value", format!("{} {}", derive, value));
+            }
+            ["message", name, ..] => {
+                message = name;
+                let path = format!(".abi.{}", name);
+                config.type_attribute(path, format!("{} #[serde(default)]", derive));
+            }
+            // oneof 生成的 enum 会匹配到 message 的路径，需要单独指定
+            ["oneof", name, ..] => {
+                config.type_attribute(format!(".abi.{}.{}", message, name), derive);
+            }
+            // prost 生成的 enum 已经 derive 了 PartialOrd
+            ["enum", name, ..] => {
+                let serde = "#[derive(serde::Serialize, serde::Deserialize)] \
+                             #[serde(rename_all = \"snake_case\")]";
+                config.type_attribute(format!(".abi.{}", name), serde);
+            }
+            _ => {}
+        }
+    }
+
+    // 同时生成 KvService 的 gRPC 代码
+    tonic_build::configure()
         .out_dir("src/pb")
-        .compile_protos(&["abi.proto"], &["."])
+        .compile_with_config(config, &["abi.proto"], &["."])
         .unwrap();
 
     Command::new("cargo")
-        .args(&["fmt", "--", "src/*.rs"])
+        .args(["fmt", "--", "src/*.rs"])
         .status()
         .expect("cargo fmt failed");
 
@@ -1,13 +1,8 @@
 use anyhow::Result;
 use async_prost::AsyncProstStream;
-use dashmap::DashMap;
 use futures::prelude::*;
 
-use kv_server::{
-    command_request::RequestData, CommandRequest, CommandResponse, Hset, KvError, Kvpair, Value,
-};
-
-use std::sync::Arc;
+use kv_server::{CommandRequest, CommandResponse};
 
 use tokio::net::TcpListener;
 use tracing::info;
@@ -34,10 +29,11 @@ async fn main() -> Result<()> {
             while let Some(Ok(msg)) = stream.next().await {
                 info!("Got a new command {:#?}", msg);
 
-                let mut resp = CommandResponse::default();
-
-                resp.status = 404;
-                resp.message = "Not Found".to_string();
+                let resp = CommandResponse {
+                    status: 404,
+                    message: "Not Found".to_string(),
+                    ..Default::default()
+                };
                 stream.send(resp).await.unwrap()
             }
 
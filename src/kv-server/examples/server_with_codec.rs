@@ -28,10 +28,14 @@ async fn main() -> Result<()> {
             while let Some(Ok(mut buf)) = stream.next().await {
                 let cmd = CommandRequest::decode(&buf[..]).unwrap();
                 info!("Got a new command: {:?}", cmd);
-                let res = svc.execute(cmd);
-
-                res.encode(&mut buf).unwrap();
-                stream.send(buf.freeze()).await.unwrap();
+                let mut res = svc.execute(cmd);
+
+                // 每个 response 单独作为一个 frame 发出去
+                while let Some(data) = res.next().await {
+                    buf.clear();
+                    data.encode(&mut buf).unwrap();
+                    stream.send(buf.clone().freeze()).await.unwrap();
+                }
             }
             info!("Client {:?} disconnected", addr);
         });
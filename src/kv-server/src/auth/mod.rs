@@ -0,0 +1,343 @@
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+
+use crate::{command_request::RequestData, CommandRequest, KvError};
+
+/// 对 table 的权限，高的权限包含低的权限
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// 读取数据，订阅主题
+    Read,
+    /// 修改数据，发布到主题
+    Write,
+    /// 所有操作，对 "*" 有 admin 权限才能复制整个数据库
+    Admin,
+}
+
+/// 认证过的用户
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub name: String,
+    // table 的模式和对应的权限，模式里 * 匹配任意字符串，? 匹配一个字符
+    tables: Vec<(String, Permission)>,
+}
+
+impl Principal {
+    /// 对 table 拥有的最高权限，主题的名字也按 table 检查
+    pub fn permission(&self, table: &str) -> Option<Permission> {
+        self.tables
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, table))
+            .map(|(_, permission)| *permission)
+            .max()
+    }
+
+    fn require(&self, table: &str, needed: Permission) -> Result<(), KvError> {
+        match self.permission(table) {
+            Some(permission) if permission >= needed => Ok(()),
+            _ => Err(KvError::PermissionDenied(format!(
+                "{} has no {:?} permission on {}",
+                self.name, needed, table
+            ))),
+        }
+    }
+
+    // 复制会读取所有的数据，需要对 "*" 有 admin 权限
+    fn require_all(&self) -> Result<(), KvError> {
+        let admin = self
+            .tables
+            .iter()
+            .any(|(pattern, permission)| pattern == "*" && *permission == Permission::Admin);
+        match admin {
+            true => Ok(()),
+            false => Err(KvError::PermissionDenied(format!(
+                "{} has no Admin permission on *",
+                self.name
+            ))),
+        }
+    }
+}
+
+/// token 到用户和权限的映射，从 TOML 格式的 ACL 文件加载：
+///
+/// ```toml
+/// [[users]]
+/// name = "app"
+/// token = "secret"
+/// tables = { "orders:*" = "write", "*" = "read" }
+/// ```
+#[derive(Clone, Default)]
+pub struct Acl {
+    users: HashMap<String, Arc<Principal>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclFile {
+    #[serde(default)]
+    users: Vec<UserEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserEntry {
+    name: String,
+    token: String,
+    #[serde(default)]
+    tables: BTreeMap<String, Permission>,
+}
+
+impl Acl {
+    /// 从 TOML 文件加载 ACL
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| KvError::ConfigError(format!("cannot read {}: {}", path.display(), e)))?;
+        Self::from_toml(&content)
+    }
+
+    /// 解析 TOML 格式的 ACL，token 不能为空也不能重复
+    pub fn from_toml(content: &str) -> Result<Self, KvError> {
+        let file: AclFile =
+            toml::from_str(content).map_err(|e| KvError::ConfigError(e.to_string()))?;
+        let mut acl = Self::default();
+        for user in file.users {
+            if user.token.is_empty() {
+                return Err(KvError::ConfigError(format!(
+                    "token of user {} is empty",
+                    user.name
+                )));
+            }
+            let principal = Principal {
+                name: user.name,
+                tables: user.tables.into_iter().collect(),
+            };
+            if let Some(other) = acl.users.insert(user.token, Arc::new(principal)) {
+                return Err(KvError::ConfigError(format!(
+                    "token of user {} is already used",
+                    other.name
+                )));
+            }
+        }
+        Ok(acl)
+    }
+
+    /// 添加一个用户，pattern 的语法见 Principal
+    pub fn add_user(
+        &mut self,
+        name: impl Into<String>,
+        token: impl Into<String>,
+        tables: impl IntoIterator<Item = (String, Permission)>,
+    ) {
+        let principal = Principal {
+            name: name.into(),
+            tables: tables.into_iter().collect(),
+        };
+        self.users.insert(token.into(), Arc::new(principal));
+    }
+
+    /// 找到 token 对应的用户
+    pub fn authenticate(&self, token: &str) -> Result<Arc<Principal>, KvError> {
+        self.users
+            .get(token)
+            .cloned()
+            .ok_or_else(|| KvError::PermissionDenied("invalid token".into()))
+    }
+
+    /// 检查用户是否可以执行命令，BATCH 里的每个子命令都需要有权限
+    pub fn check(
+        &self,
+        principal: Option<&Principal>,
+        cmd: &CommandRequest,
+    ) -> Result<(), KvError> {
+        let Some(principal) = principal else {
+            return Err(KvError::PermissionDenied("authentication required".into()));
+        };
+        check(principal, cmd)
+    }
+}
+
+fn check(principal: &Principal, cmd: &CommandRequest) -> Result<(), KvError> {
+    use Permission::*;
+    match &cmd.request_data {
+        Some(RequestData::Hget(v)) => principal.require(&v.table, Read),
+        Some(RequestData::Hgetall(v)) => principal.require(&v.table, Read),
+        Some(RequestData::Hmget(v)) => principal.require(&v.table, Read),
+        Some(RequestData::Hexist(v)) => principal.require(&v.table, Read),
+        Some(RequestData::Hmexist(v)) => principal.require(&v.table, Read),
+        Some(RequestData::Httl(v)) => principal.require(&v.table, Read),
+        Some(RequestData::Hscan(v)) => principal.require(&v.table, Read),
+        Some(RequestData::Hset(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Hmset(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Hdel(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Hmdel(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Hexpire(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Hpersist(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Hincrby(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Hincrbyfloat(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Hsetnx(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Hcas(v)) => principal.require(&v.table, Write),
+        Some(RequestData::Watch(v)) => principal.require(&v.table, Read),
+        Some(RequestData::Subscribe(v)) => principal.require(&v.topic, Read),
+        Some(RequestData::Unsubscribe(v)) => principal.require(&v.topic, Read),
+        Some(RequestData::Publish(v)) => principal.require(&v.topic, Write),
+        Some(RequestData::Batch(v)) => v.commands.iter().try_for_each(|cmd| check(principal, cmd)),
+        Some(RequestData::Replicate(_)) => principal.require_all(),
+        // AUTH 由 Service 单独处理，没有数据的命令会被 dispatch 拒绝
+        Some(RequestData::Auth(_)) | None => Ok(()),
+    }
+}
+
+// 下一个 session 的 id
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+/// 一个连接的状态，记住 AUTH 认证过的用户
+/// 每个 session 有唯一的 id，clone 出来的 session 和原来的 id 一样，并且共享认证的结果
+/// 比如 yamux 同一个连接上的 stream 共享 session，在任何一个 stream 上 AUTH 对所有 stream 都有效
+#[derive(Debug, Clone)]
+pub struct Session {
+    id: u64,
+    principal: Arc<RwLock<Option<Arc<Principal>>>>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            id: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
+            principal: Default::default(),
+        }
+    }
+}
+
+impl Session {
+    /// session 的 id，订阅只能由创建它的 session 取消
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// 认证过的用户，没有认证时为 None
+    pub fn principal(&self) -> Option<Arc<Principal>> {
+        self.principal
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub(crate) fn set_principal(&mut self, principal: Arc<Principal>) {
+        *self
+            .principal
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(principal);
+    }
+}
+
+// * 匹配任意字符串，? 匹配一个字符，失败时回到上一个 * 多匹配一个字符
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACL: &str = r#"
+        [[users]]
+        name = "app"
+        token = "app-token"
+        tables = { "orders:*" = "write", "*" = "read" }
+
+        [[users]]
+        name = "root"
+        token = "root-token"
+        tables = { "*" = "admin" }
+    "#;
+
+    #[test]
+    fn glob_match_should_work() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("orders:*", "orders:2021"));
+        assert!(glob_match("t?", "t1"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("orders:*", "users"));
+        assert!(!glob_match("t?", "t12"));
+        assert!(!glob_match("a*b", "ab c"));
+    }
+
+    #[test]
+    fn acl_should_check_permissions() {
+        let acl = Acl::from_toml(ACL).unwrap();
+        assert!(acl.authenticate("bad-token").is_err());
+        let app = acl.authenticate("app-token").unwrap();
+        assert_eq!(app.name, "app");
+        assert_eq!(app.permission("orders:1"), Some(Permission::Write));
+        assert_eq!(app.permission("users"), Some(Permission::Read));
+
+        let check = |principal: Option<&Principal>, cmd| acl.check(principal, &cmd);
+        let app = Some(app.as_ref());
+        assert!(check(app, CommandRequest::new_hget("users", "u1")).is_ok());
+        assert!(check(app, CommandRequest::new_hset("orders:1", "k", 1i64.into())).is_ok());
+        assert!(check(app, CommandRequest::new_hset("users", "u1", 1i64.into())).is_err());
+        assert!(check(app, CommandRequest::new_replicate(0, 0)).is_err());
+        let batch = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hset("orders:1", "k", 1i64.into()),
+                CommandRequest::new_hdel("users", "u1"),
+            ],
+            false,
+        );
+        assert!(check(app, batch).is_err());
+        assert_eq!(
+            check(None, CommandRequest::new_hget("users", "u1")),
+            Err(KvError::PermissionDenied("authentication required".into()))
+        );
+
+        let root = acl.authenticate("root-token").unwrap();
+        assert!(check(Some(&root), CommandRequest::new_replicate(0, 0)).is_ok());
+    }
+
+    #[test]
+    fn invalid_acl_should_be_rejected() {
+        let cases = [
+            "[[users]]\nname = \"a\"\ntoken = \"\"",
+            "[[users]]\nname = \"a\"\ntoken = \"t\"\ntables = { \"*\" = \"owner\" }",
+            "[[users]]\nname = \"a\"\ntoken = \"t\"\n[[users]]\nname = \"b\"\ntoken = \"t\"",
+        ];
+        for case in cases {
+            let result = Acl::from_toml(case);
+            assert!(matches!(result, Err(KvError::ConfigError(_))), "{}", case);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+//! kv-cli：KV Server 的命令行客户端
+//! 不带命令时进入交互模式，带命令时执行一次就退出，比如 `kv-cli hget t1 k1`
+
+mod output;
+mod parser;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use futures::StreamExt;
+use kv_server::{command_request::RequestData, ClientOptions, CommandRequest, KvClient};
+use kv_server::{CommandResponse, StreamResult, TlsClientConnector};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+use std::path::{Path, PathBuf};
+
+#[derive(Parser)]
+#[command(name = "kv-cli", about = "Command line client for kv-server")]
+struct Args {
+    /// 服务器地址
+    #[arg(short, long, default_value = "127.0.0.1:9527")]
+    addr: String,
+    /// 以 JSON 格式输出
+    #[arg(long)]
+    json: bool,
+    /// 使用 TLS 连接，没有指定 --ca 时使用公共根证书
+    #[arg(long)]
+    tls: bool,
+    /// 服务器证书的 CA
+    #[arg(long)]
+    ca: Option<PathBuf>,
+    /// 客户端证书，服务器要求双向认证时使用
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+    /// 客户端证书的私钥
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+    /// 服务器证书里的域名
+    #[arg(long, default_value = "localhost")]
+    domain: String,
+    /// 服务器要求认证时使用的 token
+    #[arg(long)]
+    token: Option<String>,
+    /// 要执行的命令，比如 `hset t1 k1 42`
+    command: Vec<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let mut options = ClientOptions::new(&args.addr);
+    if args.tls || args.ca.is_some() {
+        let identity = args.cert.as_deref().zip(args.key.as_deref());
+        options.tls = Some(TlsClientConnector::from_files(
+            &args.domain,
+            identity,
+            args.ca.as_deref(),
+        )?);
+    }
+    options.token = args.token;
+    let client = KvClient::new(options);
+
+    if args.command.is_empty() {
+        return repl(&client, args.json).await;
+    }
+
+    let cmd = parser::parse_args(&args.command)?;
+    if !run(&client, cmd, args.json).await? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// 交互模式，历史记录保存在 ~/.kv_cli_history
+async fn repl(client: &KvClient, json: bool) -> Result<()> {
+    let mut editor: Editor<CliHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(CliHelper));
+    let history = std::env::var_os("HOME").map(|home| Path::new(&home).join(".kv_cli_history"));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline("kv> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let _ = editor.add_history_entry(line.as_str());
+
+        match line.trim().to_lowercase().as_str() {
+            "exit" | "quit" => break,
+            "help" => {
+                println!("Commands: {}", parser::COMMANDS.join(" "));
+                continue;
+            }
+            _ => {}
+        }
+
+        let result = match parser::parse(&line) {
+            Ok(Some(cmd)) => run(client, cmd, json).await,
+            Ok(None) => continue,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("(error) {}", e);
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
+
+// 执行命令并输出结果，返回命令是否成功
+async fn run(client: &KvClient, cmd: CommandRequest, json: bool) -> Result<bool> {
+    match &cmd.request_data {
+        Some(RequestData::Subscribe(sub)) => {
+            let stream = client.subscribe(&sub.topic).await?;
+            eprintln!(
+                "Subscribed to {} (id {}), press Ctrl-C to stop",
+                sub.topic, stream.id
+            );
+            follow(stream, json).await?;
+            return Ok(true);
+        }
+        Some(RequestData::Watch(watch)) => {
+            let stream = client.watch(&watch.table, &watch.key_prefix).await?;
+            eprintln!(
+                "Watching {} (id {}), press Ctrl-C to stop",
+                watch.table, stream.id
+            );
+            follow(stream, json).await?;
+            return Ok(true);
+        }
+        _ => {}
+    }
+
+    let res = client.execute(cmd).await?;
+    print(&res, json);
+    Ok((200..300).contains(&res.status))
+}
+
+// 一直输出服务器推送的数据，直到 Ctrl-C
+async fn follow(mut stream: StreamResult, json: bool) -> Result<()> {
+    loop {
+        tokio::select! {
+            res = stream.next() => match res {
+                Some(res) => print(&res?, json),
+                None => bail!("connection closed by server"),
+            },
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+fn print(res: &CommandResponse, json: bool) {
+    if json {
+        println!("{}", output::format_json(res));
+    } else {
+        println!("{}", output::format_text(res));
+    }
+}
+
+// 补全命令的名字
+struct CliHelper;
+
+impl Completer for CliHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let prefix = &line[..pos];
+        // 只补全第一个词
+        if prefix.trim_start().contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let start = prefix.len() - prefix.trim_start().len();
+        let word = prefix[start..].to_uppercase();
+        let candidates = parser::COMMANDS
+            .iter()
+            .filter(|name| name.starts_with(&word))
+            .map(|name| Pair {
+                display: name.to_string(),
+                replacement: format!("{} ", name),
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for CliHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CliHelper {}
+
+impl Validator for CliHelper {}
+
+impl Helper for CliHelper {}
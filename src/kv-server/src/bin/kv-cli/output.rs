@@ -0,0 +1,199 @@
+use comfy_table::{presets::UTF8_FULL, Table};
+use kv_server::{value, ChangeEvent, ChangeKind, CommandResponse, Kvpair, Value};
+use serde_json::json;
+use std::fmt::Write;
+
+/// 把 response 格式化成给人看的文本，值使用和输入一样的字面量语法
+pub fn format_text(res: &CommandResponse) -> String {
+    let mut out = String::new();
+    if !(200..300).contains(&res.status) {
+        return format!("(error {}) {}", res.status, res.message);
+    }
+
+    if !res.responses.is_empty() {
+        // BATCH 的每个子命令
+        for (i, res) in res.responses.iter().enumerate() {
+            let _ = writeln!(out, "[{}] {}", i, format_text(res).replace('\n', "\n    "));
+        }
+        return out.trim_end().to_owned();
+    }
+
+    if !res.events.is_empty() {
+        // WATCH 推送的修改事件
+        for event in &res.events {
+            let _ = writeln!(out, "{}", format_event(event));
+        }
+        return out.trim_end().to_owned();
+    }
+
+    if !res.pairs.is_empty() {
+        out.push_str(&format_pairs(&res.pairs));
+        out.push('\n');
+    }
+    match res.values.as_slice() {
+        [] if res.pairs.is_empty() => out.push_str("OK"),
+        [] => {}
+        [v] => out.push_str(&format_literal(v)),
+        values => {
+            for (i, v) in values.iter().enumerate() {
+                let _ = writeln!(out, "{}) {}", i + 1, format_literal(v));
+            }
+        }
+    }
+    if !res.cursor.is_empty() {
+        let _ = write!(out, "\n(cursor) {:?}", res.cursor);
+    }
+    out.trim_end().to_owned()
+}
+
+fn format_pairs(pairs: &[Kvpair]) -> String {
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL).set_header(["key", "value"]);
+    for pair in pairs {
+        let value = pair.value.as_ref().map(format_literal).unwrap_or_default();
+        table.add_row([pair.key.clone(), value]);
+    }
+    table.to_string()
+}
+
+fn format_event(event: &ChangeEvent) -> String {
+    let value = |v: &Option<Value>| v.as_ref().map(format_literal).unwrap_or("(nil)".into());
+    match ChangeKind::from_i32(event.kind) {
+        Some(ChangeKind::Overflow) => format!("(overflow) {} events dropped", event.dropped),
+        kind => format!(
+            "#{} {} {} {}: {} -> {}",
+            event.seq,
+            match kind {
+                Some(ChangeKind::Delete) => "del",
+                _ => "set",
+            },
+            event.table,
+            event.key,
+            value(&event.old),
+            value(&event.new)
+        ),
+    }
+}
+
+/// 值的字面量，缺省的 Value 表示不存在
+pub fn format_literal(v: &Value) -> String {
+    match &v.value {
+        None => "(nil)".into(),
+        Some(value::Value::String(s)) => format!("{:?}", s),
+        Some(value::Value::Integer(i)) => i.to_string(),
+        // Debug 格式总是带小数点，不会和整数混淆
+        Some(value::Value::Float(f)) => format!("{:?}", f),
+        Some(value::Value::Bool(b)) => b.to_string(),
+        Some(value::Value::Binary(b)) => {
+            let mut s = String::from("b\"");
+            for &c in b.iter() {
+                match c {
+                    b'"' => s.push_str("\\\""),
+                    b'\\' => s.push_str("\\\\"),
+                    0x20..=0x7e => s.push(c as char),
+                    _ => {
+                        let _ = write!(s, "\\x{:02x}", c);
+                    }
+                }
+            }
+            s.push('"');
+            s
+        }
+    }
+}
+
+/// 把 response 格式化成 JSON，二进制表示成字节数组
+pub fn format_json(res: &CommandResponse) -> serde_json::Value {
+    let mut out = json!({ "status": res.status });
+    if !res.message.is_empty() {
+        out["message"] = res.message.clone().into();
+    }
+    if !res.values.is_empty() {
+        out["values"] = res.values.iter().map(json_value).collect();
+    }
+    if !res.pairs.is_empty() {
+        out["pairs"] = res
+            .pairs
+            .iter()
+            .map(|pair| {
+                let value = pair.value.as_ref().map(json_value);
+                json!({ "key": pair.key, "value": value })
+            })
+            .collect();
+    }
+    if !res.responses.is_empty() {
+        out["responses"] = res.responses.iter().map(format_json).collect();
+    }
+    if !res.events.is_empty() {
+        out["events"] = serde_json::to_value(&res.events).unwrap_or_default();
+    }
+    if !res.cursor.is_empty() {
+        out["cursor"] = res.cursor.clone().into();
+    }
+    out
+}
+
+fn json_value(v: &Value) -> serde_json::Value {
+    v.clone().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn format_literal_should_work() {
+        assert_eq!(format_literal(&"a\"b".into()), r#""a\"b""#);
+        assert_eq!(format_literal(&42i64.into()), "42");
+        assert_eq!(format_literal(&1f64.into()), "1.0");
+        assert_eq!(format_literal(&true.into()), "true");
+        assert_eq!(format_literal(&Value::default()), "(nil)");
+        let binary = Bytes::from_static(b"a\x00\"").into();
+        assert_eq!(format_literal(&binary), r#"b"a\x00\"""#);
+    }
+
+    #[test]
+    fn format_text_should_work() {
+        let res: CommandResponse = vec![Value::from(1i64), Value::default()].into();
+        assert_eq!(format_text(&res), "1) 1\n2) (nil)");
+
+        let res: CommandResponse = vec![Kvpair::new("k1", "v1".into())].into();
+        let text = format_text(&res);
+        assert!(text.contains("key") && text.contains("\"v1\""));
+
+        // HSCAN 还有下一页时显示 cursor
+        let mut res: CommandResponse = vec![Kvpair::new("k1", "v1".into())].into();
+        res.cursor = "k2".into();
+        assert!(format_text(&res).ends_with("\n(cursor) \"k2\""));
+
+        let res = CommandResponse {
+            status: 404,
+            message: "Not found".into(),
+            ..Default::default()
+        };
+        assert_eq!(format_text(&res), "(error 404) Not found");
+
+        let mut event = ChangeEvent::new("t1", "k1", None, Some(1i64.into()));
+        event.seq = 3;
+        assert_eq!(format_text(&event.into()), "#3 set t1 k1: (nil) -> 1");
+        let res = ChangeEvent::overflow(2).into();
+        assert_eq!(format_text(&res), "(overflow) 2 events dropped");
+    }
+
+    #[test]
+    fn format_json_should_work() {
+        let mut res: CommandResponse = vec![Kvpair::new("k1", 1.5f64.into())].into();
+        res.values = vec![Bytes::from_static(b"\x01").into()];
+        res.cursor = "k2".into();
+        assert_eq!(
+            format_json(&res),
+            json!({
+                "status": 200,
+                "values": [{ "binary": [1] }],
+                "pairs": [{ "key": "k1", "value": 1.5 }],
+                "cursor": "k2",
+            })
+        );
+    }
+}
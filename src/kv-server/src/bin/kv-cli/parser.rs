@@ -0,0 +1,451 @@
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use kv_server::{CommandRequest, Kvpair, Value};
+use std::time::Duration;
+
+/// 支持的命令，用于 REPL 的补全
+pub const COMMANDS: &[&str] = &[
+    "HGET",
+    "HGETALL",
+    "HMGET",
+    "HSET",
+    "HMSET",
+    "HDEL",
+    "HMDEL",
+    "HEXIST",
+    "HMEXIST",
+    "HEXPIRE",
+    "HTTL",
+    "HPERSIST",
+    "HINCRBY",
+    "HINCRBYFLOAT",
+    "HSETNX",
+    "HCAS",
+    "HSCAN",
+    "PUBLISH",
+    "SUBSCRIBE",
+    "WATCH",
+];
+
+// 一个参数。加了引号的一定是字符串，没有引号的会按字面量推断类型
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Bare(String),
+    Quoted(String),
+    Binary(Vec<u8>),
+}
+
+// 只接受数字形式的浮点数，NaN、inf 这些单词还是当作字符串
+fn float(s: &str) -> Option<f64> {
+    let numeric = s.bytes().any(|c| c.is_ascii_digit())
+        && s.bytes()
+            .all(|c| c.is_ascii_digit() || matches!(c, b'+' | b'-' | b'.' | b'e' | b'E'));
+    match numeric {
+        true => s.parse().ok(),
+        false => None,
+    }
+}
+
+impl Token {
+    // table、key 之类的参数
+    fn text(self) -> Result<String> {
+        match self {
+            Token::Bare(s) | Token::Quoted(s) => Ok(s),
+            Token::Binary(_) => bail!("expect a string, got a binary literal"),
+        }
+    }
+
+    // 值的字面量：整数、浮点数、true/false、b"..." 表示二进制，其它都是字符串
+    fn value(self) -> Value {
+        match self {
+            Token::Bare(s) => {
+                if let Ok(i) = s.parse::<i64>() {
+                    i.into()
+                } else if let Some(f) = float(&s) {
+                    f.into()
+                } else {
+                    match s.as_str() {
+                        "true" => true.into(),
+                        "false" => false.into(),
+                        _ => s.into(),
+                    }
+                }
+            }
+            Token::Quoted(s) => s.into(),
+            Token::Binary(b) => Bytes::from(b).into(),
+        }
+    }
+
+    fn number<T: std::str::FromStr>(self, name: &str) -> Result<T> {
+        let s = self.text()?;
+        s.parse()
+            .map_err(|_| anyhow!("{} should be a number, got {:?}", name, s))
+    }
+
+    // 以秒为单位的时间，可以是小数
+    fn seconds(self) -> Result<Duration> {
+        let secs: f64 = self.number("seconds")?;
+        Duration::try_from_secs_f64(secs).map_err(|e| anyhow!("invalid seconds: {}", e))
+    }
+}
+
+/// 把一行输入解析成命令，空行返回 None
+pub fn parse(line: &str) -> Result<Option<CommandRequest>> {
+    let tokens = tokenize(line)?;
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    parse_tokens(tokens).map(Some)
+}
+
+/// 解析已经分好的参数，用于一次性执行的模式，参数的引号已经被 shell 去掉了
+pub fn parse_args(args: &[String]) -> Result<CommandRequest> {
+    let tokens = args
+        .iter()
+        .map(
+            |arg| match arg.strip_prefix("b\"").and_then(|s| s.strip_suffix('"')) {
+                Some(s) => unescape(s).map(Token::Binary),
+                None => Ok(Token::Bare(arg.clone())),
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    if tokens.is_empty() {
+        bail!("missing command");
+    }
+    parse_tokens(tokens)
+}
+
+fn parse_tokens(tokens: Vec<Token>) -> Result<CommandRequest> {
+    let mut args = tokens.into_iter();
+    let name = args.next().unwrap().text()?.to_uppercase();
+    let mut args = Args { name: &name, args };
+
+    let cmd = match name.as_str() {
+        "HGET" => CommandRequest::new_hget(args.text()?, args.text()?),
+        "HGETALL" => CommandRequest::new_hgetall(args.text()?),
+        "HMGET" => CommandRequest::new_hmget(args.text()?, args.texts()?),
+        "HSET" => {
+            let (table, key, value) = (args.text()?, args.text()?, args.value()?);
+            match args.option("EX")? {
+                Some(ttl) => CommandRequest::new_hset_with_ttl(table, key, value, ttl.seconds()?),
+                None => CommandRequest::new_hset(table, key, value),
+            }
+        }
+        "HMSET" => {
+            let table = args.text()?;
+            let mut pairs = Vec::new();
+            while let Some(key) = args.next() {
+                pairs.push(Kvpair::new(key.text()?, args.value()?));
+            }
+            if pairs.is_empty() {
+                bail!("HMSET needs at least one key and value");
+            }
+            CommandRequest::new_hmset(table, pairs)
+        }
+        "HDEL" => CommandRequest::new_hdel(args.text()?, args.text()?),
+        "HMDEL" => CommandRequest::new_hmdel(args.text()?, args.texts()?),
+        "HEXIST" => CommandRequest::new_hexist(args.text()?, args.text()?),
+        "HMEXIST" => CommandRequest::new_hmexist(args.text()?, args.texts()?),
+        "HEXPIRE" => {
+            CommandRequest::new_hexpire(args.text()?, args.text()?, args.token()?.seconds()?)
+        }
+        "HTTL" => CommandRequest::new_httl(args.text()?, args.text()?),
+        "HPERSIST" => CommandRequest::new_hpersist(args.text()?, args.text()?),
+        "HINCRBY" => {
+            CommandRequest::new_hincrby(args.text()?, args.text()?, args.token()?.number("delta")?)
+        }
+        "HINCRBYFLOAT" => CommandRequest::new_hincrbyfloat(
+            args.text()?,
+            args.text()?,
+            args.token()?.number("delta")?,
+        ),
+        "HSETNX" => CommandRequest::new_hsetnx(args.text()?, args.text()?, args.value()?),
+        "HCAS" => {
+            let (table, key) = (args.text()?, args.text()?);
+            // nil 表示期望 key 不存在
+            let expected = match args.token()? {
+                Token::Bare(s) if s == "nil" => None,
+                token => Some(token.value()),
+            };
+            CommandRequest::new_hcas(table, key, expected, args.value()?)
+        }
+        "HSCAN" => {
+            let table = args.text()?;
+            let (mut start, mut end, mut prefix, mut cursor) = Default::default();
+            let mut limit = 0;
+            while let Some(option) = args.next() {
+                let option = option.text()?.to_uppercase();
+                let arg = args.token()?;
+                match option.as_str() {
+                    "START" => start = arg.text()?,
+                    "END" => end = arg.text()?,
+                    "PREFIX" => prefix = arg.text()?,
+                    "CURSOR" => cursor = arg.text()?,
+                    "LIMIT" => limit = arg.number("LIMIT")?,
+                    _ => bail!("unknown HSCAN option {}", option),
+                }
+            }
+            CommandRequest::new_hscan(table, start, end, prefix, limit, cursor)
+        }
+        "PUBLISH" => {
+            let topic = args.text()?;
+            CommandRequest::new_publish(topic, args.by_ref().map(Token::value).collect())
+        }
+        "SUBSCRIBE" => CommandRequest::new_subscribe(args.text()?),
+        "WATCH" => {
+            let table = args.text()?;
+            let prefix = match args.next() {
+                Some(prefix) => prefix.text()?,
+                None => String::new(),
+            };
+            CommandRequest::new_watch(table, prefix)
+        }
+        _ => bail!("unknown command {}", name),
+    };
+
+    if args.next().is_some() {
+        bail!("too many arguments for {}", name);
+    }
+    Ok(cmd)
+}
+
+// 按顺序取参数，缺少参数时报告是哪个命令
+struct Args<'a, I> {
+    name: &'a str,
+    args: I,
+}
+
+impl<I: Iterator<Item = Token>> Iterator for Args<'_, I> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        self.args.next()
+    }
+}
+
+impl<I: Iterator<Item = Token>> Args<'_, I> {
+    fn token(&mut self) -> Result<Token> {
+        self.next()
+            .ok_or_else(|| anyhow!("missing arguments for {}", self.name))
+    }
+
+    fn text(&mut self) -> Result<String> {
+        self.token()?.text()
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        Ok(self.token()?.value())
+    }
+
+    // 剩下的参数都是 key，至少要有一个
+    fn texts(&mut self) -> Result<Vec<String>> {
+        let keys = self.by_ref().map(Token::text).collect::<Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            bail!("missing arguments for {}", self.name);
+        }
+        Ok(keys)
+    }
+
+    // 可选的 `NAME arg`
+    fn option(&mut self, name: &str) -> Result<Option<Token>> {
+        match self.next() {
+            None => Ok(None),
+            Some(token) if token.clone().text()?.eq_ignore_ascii_case(name) => {
+                self.token().map(Some)
+            }
+            Some(_) => bail!("too many arguments for {}", self.name),
+        }
+    }
+}
+
+// 按空白分隔参数，"..." 是字符串，b"..." 是二进制，引号里支持 \" \\ \n \t \xHH 转义
+fn tokenize(line: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || c == '"' {
+                break;
+            }
+            word.push(c);
+            chars.next();
+        }
+        if chars.peek() != Some(&'"') {
+            tokens.push(Token::Bare(word));
+            continue;
+        }
+
+        // 引号开始的字符串，前面只能是空的或者 b
+        chars.next();
+        let mut quoted = String::new();
+        loop {
+            match chars.next() {
+                None => bail!("unterminated string"),
+                Some('"') => break,
+                Some('\\') => {
+                    quoted.push('\\');
+                    quoted.push(chars.next().ok_or_else(|| anyhow!("unterminated string"))?);
+                }
+                Some(c) => quoted.push(c),
+            }
+        }
+        if chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            bail!("expect whitespace after closing quote");
+        }
+        let bytes = unescape(&quoted)?;
+        tokens.push(match word.as_str() {
+            "" => Token::Quoted(String::from_utf8(bytes)?),
+            "b" => Token::Binary(bytes),
+            _ => bail!("unexpected quote after {:?}", word),
+        });
+    }
+    Ok(tokens)
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut tmp = [0; 4];
+            buf.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => buf.push(b'\n'),
+            Some('r') => buf.push(b'\r'),
+            Some('t') => buf.push(b'\t'),
+            Some('0') => buf.push(0),
+            Some('\\') => buf.push(b'\\'),
+            Some('"') => buf.push(b'"'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| anyhow!("invalid escape \\x{}", hex))?;
+                buf.push(byte);
+            }
+            c => bail!(
+                "invalid escape \\{}",
+                c.map(String::from).unwrap_or_default()
+            ),
+        }
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_should_work() {
+        let tokens = tokenize(r#"  hset t1 "hello world" b"\x00\xffa\"" 42 "#).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Bare("hset".into()),
+                Token::Bare("t1".into()),
+                Token::Quoted("hello world".into()),
+                Token::Binary(vec![0, 0xff, b'a', b'"']),
+                Token::Bare("42".into()),
+            ]
+        );
+        assert!(tokenize(r#"hget "t1"#).is_err());
+        assert!(tokenize(r#"hget x"t1""#).is_err());
+        assert!(tokenize(r#"hget "t1"k"#).is_err());
+    }
+
+    #[test]
+    fn values_should_be_typed_by_literal() {
+        let cases: Vec<(&str, Value)> = vec![
+            ("42", 42i64.into()),
+            ("-1.5", (-1.5f64).into()),
+            ("1e3", 1000f64.into()),
+            ("NaN", "NaN".into()),
+            ("inf", "inf".into()),
+            ("-Infinity", "-Infinity".into()),
+            ("true", true.into()),
+            ("false", false.into()),
+            ("hello", "hello".into()),
+            (r#""42""#, "42".into()),
+            (r#"b"abc""#, Bytes::from_static(b"abc").into()),
+        ];
+        for (literal, value) in cases {
+            let cmd = parse(&format!("HSET t1 k1 {}", literal)).unwrap();
+            assert_eq!(cmd, Some(CommandRequest::new_hset("t1", "k1", value)));
+        }
+    }
+
+    #[test]
+    fn parse_should_work() {
+        let cases = vec![
+            ("", None),
+            ("hget t1 k1", Some(CommandRequest::new_hget("t1", "k1"))),
+            (
+                "HMGET t1 k1 k2",
+                Some(CommandRequest::new_hmget(
+                    "t1",
+                    vec!["k1".into(), "k2".into()],
+                )),
+            ),
+            (
+                "hset t1 k1 v1 ex 1.5",
+                Some(CommandRequest::new_hset_with_ttl(
+                    "t1",
+                    "k1",
+                    "v1".into(),
+                    Duration::from_millis(1500),
+                )),
+            ),
+            (
+                "hmset t1 k1 1 k2 2",
+                Some(CommandRequest::new_hmset(
+                    "t1",
+                    vec![
+                        Kvpair::new("k1", 1i64.into()),
+                        Kvpair::new("k2", 2i64.into()),
+                    ],
+                )),
+            ),
+            (
+                "hcas t1 k1 nil 1",
+                Some(CommandRequest::new_hcas("t1", "k1", None, 1i64.into())),
+            ),
+            (
+                "hscan t1 prefix a limit 10",
+                Some(CommandRequest::new_hscan("t1", "", "", "a", 10, "")),
+            ),
+        ];
+        for (line, cmd) in cases {
+            assert_eq!(parse(line).unwrap(), cmd, "{}", line);
+        }
+
+        for line in [
+            "foo t1",
+            "hget t1",
+            "hget t1 k1 k2",
+            "hmset t1 k1",
+            "hincrby t1 k1 x",
+        ] {
+            assert!(parse(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parse_args_should_work() {
+        let args: Vec<String> = ["hset", "t1", "hello world", r#"b"\x01""#]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            parse_args(&args).unwrap(),
+            CommandRequest::new_hset("t1", "hello world", Bytes::from_static(b"\x01").into())
+        );
+    }
+}
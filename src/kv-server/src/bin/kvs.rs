@@ -0,0 +1,59 @@
+//! kvs：按 TOML 配置文件运行的 KV Server，收到 SIGINT / SIGTERM 后退出
+
+use anyhow::Result;
+use clap::Parser;
+use kv_server::{Server, ServerConfig};
+use std::path::PathBuf;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser)]
+#[command(name = "kvs", about = "KV server")]
+struct Args {
+    /// 配置文件，不指定的话使用缺省配置
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let config = match &args.config {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+
+    // RUST_LOG 优先于配置文件
+    let filter =
+        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(&config.log.level))?;
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+
+    Server::bind(config).await?.run(shutdown_signal()).await?;
+    info!("Server stopped");
+    Ok(())
+}
+
+// 等待 Ctrl-C 或者 SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
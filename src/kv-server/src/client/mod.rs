@@ -0,0 +1,533 @@
+mod pool;
+
+use std::{sync::Arc, time::Duration};
+use tokio::time;
+use tracing::warn;
+
+use crate::{
+    CommandRequest, CommandResponse, KvError, Kvpair, ScanRange, StreamResult, TlsClientConnector,
+    Value,
+};
+use pool::Pool;
+
+/// KvClient 的配置
+#[derive(Clone)]
+pub struct ClientOptions {
+    /// 服务器地址
+    pub addr: String,
+    /// 最多同时使用的连接数
+    pub pool_size: usize,
+    pub connect_timeout: Duration,
+    /// 单次请求的超时时间，超时的连接会被关闭
+    pub request_timeout: Duration,
+    /// I/O 出错后最多重试的次数
+    pub retries: usize,
+    /// 重试之间的退避时间，每次翻倍，直到 max_backoff
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// 使用 TLS 连接服务器
+    pub tls: Option<TlsClientConnector>,
+    /// 服务器要求认证时使用的 token，每个新的连接都会先发送 AUTH
+    pub token: Option<String>,
+}
+
+impl ClientOptions {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            pool_size: 8,
+            connect_timeout: Duration::from_secs(3),
+            request_timeout: Duration::from_secs(10),
+            retries: 3,
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            tls: None,
+            token: None,
+        }
+    }
+}
+
+/// HSCAN 返回的一页数据，cursor 为 None 表示没有更多数据了
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanPage<T> {
+    pub pairs: Vec<(String, T)>,
+    pub cursor: Option<String>,
+}
+
+/// 带连接池的 KV 客户端，clone 之后共享同一个连接池
+/// I/O 出错时按指数退避重新连接并重试：只读的命令总是会重试，
+/// 修改数据的命令只在连接失败时重试，因为请求发出后出错的话，无法知道服务器有没有执行
+#[derive(Clone)]
+pub struct KvClient {
+    pool: Arc<Pool>,
+}
+
+impl KvClient {
+    /// 创建客户端，连接在第一次使用时才会建立
+    pub fn new(options: ClientOptions) -> Self {
+        Self {
+            pool: Arc::new(Pool::new(options)),
+        }
+    }
+
+    /// 使用缺省配置连接服务器
+    pub async fn connect(addr: impl Into<String>) -> Result<Self, KvError> {
+        let client = Self::new(ClientOptions::new(addr));
+        client.pool.get().await?.release();
+        Ok(client)
+    }
+
+    /// 执行命令，返回服务器的 response，不检查 response 的状态码
+    pub async fn execute(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let options = self.pool.options();
+        let mut backoff = options.min_backoff;
+        let mut attempt = 0;
+        loop {
+            let (e, sent) = match self.try_execute(&cmd).await {
+                Ok(res) => return Ok(res),
+                Err(v) => v,
+            };
+            // 服务器拒绝的请求（比如认证失败）重试也不会成功
+            let rejected = matches!(e, KvError::ServerError(..));
+            if rejected || attempt >= options.retries || (sent && cmd.is_mutating()) {
+                return Err(e);
+            }
+            warn!("Request failed: {}, retry in {:?}", e, backoff);
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(options.max_backoff);
+            attempt += 1;
+        }
+    }
+
+    // 出错时同时返回请求是否可能已经发出
+    async fn try_execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, (KvError, bool)> {
+        let mut conn = self.pool.get().await.map_err(|e| (e, false))?;
+        let timeout = self.pool.options().request_timeout;
+        let result = match time::timeout(timeout, conn.conn.execute(cmd.clone())).await {
+            Ok(result) => result,
+            Err(_) => Err(KvError::Timeout(format!("request after {:?}", timeout))),
+        };
+        match result {
+            Ok(res) => {
+                conn.release();
+                Ok(res)
+            }
+            Err(e) => {
+                self.pool.clear();
+                Err((e, true))
+            }
+        }
+    }
+
+    // 执行命令，非 2xx 的 response 转换成错误
+    async fn request(&self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let res = self.execute(cmd).await?;
+        match res.status {
+            200..=299 => Ok(res),
+            status => Err(KvError::ServerError(status, res.message)),
+        }
+    }
+
+    // 执行命令，返回第一个 value
+    async fn request_value(&self, cmd: CommandRequest) -> Result<Value, KvError> {
+        let res = self.request(cmd).await?;
+        res.values
+            .into_iter()
+            .next()
+            .ok_or_else(|| KvError::Internal("Response has no value".into()))
+    }
+
+    async fn request_bool(&self, cmd: CommandRequest) -> Result<bool, KvError> {
+        self.request_value(cmd).await?.try_into()
+    }
+
+    async fn request_values(&self, cmd: CommandRequest) -> Result<Vec<Option<Value>>, KvError> {
+        let res = self.request(cmd).await?;
+        Ok(res.values.into_iter().map(optional).collect())
+    }
+
+    /// 读取一个 key，不存在时返回 None
+    pub async fn hget<T>(&self, table: &str, key: &str) -> Result<Option<T>, KvError>
+    where
+        T: TryFrom<Value, Error = KvError>,
+    {
+        match self
+            .request_value(CommandRequest::new_hget(table, key))
+            .await
+        {
+            Ok(v) => v.try_into().map(Some),
+            Err(KvError::ServerError(404, _)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 读取一组 key，不存在的 key 对应 None
+    pub async fn hmget<T>(&self, table: &str, keys: &[&str]) -> Result<Vec<Option<T>>, KvError>
+    where
+        T: TryFrom<Value, Error = KvError>,
+    {
+        let cmd = CommandRequest::new_hmget(table, to_strings(keys));
+        self.request_values(cmd)
+            .await?
+            .into_iter()
+            .map(|v| v.map(T::try_from).transpose())
+            .collect()
+    }
+
+    /// 读取 table 里所有的 kvpair
+    pub async fn hgetall<T>(&self, table: &str) -> Result<Vec<(String, T)>, KvError>
+    where
+        T: TryFrom<Value, Error = KvError>,
+    {
+        let res = self.request(CommandRequest::new_hgetall(table)).await?;
+        from_pairs(res.pairs)
+    }
+
+    /// 按 key 的顺序读取一页数据，把返回的 cursor 传进来可以读取下一页
+    pub async fn hscan<T>(
+        &self,
+        table: &str,
+        range: &ScanRange,
+        limit: u32,
+        cursor: Option<String>,
+    ) -> Result<ScanPage<T>, KvError>
+    where
+        T: TryFrom<Value, Error = KvError>,
+    {
+        let cmd = CommandRequest::new_hscan(
+            table,
+            &range.start,
+            &range.end,
+            &range.prefix,
+            limit,
+            cursor.unwrap_or_default(),
+        );
+        let res = self.request(cmd).await?;
+        Ok(ScanPage {
+            pairs: from_pairs(res.pairs)?,
+            cursor: (!res.cursor.is_empty()).then_some(res.cursor),
+        })
+    }
+
+    /// 写入一个 key，返回之前的值
+    pub async fn hset(
+        &self,
+        table: &str,
+        key: &str,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hset(table, key, value.into());
+        self.request_value(cmd).await.map(optional)
+    }
+
+    /// 写入一个带过期时间的 key，返回之前的值
+    pub async fn hset_with_ttl(
+        &self,
+        table: &str,
+        key: &str,
+        value: impl Into<Value>,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hset_with_ttl(table, key, value.into(), ttl);
+        self.request_value(cmd).await.map(optional)
+    }
+
+    /// 写入一组 key，返回它们之前的值
+    pub async fn hmset<K, V>(
+        &self,
+        table: &str,
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Vec<Option<Value>>, KvError>
+    where
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        let pairs = pairs
+            .into_iter()
+            .map(|(k, v)| Kvpair::new(k, v.into()))
+            .collect();
+        self.request_values(CommandRequest::new_hmset(table, pairs))
+            .await
+    }
+
+    /// 删除一个 key，返回之前的值
+    pub async fn hdel(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hdel(table, key);
+        self.request_value(cmd).await.map(optional)
+    }
+
+    /// 删除一组 key，返回它们之前的值
+    pub async fn hmdel(&self, table: &str, keys: &[&str]) -> Result<Vec<Option<Value>>, KvError> {
+        let cmd = CommandRequest::new_hmdel(table, to_strings(keys));
+        self.request_values(cmd).await
+    }
+
+    pub async fn hexist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.request_bool(CommandRequest::new_hexist(table, key))
+            .await
+    }
+
+    /// 设置过期时间，key 不存在时返回 false
+    pub async fn hexpire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.request_bool(CommandRequest::new_hexpire(table, key, ttl))
+            .await
+    }
+
+    /// 剩余的过期时间，key 不存在返回 None，没有过期时间返回 Some(None)
+    pub async fn httl(&self, table: &str, key: &str) -> Result<Option<Option<Duration>>, KvError> {
+        let ttl: i64 = match self
+            .request_value(CommandRequest::new_httl(table, key))
+            .await
+        {
+            Ok(v) => v.try_into()?,
+            Err(KvError::ServerError(404, _)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(u64::try_from(ttl).ok().map(Duration::from_millis)))
+    }
+
+    /// 去掉过期时间，返回之前是否有过期时间
+    pub async fn hpersist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.request_bool(CommandRequest::new_hpersist(table, key))
+            .await
+    }
+
+    /// 整数自增，返回新的值
+    pub async fn hincrby(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let cmd = CommandRequest::new_hincrby(table, key, delta);
+        self.request_value(cmd).await?.try_into()
+    }
+
+    /// 浮点数自增，返回新的值
+    pub async fn hincrbyfloat(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let cmd = CommandRequest::new_hincrbyfloat(table, key, delta);
+        self.request_value(cmd).await?.try_into()
+    }
+
+    /// key 不存在时才写入，返回是否写入
+    pub async fn hsetnx(
+        &self,
+        table: &str,
+        key: &str,
+        value: impl Into<Value>,
+    ) -> Result<bool, KvError> {
+        self.request_bool(CommandRequest::new_hsetnx(table, key, value.into()))
+            .await
+    }
+
+    /// 当前值等于 expected 时才写入，expected 为 None 表示 key 不存在，返回是否写入
+    pub async fn hcas(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<Value>,
+        new: impl Into<Value>,
+    ) -> Result<bool, KvError> {
+        let cmd = CommandRequest::new_hcas(table, key, expected, new.into());
+        self.request_bool(cmd).await
+    }
+
+    /// 一次执行多个命令，返回每个命令的 response
+    pub async fn batch(
+        &self,
+        commands: Vec<CommandRequest>,
+        atomic: bool,
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        let res = self
+            .request(CommandRequest::new_batch(commands, atomic))
+            .await?;
+        Ok(res.responses)
+    }
+
+    /// 往主题里发布数据
+    pub async fn publish(&self, topic: &str, values: Vec<Value>) -> Result<(), KvError> {
+        self.request(CommandRequest::new_publish(topic, values))
+            .await?;
+        Ok(())
+    }
+
+    /// 订阅主题，订阅使用单独的连接，不占用连接池
+    pub async fn subscribe(&self, topic: &str) -> Result<StreamResult, KvError> {
+        let conn = self.pool.connect().await?;
+        conn.execute_streaming(CommandRequest::new_subscribe(topic))
+            .await
+    }
+
+    /// 监听 table 里 key 以 key_prefix 开头的修改，和订阅一样使用单独的连接
+    pub async fn watch(&self, table: &str, key_prefix: &str) -> Result<StreamResult, KvError> {
+        let conn = self.pool.connect().await?;
+        conn.execute_streaming(CommandRequest::new_watch(table, key_prefix))
+            .await
+    }
+}
+
+// HMGET 之类的命令用缺省的 Value 表示 key 不存在
+fn optional(v: Value) -> Option<Value> {
+    v.value.is_some().then_some(v)
+}
+
+fn to_strings(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|k| k.to_string()).collect()
+}
+
+fn from_pairs<T>(pairs: Vec<Kvpair>) -> Result<Vec<(String, T)>, KvError>
+where
+    T: TryFrom<Value, Error = KvError>,
+{
+    pairs
+        .into_iter()
+        .map(|pair| Ok((pair.key, pair.value.unwrap_or_default().try_into()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, ProstServerStream, Service, ServiceInner};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn typed_api_should_work() {
+        let addr = start_server(Arc::default()).await;
+        let client = KvClient::connect(addr).await.unwrap();
+
+        assert_eq!(client.hset("t1", "k1", "v1").await, Ok(None));
+        assert_eq!(client.hset("t1", "k1", "v2").await, Ok(Some("v1".into())));
+        assert_eq!(
+            client.hget::<String>("t1", "k1").await,
+            Ok(Some("v2".into()))
+        );
+        assert_eq!(client.hget::<String>("t1", "k2").await, Ok(None));
+        assert!(client.hget::<i64>("t1", "k1").await.is_err());
+
+        client
+            .hmset("t2", [("a", 1i64), ("b", 2), ("c", 3)])
+            .await
+            .unwrap();
+        assert_eq!(
+            client.hmget::<i64>("t2", &["a", "x", "c"]).await,
+            Ok(vec![Some(1), None, Some(3)])
+        );
+        assert_eq!(client.hincrby("t2", "a", 10).await, Ok(11));
+        assert_eq!(client.hsetnx("t2", "a", 0i64).await, Ok(false));
+        assert_eq!(
+            client.hcas("t2", "b", Some(2.into()), 20i64).await,
+            Ok(true)
+        );
+        assert_eq!(client.hdel("t2", "c").await, Ok(Some(3i64.into())));
+        assert_eq!(client.hexist("t2", "c").await, Ok(false));
+
+        let mut pairs = client.hgetall::<i64>("t2").await.unwrap();
+        pairs.sort();
+        assert_eq!(pairs, vec![("a".into(), 11), ("b".into(), 20)]);
+
+        let page = client
+            .hscan::<i64>("t2", &ScanRange::default(), 1, None)
+            .await
+            .unwrap();
+        assert_eq!(page.pairs, vec![("a".into(), 11)]);
+        let page = client
+            .hscan::<i64>("t2", &ScanRange::default(), 1, page.cursor)
+            .await
+            .unwrap();
+        assert_eq!(page.pairs, vec![("b".into(), 20)]);
+        assert_eq!(page.cursor, None);
+
+        let ttl = Duration::from_secs(100);
+        assert_eq!(client.httl("t2", "a").await, Ok(Some(None)));
+        assert_eq!(client.hexpire("t2", "a", ttl).await, Ok(true));
+        assert!(client.httl("t2", "a").await.unwrap().unwrap().unwrap() > ttl / 2);
+        assert_eq!(client.hpersist("t2", "a").await, Ok(true));
+        assert_eq!(client.httl("t2", "x").await, Ok(None));
+
+        // 服务器返回的错误
+        let e = client.hincrby("t1", "k1", 1).await.unwrap_err();
+        assert!(matches!(e, KvError::ServerError(400, _)));
+    }
+
+    #[tokio::test]
+    async fn pool_should_limit_connections() {
+        let conns = Arc::new(AtomicUsize::new(0));
+        let addr = start_server(conns.clone()).await;
+        let mut options = ClientOptions::new(addr);
+        options.pool_size = 2;
+        let client = KvClient::new(options);
+
+        let tasks: Vec<_> = (0..20)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move { client.hincrby("t1", "counter", i).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert_eq!(client.hget::<i64>("t1", "counter").await, Ok(Some(190)));
+        assert!(conns.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[tokio::test]
+    async fn client_should_reconnect_with_backoff() {
+        // 先拿到一个空闲的端口，服务器稍后才启动
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let mut options = ClientOptions::new(addr.to_string());
+        options.min_backoff = Duration::from_millis(50);
+        options.retries = 10;
+        let client = KvClient::new(options);
+        let task = tokio::spawn({
+            let client = client.clone();
+            async move { client.hset("t1", "k1", "v1").await }
+        });
+
+        time::sleep(Duration::from_millis(120)).await;
+        let listener = TcpListener::bind(addr).await.unwrap();
+        serve(listener, Arc::default());
+        assert_eq!(task.await.unwrap(), Ok(None));
+        assert_eq!(
+            client.hget::<String>("t1", "k1").await,
+            Ok(Some("v1".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn request_should_time_out() {
+        // 只接受连接，从不回复
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                conns.push(stream);
+            }
+        });
+
+        let mut options = ClientOptions::new(addr.to_string());
+        options.request_timeout = Duration::from_millis(50);
+        options.retries = 0;
+        let client = KvClient::new(options);
+        let e = client.hget::<String>("t1", "k1").await.unwrap_err();
+        assert!(matches!(e, KvError::Timeout(_)));
+    }
+
+    async fn start_server(conns: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve(listener, conns);
+        addr.to_string()
+    }
+
+    // 同一个 Service 处理所有连接，conns 记录建立过的连接数
+    fn serve(listener: TcpListener, conns: Arc<AtomicUsize>) {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                conns.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(ProstServerStream::new(stream, service.clone()).process());
+            }
+        });
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tokio::{
+    net::TcpStream,
+    sync::{Semaphore, SemaphorePermit},
+    time,
+};
+use tracing::debug;
+
+use super::ClientOptions;
+use crate::{CommandRequest, Io, KvError, ProstClientStream};
+
+pub(super) type Connection = ProstClientStream<Box<dyn Io>>;
+
+/// 到同一个服务器的连接池，最多同时使用 pool_size 个连接
+pub(super) struct Pool {
+    options: ClientOptions,
+    idle: Mutex<Vec<Connection>>,
+    permits: Semaphore,
+}
+
+/// 从连接池里借出的连接
+/// 请求成功后调用 release 放回连接池，直接 drop 的连接状态未知，会被关闭
+pub(super) struct PooledConnection<'a> {
+    pub conn: Connection,
+    pool: &'a Pool,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl PooledConnection<'_> {
+    pub fn release(self) {
+        self.pool.idle().push(self.conn);
+    }
+}
+
+impl Pool {
+    pub fn new(options: ClientOptions) -> Self {
+        Self {
+            permits: Semaphore::new(options.pool_size.max(1)),
+            options,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
+    /// 借出一个空闲的连接，没有的话建立新的连接
+    pub async fn get(&self) -> Result<PooledConnection<'_>, KvError> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .map_err(|e| KvError::Internal(e.to_string()))?;
+        // 先取出来，不能在 await 的时候持有锁
+        let idle = self.idle().pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => self.connect().await?,
+        };
+        Ok(PooledConnection {
+            conn,
+            pool: self,
+            _permit: permit,
+        })
+    }
+
+    /// 建立一个新的连接，不放进连接池
+    pub async fn connect(&self) -> Result<Connection, KvError> {
+        let addr = &self.options.addr;
+        let connect = async {
+            let stream = TcpStream::connect(addr).await?;
+            let stream: Box<dyn Io> = match &self.options.tls {
+                Some(connector) => Box::new(connector.connect(stream).await?),
+                None => Box::new(stream),
+            };
+            let mut conn = ProstClientStream::new(stream);
+            if let Some(token) = &self.options.token {
+                let res = conn.execute(CommandRequest::new_auth(token)).await?;
+                if res.status != 200 {
+                    return Err(KvError::ServerError(res.status, res.message));
+                }
+            }
+            Ok::<_, KvError>(conn)
+        };
+        let conn = time::timeout(self.options.connect_timeout, connect)
+            .await
+            .map_err(|_| KvError::Timeout(format!("connect to {}", addr)))??;
+        debug!("Connected to {}", addr);
+        Ok(conn)
+    }
+
+    /// 关闭所有空闲的连接，一个连接出错时，其它连接多半也已经断开了
+    pub fn clear(&self) {
+        self.idle().clear();
+    }
+
+    fn idle(&self) -> MutexGuard<'_, Vec<Connection>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
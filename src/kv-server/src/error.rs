@@ -7,14 +7,28 @@ use thiserror::Error;
 #[derive(Debug, Error, PartialEq)]
 pub enum KvError {
     // 使用字段属性定义错误内容
-    #[error("Not found for table: {},key: {1}")]
+    #[error("Not found for table: {0}, key: {1}")]
     NotFound(String, String),
     #[error("Cannot parse command: `{0}`")]
     InvalidCommand(String),
-    #[error("Cannot convert value {:0} to {1}")]
+    #[error("Cannot convert value {0:?} to {1}")]
     ConvertError(Value, &'static str),
-    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {}")]
+    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
     StorageError(&'static str, String, String, String),
+    #[error("Server is read-only: {0}")]
+    ReadOnly(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Too many requests: {0}")]
+    RateLimited(String),
+    #[error("Frame is too large: {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Server returned {0}: {1}")]
+    ServerError(u32, String),
+    #[error("Invalid config: {0}")]
+    ConfigError(String),
     #[error("frame error")]
     FrameError,
 
@@ -25,10 +39,52 @@ pub enum KvError {
     DecodeError(#[from] prost::DecodeError),
     #[error("Internal error: {0}")]
     Internal(String),
+    #[error("I/O error: {0}")]
+    IoError(String),
+    #[error("Certificate parse error: error to load {0} {1}")]
+    CertificateParseError(&'static str, &'static str),
+    #[error("TLS error: {0}")]
+    TlsError(String),
+    #[error("Yamux connection error: {0}")]
+    YamuxError(String),
+}
+
+impl KvError {
+    /// 错误的类型，用于统计
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KvError::NotFound(..) => "NotFound",
+            KvError::InvalidCommand(_) => "InvalidCommand",
+            KvError::ConvertError(..) => "ConvertError",
+            KvError::StorageError(..) => "StorageError",
+            KvError::ReadOnly(_) => "ReadOnly",
+            KvError::PermissionDenied(_) => "PermissionDenied",
+            KvError::RateLimited(_) => "RateLimited",
+            KvError::FrameTooLarge(_) => "FrameTooLarge",
+            KvError::Timeout(_) => "Timeout",
+            KvError::ServerError(..) => "ServerError",
+            KvError::ConfigError(_) => "ConfigError",
+            KvError::FrameError => "FrameError",
+            KvError::EncodeError(_) => "EncodeError",
+            KvError::DecodeError(_) => "DecodeError",
+            KvError::Internal(_) => "Internal",
+            KvError::IoError(_) => "IoError",
+            KvError::CertificateParseError(..) => "CertificateParseError",
+            KvError::TlsError(_) => "TlsError",
+            KvError::YamuxError(_) => "YamuxError",
+        }
+    }
+}
+
+// std::io::Error 没有实现 PartialEq，所以只保留错误信息
+impl From<std::io::Error> for KvError {
+    fn from(e: std::io::Error) -> Self {
+        KvError::IoError(e.to_string())
+    }
 }
 
 impl From<StdError> for KvError {
-    fn from(value: StdError) -> Self {
+    fn from(_: StdError) -> Self {
         KvError::InvalidCommand("Invalid Commmad".to_string())
     }
 }
@@ -0,0 +1,307 @@
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Path, State},
+    http::{header::AUTHORIZATION, HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::info;
+
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, Incoming, KvError, RateLimiter,
+    Service, Storage, Value,
+};
+
+/// HTTP/JSON 网关的路由
+/// - GET/PUT/DELETE /tables/:t/keys/:k：读取、写入（body 是 JSON 的值）、删除一个 key
+/// - POST /command：执行 JSON 格式的 CommandRequest，不支持返回 stream 的命令
+///
+/// 返回 JSON 格式的 CommandResponse，HTTP 状态码就是 CommandResponse.status
+/// 服务器要求认证时，在 Authorization 头里带上 `Bearer <token>`
+pub fn gateway<Store: Storage>(service: Service<Store>) -> Router {
+    Router::new()
+        .route(
+            "/tables/:table/keys/:key",
+            get(get_key::<Store>)
+                .put(put_key::<Store>)
+                .delete(delete_key::<Store>),
+        )
+        .route("/command", post(command::<Store>))
+        .with_state(service)
+}
+
+/// 在 incoming 上提供 HTTP/JSON 网关，配置了 TLS 的话使用 HTTPS
+/// 配置了限速时，超过速率的请求返回 429
+pub async fn serve_gateway<Store: Storage>(
+    incoming: Incoming,
+    service: Service<Store>,
+) -> Result<(), KvError> {
+    let scheme = if incoming.is_tls() { "https" } else { "http" };
+    info!(
+        "Serving HTTP gateway on {}://{}",
+        scheme,
+        incoming.local_addr()?
+    );
+    let (incoming, limiter) = incoming.split(&["http/1.1"], Some(refusal()));
+    let mut app = gateway(service);
+    if let Some(limiter) = limiter {
+        app = app.layer(middleware::from_fn_with_state(limiter, rate_limit));
+    }
+    axum::Server::builder(hyper::server::accept::from_stream(incoming))
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .map_err(|e| KvError::Internal(e.to_string()))
+}
+
+// 超过连接数限制时回复的 429，body 和其它请求的回复一样是 JSON 格式的 CommandResponse
+fn refusal() -> Bytes {
+    let e = KvError::RateLimited("max number of connections reached".into());
+    let body = serde_json::to_string(&CommandResponse::from(e)).unwrap_or_default();
+    let reply = format!(
+        "HTTP/1.1 429 Too Many Requests\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    reply.into()
+}
+
+// 每个请求按客户端 IP 拿一个令牌
+async fn rate_limit<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match limiter.client(addr.ip()).acquire() {
+        Ok(()) => next.run(req).await,
+        Err(e) => respond(e.into()),
+    }
+}
+
+async fn get_key<Store: Storage>(
+    State(service): State<Service<Store>>,
+    Path((table, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    execute(&service, &headers, CommandRequest::new_hget(table, key)).await
+}
+
+async fn put_key<Store: Storage>(
+    State(service): State<Service<Store>>,
+    Path((table, key)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    match parse::<Value>(&body) {
+        Ok(value) => {
+            execute(
+                &service,
+                &headers,
+                CommandRequest::new_hset(table, key, value),
+            )
+            .await
+        }
+        Err(e) => respond(e.into()),
+    }
+}
+
+async fn delete_key<Store: Storage>(
+    State(service): State<Service<Store>>,
+    Path((table, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    execute(&service, &headers, CommandRequest::new_hdel(table, key)).await
+}
+
+async fn command<Store: Storage>(
+    State(service): State<Service<Store>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let cmd = match parse::<CommandRequest>(&body) {
+        Ok(cmd) => cmd,
+        Err(e) => return respond(e.into()),
+    };
+    match cmd.request_data {
+        Some(RequestData::Subscribe(_))
+        | Some(RequestData::Unsubscribe(_))
+        | Some(RequestData::Replicate(_))
+        | Some(RequestData::Watch(_)) => {
+            let e = KvError::InvalidCommand("Streaming command is not supported over HTTP".into());
+            respond(e.into())
+        }
+        _ => execute(&service, &headers, cmd).await,
+    }
+}
+
+// HTTP 没有连接状态，每个请求都先用 Authorization 里的 token 认证
+async fn execute<Store: Storage>(
+    service: &Service<Store>,
+    headers: &HeaderMap,
+    cmd: CommandRequest,
+) -> Response {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match service.session(token) {
+        Ok(mut session) => respond(first(service.execute_in(cmd, &mut session)).await),
+        Err(e) => respond(e.into()),
+    }
+}
+
+async fn first(mut stream: crate::StreamingResponse) -> CommandResponse {
+    match stream.next().await {
+        Some(res) => Arc::unwrap_or_clone(res),
+        None => KvError::Internal("Didn't get any response".into()).into(),
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, KvError> {
+    serde_json::from_slice(body)
+        .map_err(|e| KvError::InvalidCommand(format!("invalid JSON: {}", e)))
+}
+
+fn respond(res: CommandResponse) -> Response {
+    let status = u16::try_from(res.status)
+        .ok()
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    (status, Json(res)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Acl, MemTable, Permission, ServiceInner};
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::Semaphore,
+    };
+
+    #[tokio::test]
+    async fn gateway_should_handle_keys() {
+        let addr = start(ServiceInner::new(MemTable::new()).into()).await;
+
+        let (status, res) = http(addr, "PUT", "/tables/t1/keys/k1", r#"{"binary":[1,2]}"#).await;
+        assert_eq!((status, &res["values"]), (200, &json!([null])));
+        let (status, res) = http(addr, "GET", "/tables/t1/keys/k1", "").await;
+        assert_eq!(
+            (status, &res["values"]),
+            (200, &json!([{ "binary": [1, 2] }]))
+        );
+
+        let (status, res) = http(addr, "DELETE", "/tables/t1/keys/k1", "").await;
+        assert_eq!(
+            (status, &res["values"]),
+            (200, &json!([{ "binary": [1, 2] }]))
+        );
+        let (status, res) = http(addr, "GET", "/tables/t1/keys/k1", "").await;
+        assert_eq!((status, &res["error"]), (404, &json!("NotFound")));
+
+        let (status, _) = http(addr, "PUT", "/tables/t1/keys/k1", "{").await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn gateway_should_execute_json_commands() {
+        let addr = start(ServiceInner::new(MemTable::new()).into()).await;
+
+        let cmd = json!({
+            "request_data": { "hmset": {
+                "table": "t1",
+                "pairs": [{ "key": "k1", "value": 1 }, { "key": "k2", "value": 1.5 }],
+            }}
+        });
+        let (status, _) = http(addr, "POST", "/command", &cmd.to_string()).await;
+        assert_eq!(status, 200);
+
+        let cmd =
+            json!({ "request_data": { "hmget": { "table": "t1", "keys": ["k1", "k2", "k3"] }}});
+        let (status, res) = http(addr, "POST", "/command", &cmd.to_string()).await;
+        assert_eq!((status, &res["values"]), (200, &json!([1, 1.5, null])));
+
+        let cmd = json!({ "request_data": { "subscribe": { "topic": "lobby" }}});
+        let (status, _) = http(addr, "POST", "/command", &cmd.to_string()).await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn gateway_should_use_bearer_token() {
+        let mut acl = Acl::default();
+        acl.add_user("app", "secret", [("t1".into(), Permission::Write)]);
+        let addr = start(ServiceInner::new(MemTable::new()).acl(acl).into()).await;
+
+        let (status, _) = http(addr, "PUT", "/tables/t1/keys/k1", "\"v1\"").await;
+        assert_eq!(status, 403);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = "GET /tables/t1/keys/k1 HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer secret\r\nConnection: close\r\n\r\n";
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 404"), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn gateway_should_apply_limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Incoming::new(listener).rate_limit(Arc::new(RateLimiter::new(1, 1)));
+        let service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(serve_gateway(incoming, service));
+
+        let (status, _) = http(addr, "GET", "/tables/t1/keys/k1", "").await;
+        assert_eq!(status, 404);
+        let (status, res) = http(addr, "GET", "/tables/t1/keys/k1", "").await;
+        assert_eq!((status, &res["status"]), (429, &json!(429)));
+
+        // 没有空闲的连接数时回复 429 之后关闭连接
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Incoming::new(listener).max_connections(Arc::new(Semaphore::new(0)));
+        let service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(serve_gateway(incoming, service));
+
+        let (status, res) = http(addr, "GET", "/tables/t1/keys/k1", "").await;
+        assert_eq!((status, &res["status"]), (429, &json!(429)));
+    }
+
+    async fn start(service: Service) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_gateway(Incoming::new(listener), service));
+        addr
+    }
+
+    // 发送一个 HTTP 请求，返回状态码和 JSON body
+    async fn http(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> (u16, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+
+        let status = res[9..12].parse().unwrap();
+        let (_, body) = res.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+}
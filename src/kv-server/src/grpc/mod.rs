@@ -0,0 +1,299 @@
+use futures::StreamExt;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Code, Request, Response, Status};
+use tracing::info;
+
+use crate::{
+    command_request::RequestData,
+    kv_service_server::{KvService, KvServiceServer},
+    CommandRequest, CommandResponse, Incoming, KvError, MemTable, RateLimiter, Service, Session,
+    Storage, Subscribe, Watch,
+};
+
+// 转发订阅数据和修改事件的 channel 的大小
+const STREAM_BUFFER: usize = 128;
+
+/// 用 gRPC 提供 KvService，和 prost 协议共享同一个 Service
+/// 服务器要求认证时，在 metadata 的 authorization 里带上 `Bearer <token>`
+pub struct GrpcService<Store = MemTable> {
+    service: Service<Store>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl<Store: Storage> GrpcService<Store> {
+    pub fn new(service: Service<Store>) -> Self {
+        Self {
+            service,
+            limiter: None,
+        }
+    }
+
+    /// 按客户端 IP 限制请求速率，超过速率的请求返回 RateLimited
+    pub fn rate_limit(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// 转换成可以加到 tonic Server 上的服务
+    pub fn into_server(self) -> KvServiceServer<Self> {
+        KvServiceServer::new(self)
+    }
+
+    // 每个请求先检查速率，再用 metadata 里的 token 认证
+    fn session<T>(&self, request: &Request<T>) -> Result<Session, KvError> {
+        if let (Some(limiter), Some(addr)) =
+            (&self.limiter, request.extensions().get::<SocketAddr>())
+        {
+            limiter.client(addr.ip()).acquire()?;
+        }
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        self.service.session(token)
+    }
+}
+
+#[tonic::async_trait]
+impl<Store: Storage> KvService for GrpcService<Store> {
+    // 和 prost 协议一样，命令执行的错误放在 CommandResponse 里返回
+    async fn execute(
+        &self,
+        request: Request<CommandRequest>,
+    ) -> Result<Response<CommandResponse>, Status> {
+        let mut session = match self.session(&request) {
+            Ok(session) => session,
+            Err(e) => return Ok(Response::new(e.into())),
+        };
+        let cmd = request.into_inner();
+        let res = match cmd.request_data {
+            Some(RequestData::Subscribe(_))
+            | Some(RequestData::Unsubscribe(_))
+            | Some(RequestData::Replicate(_))
+            | Some(RequestData::Watch(_)) => {
+                KvError::InvalidCommand("Streaming command is not supported by Execute".into())
+                    .into()
+            }
+            _ => match self.service.execute_in(cmd, &mut session).next().await {
+                Some(res) => Arc::unwrap_or_clone(res),
+                None => KvError::Internal("Didn't get any response".into()).into(),
+            },
+        };
+        Ok(Response::new(res))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<CommandResponse, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<Subscribe>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        self.stream(request, RequestData::Subscribe).await
+    }
+
+    type WatchStream = ReceiverStream<Result<CommandResponse, Status>>;
+
+    async fn watch(&self, request: Request<Watch>) -> Result<Response<Self::WatchStream>, Status> {
+        self.stream(request, RequestData::Watch).await
+    }
+}
+
+impl<Store: Storage> GrpcService<Store> {
+    // 执行返回 stream 的命令，失败（比如没有权限）时返回错误的 Status，不会建立 stream
+    async fn stream<T>(
+        &self,
+        request: Request<T>,
+        data: impl FnOnce(T) -> RequestData,
+    ) -> Result<Response<ReceiverStream<Result<CommandResponse, Status>>>, Status> {
+        let mut session = self.session(&request).map_err(|e| to_status(&e.into()))?;
+        let cmd = CommandRequest {
+            request_data: Some(data(request.into_inner())),
+        };
+        let mut stream = self.service.execute_in(cmd, &mut session);
+        let first = match stream.next().await {
+            Some(res) if res.status == 200 => res,
+            Some(res) => return Err(to_status(&res)),
+            None => return Err(Status::internal("Didn't get any response")),
+        };
+
+        // 返回的 stream 不是 Sync 的，需要通过 channel 转发
+        // 客户端关闭 stream 后 drop 原来的 stream，自动取消订阅或者停止监听
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            let mut next = Some(first);
+            loop {
+                let res = match next.take() {
+                    Some(res) => res,
+                    None => tokio::select! {
+                        res = stream.next() => match res {
+                            Some(res) => res,
+                            None => break,
+                        },
+                        _ = tx.closed() => break,
+                    },
+                };
+                if tx.send(Ok(Arc::unwrap_or_clone(res))).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+// 出错的 CommandResponse 转换成对应的 gRPC 状态
+fn to_status(res: &CommandResponse) -> Status {
+    let code = match res.status {
+        400 => Code::InvalidArgument,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        429 => Code::ResourceExhausted,
+        _ => Code::Internal,
+    };
+    Status::new(code, res.message.clone())
+}
+
+/// 在 incoming 上提供 gRPC 服务，配置了 TLS 的话使用 TLS
+/// 超过连接数限制的连接会被直接关闭，客户端会看到 UNAVAILABLE
+pub async fn serve_grpc<Store: Storage>(
+    incoming: Incoming,
+    service: Service<Store>,
+) -> Result<(), KvError> {
+    info!("Serving gRPC on {}", incoming.local_addr()?);
+    // 要先完成 HTTP/2 握手才能回复 RESOURCE_EXHAUSTED，超过连接数限制时直接关闭连接
+    let (incoming, limiter) = incoming.split(&["h2"], None);
+    let mut service = GrpcService::new(service);
+    if let Some(limiter) = limiter {
+        service = service.rate_limit(limiter);
+    }
+    Server::builder()
+        .add_service(service.into_server())
+        .serve_with_incoming(incoming)
+        .await
+        .map_err(|e| KvError::Internal(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{kv_service_client::KvServiceClient, Acl, Permission, ServiceInner, Value};
+    use std::io;
+    use tokio::{io::duplex, net::TcpListener};
+    use tonic::{
+        metadata::MetadataValue,
+        transport::{Channel, Endpoint, Uri},
+    };
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn grpc_execute_should_work() {
+        let mut client = connect(ServiceInner::new(MemTable::new()).into()).await;
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd).await.unwrap().into_inner();
+        assert_eq!(res.status, 200);
+        let res = client
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(res.values, [Value::from("v1")]);
+
+        let cmd = CommandRequest::new_subscribe("lobby");
+        let res = client.execute(cmd).await.unwrap().into_inner();
+        assert_eq!(res.status, 400);
+    }
+
+    #[tokio::test]
+    async fn grpc_subscribe_should_stream_published_values() {
+        let mut client = connect(ServiceInner::new(MemTable::new()).into()).await;
+
+        let sub = Subscribe {
+            topic: "lobby".into(),
+        };
+        let mut stream = client.subscribe(sub).await.unwrap().into_inner();
+        let first = stream.message().await.unwrap().unwrap();
+        assert_eq!(first.status, 200);
+
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        client.execute(cmd).await.unwrap();
+        let res = stream.message().await.unwrap().unwrap();
+        assert_eq!(res.values, [Value::from("hello")]);
+    }
+
+    #[tokio::test]
+    async fn grpc_should_authenticate_with_metadata() {
+        let mut acl = Acl::default();
+        acl.add_user("app", "secret", [("t1".into(), Permission::Read)]);
+        let mut client = connect(ServiceInner::new(MemTable::new()).acl(acl).into()).await;
+
+        let res = client
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+        assert_eq!(res.into_inner().status, 403);
+
+        let mut request = Request::new(CommandRequest::new_hget("t1", "k1"));
+        let token = MetadataValue::from_str("Bearer secret").unwrap();
+        request.metadata_mut().insert("authorization", token);
+        let res = client.execute(request).await.unwrap();
+        assert_eq!(res.into_inner().status, 404);
+
+        let sub = Subscribe {
+            topic: "lobby".into(),
+        };
+        let status = client.subscribe(sub).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn grpc_should_limit_requests_by_peer_ip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = Incoming::new(listener).rate_limit(Arc::new(RateLimiter::new(1, 1)));
+        tokio::spawn(serve_grpc(
+            incoming,
+            ServiceInner::new(MemTable::new()).into(),
+        ));
+
+        let mut client = KvServiceClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute(cmd.clone()).await.unwrap().into_inner();
+        assert_eq!(res.status, 404);
+        let res = client.execute(cmd).await.unwrap().into_inner();
+        assert_eq!(res.status, 429);
+
+        let sub = Subscribe {
+            topic: "lobby".into(),
+        };
+        let status = client.subscribe(sub).await.unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    // 通过 duplex 在进程内建立 gRPC 连接
+    async fn connect(service: Service) -> KvServiceClient<Channel> {
+        let (client, server) = duplex(4096);
+        let incoming = futures::stream::iter([Ok::<_, io::Error>(server)]);
+        tokio::spawn(
+            Server::builder()
+                .add_service(GrpcService::new(service).into_server())
+                .serve_with_incoming(incoming),
+        );
+
+        let mut client = Some(client);
+        let channel = Endpoint::try_from("http://[::]:50051")
+            .unwrap()
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let client = client.take();
+                async move { client.ok_or_else(|| io::Error::other("already connected")) }
+            }))
+            .await
+            .unwrap();
+        KvServiceClient::new(channel)
+    }
+}
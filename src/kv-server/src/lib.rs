@@ -8,13 +8,28 @@
 // 再需要把查询结果返回去
 // 整个过程应该支持异步编程
 
+mod auth;
+mod client;
 mod error;
+mod gateway;
+mod grpc;
+mod metrics;
 mod network;
 mod pb;
+mod replication;
+mod server;
 mod service;
 mod storage;
+pub use auth::*;
+pub use client::*;
 pub use error::KvError;
+pub use gateway::*;
+pub use grpc::*;
+pub use metrics::*;
 pub use network::*;
 pub use pb::abi::*;
+pub use pb::Redacted;
+pub use replication::*;
+pub use server::*;
 pub use service::*;
 pub use storage::*;
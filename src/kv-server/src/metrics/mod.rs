@@ -0,0 +1,310 @@
+use prometheus_client::{
+    encoding::{text, EncodeLabelSet},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+use crate::{Completion, KvError, Service, Storage};
+
+// 读取 HTTP 请求头的上限
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CommandLabels {
+    command: &'static str,
+    status: u32,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LatencyLabels {
+    command: &'static str,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ErrorLabels {
+    error: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TableLabels {
+    table: String,
+}
+
+/// KV Server 的 Prometheus metrics
+/// 命令相关的数据通过 ServiceInner::metrics 注册的 hook 记录，table 的 key 数量在抓取时统计
+pub struct Metrics {
+    registry: Registry,
+    commands: Family<CommandLabels, Counter>,
+    errors: Family<ErrorLabels, Counter>,
+    latency: Family<LatencyLabels, Histogram, fn() -> Histogram>,
+    connections: Gauge,
+    connections_total: Counter,
+    keys: Family<TableLabels, Gauge>,
+}
+
+/// 一个活跃的连接，drop 时减少连接数
+pub struct ConnectionGuard(Arc<Metrics>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.dec();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("kv"),
+            commands: Default::default(),
+            errors: Default::default(),
+            // 100us 到 3.2s
+            latency: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(1e-4, 2.0, 16))
+            }),
+            connections: Default::default(),
+            connections_total: Default::default(),
+            keys: Default::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "commands",
+            "Number of executed commands",
+            metrics.commands.clone(),
+        );
+        registry.register(
+            "errors",
+            "Number of failed commands by error kind",
+            metrics.errors.clone(),
+        );
+        registry.register(
+            "command_duration_seconds",
+            "Time to execute a command",
+            metrics.latency.clone(),
+        );
+        registry.register(
+            "connections",
+            "Number of active connections",
+            metrics.connections.clone(),
+        );
+        registry.register(
+            "connections_accepted",
+            "Number of accepted connections",
+            metrics.connections_total.clone(),
+        );
+        registry.register(
+            "table_keys",
+            "Number of keys in each table",
+            metrics.keys.clone(),
+        );
+        metrics
+    }
+
+    /// 记录一个执行完成的命令
+    pub fn record(&self, completion: &Completion) {
+        let command = completion.command;
+        let labels = CommandLabels {
+            command,
+            status: completion.status,
+        };
+        self.commands.get_or_create(&labels).inc();
+        self.latency
+            .get_or_create(&LatencyLabels { command })
+            .observe(completion.elapsed.as_secs_f64());
+        if !completion.error.is_empty() {
+            let labels = ErrorLabels {
+                error: completion.error.clone(),
+            };
+            self.errors.get_or_create(&labels).inc();
+        }
+    }
+
+    /// 记录一个新的连接，返回的 guard 在连接关闭时 drop
+    pub fn connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.inc();
+        self.connections_total.inc();
+        ConnectionGuard(Arc::clone(self))
+    }
+
+    /// 统计每个 table 的 key 数量，已经删除的 table 不再输出
+    pub fn update_tables(&self, store: &impl Storage) -> Result<(), KvError> {
+        let mut counts = Vec::new();
+        for table in store.tables()? {
+            let count = store.count(&table)?;
+            counts.push((table, count));
+        }
+        self.keys.clear();
+        for (table, count) in counts {
+            self.keys
+                .get_or_create(&TableLabels { table })
+                .set(count as i64);
+        }
+        Ok(())
+    }
+
+    /// 输出 Prometheus 的文本格式
+    pub fn encode(&self) -> Result<String, KvError> {
+        let mut buf = String::new();
+        text::encode(&mut buf, &self.registry).map_err(|e| KvError::Internal(e.to_string()))?;
+        Ok(buf)
+    }
+}
+
+/// 在 listener 上提供 HTTP 接口，GET /metrics 返回 metrics
+pub async fn serve_metrics<Store: Storage>(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    service: Service<Store>,
+) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving metrics on http://{}/metrics", addr);
+    }
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        let (metrics, service) = (Arc::clone(&metrics), service.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_http(stream, metrics, service).await {
+                warn!("Failed to serve metrics: {}", e);
+            }
+        });
+    }
+}
+
+// 只支持最简单的 HTTP/1.x 请求，回复之后关闭连接
+async fn handle_http<Store: Storage>(
+    mut stream: TcpStream,
+    metrics: Arc<Metrics>,
+    service: Service<Store>,
+) -> Result<(), KvError> {
+    let mut buf = Vec::new();
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_HEAD {
+            return Err(KvError::Internal("HTTP request head is too large".into()));
+        }
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.split_whitespace();
+    let (method, target) = (parts.next(), parts.next().unwrap_or_default());
+    let path = target.split('?').next();
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => {
+            let m = Arc::clone(&metrics);
+            service
+                .with_store(move |store| m.update_tables(store))
+                .await??;
+            ("200 OK", metrics.encode()?)
+        }
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    let res = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(res.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, ServiceInner};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn metrics_should_record_commands() {
+        let metrics = Arc::new(Metrics::new());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .metrics(Arc::clone(&metrics))
+            .into();
+        let cmds = [
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hset("t1", "k2", "v2".into()),
+            CommandRequest::new_hget("t1", "k3"),
+            CommandRequest::new_hincrby("t1", "k1", 1),
+        ];
+        for cmd in cmds {
+            service.execute(cmd).next().await.unwrap();
+        }
+        let _conn = metrics.connection();
+
+        let text = metrics.encode().unwrap();
+        for line in [
+            r#"kv_commands_total{command="hset",status="200"} 2"#,
+            r#"kv_commands_total{command="hget",status="404"} 1"#,
+            r#"kv_errors_total{error="NotFound"} 1"#,
+            r#"kv_errors_total{error="InvalidCommand"} 1"#,
+            r#"kv_command_duration_seconds_count{command="hset"} 2"#,
+            "kv_connections 1",
+            "kv_connections_accepted_total 1",
+        ] {
+            assert!(text.contains(line), "{} not in\n{}", line, text);
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_should_work() {
+        let metrics = Arc::new(Metrics::new());
+        let service: Service = ServiceInner::new(MemTable::new())
+            .metrics(Arc::clone(&metrics))
+            .into();
+        for table in ["t1", "t2"] {
+            let cmd = CommandRequest::new_hset(table, "k1", "v1".into());
+            service.execute(cmd).next().await.unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(listener, metrics, service));
+
+        let res = http_get(addr, "/metrics").await;
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
+        assert!(res.contains(r#"kv_table_keys{table="t1"} 1"#));
+        assert!(res.contains(r#"kv_table_keys{table="t2"} 1"#));
+
+        let res = http_get(addr, "/foo").await;
+        assert!(res.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    async fn http_get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res
+    }
+}
@@ -1,22 +1,92 @@
 use crate::{CommandRequest, CommandResponse, KvError};
 use bytes::{Buf, BufMut, BytesMut};
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 
-use flate2::{read::GzDecoder, write::GzEncoder, Compress, Compression};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
-use tokio::io::{AsyncBufRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
+// frame header 是一个 u32（大端）：
+// | 31: 是否压缩 | 30-29: 压缩算法 | 28-0: 长度 |
+// 没压缩时，30-29 位用来告诉对端自己偏好的压缩算法，对端回复大消息时可以照着用
 pub const LEN_LEN: usize = 4;
-const MAX_FRAME: usize = 2 * 1024 * 1024 * 1024;
+// 长度字段只有 29 位，实际限制在 64M 以内
+pub const MAX_FRAME: usize = 64 * 1024 * 1024;
 const COMPRESSION_LIMIT: usize = 1436;
+// 跳过超长的 frame 时每次最多读取的字节数
+const SKIP_CHUNK: usize = 64 * 1024;
 const COMPRESSION_BIT: usize = 1 << 31;
+const COMPRESSOR_SHIFT: usize = 29;
+const COMPRESSOR_MASK: usize = 0b11 << COMPRESSOR_SHIFT;
+const LEN_MASK: usize = (1 << COMPRESSOR_SHIFT) - 1;
+
+/// 支持的压缩算法，编号写在 frame header 的 30-29 位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compressor {
+    #[default]
+    Gzip = 0,
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl Compressor {
+    fn from_bits(bits: usize) -> Result<Self, KvError> {
+        match bits {
+            0 => Ok(Compressor::Gzip),
+            1 => Ok(Compressor::Zstd),
+            2 => Ok(Compressor::Lz4),
+            _ => Err(KvError::FrameError),
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>, KvError> {
+        let payload = match self {
+            Compressor::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            Compressor::Zstd => zstd::stream::encode_all(data, 0)?,
+            Compressor::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder.write_all(data)?;
+                encoder
+                    .finish()
+                    .map_err(|e| KvError::IoError(e.to_string()))?
+            }
+        };
+        Ok(payload)
+    }
+
+    // 解压后的数据同样不能超过 max_frame，避免被压缩炸弹撑爆内存
+    fn decompress(self, data: &[u8], max_frame: usize) -> Result<Vec<u8>, KvError> {
+        let reader: Box<dyn Read + '_> = match self {
+            Compressor::Gzip => Box::new(GzDecoder::new(data)),
+            Compressor::Zstd => Box::new(zstd::stream::read::Decoder::new(data)?),
+            Compressor::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(data)),
+        };
+
+        let mut buf = Vec::with_capacity((data.len() * 2).min(max_frame));
+        reader.take(max_frame as u64 + 1).read_to_end(&mut buf)?;
+        if buf.len() > max_frame {
+            return Err(KvError::FrameTooLarge(buf.len()));
+        }
+        Ok(buf)
+    }
+}
 
 pub trait FrameCoder
 where
     Self: Message + Sized + Default,
 {
+    /// 把消息编码成 frame，超过 COMPRESSION_LIMIT 时用 gzip 压缩
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<(), KvError> {
+        self.encode_frame_with(buf, Compressor::default())
+    }
+
+    /// 把消息编码成 frame，超过 COMPRESSION_LIMIT 时用指定的算法压缩
+    fn encode_frame_with(&self, buf: &mut BytesMut, compressor: Compressor) -> Result<(), KvError> {
         // 获取信息长度
         let size = self.encoded_len();
 
@@ -25,67 +95,157 @@ where
             return Err(KvError::FrameError);
         }
 
-        // 把信息长度装入buf
-
-        buf.put_u32(size as _);
-
-        // 判断 是否超过压缩长度
-
-        if size > COMPRESSION_LIMIT {
-            // 创建一个新buf
-            let mut buf1 = Vec::with_capacity(size);
-            // 编码进buf
-
-            self.encode(&mut buf1)?;
+        let preferred = (compressor as usize) << COMPRESSOR_SHIFT;
 
-            // 拿走4字节长度，清空
-
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear();
+        // 没有超过压缩长度，直接写入长度和消息
+        if size <= COMPRESSION_LIMIT {
+            buf.reserve(LEN_LEN + size);
+            buf.put_u32((preferred | size) as _);
+            self.encode(buf)?;
+            return Ok(());
+        }
 
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
-            encoder.write_all(&buf1[..]);
+        // 先编码到一个新 buf，再压缩
+        let mut buf1 = Vec::with_capacity(size);
+        self.encode(&mut buf1)?;
+        let payload = compressor.compress(&buf1)?;
+        if payload.len() > MAX_FRAME {
+            return Err(KvError::FrameError);
+        }
+        debug!(
+            "Encode a frame: size {}({}), compressor {:?}",
+            size,
+            payload.len(),
+            compressor
+        );
+
+        // 写入压缩后的长度，并设置 compression bit
+        buf.reserve(LEN_LEN + payload.len());
+        buf.put_u32((COMPRESSION_BIT | preferred | payload.len()) as _);
+        buf.put_slice(&payload);
+        Ok(())
+    }
 
-            //   let payload = encoder.finish()?.into_inner();
-            let payload = encoder.finish();
-            //   debug!("Encode a frame:size {}({})", size, payload.len());
+    fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
+        Self::decode_frame_with(buf, MAX_FRAME).map(|(msg, _)| msg)
+    }
 
-            //   buf.unsplit(payload);
+    /// 解码 frame，同时返回对端在 header 里使用或偏好的压缩算法
+    /// 解压后超过 max_frame 时返回 FrameTooLarge，这时 frame 已经从 buf 里取出，可以继续解码下一个
+    /// header 不对或者 frame 不完整时返回 FrameError，不会修改 buf
+    fn decode_frame_with(
+        buf: &mut BytesMut,
+        max_frame: usize,
+    ) -> Result<(Self, Compressor), KvError> {
+        if buf.len() < LEN_LEN {
+            return Err(KvError::FrameError);
+        }
 
-            Ok(())
-        } else {
-            self.encode(buf)?;
-            Ok(())
+        // 先看 4 字节的 header，从中拿出长度、compression bit 和压缩算法
+        // 确认整个 frame 都在 buf 里之后才取出 header，否则 buf 保持不变
+        let header = (&buf[..LEN_LEN]).get_u32() as usize;
+        let (len, compressed, compressor) = decode_header(header)?;
+        debug!(
+            "Got a frame: msg len {}, compressed {}, compressor {:?}",
+            len, compressed, compressor
+        );
+        if LEN_LEN + len > buf.len() {
+            return Err(KvError::FrameError);
         }
-    }
 
-    fn decode_from(buf: &mut BytesMut) -> Result<Self, KvError> {
-        // 先取 4 字节，从中拿出长度和 compression bit
-        let header = buf.get_u32() as usize;
-        let (len, compressed) = decode_header(header);
-        debug!("Got a frame: msg len {}, compressed {}", len, compressed);
-        if compressed {
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            let mut buf1 = Vec::with_capacity(len * 2);
-            decoder.read_to_end(&mut buf1);
-            buf.advance(len);
+        buf.advance(LEN_LEN);
+        let payload = buf.split_to(len);
+        let msg = if compressed {
             // decode 成相应的消息
-            Ok(Self::decode(&buf1[..buf1.len()])?)
+            Self::decode(&compressor.decompress(&payload, max_frame)?[..])?
         } else {
-            let msg = Self::decode(&buf[..len])?;
-            buf.advance(len);
-            Ok(msg)
-        }
+            Self::decode(&payload[..])?
+        };
+        Ok((msg, compressor))
     }
 }
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
 
-fn decode_header(header: usize) -> (usize, bool) {
-    let len = header & !COMPRESSION_BIT;
+fn decode_header(header: usize) -> Result<(usize, bool, Compressor), KvError> {
+    let len = header & LEN_MASK;
     let compressed = header & COMPRESSION_BIT == COMPRESSION_BIT;
-    (len, compressed)
+    let compressor = Compressor::from_bits((header & COMPRESSOR_MASK) >> COMPRESSOR_SHIFT)?;
+    if len > MAX_FRAME {
+        return Err(KvError::FrameError);
+    }
+    Ok((len, compressed, compressor))
+}
+
+/// 从 stream 里读出一个完整的 frame 放到 buf 中：先读 4 字节 header，再读够 len 字节
+/// 已经读到的数据都留在 buf 里，所以 future 被中途丢弃也不会丢数据，下次接着读即可
+/// 对端在 frame 边界上正常关闭时返回 Ok(false)
+pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<bool, KvError>
+where
+    S: AsyncRead + Unpin,
+{
+    read_frame_with(stream, buf, MAX_FRAME).await
+}
+
+/// 和 read_frame 一样，但 frame 超过 max_frame 时返回 FrameTooLarge，header 留在 buf 的开头
+/// 调用者可以用 skip_frame 跳过它，继续读下一个 frame
+pub async fn read_frame_with<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    max_frame: usize,
+) -> Result<bool, KvError>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        let want = if buf.len() >= LEN_LEN {
+            // 超长的 frame 直接报错，不用等 body 读完
+            let (len, _, _) = decode_header((&buf[..LEN_LEN]).get_u32() as usize)?;
+            if len > max_frame {
+                return Err(KvError::FrameTooLarge(len));
+            }
+            if buf.len() >= LEN_LEN + len {
+                return Ok(true);
+            }
+            LEN_LEN + len
+        } else {
+            LEN_LEN
+        };
+
+        buf.reserve(want - buf.len());
+        if stream.read_buf(buf).await? == 0 {
+            if buf.is_empty() {
+                return Ok(false);
+            }
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
+/// 丢掉接下来的 remaining 字节，先丢 buf 里已有的数据，读出来的数据不会一直占着内存
+/// 和 read_frame 一样可以被中途丢弃，remaining 记录了还要丢掉多少
+pub async fn skip_frame<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    remaining: &mut usize,
+) -> Result<(), KvError>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        let n = buf.len().min(*remaining);
+        buf.advance(n);
+        *remaining -= n;
+        if *remaining == 0 {
+            return Ok(());
+        }
+
+        buf.reserve((*remaining).min(SKIP_CHUNK));
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
 }
 
 #[cfg(test)]
@@ -102,7 +262,7 @@ mod tests {
         cmd.encode_frame(&mut buf).unwrap();
 
         // 最高位没设置
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
 
         let cmd1 = CommandRequest::decode_frame(&mut buf).unwrap();
         assert_eq!(cmd, cmd1);
@@ -117,7 +277,7 @@ mod tests {
         res.encode_frame(&mut buf).unwrap();
 
         // 最高位没设置
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
 
         let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
         assert_eq!(res, res1);
@@ -132,12 +292,132 @@ mod tests {
         res.encode_frame(&mut buf).unwrap();
 
         // 最高位设置了
-        assert_eq!(is_compressed(&buf), true);
+        assert!(is_compressed(&buf));
 
         let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
         assert_eq!(res, res1);
     }
 
+    #[test]
+    fn command_response_zstd_and_lz4_encode_decode_should_work() {
+        for compressor in [Compressor::Zstd, Compressor::Lz4] {
+            let mut buf = BytesMut::new();
+
+            let value: Value = Bytes::from(vec![1u8; COMPRESSION_LIMIT * 10]).into();
+            let res: CommandResponse = value.into();
+            res.encode_frame_with(&mut buf, compressor).unwrap();
+
+            assert!(is_compressed(&buf));
+            // 压缩后的长度应该比原始数据短
+            assert!(buf.len() < COMPRESSION_LIMIT * 10);
+
+            let (res1, compressor1) =
+                CommandResponse::decode_frame_with(&mut buf, MAX_FRAME).unwrap();
+            assert_eq!(res, res1);
+            assert_eq!(compressor, compressor1);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn uncompressed_frame_should_carry_preferred_compressor() {
+        let mut buf = BytesMut::new();
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        cmd.encode_frame_with(&mut buf, Compressor::Lz4).unwrap();
+        assert!(!is_compressed(&buf));
+
+        let (cmd1, compressor) = CommandRequest::decode_frame_with(&mut buf, MAX_FRAME).unwrap();
+        assert_eq!(cmd, cmd1);
+        assert_eq!(compressor, Compressor::Lz4);
+    }
+
+    #[test]
+    fn oversized_frame_should_be_rejected() {
+        let mut buf = BytesMut::new();
+
+        let value: Value = Bytes::from(vec![0u8; MAX_FRAME + 1]).into();
+        let res: CommandResponse = value.into();
+        assert_eq!(res.encode_frame(&mut buf), Err(KvError::FrameError));
+
+        // header 里声明的长度超过 MAX_FRAME 也要报错
+        buf.put_u32((MAX_FRAME + 1) as _);
+        assert_eq!(
+            CommandResponse::decode_frame(&mut buf),
+            Err(KvError::FrameError)
+        );
+    }
+
+    #[test]
+    fn truncated_frame_should_be_rejected() {
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hget("t1", "k1")
+            .encode_frame(&mut buf)
+            .unwrap();
+        let full = buf.clone();
+        buf.truncate(buf.len() - 1);
+
+        assert_eq!(
+            CommandRequest::decode_frame(&mut buf),
+            Err(KvError::FrameError)
+        );
+        // 失败时 buf 不变，数据到齐后可以重新解码
+        buf.extend_from_slice(&full[buf.len()..]);
+        assert_eq!(
+            CommandRequest::decode_frame(&mut buf),
+            Ok(CommandRequest::new_hget("t1", "k1"))
+        );
+    }
+
+    #[test]
+    fn corrupted_gzip_frame_should_return_error() {
+        let mut buf = BytesMut::new();
+        buf.put_u32((COMPRESSION_BIT | 4) as _);
+        buf.put_slice(b"oops");
+
+        assert!(matches!(
+            CommandResponse::decode_frame(&mut buf),
+            Err(KvError::IoError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_frame_should_reject_oversized_header_early() {
+        let mut buf = BytesMut::new();
+        let mut data = &((MAX_FRAME + 1) as u32).to_be_bytes()[..];
+        assert_eq!(
+            read_frame(&mut data, &mut buf).await,
+            Err(KvError::FrameError)
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_should_be_skipped() {
+        let mut data = BytesMut::new();
+        CommandRequest::new_hset("t1", "k1", "v1".into())
+            .encode_frame(&mut data)
+            .unwrap();
+        let len = data.len() - LEN_LEN;
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        cmd.encode_frame(&mut data).unwrap();
+
+        let mut stream = &data[..];
+        let mut buf = BytesMut::new();
+        assert_eq!(
+            read_frame_with(&mut stream, &mut buf, len - 1).await,
+            Err(KvError::FrameTooLarge(len))
+        );
+        let mut remaining = LEN_LEN + len;
+        skip_frame(&mut stream, &mut buf, &mut remaining)
+            .await
+            .unwrap();
+        assert_eq!(
+            read_frame_with(&mut stream, &mut buf, len - 1).await,
+            Ok(true)
+        );
+        assert_eq!(CommandRequest::decode_frame(&mut buf), Ok(cmd));
+    }
+
     fn is_compressed(data: &[u8]) -> bool {
         if let &[v] = &data[..1] {
             v >> 7 == 1
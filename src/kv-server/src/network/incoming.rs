@@ -0,0 +1,236 @@
+use bytes::Bytes;
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    time,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+
+use super::{Io, RateLimiter, TlsServerAcceptor, HANDSHAKE_TIMEOUT};
+use crate::KvError;
+
+// 已经 accept 但还没被取走的连接数
+const BACKLOG: usize = 64;
+/// 拒绝连接时最多花多少时间回复客户端
+pub(crate) const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+/// 一个 listener 最多同时回复多少个被拒绝的连接，再多的连接直接关闭
+pub(crate) const MAX_REJECTING: usize = 64;
+
+/// HTTP 网关和 gRPC 这类自己处理连接的服务使用的 listener
+/// 可以配置 TLS、最大连接数和每个客户端 IP 的请求速率
+pub struct Incoming {
+    listener: TcpListener,
+    acceptor: Option<TlsServerAcceptor>,
+    permits: Option<Arc<Semaphore>>,
+    limiter: Option<Arc<RateLimiter>>,
+}
+
+impl Incoming {
+    pub fn new(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            acceptor: None,
+            permits: None,
+            limiter: None,
+        }
+    }
+
+    /// 使用 TLS，ALPN 由具体的服务设置
+    pub fn tls(mut self, acceptor: TlsServerAcceptor) -> Self {
+        self.acceptor = Some(acceptor);
+        self
+    }
+
+    /// 每个连接占用一个 permit，拿不到时回复 429 之后关闭新的连接
+    /// gRPC 要先完成 HTTP/2 握手才能回复，所以超过限制的 gRPC 连接直接关闭，客户端会看到 UNAVAILABLE
+    /// 可以和其它 listener 共享同一个 Semaphore
+    pub fn max_connections(mut self, permits: Arc<Semaphore>) -> Self {
+        self.permits = Some(permits);
+        self
+    }
+
+    /// 按客户端 IP 限制请求速率，由具体的服务在每个请求上检查
+    pub fn rate_limit(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, KvError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub(crate) fn is_tls(&self) -> bool {
+        self.acceptor.is_some()
+    }
+
+    /// 拆成连接的 stream 和 limiter，alpn 是 TLS 握手时协商的协议
+    /// 超过连接数限制时回复 refusal 之后关闭连接，没有 refusal 时直接关闭
+    /// 在后台 accept 连接，配置了 TLS 的话先完成握手，握手失败的连接直接关闭
+    /// 返回的 stream 被 drop 之后停止 accept
+    pub(crate) fn split(
+        self,
+        alpn: &[&str],
+        refusal: Option<Bytes>,
+    ) -> (ReceiverStream<io::Result<Conn>>, Option<Arc<RateLimiter>>) {
+        let Self {
+            listener,
+            acceptor,
+            permits,
+            limiter,
+        } = self;
+        let acceptor = acceptor.map(|v| v.alpn(alpn));
+        let rejecting = Arc::new(Semaphore::new(MAX_REJECTING));
+        let (tx, rx) = mpsc::channel(BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("Failed to accept connection: {}", e);
+                            continue;
+                        }
+                    },
+                    _ = tx.closed() => break,
+                };
+                let permit = match &permits {
+                    Some(permits) => match Arc::clone(permits).try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            warn!("Too many connections, rejected {}", addr);
+                            let permit = Arc::clone(&rejecting).try_acquire_owned();
+                            if let (Some(reply), Ok(permit)) = (refusal.clone(), permit) {
+                                let acceptor = acceptor.clone();
+                                tokio::spawn(refuse(stream, addr, acceptor, reply, permit));
+                            }
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let Some(acceptor) = acceptor.clone() else {
+                    let conn = Conn {
+                        stream: Box::new(stream),
+                        addr,
+                        _permit: permit,
+                    };
+                    if tx.send(Ok(conn)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                };
+                // 在单独的任务里握手，慢的客户端不会挡住其它连接
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let conn = Conn {
+                                stream: Box::new(stream),
+                                addr,
+                                _permit: permit,
+                            };
+                            let _ = tx.send(Ok(conn)).await;
+                        }
+                        Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => warn!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        (ReceiverStream::new(rx), limiter)
+    }
+}
+
+/// 回复 reply 之后关闭被拒绝的连接，最多花 REJECT_TIMEOUT，结束后释放 permit
+pub(crate) async fn refuse(
+    stream: TcpStream,
+    addr: SocketAddr,
+    acceptor: Option<TlsServerAcceptor>,
+    reply: Bytes,
+    _permit: OwnedSemaphorePermit,
+) {
+    let reply = async move {
+        match acceptor {
+            Some(acceptor) => write_and_close(acceptor.accept(stream).await?, &reply).await,
+            None => write_and_close(stream, &reply).await,
+        }
+    };
+    match time::timeout(REJECT_TIMEOUT, reply).await {
+        Ok(Err(e)) => warn!("Failed to reject {}: {}", addr, e),
+        Err(_) => warn!("Timed out rejecting {}", addr),
+        Ok(Ok(())) => {}
+    }
+}
+
+async fn write_and_close<S>(mut stream: S, reply: &[u8]) -> Result<(), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(reply).await?;
+    stream.shutdown().await?;
+    // 读完客户端已经发来的数据再关闭，否则客户端可能收到 RST 而读不到回复
+    let mut buf = [0u8; 4096];
+    while stream.read(&mut buf).await? > 0 {}
+    Ok(())
+}
+
+/// accept 下来的 TCP 或者 TLS 连接，关闭时释放占用的 permit
+pub(crate) struct Conn {
+    stream: Box<dyn Io>,
+    addr: SocketAddr,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// gRPC 的请求可以从 extensions 里拿到客户端的地址
+impl tonic::transport::server::Connected for Conn {
+    type ConnectInfo = SocketAddr;
+
+    fn connect_info(&self) -> Self::ConnectInfo {
+        self.addr
+    }
+}
+
+// HTTP 网关的请求可以通过 ConnectInfo 拿到客户端的地址
+impl axum::extract::connect_info::Connected<&Conn> for SocketAddr {
+    fn connect_info(target: &Conn) -> Self {
+        target.addr
+    }
+}
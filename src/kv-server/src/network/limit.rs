@@ -0,0 +1,135 @@
+use dashmap::DashMap;
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+
+use crate::KvError;
+
+// 记录的客户端超过这个数量时，清理掉已经不用的令牌桶
+const PURGE_THRESHOLD: usize = 10_000;
+
+/// 按客户端的 IP 限制每秒的请求数，每个客户端一个令牌桶
+/// 同一个 IP 的所有连接共享一个令牌桶
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<IpAddr, Arc<Mutex<Bucket>>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // 按经过的时间补充令牌，不超过 burst
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    /// 每秒补充 rate 个令牌，最多攒 burst 个，burst 为 0 时和 rate 一样
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let burst = match burst {
+            0 => rate,
+            n => n,
+        };
+        Self {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// 拿到客户端的令牌桶，连接建立时调用一次
+    pub fn client(&self, ip: IpAddr) -> ClientLimiter {
+        if self.buckets.len() > PURGE_THRESHOLD {
+            self.purge();
+        }
+        let bucket = self
+            .buckets
+            .entry(ip)
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Bucket {
+                    tokens: self.burst,
+                    updated: Instant::now(),
+                }))
+            })
+            .clone();
+        ClientLimiter {
+            ip,
+            rate: self.rate,
+            burst: self.burst,
+            bucket,
+        }
+    }
+
+    // 没有连接在用、并且已经攒满的令牌桶可以删掉，下次重新创建的效果是一样的
+    fn purge(&self) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            if Arc::strong_count(bucket) > 1 {
+                return true;
+            }
+            let mut bucket = bucket.lock().unwrap_or_else(PoisonError::into_inner);
+            bucket.refill(rate, burst);
+            bucket.tokens < burst
+        });
+    }
+}
+
+/// 一个客户端的令牌桶，见 RateLimiter::client
+#[derive(Clone)]
+pub struct ClientLimiter {
+    ip: IpAddr,
+    rate: f64,
+    burst: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl ClientLimiter {
+    /// 每个请求拿一个令牌，没有令牌时返回 RateLimited
+    pub fn acquire(&self) -> Result<(), KvError> {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        bucket.refill(self.rate, self.burst);
+        if bucket.tokens < 1.0 {
+            return Err(KvError::RateLimited(format!(
+                "{} exceeds {} requests per second",
+                self.ip, self.rate
+            )));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn rate_limiter_should_share_bucket_by_ip() {
+        let limiter = RateLimiter::new(10, 2);
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let (c1, c2) = (limiter.client(ip), limiter.client(ip));
+        assert!(c1.acquire().is_ok());
+        assert!(c2.acquire().is_ok());
+        assert!(matches!(c1.acquire(), Err(KvError::RateLimited(_))));
+
+        // 其它客户端不受影响
+        let other = limiter.client("127.0.0.2".parse().unwrap());
+        assert!(other.acquire().is_ok());
+
+        // 100ms 之后补充了一个令牌
+        thread::sleep(Duration::from_millis(120));
+        assert!(c2.acquire().is_ok());
+        assert!(c2.acquire().is_err());
+    }
+}
@@ -1 +1,559 @@
 mod frame;
+mod incoming;
+mod limit;
+mod multiplex;
+mod resp;
+mod stream;
+mod stream_result;
+mod tls;
+
+pub use frame::{read_frame, read_frame_with, skip_frame, Compressor, FrameCoder, MAX_FRAME};
+pub use incoming::Incoming;
+pub(crate) use incoming::{refuse, MAX_REJECTING};
+pub use limit::{ClientLimiter, RateLimiter};
+pub use multiplex::YamuxCtrl;
+pub use resp::{RespCodec, RespServerStream, RespValue};
+pub use stream::ProstStream;
+pub use stream_result::StreamResult;
+pub(crate) use tls::HANDSHAKE_TIMEOUT;
+pub use tls::{TlsClientConnector, TlsServerAcceptor};
+
+use futures::{future::BoxFuture, stream::SelectAll, FutureExt, SinkExt, StreamExt};
+use std::{collections::VecDeque, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{info, warn};
+
+use crate::{
+    command_request::RequestData, CommandRequest, CommandResponse, KvError, Service, Session,
+    Storage, StreamingResponse,
+};
+
+/// TCP 或者 TLS 连接，用于需要同时处理两种连接的地方
+pub(crate) trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// 一个连接默认最多排队等待执行的请求数
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 16;
+
+/// 处理服务器端的某个 accept 下来的 socket 的读写
+pub struct ProstServerStream<S, Store> {
+    inner: ProstStream<S, CommandRequest, CommandResponse>,
+    service: Service<Store>,
+    session: Session,
+    max_in_flight: usize,
+    limiter: Option<ClientLimiter>,
+}
+
+// 排队等待回复的请求：第一个 response，返回 stream 的命令剩下的 response，以及是否占用 in-flight
+type Pending = BoxFuture<'static, (Arc<CommandResponse>, Option<StreamingResponse>, bool)>;
+
+/// 处理客户端 socket 的读写
+pub struct ProstClientStream<S> {
+    inner: ProstStream<S, CommandResponse, CommandRequest>,
+}
+
+impl<S, Store> ProstServerStream<S, Store>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: Storage,
+{
+    pub fn new(stream: S, service: Service<Store>) -> Self {
+        Self {
+            inner: ProstStream::new(stream),
+            service,
+            session: Session::default(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            limiter: None,
+        }
+    }
+
+    /// 最多排队等待执行的请求数，超过的请求直接返回 429
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    /// 请求的最大长度，超过的请求不会被读进内存，直接返回 413
+    pub fn max_frame(mut self, max_frame: usize) -> Self {
+        self.inner.set_max_frame(max_frame);
+        self
+    }
+
+    /// 按客户端限制每秒的请求数，超过的请求直接返回 429
+    pub fn rate_limit(mut self, limiter: ClientLimiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// 使用已有的 session，比如 yamux 同一个连接上的 stream 共享一个 session
+    /// 这样在一个 stream 上订阅，可以在另一个 stream 上取消订阅，在一个 stream 上 AUTH 对所有 stream 都有效
+    pub fn session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+
+    // 不断读取请求，交给 service 执行，再把响应写回去，直到对端关闭
+    // 客户端可以不等响应连续发送请求，请求会被读进队列，按顺序一个一个执行
+    pub async fn process(mut self) -> Result<(), KvError> {
+        let mut pending: VecDeque<Pending> = VecDeque::new();
+        let mut in_flight = 0;
+        // 返回 stream 的命令剩下的 response，推送的同时继续读取请求
+        // 这样可以在同一个连接上取消订阅，对端关闭时也能马上停止订阅和监听
+        let mut streams: SelectAll<StreamingResponse> = SelectAll::new();
+        loop {
+            // 被拒绝的请求也要排队回复，排队的太多时先不读了
+            let readable = pending.len() < 2 * self.max_in_flight;
+            tokio::select! {
+                // 优先读取请求，这样才能及时发现排队太多的请求
+                biased;
+                cmd = self.inner.next(), if readable => match cmd {
+                    Some(Ok(cmd)) => {
+                        let admitted = match in_flight < self.max_in_flight {
+                            true => self.limiter.as_ref().map_or(Ok(()), |v| v.acquire()),
+                            false => Err(KvError::RateLimited(format!(
+                                "more than {} requests in flight",
+                                self.max_in_flight
+                            ))),
+                        };
+                        match admitted {
+                            Ok(()) => {
+                                in_flight += 1;
+                                let streaming = is_streaming(&cmd);
+                                pending.push_back(self.execute(cmd, streaming));
+                            }
+                            Err(e) => {
+                                warn!("Reject command {}: {}", cmd.name(), e);
+                                pending.push_back(reject(e));
+                            }
+                        }
+                    }
+                    // 超长的 frame 已经被跳过了，连接还可以继续用
+                    Some(Err(e @ KvError::FrameTooLarge(_))) => {
+                        warn!("Reject request: {}", e);
+                        pending.push_back(reject(e));
+                    }
+                    Some(Err(e)) => return Err(e),
+                    None => break,
+                },
+                (res, rest, admitted) = front(&mut pending), if !pending.is_empty() => {
+                    pending.pop_front();
+                    if admitted {
+                        in_flight -= 1;
+                    }
+                    self.send(&res).await?;
+                    if let Some(rest) = rest {
+                        streams.push(rest);
+                    }
+                }
+                Some(res) = streams.next(), if !streams.is_empty() => self.send(&res).await?,
+            }
+        }
+
+        // 对端关闭了写，已经收到的请求还是要回复，订阅和监听则直接结束
+        drop(streams);
+        // 对端关闭了写，已经收到的请求还是要回复
+        while let Some(res) = pending.pop_front() {
+            let (res, _, _) = res.await;
+            self.send(&res).await?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, cmd: CommandRequest, streaming: bool) -> Pending {
+        info!("Got a new command: {:?}", cmd.redacted());
+        // 回复时使用客户端偏好的压缩算法
+        if let Some(compressor) = self.inner.peer_compressor() {
+            self.inner.set_compressor(compressor);
+        }
+        // AUTH 在 execute_in 里同步修改 session，所以之后的请求都能看到认证的结果
+        let res = self.service.execute_in(cmd, &mut self.session);
+        res.into_future()
+            .map(move |(first, rest)| {
+                let first = first.unwrap_or_else(|| {
+                    Arc::new(KvError::Internal("Didn't get any response".into()).into())
+                });
+                (first, streaming.then_some(rest), true)
+            })
+            .boxed()
+    }
+
+    async fn send(&mut self, res: &CommandResponse) -> Result<(), KvError> {
+        self.inner.send(res).await?;
+        self.service.notify_after_send();
+        Ok(())
+    }
+}
+
+// 只执行队列里的第一个请求，保证同一个连接上的请求按顺序执行
+async fn front(
+    pending: &mut VecDeque<Pending>,
+) -> (Arc<CommandResponse>, Option<StreamingResponse>, bool) {
+    match pending.front_mut() {
+        Some(fut) => fut.await,
+        None => futures::future::pending().await,
+    }
+}
+
+fn reject(e: KvError) -> Pending {
+    let res = Arc::new(CommandResponse::from(e));
+    futures::future::ready((res, None, false)).boxed()
+}
+
+// 会一直返回 response 的命令
+fn is_streaming(cmd: &CommandRequest) -> bool {
+    matches!(
+        cmd.request_data,
+        Some(RequestData::Subscribe(_))
+            | Some(RequestData::Replicate(_))
+            | Some(RequestData::Watch(_))
+    )
+}
+
+impl<S> ProstClientStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S) -> Self {
+        Self {
+            inner: ProstStream::new(stream),
+        }
+    }
+
+    /// 指定客户端偏好的压缩算法，服务器回复大消息时也会使用它
+    pub fn with_compressor(mut self, compressor: Compressor) -> Self {
+        self.inner.set_compressor(compressor);
+        self
+    }
+
+    // 发送一个请求，返回服务器推送过来的 response stream，用于 Subscribe
+    pub async fn execute_streaming(mut self, cmd: CommandRequest) -> Result<StreamResult, KvError>
+    where
+        S: 'static,
+    {
+        self.inner.send(&cmd).await?;
+        StreamResult::new(self.inner).await
+    }
+
+    // 发送一个请求，返回服务器之后推送过来的所有 response，用于 Replicate
+    pub async fn execute_stream(
+        mut self,
+        cmd: CommandRequest,
+    ) -> Result<ProstStream<S, CommandResponse, CommandRequest>, KvError> {
+        self.inner.send(&cmd).await?;
+        Ok(self.inner)
+    }
+
+    // 发送一个请求，并等待它的响应
+    pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
+        let stream = &mut self.inner;
+        stream.send(&cmd).await?;
+
+        match stream.next().await {
+            Some(v) => v,
+            None => Err(KvError::Internal("Didn't get any response".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, MemTable, ServiceInner, Value};
+    use bytes::Bytes;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn client_server_basic_communication_should_work() {
+        let mut client = start_server();
+
+        // 发送 HSET，等待回应
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute(cmd).await.unwrap();
+
+        // 第一次 HSET 服务器应该返回 None
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        // 再发一个 HSET
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute(cmd).await.unwrap();
+
+        // 服务器应该返回上一次的结果
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn server_should_reply_with_client_preferred_compressor() {
+        for compressor in [Compressor::Gzip, Compressor::Zstd, Compressor::Lz4] {
+            let (client, server) = duplex(4096);
+            let service: Service = ServiceInner::new(MemTable::new()).into();
+            tokio::spawn(ProstServerStream::new(server, service).process());
+
+            // 直接用 ProstStream，方便拿到服务器回复时使用的压缩算法
+            let mut client = ProstStream::<_, CommandResponse, CommandRequest>::new(client)
+                .with_compressor(compressor);
+            let big: Value = Bytes::from(vec![7u8; 16 * 1024]).into();
+            let cmd = CommandRequest::new_hset("t1", "k1", big.clone());
+            client.send(&cmd).await.unwrap();
+            client.next().await.unwrap().unwrap();
+
+            client
+                .send(&CommandRequest::new_hget("t1", "k1"))
+                .await
+                .unwrap();
+            let res = client.next().await.unwrap().unwrap();
+            assert_res_ok(res, &[big], &[]);
+            assert_eq!(client.peer_compressor(), Some(compressor));
+        }
+    }
+
+    #[tokio::test]
+    async fn client_server_over_tls_should_work() {
+        use tokio::net::{TcpListener, TcpStream};
+
+        let acceptor = tls::tls_utils::tls_acceptor(true).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // 同一个 Service，底层 stream 换成 TLS stream 就行
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            ProstServerStream::new(stream, service)
+                .process()
+                .await
+                .unwrap();
+        });
+
+        let connector = tls::tls_utils::tls_connector(true).unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = connector.connect(stream).await.unwrap();
+        let mut client = ProstClientStream::new(stream);
+
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await
+            .unwrap();
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = client
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn server_should_call_after_send_hooks() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .fn_after_send(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .into();
+        let (client, server) = duplex(4096);
+        tokio::spawn(ProstServerStream::new(server, service).process());
+
+        let mut client = ProstClientStream::new(client);
+        for _ in 0..2 {
+            client
+                .execute(CommandRequest::new_hget("t1", "k1"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn server_should_stop_when_client_closed() {
+        let (client, server) = duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let handle = tokio::spawn(ProstServerStream::new(server, service).process());
+
+        drop(client);
+        assert_eq!(handle.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn server_should_read_requests_while_streaming() {
+        let (client, server) = duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(ProstServerStream::new(server, service.clone()).process());
+
+        let mut client = ProstStream::<_, CommandResponse, CommandRequest>::new(client);
+        client
+            .send(&CommandRequest::new_subscribe("lobby"))
+            .await
+            .unwrap();
+        let res = client.next().await.unwrap().unwrap();
+        let id = i64::try_from(res.values[0].clone()).unwrap() as u32;
+
+        // 订阅还在推送数据的时候，同一个连接上的请求也会被执行
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        client.send(&cmd).await.unwrap();
+        let mut values = Vec::new();
+        for _ in 0..2 {
+            values.push(client.next().await.unwrap().unwrap().values);
+        }
+        values.sort_by_key(|v| v.len());
+        assert_eq!(values, [vec![], vec![Value::from("hello")]]);
+
+        client
+            .send(&CommandRequest::new_unsubscribe("lobby", id))
+            .await
+            .unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap().status, 200);
+        assert_eq!(service.subscription_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn server_should_release_subscription_when_client_closed() {
+        let (client, server) = duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let handle = tokio::spawn(ProstServerStream::new(server, service.clone()).process());
+
+        let mut client = ProstStream::<_, CommandResponse, CommandRequest>::new(client);
+        client
+            .send(&CommandRequest::new_subscribe("lobby"))
+            .await
+            .unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap().status, 200);
+        assert_eq!(service.subscription_count(), 1);
+
+        // 没有任何发布，客户端断开后连接和订阅也会被释放
+        drop(client);
+        assert_eq!(handle.await.unwrap(), Ok(()));
+        assert_eq!(service.subscription_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn server_should_release_watcher_when_client_closed() {
+        let (client, server) = duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let handle = tokio::spawn(ProstServerStream::new(server, service.clone()).process());
+        let watchers = || service.with_store(|store| store.feed().watcher_count());
+
+        let mut client = ProstStream::<_, CommandResponse, CommandRequest>::new(client);
+        client
+            .send(&CommandRequest::new_watch("t1", ""))
+            .await
+            .unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap().status, 200);
+        assert_eq!(watchers().await.unwrap(), 1);
+
+        // 表上没有任何修改，客户端断开后 watcher 也会被删掉，之后的写入不用再生成事件
+        drop(client);
+        assert_eq!(handle.await.unwrap(), Ok(()));
+        assert_eq!(watchers().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn server_should_reject_requests_over_in_flight_limit() {
+        let (client, server) = duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(
+            ProstServerStream::new(server, service)
+                .max_in_flight(1)
+                .process(),
+        );
+
+        // 不等回复连续发送请求，第二个请求排队时第一个还没执行完
+        let mut client = ProstStream::<_, CommandResponse, CommandRequest>::new(client);
+        client
+            .feed(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await
+            .unwrap();
+        client
+            .feed(&CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+        client
+            .send(&CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+
+        let res = client.next().await.unwrap().unwrap();
+        assert_res_ok(res, &[Value::default()], &[]);
+        assert_eq!(client.next().await.unwrap().unwrap().status, 429);
+        let res = client.next().await.unwrap().unwrap();
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn server_should_reject_requests_over_rate_limit() {
+        let (client, server) = duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let limiter = RateLimiter::new(1, 1).client("127.0.0.1".parse().unwrap());
+        tokio::spawn(
+            ProstServerStream::new(server, service)
+                .rate_limit(limiter)
+                .process(),
+        );
+
+        let mut client = ProstClientStream::new(client);
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert_eq!(client.execute(cmd.clone()).await.unwrap().status, 404);
+        let res = client.execute(cmd).await.unwrap();
+        assert_eq!(res.status, 429);
+        assert!(res.message.contains("requests per second"));
+    }
+
+    #[tokio::test]
+    async fn server_should_reject_oversized_frame_and_keep_connection() {
+        let (client, server) = duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(
+            ProstServerStream::new(server, service)
+                .max_frame(1024)
+                .process(),
+        );
+
+        // 用不能压缩的数据，保证发出的 frame 超过限制
+        let mut client = ProstClientStream::new(client);
+        let mut seed = 1u32;
+        let data = (0..8 * 1024).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        });
+        let big: Value = Bytes::from_iter(data).into();
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", big))
+            .await
+            .unwrap();
+        assert_eq!(res.status, 413);
+
+        // 压缩后没有超过限制，但解压后超过了
+        let big: Value = Bytes::from(vec![b'x'; 8 * 1024]).into();
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", big))
+            .await
+            .unwrap();
+        assert_eq!(res.status, 413);
+
+        // 超长的请求被跳过了，之后的请求不受影响
+        let res = client
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await
+            .unwrap();
+        assert_res_ok(res, &[Value::default()], &[]);
+    }
+
+    fn start_server() -> ProstClientStream<tokio::io::DuplexStream> {
+        let (client, server) = duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        tokio::spawn(async move {
+            ProstServerStream::new(server, service)
+                .process()
+                .await
+                .unwrap();
+        });
+        ProstClientStream::new(client)
+    }
+}
@@ -0,0 +1,271 @@
+use std::{collections::VecDeque, future::Future, marker::PhantomData, task::Poll};
+
+use futures::future::{self, poll_fn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, oneshot},
+};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tracing::warn;
+use yamux::{Config, Connection, ConnectionError, Mode};
+
+use crate::{KvError, ProstClientStream};
+
+// 打开一个新 stream 的请求，结果通过 oneshot 返回
+type OpenRequest = oneshot::Sender<Result<yamux::Stream, ConnectionError>>;
+
+/// Yamux 控制结构
+/// yamux 连接由后台任务驱动，YamuxCtrl 通过 channel 让后台任务打开新的 stream
+pub struct YamuxCtrl<S> {
+    // 请求打开新 stream
+    ctrl: mpsc::UnboundedSender<OpenRequest>,
+    _conn: PhantomData<S>,
+}
+
+impl<S> YamuxCtrl<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    /// 创建 yamux 客户端，YamuxCtrl 被 drop 后连接会关闭
+    pub fn new_client(stream: S, config: Option<Config>) -> Self {
+        Self::new(stream, config, Mode::Client, |_| future::ready(Ok(())))
+    }
+
+    /// 创建 yamux 服务端，每个对端打开的 stream 都交给 f 处理
+    pub fn new_server<F, Fut>(stream: S, config: Option<Config>, f: F) -> Self
+    where
+        F: FnMut(Compat<yamux::Stream>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), KvError>> + Send + 'static,
+    {
+        Self::new(stream, config, Mode::Server, f)
+    }
+
+    fn new<F, Fut>(stream: S, config: Option<Config>, mode: Mode, f: F) -> Self
+    where
+        F: FnMut(Compat<yamux::Stream>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), KvError>> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let conn = Connection::new(stream.compat(), config.unwrap_or_default(), mode);
+        let close_on_drop = matches!(mode, Mode::Client);
+
+        tokio::spawn(async move {
+            if let Err(e) = drive(conn, rx, close_on_drop, f).await {
+                warn!("Yamux connection error: {:?}", e);
+            }
+        });
+
+        Self {
+            ctrl: tx,
+            _conn: PhantomData,
+        }
+    }
+
+    /// 打开一个新的 stream，返回可以直接收发 prost 消息的 ProstClientStream
+    pub async fn open_stream(
+        &mut self,
+    ) -> Result<ProstClientStream<Compat<yamux::Stream>>, KvError> {
+        let (tx, rx) = oneshot::channel();
+        self.ctrl
+            .send(tx)
+            .map_err(|_| KvError::YamuxError(ConnectionError::Closed.to_string()))?;
+
+        let stream = rx
+            .await
+            .map_err(|_| KvError::YamuxError(ConnectionError::Closed.to_string()))?
+            .map_err(|e| KvError::YamuxError(e.to_string()))?;
+        Ok(ProstClientStream::new(stream.compat()))
+    }
+}
+
+// 驱动 yamux 连接：处理打开 stream 的请求，并把对端打开的 stream 交给 f
+async fn drive<T, F, Fut>(
+    mut conn: Connection<T>,
+    mut rx: mpsc::UnboundedReceiver<OpenRequest>,
+    close_on_drop: bool,
+    mut f: F,
+) -> Result<(), ConnectionError>
+where
+    T: futures::AsyncRead + futures::AsyncWrite + Unpin,
+    F: FnMut(Compat<yamux::Stream>) -> Fut,
+    Fut: Future<Output = Result<(), KvError>> + Send + 'static,
+{
+    let mut pending: VecDeque<OpenRequest> = VecDeque::new();
+    let mut ctrl_closed = false;
+
+    poll_fn(|cx| loop {
+        // 收集打开新 stream 的请求
+        while !ctrl_closed {
+            match rx.poll_recv(cx) {
+                Poll::Ready(Some(tx)) => pending.push_back(tx),
+                Poll::Ready(None) => ctrl_closed = true,
+                Poll::Pending => break,
+            }
+        }
+
+        if ctrl_closed && close_on_drop && pending.is_empty() {
+            return conn.poll_close(cx);
+        }
+
+        while let Some(tx) = pending.pop_front() {
+            match conn.poll_new_outbound(cx) {
+                Poll::Ready(result) => {
+                    let _ = tx.send(result);
+                }
+                Poll::Pending => {
+                    pending.push_front(tx);
+                    break;
+                }
+            }
+        }
+
+        // 驱动连接读写，同时拿到对端打开的 stream
+        match conn.poll_next_inbound(cx) {
+            Poll::Ready(Some(Ok(stream))) => {
+                let fut = f(stream.compat());
+                tokio::spawn(async move {
+                    if let Err(e) = fut.await {
+                        warn!("Failed to process yamux stream: {:?}", e);
+                    }
+                });
+            }
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+            Poll::Ready(None) => return Poll::Ready(Ok(())),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_ok, Acl, CommandRequest, MemTable, Permission, ProstServerStream, Service,
+        ServiceInner, Session, Value,
+    };
+    use futures::StreamExt;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn yamux_ctrl_client_server_should_work() {
+        let mut ctrl = start_server();
+
+        let mut stream = ctrl.open_stream().await.unwrap();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = stream.execute(cmd).await.unwrap();
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        // 另一个逻辑 stream 共享同一个 Service
+        let mut stream = ctrl.open_stream().await.unwrap();
+        let res = stream
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn yamux_streams_should_run_concurrently() {
+        let mut ctrl = start_server();
+
+        let mut handles = Vec::new();
+        for i in 0..10 {
+            let mut stream = ctrl.open_stream().await.unwrap();
+            handles.push(tokio::spawn(async move {
+                for j in 0..10i64 {
+                    let key = format!("k{}-{}", i, j);
+                    let res = stream
+                        .execute(CommandRequest::new_hset("t1", key, j.into()))
+                        .await
+                        .unwrap();
+                    assert_res_ok(res, &[Value::default()], &[]);
+                }
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let mut stream = ctrl.open_stream().await.unwrap();
+        let res = stream
+            .execute(CommandRequest::new_hmget("t1", vec!["k3-7".into()]))
+            .await
+            .unwrap();
+        assert_res_ok(res, &[7.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn yamux_pub_sub_should_work() {
+        let mut ctrl = start_server();
+
+        // 订阅占用一个 stream，发布和取消订阅用其它 stream
+        let stream = ctrl.open_stream().await.unwrap();
+        let mut sub = stream
+            .execute_streaming(CommandRequest::new_subscribe("lobby"))
+            .await
+            .unwrap();
+
+        let mut stream = ctrl.open_stream().await.unwrap();
+        let res = stream
+            .execute(CommandRequest::new_publish("lobby", vec!["hello".into()]))
+            .await
+            .unwrap();
+        assert_res_ok(res, &[], &[]);
+
+        let data = sub.next().await.unwrap().unwrap();
+        assert_res_ok(data, &["hello".into()], &[]);
+
+        let res = stream
+            .execute(CommandRequest::new_unsubscribe("lobby", sub.id))
+            .await
+            .unwrap();
+        assert_res_ok(res, &[], &[]);
+
+        // 取消订阅后，服务器不再推送数据，但 stream 还能继续用
+        let mut stream = ctrl.open_stream().await.unwrap();
+        stream
+            .execute(CommandRequest::new_publish("lobby", vec!["world".into()]))
+            .await
+            .unwrap();
+        let next = tokio::time::timeout(std::time::Duration::from_millis(100), sub.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    async fn yamux_streams_should_share_authentication() {
+        let mut acl = Acl::default();
+        acl.add_user("app", "secret", [("t1".into(), Permission::Read)]);
+        let mut ctrl = start_server_with(ServiceInner::new(MemTable::new()).acl(acl).into());
+
+        // 在 AUTH 之前打开的 stream 也能用上认证的结果
+        let mut first = ctrl.open_stream().await.unwrap();
+        let mut second = ctrl.open_stream().await.unwrap();
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert_eq!(second.execute(cmd.clone()).await.unwrap().status, 403);
+
+        let res = first
+            .execute(CommandRequest::new_auth("secret"))
+            .await
+            .unwrap();
+        assert_res_ok(res, &["app".into()], &[]);
+        assert_eq!(second.execute(cmd).await.unwrap().status, 404);
+    }
+
+    fn start_server() -> YamuxCtrl<tokio::io::DuplexStream> {
+        start_server_with(ServiceInner::new(MemTable::new()).into())
+    }
+
+    fn start_server_with(service: Service) -> YamuxCtrl<tokio::io::DuplexStream> {
+        let (client, server) = duplex(4096);
+
+        // 同一个连接上的 stream 共享 session
+        let session = Session::default();
+        YamuxCtrl::new_server(server, None, move |stream| {
+            ProstServerStream::new(stream, service.clone())
+                .session(session.clone())
+                .process()
+        });
+        YamuxCtrl::new_client(client, None)
+    }
+}
@@ -0,0 +1,571 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use std::ops::Range;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::info;
+
+use super::{ClientLimiter, MAX_FRAME};
+use crate::Value;
+use crate::{value, CommandRequest, CommandResponse, KvError, Kvpair, Service, Session, Storage};
+
+// inline 命令一行的最大长度，和 Redis 一样
+const MAX_INLINE: usize = 64 * 1024;
+// 一个命令最多的参数个数
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// RESP2 的回复
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// None 表示 nil
+    Bulk(Option<Bytes>),
+    Array(Vec<RespValue>),
+}
+
+/// RESP2 的编解码：解码出命令的参数，编码 RespValue
+/// 除了 RESP 数组，也支持 telnet 之类的工具发送的用空格分隔的 inline 命令
+#[derive(Debug)]
+pub struct RespCodec {
+    // 一个命令（包括所有参数）的最大字节数
+    max_frame: usize,
+}
+
+impl Default for RespCodec {
+    fn default() -> Self {
+        Self::new(MAX_FRAME)
+    }
+}
+
+impl RespCodec {
+    /// 超过 max_frame 字节的命令返回 FrameTooLarge
+    pub fn new(max_frame: usize) -> Self {
+        Self { max_frame }
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Bytes>;
+    type Error = KvError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some((args, len)) = parse(src, self.max_frame)? else {
+            return Ok(None);
+        };
+        let frame = src.split_to(len).freeze();
+        Ok(Some(args.into_iter().map(|r| frame.slice(r)).collect()))
+    }
+}
+
+impl Encoder<RespValue> for RespCodec {
+    type Error = KvError;
+
+    fn encode(&mut self, item: RespValue, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        encode(&item, dst);
+        Ok(())
+    }
+}
+
+fn encode(value: &RespValue, dst: &mut BytesMut) {
+    match value {
+        RespValue::Simple(s) => put_line(dst, b'+', s.as_bytes()),
+        // 错误信息里不能有换行
+        RespValue::Error(s) => put_line(dst, b'-', s.replace(['\r', '\n'], " ").as_bytes()),
+        RespValue::Integer(i) => put_line(dst, b':', i.to_string().as_bytes()),
+        RespValue::Bulk(None) => dst.put_slice(b"$-1\r\n"),
+        RespValue::Bulk(Some(b)) => {
+            put_line(dst, b'$', b.len().to_string().as_bytes());
+            dst.put_slice(b);
+            dst.put_slice(b"\r\n");
+        }
+        RespValue::Array(values) => {
+            put_line(dst, b'*', values.len().to_string().as_bytes());
+            for v in values {
+                encode(v, dst);
+            }
+        }
+    }
+}
+
+fn put_line(dst: &mut BytesMut, prefix: u8, line: &[u8]) {
+    dst.put_u8(prefix);
+    dst.put_slice(line);
+    dst.put_slice(b"\r\n");
+}
+
+// 每个参数在 buf 里的位置，以及命令的总长度
+type Parsed = (Vec<Range<usize>>, usize);
+
+// 解析一个完整的命令，数据不完整时返回 None，命令的总长度不能超过 max
+fn parse(buf: &[u8], max: usize) -> Result<Option<Parsed>, KvError> {
+    let Some((line, mut pos)) = read_line(buf, 0) else {
+        if buf.len() > MAX_INLINE.min(max) {
+            return Err(protocol_error("too big inline request"));
+        }
+        return Ok(None);
+    };
+
+    if buf[0] != b'*' {
+        let mut args = Vec::new();
+        let mut start = None;
+        for i in line.start..=line.end {
+            match (
+                buf.get(i)
+                    .filter(|c| i < line.end && !c.is_ascii_whitespace()),
+                start,
+            ) {
+                (Some(_), None) => start = Some(i),
+                (None, Some(s)) => {
+                    args.push(s..i);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        return Ok(Some((args, pos)));
+    }
+
+    let count = parse_len(&buf[line.start + 1..line.end], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let Some((line, next)) = read_line(buf, pos) else {
+            if buf.len() > max {
+                return Err(KvError::FrameTooLarge(buf.len()));
+            }
+            return Ok(None);
+        };
+        if buf[line.start] != b'$' {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_len(&buf[line.start + 1..line.end], MAX_FRAME)?;
+        let end = next + len;
+        // 不用等数据到齐，知道长度就可以拒绝
+        if end + 2 > max {
+            return Err(KvError::FrameTooLarge(end + 2));
+        }
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(protocol_error("bulk string is not terminated by CRLF"));
+        }
+        args.push(next..end);
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+// 从 start 开始读一行，返回不包括行尾的范围和下一行的开始，行尾可以是 \r\n 或者 \n
+fn read_line(buf: &[u8], start: usize) -> Option<(Range<usize>, usize)> {
+    let n = buf[start..].iter().position(|&c| c == b'\n')?;
+    let end = start + n;
+    match end > start && buf[end - 1] == b'\r' {
+        true => Some((start..end - 1, end + 1)),
+        false => Some((start..end, end + 1)),
+    }
+}
+
+fn parse_len(s: &[u8], max: usize) -> Result<usize, KvError> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|len| *len <= max && !s.is_empty())
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(msg: &str) -> KvError {
+    KvError::InvalidCommand(format!("Protocol error: {}", msg))
+}
+
+// 把 CommandResponse 转换成 RESP 的方式，和 Redis 对应命令的回复一致
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reply {
+    /// 一个值，不存在时是 nil
+    Value,
+    /// 值的数组
+    Values,
+    /// key 和 value 交替的数组
+    Pairs,
+    /// 新增的 field 的个数，即之前没有值的个数
+    Added,
+    /// 删除的 field 的个数，即之前有值的个数
+    Removed,
+    /// bool 转换成 1 或 0
+    Integer,
+    Ok,
+}
+
+enum Action {
+    Execute(CommandRequest, Reply),
+    Reply(RespValue),
+    Quit,
+}
+
+// 解析 RESP 命令，Redis 的 key 对应 table，field 对应 key
+fn parse_action(args: &[Bytes]) -> Result<Action, String> {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let args = &args[1..];
+    let arity_error = || {
+        format!(
+            "wrong number of arguments for '{}' command",
+            name.to_lowercase()
+        )
+    };
+    let strings = |args: &[Bytes]| -> Result<Vec<String>, String> {
+        args.iter()
+            .map(|b| String::from_utf8(b.to_vec()).map_err(|_| "key is not valid UTF-8".into()))
+            .collect()
+    };
+
+    let action = match name.as_str() {
+        "HGET" if args.len() == 2 => {
+            let [table, key] = <[String; 2]>::try_from(strings(args)?).unwrap();
+            Action::Execute(CommandRequest::new_hget(table, key), Reply::Value)
+        }
+        "HSET" if args.len() >= 3 && args.len() % 2 == 1 => {
+            let table = strings(&args[..1])?.remove(0);
+            let mut pairs = Vec::with_capacity(args.len() / 2);
+            for pair in args[1..].chunks(2) {
+                let key = strings(&pair[..1])?.remove(0);
+                pairs.push(Kvpair::new(key, to_value(&pair[1])));
+            }
+            let cmd = match pairs.len() {
+                1 => {
+                    let pair = pairs.remove(0);
+                    CommandRequest::new_hset(table, pair.key, pair.value.unwrap_or_default())
+                }
+                _ => CommandRequest::new_hmset(table, pairs),
+            };
+            Action::Execute(cmd, Reply::Added)
+        }
+        "HGETALL" if args.len() == 1 => {
+            let table = strings(args)?.remove(0);
+            Action::Execute(CommandRequest::new_hgetall(table), Reply::Pairs)
+        }
+        "HMGET" if args.len() >= 2 => {
+            let mut keys = strings(args)?;
+            let table = keys.remove(0);
+            Action::Execute(CommandRequest::new_hmget(table, keys), Reply::Values)
+        }
+        "HDEL" if args.len() >= 2 => {
+            let mut keys = strings(args)?;
+            let table = keys.remove(0);
+            let cmd = match keys.len() {
+                1 => CommandRequest::new_hdel(table, keys.remove(0)),
+                _ => CommandRequest::new_hmdel(table, keys),
+            };
+            Action::Execute(cmd, Reply::Removed)
+        }
+        "HEXISTS" if args.len() == 2 => {
+            let [table, key] = <[String; 2]>::try_from(strings(args)?).unwrap();
+            Action::Execute(CommandRequest::new_hexist(table, key), Reply::Integer)
+        }
+        // AUTH password 或者 AUTH username password，只使用 password 作为 token
+        "AUTH" if (1..=2).contains(&args.len()) => {
+            let token = strings(&args[args.len() - 1..])?.remove(0);
+            Action::Execute(CommandRequest::new_auth(token), Reply::Ok)
+        }
+        "PING" if args.is_empty() => Action::Reply(RespValue::Simple("PONG".into())),
+        "PING" if args.len() == 1 => Action::Reply(RespValue::Bulk(Some(args[0].clone()))),
+        // redis-cli 启动时会发送 COMMAND DOCS 获取命令的文档
+        "COMMAND" => Action::Reply(RespValue::Array(Vec::new())),
+        "QUIT" => Action::Quit,
+        "HGET" | "HSET" | "HGETALL" | "HMGET" | "HDEL" | "HEXISTS" | "AUTH" | "PING" => {
+            return Err(arity_error())
+        }
+        _ => return Err(format!("unknown command '{}'", name.to_lowercase())),
+    };
+    Ok(action)
+}
+
+// 参数是 UTF-8 的话保存成字符串，否则保存成二进制
+fn to_value(b: &Bytes) -> Value {
+    match std::str::from_utf8(b) {
+        Ok(s) => s.into(),
+        Err(_) => b.clone().into(),
+    }
+}
+
+fn to_resp(res: &CommandResponse, reply: Reply) -> RespValue {
+    match res.status {
+        200..=299 => {}
+        404 if reply == Reply::Value => return RespValue::Bulk(None),
+        403 if res.error == "PermissionDenied" => {
+            return RespValue::Error(format!("NOPERM {}", res.message))
+        }
+        _ => return RespValue::Error(format!("ERR {}", res.message)),
+    }
+
+    match reply {
+        Reply::Value => to_bulk(res.values.first()),
+        Reply::Values => RespValue::Array(res.values.iter().map(Some).map(to_bulk).collect()),
+        Reply::Pairs => RespValue::Array(
+            res.pairs
+                .iter()
+                .flat_map(|pair| {
+                    let key = Bytes::from(pair.key.clone());
+                    [RespValue::Bulk(Some(key)), to_bulk(pair.value.as_ref())]
+                })
+                .collect(),
+        ),
+        Reply::Added => {
+            RespValue::Integer(res.values.iter().filter(|v| v.value.is_none()).count() as _)
+        }
+        Reply::Removed => {
+            RespValue::Integer(res.values.iter().filter(|v| v.value.is_some()).count() as _)
+        }
+        Reply::Integer => match res.values.first().and_then(|v| v.value.as_ref()) {
+            Some(value::Value::Bool(b)) => RespValue::Integer(*b as _),
+            _ => RespValue::Error("ERR unexpected response".into()),
+        },
+        Reply::Ok => RespValue::Simple("OK".into()),
+    }
+}
+
+// 其它前端写入的非字符串的值，转换成它们的文本形式
+fn to_bulk(v: Option<&Value>) -> RespValue {
+    let bytes = match v.and_then(|v| v.value.as_ref()) {
+        None => return RespValue::Bulk(None),
+        Some(value::Value::String(s)) => Bytes::from(s.clone()),
+        Some(value::Value::Binary(b)) => b.clone(),
+        Some(value::Value::Integer(i)) => Bytes::from(i.to_string()),
+        Some(value::Value::Float(f)) => Bytes::from(f.to_string()),
+        Some(value::Value::Bool(b)) => Bytes::from(b.to_string()),
+    };
+    RespValue::Bulk(Some(bytes))
+}
+
+/// 用 RESP2 协议处理服务器端 accept 下来的 socket，和 ProstServerStream 共享同一个 Service
+/// 命令按顺序一个一个执行，回复写出去之后才读下一个命令，所以最多只有一个命令在执行，
+/// 客户端 pipeline 的命令留在 socket 里，读缓存最多是 max_frame
+pub struct RespServerStream<S, Store> {
+    inner: Framed<S, RespCodec>,
+    service: Service<Store>,
+    session: Session,
+    limiter: Option<ClientLimiter>,
+}
+
+impl<S, Store> RespServerStream<S, Store>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: Storage,
+{
+    pub fn new(stream: S, service: Service<Store>) -> Self {
+        Self {
+            inner: Framed::new(stream, RespCodec::default()),
+            service,
+            session: Session::default(),
+            limiter: None,
+        }
+    }
+
+    /// 一个命令的最大字节数，超过时回复错误并关闭连接
+    pub fn max_frame(mut self, max_frame: usize) -> Self {
+        self.inner.codec_mut().max_frame = max_frame;
+        self
+    }
+
+    /// 按客户端限制每秒的命令数，超过的命令直接回复错误
+    pub fn rate_limit(mut self, limiter: ClientLimiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    // 不断读取命令并回复，直到对端关闭或者发送 QUIT，协议出错时回复错误后关闭连接
+    pub async fn process(mut self) -> Result<(), KvError> {
+        while let Some(args) = self.inner.next().await {
+            let args = match args {
+                Ok(args) => args,
+                Err(e) => {
+                    let _ = self
+                        .inner
+                        .send(RespValue::Error(format!("ERR {}", e)))
+                        .await;
+                    return Err(e);
+                }
+            };
+            if args.is_empty() {
+                continue;
+            }
+
+            if let Some(Err(e)) = self.limiter.as_ref().map(|v| v.acquire()) {
+                self.inner
+                    .send(RespValue::Error(format!("ERR {}", e)))
+                    .await?;
+                continue;
+            }
+
+            let reply = match parse_action(&args) {
+                Ok(Action::Execute(cmd, reply)) => {
+                    info!("Got a new RESP command: {:?}", cmd.redacted());
+                    match self.service.execute_in(cmd, &mut self.session).next().await {
+                        Some(res) => to_resp(&res, reply),
+                        None => RespValue::Error("ERR didn't get any response".into()),
+                    }
+                }
+                Ok(Action::Reply(reply)) => reply,
+                Ok(Action::Quit) => {
+                    self.inner.send(RespValue::Simple("OK".into())).await?;
+                    return Ok(());
+                }
+                Err(msg) => RespValue::Error(format!("ERR {}", msg)),
+            };
+            self.inner.send(reply).await?;
+            self.service.notify_after_send();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Acl, MemTable, Permission, RateLimiter, ServiceInner};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    #[test]
+    fn resp_codec_should_decode_commands() {
+        let mut codec = RespCodec::default();
+        let mut buf = BytesMut::from(&b"*3\r\n$4\r\nHGET\r\n$2\r\nt1\r\n$2\r\nk"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(b"1\r\nPING  hello\r\n");
+        let args = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(args, ["HGET", "t1", "k1"].map(Bytes::from));
+        let args = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(args, ["PING", "hello"].map(Bytes::from));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"*1\r\n$x\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&b"*1\r\n$2\r\nabc\r\n"[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn resp_codec_should_limit_command_size() {
+        let mut codec = RespCodec::new(64);
+        // 每个参数都不大，但是加起来超过了限制
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"*10\r\n");
+        for _ in 0..10 {
+            buf.extend_from_slice(b"$8\r\nabcdefgh\r\n");
+        }
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(KvError::FrameTooLarge(_))
+        ));
+
+        // 知道参数的长度就拒绝，不等数据到齐
+        let mut buf = BytesMut::from(&b"*1\r\n$1000\r\n"[..]);
+        assert_eq!(codec.decode(&mut buf), Err(KvError::FrameTooLarge(1013)));
+
+        // 没有换行的长度也不能一直缓存下去
+        let mut buf = BytesMut::from(&b"*1\r\n$"[..]);
+        buf.extend_from_slice(&[b'1'; 64]);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let mut buf = BytesMut::from(&b"*2\r\n$4\r\nPING\r\n$4\r\nPONG\r\n"[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn resp_codec_should_encode_values() {
+        let value = RespValue::Array(vec![
+            RespValue::Simple("OK".into()),
+            RespValue::Error("ERR bad\nthing".into()),
+            RespValue::Integer(-1),
+            RespValue::Bulk(Some(Bytes::from_static(b"v1"))),
+            RespValue::Bulk(None),
+        ]);
+        let mut buf = BytesMut::new();
+        RespCodec::default().encode(value, &mut buf).unwrap();
+        assert_eq!(
+            &buf[..],
+            b"*5\r\n+OK\r\n-ERR bad thing\r\n:-1\r\n$2\r\nv1\r\n$-1\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn resp_server_should_handle_hash_commands() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let mut client = start(service);
+
+        let cases: [(&[u8], &[u8]); 10] = [
+            (b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n"),
+            (
+                b"*4\r\n$4\r\nHSET\r\n$2\r\nt1\r\n$2\r\nk1\r\n$2\r\nv1\r\n",
+                b":1\r\n",
+            ),
+            (b"HSET t1 k1 v2 k2 v2\r\n", b":1\r\n"),
+            (b"HGET t1 k1\r\n", b"$2\r\nv2\r\n"),
+            (b"HGET t1 k3\r\n", b"$-1\r\n"),
+            (b"HMGET t1 k1 k3\r\n", b"*2\r\n$2\r\nv2\r\n$-1\r\n"),
+            (b"HEXISTS t1 k2\r\n", b":1\r\n"),
+            (b"HDEL t1 k2 k3\r\n", b":1\r\n"),
+            (b"HGETALL t1\r\n", b"*2\r\n$2\r\nk1\r\n$2\r\nv2\r\n"),
+            (
+                b"HGET t1\r\n",
+                b"-ERR wrong number of arguments for 'hget' command\r\n",
+            ),
+        ];
+        for (req, expected) in cases {
+            assert_eq!(request(&mut client, req, expected.len()).await, expected);
+        }
+
+        client.write_all(b"QUIT\r\n").await.unwrap();
+        let mut res = Vec::new();
+        client.read_to_end(&mut res).await.unwrap();
+        assert_eq!(res, b"+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn resp_server_should_check_acl() {
+        let mut acl = Acl::default();
+        acl.add_user("app", "secret", [("t1".into(), Permission::Read)]);
+        let service: Service = ServiceInner::new(MemTable::new()).acl(acl).into();
+        let mut client = start(service);
+
+        let expected = b"-NOPERM Permission denied: authentication required\r\n";
+        let res = request(&mut client, b"HGET t1 k1\r\n", expected.len()).await;
+        assert_eq!(res, expected);
+        let res = request(&mut client, b"AUTH secret\r\n", 5).await;
+        assert_eq!(res, b"+OK\r\n");
+        let res = request(&mut client, b"HGET t1 k1\r\n", 5).await;
+        assert_eq!(res, b"$-1\r\n");
+        let expected = b"-NOPERM Permission denied: app has no Write permission on t1\r\n";
+        let res = request(&mut client, b"HSET t1 k1 v1\r\n", expected.len()).await;
+        assert_eq!(res, expected);
+    }
+
+    #[tokio::test]
+    async fn resp_server_should_check_rate_limit() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let limiter = RateLimiter::new(1, 1).client("127.0.0.1".parse().unwrap());
+        let (mut client, server) = duplex(4096);
+        tokio::spawn(
+            RespServerStream::new(server, service)
+                .rate_limit(limiter)
+                .process(),
+        );
+
+        assert_eq!(request(&mut client, b"PING\r\n", 7).await, b"+PONG\r\n");
+        let expected = b"-ERR Too many requests: 127.0.0.1 exceeds 1 requests per second\r\n";
+        let res = request(&mut client, b"PING\r\n", expected.len()).await;
+        assert_eq!(res, expected);
+    }
+
+    fn start(service: Service) -> DuplexStream {
+        let (client, server) = duplex(4096);
+        tokio::spawn(RespServerStream::new(server, service).process());
+        client
+    }
+
+    async fn request(client: &mut DuplexStream, req: &[u8], len: usize) -> Vec<u8> {
+        client.write_all(req).await.unwrap();
+        let mut res = vec![0; len];
+        client.read_exact(&mut res).await.unwrap();
+        res
+    }
+}
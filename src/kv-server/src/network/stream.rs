@@ -0,0 +1,250 @@
+use bytes::BytesMut;
+use futures::{ready, FutureExt, Sink, Stream};
+use std::{
+    marker::PhantomData,
+    pin::{pin, Pin},
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::frame::LEN_LEN;
+use crate::{read_frame_with, skip_frame, Compressor, FrameCoder, KvError, MAX_FRAME};
+
+/// 处理 KV server prost frame 的 stream
+/// In 是读出来的消息类型，Out 是要写出去的消息类型
+pub struct ProstStream<S, In, Out> {
+    // 底层的 stream
+    stream: S,
+    // 写缓存
+    wbuf: BytesMut,
+    // 写缓存中已经写出去的字节数
+    written: usize,
+    // 读缓存
+    rbuf: BytesMut,
+    // 发送大消息时使用的压缩算法
+    compressor: Compressor,
+    // 对端最近一个 frame 里使用或偏好的压缩算法
+    peer_compressor: Option<Compressor>,
+    // 读取的 frame 的最大长度
+    max_frame: usize,
+    // 超长的 frame 还没有丢掉的字节数
+    skip: usize,
+
+    // 类型占位符
+    _in: PhantomData<In>,
+    _out: PhantomData<Out>,
+}
+
+impl<S, In, Out> ProstStream<S, In, Out>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    /// 创建一个 ProstStream
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            written: 0,
+            wbuf: BytesMut::new(),
+            rbuf: BytesMut::new(),
+            compressor: Compressor::default(),
+            peer_compressor: None,
+            max_frame: MAX_FRAME,
+            skip: 0,
+            _in: PhantomData,
+            _out: PhantomData,
+        }
+    }
+
+    /// 指定发送时使用的压缩算法
+    pub fn with_compressor(mut self, compressor: Compressor) -> Self {
+        self.compressor = compressor;
+        self
+    }
+
+    pub fn set_compressor(&mut self, compressor: Compressor) {
+        self.compressor = compressor;
+    }
+
+    /// 读取的 frame 超过 max_frame 时返回 FrameTooLarge，并跳过这个 frame，之后可以继续读取
+    pub fn set_max_frame(&mut self, max_frame: usize) {
+        self.max_frame = max_frame.min(MAX_FRAME);
+    }
+
+    /// 对端在 frame header 里告诉我们的压缩算法，还没收到过 frame 时为 None
+    pub fn peer_compressor(&self) -> Option<Compressor> {
+        self.peer_compressor
+    }
+}
+
+// 一般来说，如果我们的 Stream 是 Unpin，最好实现一下
+impl<S, In, Out> Unpin for ProstStream<S, In, Out> where S: Unpin {}
+
+impl<S, In, Out> Stream for ProstStream<S, In, Out>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    In: Unpin + Send + FrameCoder,
+    Out: Unpin + Send,
+{
+    // 调用 next() 时，得到 Result<In, KvError>
+    type Item = Result<In, KvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        // 先丢掉上一个超长的 frame
+        if this.skip > 0 {
+            let mut fut = pin!(skip_frame(&mut this.stream, &mut this.rbuf, &mut this.skip));
+            if let Err(e) = ready!(fut.poll_unpin(cx)) {
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+
+        // 读到一半的数据都在 rbuf 里，每次 poll 重新创建 future 也不会丢数据
+        let ready = {
+            let mut fut = pin!(read_frame_with(
+                &mut this.stream,
+                &mut this.rbuf,
+                this.max_frame
+            ));
+            ready!(fut.poll_unpin(cx))
+        };
+        match ready {
+            Ok(true) => {
+                let result = In::decode_frame_with(&mut this.rbuf, this.max_frame).map(
+                    |(msg, compressor)| {
+                        this.peer_compressor = Some(compressor);
+                        msg
+                    },
+                );
+                Poll::Ready(Some(result))
+            }
+            Ok(false) => Poll::Ready(None),
+            // 不等 body 读完就返回错误，下次读取时再跳过它
+            Err(KvError::FrameTooLarge(len)) => {
+                this.skip = LEN_LEN + len;
+                Poll::Ready(Some(Err(KvError::FrameTooLarge(len))))
+            }
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+// 调用 send() 时，会把 Out 发出去
+impl<S, In, Out> Sink<&Out> for ProstStream<S, In, Out>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    In: Unpin + Send,
+    Out: Unpin + Send + FrameCoder,
+{
+    type Error = KvError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        item.encode_frame_with(&mut this.wbuf, this.compressor)?;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+
+        // 循环写入 stream 中
+        while this.written != this.wbuf.len() {
+            let n = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.wbuf[this.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(
+                    std::io::Error::from(std::io::ErrorKind::WriteZero).into()
+                ));
+            }
+            this.written += n;
+        }
+
+        // 清除 wbuf
+        this.wbuf.clear();
+        this.written = 0;
+
+        // 调用 stream 的 poll_flush 确保写入
+        ready!(Pin::new(&mut this.stream).poll_flush(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // 调用 stream 的 poll_flush 确保写入
+        ready!(self.as_mut().poll_flush(cx))?;
+
+        // 调用 stream 的 poll_shutdown 确保 stream 关闭
+        ready!(Pin::new(&mut self.stream).poll_shutdown(cx))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, CommandResponse, Value};
+    use futures::prelude::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn prost_stream_should_work() {
+        let (client, server) = duplex(4096);
+        let mut client = ProstStream::<_, CommandResponse, CommandRequest>::new(client);
+        let mut server = ProstStream::<_, CommandRequest, CommandResponse>::new(server);
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        client.send(&cmd).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), cmd);
+
+        let res: CommandResponse = Value::from("v1").into();
+        server.send(&res).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), res);
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_read_frames_written_in_pieces() {
+        let (mut client, server) = duplex(4096);
+        let mut server = ProstStream::<_, CommandRequest, CommandResponse>::new(server);
+
+        let cmd1 = CommandRequest::new_hget("t1", "k1");
+        let cmd2 = CommandRequest::default();
+        let mut buf = BytesMut::new();
+        cmd1.encode_frame(&mut buf).unwrap();
+        cmd2.encode_frame(&mut buf).unwrap();
+
+        // 一次只写 3 个字节，header 和 body 都会被拆开
+        let handle = tokio::spawn(async move {
+            for chunk in buf.chunks(3) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        assert_eq!(server.next().await.unwrap().unwrap(), cmd1);
+        assert_eq!(server.next().await.unwrap().unwrap(), cmd2);
+        handle.await.unwrap();
+
+        // 对端关闭后，stream 结束
+        assert!(server.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_error_on_truncated_frame() {
+        let (mut client, server) = duplex(4096);
+        let mut server = ProstStream::<_, CommandRequest, CommandResponse>::new(server);
+
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hget("t1", "k1")
+            .encode_frame(&mut buf)
+            .unwrap();
+        client.write_all(&buf[..buf.len() - 1]).await.unwrap();
+        drop(client);
+
+        assert!(matches!(
+            server.next().await,
+            Some(Err(KvError::IoError(_)))
+        ));
+    }
+}
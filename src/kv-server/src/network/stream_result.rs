@@ -0,0 +1,48 @@
+use futures::{Stream, StreamExt};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{CommandResponse, KvError};
+
+/// 客户端订阅主题或者监听修改后拿到的 response stream
+/// 服务器返回的第一个 response 是 subscription/watcher id，其余的是发布到主题的数据或者修改事件
+pub struct StreamResult {
+    pub id: u32,
+    inner: Pin<Box<dyn Stream<Item = Result<CommandResponse, KvError>> + Send>>,
+}
+
+impl StreamResult {
+    pub async fn new<T>(mut stream: T) -> Result<Self, KvError>
+    where
+        T: Stream<Item = Result<CommandResponse, KvError>> + Send + Unpin + 'static,
+    {
+        let id = match stream.next().await {
+            Some(Ok(CommandResponse {
+                status: 200,
+                values: v,
+                ..
+            })) => match v.first() {
+                Some(id) => i64::try_from(id.clone())? as u32,
+                None => return Err(KvError::Internal("Invalid stream".into())),
+            },
+            Some(Ok(res)) => return Err(KvError::Internal(res.message)),
+            Some(Err(e)) => return Err(e),
+            None => return Err(KvError::Internal("Invalid stream".into())),
+        };
+
+        Ok(StreamResult {
+            inner: Box::pin(stream),
+            id,
+        })
+    }
+}
+
+impl Stream for StreamResult {
+    type Item = Result<CommandResponse, KvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
@@ -0,0 +1,352 @@
+use std::{fs, io::Cursor, path::Path, sync::Arc, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use crate::KvError;
+
+/// KV Server 自己的 ALPN (Application-Layer Protocol Negotiation)
+const ALPN_KV: &str = "kv";
+
+/// 服务器等待 TLS 握手的最长时间，握手的连接已经占用了连接数，不能一直等下去
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 存放 TLS ServerConfig 并提供方法 accept 把底层的协议转换成 TLS
+#[derive(Clone)]
+pub struct TlsServerAcceptor {
+    inner: Arc<ServerConfig>,
+}
+
+/// 存放 TLS Client 并提供方法 connect 把底层的协议转换成 TLS
+#[derive(Clone)]
+pub struct TlsClientConnector {
+    pub config: Arc<ClientConfig>,
+    pub domain: Arc<String>,
+}
+
+impl TlsClientConnector {
+    /// 加载 client cert / CA cert，生成 ClientConfig
+    /// identity 是 (cert, key)，需要双向认证时提供；server_ca 为空时使用公共根证书
+    pub fn new(
+        domain: impl Into<String>,
+        identity: Option<(&str, &str)>,
+        server_ca: Option<&str>,
+    ) -> Result<Self, KvError> {
+        let mut roots = RootCertStore::empty();
+        match server_ca {
+            Some(ca) => {
+                for cert in load_certs(ca, "server", "ca")? {
+                    roots
+                        .add(cert)
+                        .map_err(|_| KvError::CertificateParseError("server", "ca"))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let mut config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(load_certs(cert, "client", "cert")?, load_key(key)?)
+                .map_err(|e| KvError::TlsError(e.to_string()))?,
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![Vec::from(ALPN_KV)];
+
+        Ok(Self {
+            config: Arc::new(config),
+            domain: Arc::new(domain.into()),
+        })
+    }
+
+    /// 从文件加载证书，参数含义和 new 一样
+    pub fn from_files(
+        domain: impl Into<String>,
+        identity: Option<(&Path, &Path)>,
+        server_ca: Option<&Path>,
+    ) -> Result<Self, KvError> {
+        let identity = match identity {
+            Some((cert, key)) => Some((read_pem(cert)?, read_pem(key)?)),
+            None => None,
+        };
+        let server_ca = server_ca.map(read_pem).transpose()?;
+        Self::new(
+            domain,
+            identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str())),
+            server_ca.as_deref(),
+        )
+    }
+
+    /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
+    pub async fn connect<S>(&self, stream: S) -> Result<client::TlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let dns = ServerName::try_from(self.domain.as_str().to_owned())
+            .map_err(|_| KvError::Internal("Invalid DNS name".into()))?;
+
+        let stream = TlsConnector::from(self.config.clone())
+            .connect(dns, stream)
+            .await?;
+
+        Ok(stream)
+    }
+}
+
+impl TlsServerAcceptor {
+    /// 加载 server cert / CA cert，生成 ServerConfig
+    /// client_ca 不为空时要求客户端提供由它签发的证书（双向认证）
+    pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self, KvError> {
+        let certs = load_certs(cert, "server", "cert")?;
+        let key = load_key(key)?;
+
+        let builder = ServerConfig::builder();
+        let builder = match client_ca {
+            None => builder.with_no_client_auth(),
+            Some(ca) => {
+                // 如果客户端证书是某个 CA 证书签发的，则把这个 CA 证书加载到信任链中
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca, "client", "ca")? {
+                    roots
+                        .add(cert)
+                        .map_err(|_| KvError::CertificateParseError("client", "ca"))?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .build()
+                    .map_err(|e| KvError::TlsError(e.to_string()))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|_| KvError::CertificateParseError("server", "cert"))?;
+        config.alpn_protocols = vec![Vec::from(ALPN_KV)];
+
+        Ok(Self {
+            inner: Arc::new(config),
+        })
+    }
+
+    /// 从文件加载证书，参数含义和 new 一样
+    pub fn from_files(
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        client_ca: Option<&Path>,
+    ) -> Result<Self, KvError> {
+        let client_ca = client_ca.map(read_pem).transpose()?;
+        Self::new(
+            &read_pem(cert.as_ref())?,
+            &read_pem(key.as_ref())?,
+            client_ca.as_deref(),
+        )
+    }
+
+    /// 换成其它协议的 ALPN，比如 HTTP 网关用 "http/1.1"，gRPC 用 "h2"
+    pub fn alpn(&self, protocols: &[&str]) -> Self {
+        let mut config = (*self.inner).clone();
+        config.alpn_protocols = protocols.iter().map(|p| Vec::from(*p)).collect();
+        Self {
+            inner: Arc::new(config),
+        }
+    }
+
+    /// 触发 TLS 协议，把底层的 stream 转换成 TLS stream
+    pub async fn accept<S>(&self, stream: S) -> Result<server::TlsStream<S>, KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let acceptor = TlsAcceptor::from(self.inner.clone());
+        Ok(acceptor.accept(stream).await?)
+    }
+}
+
+fn read_pem(path: &Path) -> Result<String, KvError> {
+    Ok(fs::read_to_string(path)?)
+}
+
+// owner 和 kind 用于错误信息，比如 ("client", "ca")
+fn load_certs(
+    cert: &str,
+    owner: &'static str,
+    kind: &'static str,
+) -> Result<Vec<CertificateDer<'static>>, KvError> {
+    let certs = rustls_pemfile::certs(&mut Cursor::new(cert))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| KvError::CertificateParseError(owner, kind))?;
+    if certs.is_empty() {
+        return Err(KvError::CertificateParseError(owner, kind));
+    }
+    Ok(certs)
+}
+
+fn load_key(key: &str) -> Result<PrivateKeyDer<'static>, KvError> {
+    // 支持 PKCS8 / PKCS1 / SEC1 格式的私钥
+    match rustls_pemfile::private_key(&mut Cursor::new(key)) {
+        Ok(Some(key)) => Ok(key),
+        _ => Err(KvError::CertificateParseError("private", "key")),
+    }
+}
+
+#[cfg(test)]
+pub mod tls_utils {
+    use super::*;
+
+    const CA_CERT: &str = include_str!("../../fixtures/ca.cert");
+    const CLIENT_CERT: &str = include_str!("../../fixtures/client.cert");
+    const CLIENT_KEY: &str = include_str!("../../fixtures/client.key");
+    const SERVER_CERT: &str = include_str!("../../fixtures/server.cert");
+    const SERVER_KEY: &str = include_str!("../../fixtures/server.key");
+
+    pub fn tls_connector(client_cert: bool) -> Result<TlsClientConnector, KvError> {
+        let ca = Some(CA_CERT);
+        let client_identity = Some((CLIENT_CERT, CLIENT_KEY));
+
+        match client_cert {
+            false => TlsClientConnector::new("kvserver.acme.inc", None, ca),
+            true => TlsClientConnector::new("kvserver.acme.inc", client_identity, ca),
+        }
+    }
+
+    pub fn tls_acceptor(client_cert: bool) -> Result<TlsServerAcceptor, KvError> {
+        let ca = Some(CA_CERT);
+        match client_cert {
+            true => TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, ca),
+            false => TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tls_utils::*;
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn tls_should_work() -> Result<(), KvError> {
+        let addr = start_server(false).await?;
+        let connector = tls_connector(false)?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_client_cert_should_work() -> Result<(), KvError> {
+        let addr = start_server(true).await?;
+        let connector = tls_connector(true)?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(stream).await?;
+        stream.write_all(b"hello world!").await?;
+        let mut buf = [0; 12];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello world!");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_without_required_client_cert_should_fail() -> Result<(), KvError> {
+        let addr = start_server(true).await?;
+        let connector = tls_connector(false)?;
+        let stream = TcpStream::connect(addr).await?;
+        // TLS 1.3 里客户端证书在握手之后才被校验，所以错误会在读写时出现
+        let result = async {
+            let mut stream = connector.connect(stream).await?;
+            stream.write_all(b"hello world!").await?;
+            let mut buf = [0; 12];
+            stream.read_exact(&mut buf).await?;
+            Ok::<_, KvError>(())
+        }
+        .await;
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_with_bad_domain_should_not_work() -> Result<(), KvError> {
+        let addr = start_server(false).await?;
+
+        let mut connector = tls_connector(false)?;
+        connector.domain = Arc::new("kvserver1.acme.inc".into());
+        let stream = TcpStream::connect(addr).await?;
+        let result = connector.connect(stream).await;
+
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tls_should_load_certificates_from_files() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures");
+        let ca = dir.join("ca.cert");
+        let acceptor = TlsServerAcceptor::from_files(
+            dir.join("server.cert"),
+            dir.join("server.key"),
+            Some(&ca),
+        );
+        assert!(acceptor.is_ok());
+
+        let cert = dir.join("client.cert");
+        let key = dir.join("client.key");
+        let connector =
+            TlsClientConnector::from_files("kvserver.acme.inc", Some((&cert, &key)), Some(&ca));
+        assert!(connector.is_ok());
+
+        // 证书和私钥放反了，应该报错
+        let result =
+            TlsServerAcceptor::from_files(dir.join("server.key"), dir.join("server.cert"), None);
+        assert!(matches!(result, Err(KvError::CertificateParseError(_, _))));
+
+        // CA 证书错误时，报告的是 CA 证书
+        let cert = fs::read_to_string(dir.join("server.cert")).unwrap();
+        let key = fs::read_to_string(dir.join("server.key")).unwrap();
+        let result = TlsServerAcceptor::new(&cert, &key, Some(&key));
+        assert_eq!(
+            result.err(),
+            Some(KvError::CertificateParseError("client", "ca"))
+        );
+        let result = TlsClientConnector::new("kvserver.acme.inc", None, Some(&key));
+        assert_eq!(
+            result.err(),
+            Some(KvError::CertificateParseError("server", "ca"))
+        );
+    }
+
+    async fn start_server(client_cert: bool) -> Result<SocketAddr, KvError> {
+        let acceptor = tls_acceptor(client_cert)?;
+
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = echo.local_addr()?;
+
+        tokio::spawn(async move {
+            let (stream, _) = echo.accept().await.unwrap();
+            let Ok(mut stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            let mut buf = [0; 12];
+            if stream.read_exact(&mut buf).await.is_ok() {
+                stream.write_all(&buf).await.unwrap();
+            }
+        });
+
+        Ok(addr)
+    }
+}
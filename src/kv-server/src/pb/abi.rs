@@ -1,15 +1,20 @@
 /// 来自客户端的命令请求
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
     #[prost(
         oneof = "command_request::RequestData",
-        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9"
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24"
     )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
-    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
+    #[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
         #[prost(message, tag = "1")]
         Hget(super::Hget),
@@ -29,10 +34,43 @@ pub mod command_request {
         Hexist(super::Hexist),
         #[prost(message, tag = "9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag = "10")]
+        Subscribe(super::Subscribe),
+        #[prost(message, tag = "11")]
+        Unsubscribe(super::Unsubscribe),
+        #[prost(message, tag = "12")]
+        Publish(super::Publish),
+        #[prost(message, tag = "13")]
+        Hexpire(super::Hexpire),
+        #[prost(message, tag = "14")]
+        Httl(super::Httl),
+        #[prost(message, tag = "15")]
+        Hpersist(super::Hpersist),
+        #[prost(message, tag = "16")]
+        Hincrby(super::Hincrby),
+        #[prost(message, tag = "17")]
+        Hincrbyfloat(super::Hincrbyfloat),
+        #[prost(message, tag = "18")]
+        Hsetnx(super::Hsetnx),
+        #[prost(message, tag = "19")]
+        Hcas(super::Hcas),
+        #[prost(message, tag = "20")]
+        Hscan(super::Hscan),
+        #[prost(message, tag = "21")]
+        Batch(super::Batch),
+        #[prost(message, tag = "22")]
+        Replicate(super::Replicate),
+        #[prost(message, tag = "23")]
+        Auth(super::Auth),
+        #[prost(message, tag = "24")]
+        Watch(super::Watch),
     }
 }
 /// 服务器的响应
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     /// 状态码；复用 HTTP 2xx/4xx/5xx 状态码
     #[prost(uint32, tag = "1")]
@@ -46,9 +84,27 @@ pub struct CommandResponse {
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// Batch 里每个命令的 response
+    #[prost(message, repeated, tag = "5")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// leader 复制给 follower 的数据
+    #[prost(message, repeated, tag = "6")]
+    pub logs: ::prost::alloc::vec::Vec<ReplicationLog>,
+    /// 出错时 KvError 的类型，比如 NotFound，用于统计和区分错误
+    #[prost(string, tag = "7")]
+    pub error: ::prost::alloc::string::String,
+    /// Watch 推送的修改事件
+    #[prost(message, repeated, tag = "8")]
+    pub events: ::prost::alloc::vec::Vec<ChangeEvent>,
+    /// Hscan 下一页的 cursor，没有更多数据时为空
+    #[prost(string, tag = "9")]
+    pub cursor: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一个 key，返回 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -56,13 +112,19 @@ pub struct Hget {
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中获取所有的 Kvpair
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一组 key，返回它们的 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -70,14 +132,19 @@ pub struct Hmget {
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 返回的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(into = "serde_json::Value", try_from = "serde_json::Value")]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
     #[prost(oneof = "value::Value", tags = "1, 2, 3, 4, 5")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
 pub mod value {
-    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
+    #[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum Value {
         #[prost(string, tag = "1")]
         String(::prost::alloc::string::String),
@@ -92,7 +159,10 @@ pub mod value {
     }
 }
 /// 返回的 kvpair
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Kvpair {
     #[prost(string, tag = "1")]
     pub key: ::prost::alloc::string::String,
@@ -101,24 +171,39 @@ pub struct Kvpair {
 }
 /// 往 table 里存一个 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
     #[prost(message, optional, tag = "2")]
     pub pair: ::core::option::Option<Kvpair>,
+    /// 过期时间（毫秒），0 表示永不过期
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
 }
 /// 往 table 中存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
     #[prost(message, repeated, tag = "2")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 过期时间（毫秒），对所有 kvpair 生效，0 表示永不过期
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
 }
 /// 从 table 中删除一个 key，返回它之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -126,7 +211,10 @@ pub struct Hdel {
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中删除一组 key，返回它们之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -134,7 +222,10 @@ pub struct Hmdel {
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 查看 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -142,10 +233,577 @@ pub struct Hexist {
     pub key: ::prost::alloc::string::String,
 }
 /// 查看一组 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
     #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 订阅某个主题，之后发布到这个主题的数据都会被收到
+/// 订阅成功后，返回的第一个 CommandResponse 里是这次订阅的 id
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Subscribe {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+}
+/// 取消对某个主题的订阅
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unsubscribe {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "2")]
+    pub id: u32,
+}
+/// 发布数据到某个主题
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Publish {
+    #[prost(string, tag = "1")]
+    pub topic: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 给 key 设置过期时间（毫秒），返回 key 是否存在
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpire {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub ttl: u64,
+}
+/// 查看 key 剩余的过期时间（毫秒），没有设置过期时间则返回 -1
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Httl {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 去掉 key 的过期时间，返回之前是否设置了过期时间
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpersist {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 把 key 的整数值加上 delta，key 不存在时从 0 开始，返回新的值
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrby {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub delta: i64,
+}
+/// 把 key 的浮点数值加上 delta，key 不存在时从 0 开始，返回新的值
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrbyfloat {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(double, tag = "3")]
+    pub delta: f64,
+}
+/// key 不存在时才写入，返回是否写入成功
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetnx {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub pair: ::core::option::Option<Kvpair>,
+}
+/// key 当前的值等于 expected 时才写入 new，返回是否写入成功
+/// expected 为空表示 key 必须不存在
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hcas {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub expected: ::core::option::Option<Value>,
+    #[prost(message, optional, tag = "4")]
+    pub new: ::core::option::Option<Value>,
+}
+/// 按 key 的顺序扫描 table，返回一页 kvpair
+/// 如果还有数据，values 里是下一页的 cursor，带上它再扫描一次就能拿到下一页
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    /// 起始的 key（包含），为空表示从头开始
+    #[prost(string, tag = "2")]
+    pub start: ::prost::alloc::string::String,
+    /// 结束的 key（不包含），为空表示扫描到最后
+    #[prost(string, tag = "3")]
+    pub end: ::prost::alloc::string::String,
+    /// 只返回有这个前缀的 key
+    #[prost(string, tag = "4")]
+    pub prefix: ::prost::alloc::string::String,
+    /// 每页最多返回的数量，0 表示使用默认值
+    #[prost(uint32, tag = "5")]
+    pub limit: u32,
+    /// 上一页返回的 cursor，为空表示第一页
+    #[prost(string, tag = "6")]
+    pub cursor: ::prost::alloc::string::String,
+}
+/// 一次执行多个命令，每个命令返回一个 response
+/// atomic 为 true 时所有命令在一个事务里执行，任何一个命令出错时全部回滚
+/// 读不到 key 以及 HSETNX/HCAS 的条件不满足不算出错
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Batch {
+    #[prost(message, repeated, tag = "1")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+    #[prost(bool, tag = "2")]
+    pub atomic: bool,
+}
+/// follower 向 leader 请求复制数据，带上已经应用的位置
+/// epoch 和 leader 不一致，或者 leader 已经没有 seq 之后的数据时，leader 会先发送全量 snapshot
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replicate {
+    #[prost(uint64, tag = "1")]
+    pub epoch: u64,
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+}
+/// 监听 table 里 key 的修改，key_prefix 为空表示监听整个 table
+/// 成功后返回的第一个 CommandResponse 里是这次监听的 id，之后每个 response 里有一个 ChangeEvent
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key_prefix: ::prost::alloc::string::String,
+}
+/// Watch 推送的一次修改
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChangeEvent {
+    /// 修改的序号，同一个 server 上单调递增
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    #[prost(enumeration = "ChangeKind", tag = "2")]
+    pub kind: i32,
+    #[prost(string, tag = "3")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub key: ::prost::alloc::string::String,
+    /// 修改之前的值，key 之前不存在时为空
+    #[prost(message, optional, tag = "5")]
+    pub old: ::core::option::Option<Value>,
+    /// 修改之后的值，DELETE 时为空
+    #[prost(message, optional, tag = "6")]
+    pub new: ::core::option::Option<Value>,
+    /// OVERFLOW 时丢掉的事件数量
+    #[prost(uint64, tag = "7")]
+    pub dropped: u64,
+}
+/// 认证当前连接，之后的命令按 token 对应的权限检查
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag = "1")]
+    pub token: ::prost::alloc::string::String,
+}
+/// leader 复制给 follower 的一条数据
+#[derive(PartialOrd, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(default)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicationLog {
+    /// leader 每次启动时生成的 epoch
+    #[prost(uint64, tag = "1")]
+    pub epoch: u64,
+    /// 修改的序号，snapshot 里的数据使用 snapshot 对应的序号
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+    /// 是否是 snapshot 的数据，snapshot 结束时会发送一条没有 command 的 log
+    #[prost(bool, tag = "3")]
+    pub snapshot: bool,
+    #[prost(message, optional, tag = "4")]
+    pub command: ::core::option::Option<CommandRequest>,
+    /// leader 生成 log 的时间（unix 时间戳，毫秒），follower 用它扣掉 ttl 里已经过去的时间
+    #[prost(uint64, tag = "5")]
+    pub timestamp: u64,
+}
+/// 修改的类型
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ChangeKind {
+    Set = 0,
+    Delete = 1,
+    /// watcher 处理得太慢，有一些事件被丢掉了，需要的话重新读取数据
+    Overflow = 2,
+}
+#[doc = r" Generated client implementations."]
+pub mod kv_service_client {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    #[doc = " gRPC 服务，和 TCP 上的 prost 协议共享同一个 Service"]
+    #[derive(Debug, Clone)]
+    pub struct KvServiceClient<T> {
+        inner: tonic::client::Grpc<T>,
+    }
+    impl KvServiceClient<tonic::transport::Channel> {
+        #[doc = r" Attempt to create a new client by connecting to a given endpoint."]
+        pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+        where
+            D: std::convert::TryInto<tonic::transport::Endpoint>,
+            D::Error: Into<StdError>,
+        {
+            let conn = tonic::transport::Endpoint::new(dst)?.connect().await?;
+            Ok(Self::new(conn))
+        }
+    }
+    impl<T> KvServiceClient<T>
+    where
+        T: tonic::client::GrpcService<tonic::body::BoxBody>,
+        T::ResponseBody: Body + Send + Sync + 'static,
+        T::Error: Into<StdError>,
+        <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+    {
+        pub fn new(inner: T) -> Self {
+            let inner = tonic::client::Grpc::new(inner);
+            Self { inner }
+        }
+        pub fn with_interceptor<F>(
+            inner: T,
+            interceptor: F,
+        ) -> KvServiceClient<InterceptedService<T, F>>
+        where
+            F: tonic::service::Interceptor,
+            T: tonic::codegen::Service<
+                http::Request<tonic::body::BoxBody>,
+                Response = http::Response<
+                    <T as tonic::client::GrpcService<tonic::body::BoxBody>>::ResponseBody,
+                >,
+            >,
+            <T as tonic::codegen::Service<http::Request<tonic::body::BoxBody>>>::Error:
+                Into<StdError> + Send + Sync,
+        {
+            KvServiceClient::new(InterceptedService::new(inner, interceptor))
+        }
+        #[doc = r" Compress requests with `gzip`."]
+        #[doc = r""]
+        #[doc = r" This requires the server to support it otherwise it might respond with an"]
+        #[doc = r" error."]
+        pub fn send_gzip(mut self) -> Self {
+            self.inner = self.inner.send_gzip();
+            self
+        }
+        #[doc = r" Enable decompressing responses with `gzip`."]
+        pub fn accept_gzip(mut self) -> Self {
+            self.inner = self.inner.accept_gzip();
+            self
+        }
+        #[doc = " 执行一个命令，不支持 Subscribe/Unsubscribe/Replicate 这类返回 stream 的命令"]
+        pub async fn execute(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CommandRequest>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Execute");
+            self.inner.unary(request.into_request(), path, codec).await
+        }
+        #[doc = " 订阅主题，第一个 response 包含订阅的 id，之后是发布到主题的数据，客户端关闭 stream 时取消订阅"]
+        pub async fn subscribe(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Subscribe>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::CommandResponse>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Subscribe");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
+        #[doc = " 监听 table 的修改，第一个 response 包含监听的 id，之后是修改事件，客户端关闭 stream 时停止监听"]
+        pub async fn watch(
+            &mut self,
+            request: impl tonic::IntoRequest<super::Watch>,
+        ) -> Result<tonic::Response<tonic::codec::Streaming<super::CommandResponse>>, tonic::Status>
+        {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static("/abi.KvService/Watch");
+            self.inner
+                .server_streaming(request.into_request(), path, codec)
+                .await
+        }
+    }
+}
+#[doc = r" Generated server implementations."]
+pub mod kv_service_server {
+    #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
+    use tonic::codegen::*;
+    #[doc = "Generated trait containing gRPC methods that should be implemented for use with KvServiceServer."]
+    #[async_trait]
+    pub trait KvService: Send + Sync + 'static {
+        #[doc = " 执行一个命令，不支持 Subscribe/Unsubscribe/Replicate 这类返回 stream 的命令"]
+        async fn execute(
+            &self,
+            request: tonic::Request<super::CommandRequest>,
+        ) -> Result<tonic::Response<super::CommandResponse>, tonic::Status>;
+        #[doc = "Server streaming response type for the Subscribe method."]
+        type SubscribeStream: futures_core::Stream<Item = Result<super::CommandResponse, tonic::Status>>
+            + Send
+            + Sync
+            + 'static;
+        #[doc = " 订阅主题，第一个 response 包含订阅的 id，之后是发布到主题的数据，客户端关闭 stream 时取消订阅"]
+        async fn subscribe(
+            &self,
+            request: tonic::Request<super::Subscribe>,
+        ) -> Result<tonic::Response<Self::SubscribeStream>, tonic::Status>;
+        #[doc = "Server streaming response type for the Watch method."]
+        type WatchStream: futures_core::Stream<Item = Result<super::CommandResponse, tonic::Status>>
+            + Send
+            + Sync
+            + 'static;
+        #[doc = " 监听 table 的修改，第一个 response 包含监听的 id，之后是修改事件，客户端关闭 stream 时停止监听"]
+        async fn watch(
+            &self,
+            request: tonic::Request<super::Watch>,
+        ) -> Result<tonic::Response<Self::WatchStream>, tonic::Status>;
+    }
+    #[doc = " gRPC 服务，和 TCP 上的 prost 协议共享同一个 Service"]
+    #[derive(Debug)]
+    pub struct KvServiceServer<T: KvService> {
+        inner: _Inner<T>,
+        accept_compression_encodings: (),
+        send_compression_encodings: (),
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: KvService> KvServiceServer<T> {
+        pub fn new(inner: T) -> Self {
+            let inner = Arc::new(inner);
+            let inner = _Inner(inner);
+            Self {
+                inner,
+                accept_compression_encodings: Default::default(),
+                send_compression_encodings: Default::default(),
+            }
+        }
+        pub fn with_interceptor<F>(inner: T, interceptor: F) -> InterceptedService<Self, F>
+        where
+            F: tonic::service::Interceptor,
+        {
+            InterceptedService::new(Self::new(inner), interceptor)
+        }
+    }
+    impl<T, B> tonic::codegen::Service<http::Request<B>> for KvServiceServer<T>
+    where
+        T: KvService,
+        B: Body + Send + Sync + 'static,
+        B::Error: Into<StdError> + Send + 'static,
+    {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = Never;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+        fn call(&mut self, req: http::Request<B>) -> Self::Future {
+            let inner = self.inner.clone();
+            match req.uri().path() {
+                "/abi.KvService/Execute" => {
+                    #[allow(non_camel_case_types)]
+                    struct ExecuteSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::UnaryService<super::CommandRequest> for ExecuteSvc<T> {
+                        type Response = super::CommandResponse;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CommandRequest>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).execute(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ExecuteSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Subscribe" => {
+                    #[allow(non_camel_case_types)]
+                    struct SubscribeSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::ServerStreamingService<super::Subscribe> for SubscribeSvc<T> {
+                        type Response = super::CommandResponse;
+                        type ResponseStream = T::SubscribeStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::Subscribe>,
+                        ) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).subscribe(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SubscribeSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/abi.KvService/Watch" => {
+                    #[allow(non_camel_case_types)]
+                    struct WatchSvc<T: KvService>(pub Arc<T>);
+                    impl<T: KvService> tonic::server::ServerStreamingService<super::Watch> for WatchSvc<T> {
+                        type Response = super::CommandResponse;
+                        type ResponseStream = T::WatchStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(&mut self, request: tonic::Request<super::Watch>) -> Self::Future {
+                            let inner = self.0.clone();
+                            let fut = async move { (*inner).watch(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = WatchSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec).apply_compression_config(
+                            accept_compression_encodings,
+                            send_compression_encodings,
+                        );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                _ => Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .header("content-type", "application/grpc")
+                        .body(empty_body())
+                        .unwrap())
+                }),
+            }
+        }
+    }
+    impl<T: KvService> Clone for KvServiceServer<T> {
+        fn clone(&self) -> Self {
+            let inner = self.inner.clone();
+            Self {
+                inner,
+                accept_compression_encodings: self.accept_compression_encodings,
+                send_compression_encodings: self.send_compression_encodings,
+            }
+        }
+    }
+    impl<T: KvService> Clone for _Inner<T> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+    impl<T: std::fmt::Debug> std::fmt::Debug for _Inner<T> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl<T: KvService> tonic::transport::NamedService for KvServiceServer<T> {
+        const NAME: &'static str = "abi.KvService";
+    }
+}
@@ -1,11 +1,12 @@
 pub mod abi;
-use std::fmt::Error;
 
 use crate::KvError;
 use abi::{command_request::RequestData, *};
 
 use bytes::Bytes;
 use http::StatusCode;
+use prost::Message;
+use std::{fmt, time::Duration};
 
 impl CommandRequest {
     /// 创建 HGET 命令,代表了一种可以转为字String的类型
@@ -22,6 +23,23 @@ impl CommandRequest {
             request_data: Some(RequestData::Hset(Hset {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
+                ttl: 0,
+            })),
+        }
+    }
+
+    /// 创建带过期时间的 HSET 命令
+    pub fn new_hset_with_ttl(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        ttl: Duration,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hset(Hset {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+                ttl: ttl_to_millis(ttl),
             })),
         }
     }
@@ -33,6 +51,346 @@ impl CommandRequest {
             })),
         }
     }
+
+    /// 创建 HMGET 命令
+    pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmget(Hmget {
+                table: table.into(),
+                keys,
+            })),
+        }
+    }
+
+    /// 创建 HMSET 命令
+    pub fn new_hmset(table: impl Into<String>, pairs: Vec<Kvpair>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table.into(),
+                pairs,
+                ttl: 0,
+            })),
+        }
+    }
+
+    /// 创建带过期时间的 HMSET 命令
+    pub fn new_hmset_with_ttl(table: impl Into<String>, pairs: Vec<Kvpair>, ttl: Duration) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmset(Hmset {
+                table: table.into(),
+                pairs,
+                ttl: ttl_to_millis(ttl),
+            })),
+        }
+    }
+
+    /// 创建 HDEL 命令
+    pub fn new_hdel(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdel(Hdel {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    /// 创建 HMDEL 命令
+    pub fn new_hmdel(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmdel(Hmdel {
+                table: table.into(),
+                keys,
+            })),
+        }
+    }
+
+    /// 创建 HEXIST 命令
+    pub fn new_hexist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexist(Hexist {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    /// 创建 HMEXIST 命令
+    pub fn new_hmexist(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmexist(Hmexist {
+                table: table.into(),
+                keys,
+            })),
+        }
+    }
+
+    /// 创建 SUBSCRIBE 命令
+    pub fn new_subscribe(topic: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe {
+                topic: topic.into(),
+            })),
+        }
+    }
+
+    /// 创建 UNSUBSCRIBE 命令
+    pub fn new_unsubscribe(topic: impl Into<String>, id: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Unsubscribe(Unsubscribe {
+                topic: topic.into(),
+                id,
+            })),
+        }
+    }
+
+    /// 创建 PUBLISH 命令
+    pub fn new_publish(topic: impl Into<String>, values: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Publish(Publish {
+                topic: topic.into(),
+                values,
+            })),
+        }
+    }
+
+    /// 创建 HEXPIRE 命令
+    pub fn new_hexpire(table: impl Into<String>, key: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpire(Hexpire {
+                table: table.into(),
+                key: key.into(),
+                ttl: ttl_to_millis(ttl),
+            })),
+        }
+    }
+
+    /// 创建 HTTL 命令
+    pub fn new_httl(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Httl(Httl {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    /// 创建 HPERSIST 命令
+    pub fn new_hpersist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hpersist(Hpersist {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    /// 创建 HINCRBY 命令
+    pub fn new_hincrby(table: impl Into<String>, key: impl Into<String>, delta: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrby(Hincrby {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+        }
+    }
+
+    /// 创建 HINCRBYFLOAT 命令
+    pub fn new_hincrbyfloat(table: impl Into<String>, key: impl Into<String>, delta: f64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrbyfloat(Hincrbyfloat {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+        }
+    }
+
+    /// 创建 HSETNX 命令
+    pub fn new_hsetnx(table: impl Into<String>, key: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetnx(Hsetnx {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+            })),
+        }
+    }
+
+    /// 创建 HCAS 命令，expected 为 None 表示 key 必须不存在
+    pub fn new_hcas(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        expected: Option<Value>,
+        new: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hcas(Hcas {
+                table: table.into(),
+                key: key.into(),
+                expected,
+                new: Some(new),
+            })),
+        }
+    }
+
+    /// 创建 HSCAN 命令，start/end/prefix/cursor 为空表示不限制
+    pub fn new_hscan(
+        table: impl Into<String>,
+        start: impl Into<String>,
+        end: impl Into<String>,
+        prefix: impl Into<String>,
+        limit: u32,
+        cursor: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hscan(Hscan {
+                table: table.into(),
+                start: start.into(),
+                end: end.into(),
+                prefix: prefix.into(),
+                limit,
+                cursor: cursor.into(),
+            })),
+        }
+    }
+
+    /// 创建 BATCH 命令，atomic 为 true 时所有命令在一个事务里执行
+    /// 原子的 batch 只在某个命令出错（比如 HINCRBY 的值不是整数）时回滚
+    /// 读不到 key 以及 HSETNX/HCAS 的条件不满足时，和单独执行一样返回 404 或者 false，不会回滚
+    pub fn new_batch(commands: Vec<CommandRequest>, atomic: bool) -> Self {
+        Self {
+            request_data: Some(RequestData::Batch(Batch { commands, atomic })),
+        }
+    }
+
+    /// 创建 REPLICATE 命令，从 epoch 的 seq 之后开始复制
+    pub fn new_replicate(epoch: u64, seq: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Replicate(Replicate { epoch, seq })),
+        }
+    }
+
+    /// 创建 AUTH 命令
+    pub fn new_auth(token: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                token: token.into(),
+            })),
+        }
+    }
+
+    /// 创建 WATCH 命令，监听 table 里 key 以 key_prefix 开头的修改
+    pub fn new_watch(table: impl Into<String>, key_prefix: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                key_prefix: key_prefix.into(),
+            })),
+        }
+    }
+
+    /// 用于日志的格式，不会输出 AUTH 的 token
+    pub fn redacted(&self) -> Redacted<'_> {
+        Redacted(self)
+    }
+
+    /// 命令的名字，用于日志和统计
+    pub fn name(&self) -> &'static str {
+        match &self.request_data {
+            Some(RequestData::Hget(_)) => "hget",
+            Some(RequestData::Hgetall(_)) => "hgetall",
+            Some(RequestData::Hmget(_)) => "hmget",
+            Some(RequestData::Hset(_)) => "hset",
+            Some(RequestData::Hmset(_)) => "hmset",
+            Some(RequestData::Hdel(_)) => "hdel",
+            Some(RequestData::Hmdel(_)) => "hmdel",
+            Some(RequestData::Hexist(_)) => "hexist",
+            Some(RequestData::Hmexist(_)) => "hmexist",
+            Some(RequestData::Subscribe(_)) => "subscribe",
+            Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+            Some(RequestData::Publish(_)) => "publish",
+            Some(RequestData::Hexpire(_)) => "hexpire",
+            Some(RequestData::Httl(_)) => "httl",
+            Some(RequestData::Hpersist(_)) => "hpersist",
+            Some(RequestData::Hincrby(_)) => "hincrby",
+            Some(RequestData::Hincrbyfloat(_)) => "hincrbyfloat",
+            Some(RequestData::Hsetnx(_)) => "hsetnx",
+            Some(RequestData::Hcas(_)) => "hcas",
+            Some(RequestData::Hscan(_)) => "hscan",
+            Some(RequestData::Batch(_)) => "batch",
+            Some(RequestData::Replicate(_)) => "replicate",
+            Some(RequestData::Auth(_)) => "auth",
+            Some(RequestData::Watch(_)) => "watch",
+            None => "unknown",
+        }
+    }
+
+    /// 命令是否会修改数据
+    pub fn is_mutating(&self) -> bool {
+        match &self.request_data {
+            Some(RequestData::Hset(_))
+            | Some(RequestData::Hmset(_))
+            | Some(RequestData::Hdel(_))
+            | Some(RequestData::Hmdel(_))
+            | Some(RequestData::Hexpire(_))
+            | Some(RequestData::Hpersist(_))
+            | Some(RequestData::Hincrby(_))
+            | Some(RequestData::Hincrbyfloat(_))
+            | Some(RequestData::Hsetnx(_))
+            | Some(RequestData::Hcas(_)) => true,
+            Some(RequestData::Batch(v)) => v.commands.iter().any(|cmd| cmd.is_mutating()),
+            _ => false,
+        }
+    }
+}
+
+// 协议里的 ttl 以毫秒为单位
+fn ttl_to_millis(ttl: Duration) -> u64 {
+    ttl.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// 见 CommandRequest::redacted
+pub struct Redacted<'a>(&'a CommandRequest);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0.request_data {
+            Some(RequestData::Auth(_)) => f.write_str("CommandRequest { Auth { token: *** } }"),
+            _ => self.0.fmt(f),
+        }
+    }
+}
+
+impl ChangeEvent {
+    /// 一次修改，new 为空表示删除，seq 在发布时分配
+    pub fn new(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        old: Option<Value>,
+        new: Option<Value>,
+    ) -> Self {
+        let kind = match new {
+            Some(_) => ChangeKind::Set,
+            None => ChangeKind::Delete,
+        };
+        Self {
+            kind: kind as _,
+            table: table.into(),
+            key: key.into(),
+            old,
+            new,
+            ..Default::default()
+        }
+    }
+
+    /// watcher 太慢，丢掉了 dropped 个事件
+    pub fn overflow(dropped: u64) -> Self {
+        Self {
+            kind: ChangeKind::Overflow as _,
+            dropped,
+            ..Default::default()
+        }
+    }
 }
 
 impl Kvpair {
@@ -70,6 +428,16 @@ impl From<i64> for Value {
     }
 }
 
+impl CommandResponse {
+    /// 没有返回数据的成功响应
+    pub fn ok() -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            ..Default::default()
+        }
+    }
+}
+
 /// 从 Value 转换成 CommandResponse
 impl From<Value> for CommandResponse {
     fn from(v: Value) -> Self {
@@ -98,13 +466,20 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            error: e.kind().into(),
+            ..Default::default()
         };
 
         match e {
             KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::ReadOnly(_) | KvError::PermissionDenied(_) => {
+                result.status = StatusCode::FORBIDDEN.as_u16() as _
+            }
+            KvError::RateLimited(_) => result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _,
+            KvError::FrameTooLarge(_) => {
+                result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _
+            }
             _ => {}
         }
 
@@ -122,6 +497,28 @@ impl From<Vec<Value>> for CommandResponse {
     }
 }
 
+/// 从 Watch 推送的事件转换成 CommandResponse
+impl From<ChangeEvent> for CommandResponse {
+    fn from(v: ChangeEvent) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            events: vec![v],
+            ..Default::default()
+        }
+    }
+}
+
+/// 从 Batch 里每个命令的 response 转换成 CommandResponse
+impl From<Vec<CommandResponse>> for CommandResponse {
+    fn from(v: Vec<CommandResponse>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            responses: v,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<bool> for Value {
     fn from(b: bool) -> Self {
         Self {
@@ -130,6 +527,20 @@ impl From<bool> for Value {
     }
 }
 
+impl From<Bytes> for Value {
+    fn from(buf: Bytes) -> Self {
+        Self {
+            value: Some(value::Value::Binary(buf)),
+        }
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Value {
+    fn from(buf: &[u8; N]) -> Self {
+        Bytes::copy_from_slice(&buf[..]).into()
+    }
+}
+
 impl From<f64> for Value {
     fn from(f: f64) -> Self {
         Self {
@@ -138,6 +549,61 @@ impl From<f64> for Value {
     }
 }
 
+// JSON 里的值：null、字符串、数字和 bool 直接对应，二进制表示成 {"binary": [..]}
+// 没有小数点的数字是 Integer，否则是 Float
+impl From<Value> for serde_json::Value {
+    fn from(v: Value) -> Self {
+        match v.value {
+            None => serde_json::Value::Null,
+            Some(value::Value::String(s)) => s.into(),
+            Some(value::Value::Integer(i)) => i.into(),
+            Some(value::Value::Float(f)) => f.into(),
+            Some(value::Value::Bool(b)) => b.into(),
+            Some(value::Value::Binary(b)) => serde_json::json!({ "binary": b.to_vec() }),
+        }
+    }
+}
+
+impl TryFrom<serde_json::Value> for Value {
+    type Error = KvError;
+
+    fn try_from(v: serde_json::Value) -> Result<Self, Self::Error> {
+        let value = match v {
+            serde_json::Value::Null => return Ok(Value::default()),
+            serde_json::Value::String(s) => value::Value::String(s),
+            serde_json::Value::Bool(b) => value::Value::Bool(b),
+            serde_json::Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+                (Some(i), _) => value::Value::Integer(i),
+                (None, Some(f)) => value::Value::Float(f),
+                _ => return Err(KvError::InvalidCommand(format!("invalid number {}", n))),
+            },
+            serde_json::Value::Object(mut map) if map.len() == 1 && map.contains_key("binary") => {
+                let bytes: Vec<u8> = serde_json::from_value(map.remove("binary").unwrap())
+                    .map_err(|e| KvError::InvalidCommand(format!("invalid binary: {}", e)))?;
+                value::Value::Binary(bytes.into())
+            }
+            v => {
+                return Err(KvError::InvalidCommand(format!(
+                    "expected a string, number, bool, null or {{\"binary\": [..]}}, got {}",
+                    v
+                )))
+            }
+        };
+        Ok(Value { value: Some(value) })
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v, "String")),
+        }
+    }
+}
+
 impl TryFrom<Value> for i64 {
     type Error = KvError;
 
@@ -199,19 +665,3 @@ impl TryFrom<&[u8]> for Value {
         Ok(msg)
     }
 }
-
-impl Value {
-    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<Vec<u8>, KvError> {
-        Ok(Vec::<u8>::new())
-    }
-
-    pub fn decode(data: &[u8]) -> Result<Value, KvError> {
-        Ok(Value {
-            value: Some(value::Value::Bool(true)),
-        })
-    }
-
-    pub fn encoded_len(&self) -> usize {
-        10_usize
-    }
-}
@@ -0,0 +1,312 @@
+use futures::StreamExt;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    task::JoinHandle,
+    time,
+};
+use tracing::{info, warn};
+
+use crate::{
+    command_request::RequestData, dispatch, storage::now_millis, CommandRequest, CommandResponse,
+    KvError, Layer, MemTable, ProstClientStream, ReplicationLog, Service, Storage,
+    TlsClientConnector,
+};
+
+// 重连的退避时间
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// 拒绝所有修改数据的命令，follower 的数据只能来自 leader
+pub struct ReadOnly;
+
+impl Layer for ReadOnly {
+    fn on_request(&self, cmd: &mut CommandRequest) -> Option<CommandResponse> {
+        cmd.is_mutating()
+            .then(|| KvError::ReadOnly("this node is a follower".into()).into())
+    }
+}
+
+/// 从 leader 复制数据的 follower，它的 Service 应该是只读的（见 ServiceInner::read_only）
+pub struct Follower<Store = MemTable> {
+    service: Service<Store>,
+    leader: String,
+    position: Arc<Position>,
+    tls: Option<TlsClientConnector>,
+    token: Option<String>,
+}
+
+// 已经应用的 leader 的 epoch 和序号，只有复制的任务会修改
+#[derive(Default)]
+struct Position {
+    epoch: AtomicU64,
+    seq: AtomicU64,
+}
+
+impl Position {
+    fn set(&self, epoch: u64, seq: u64) {
+        self.epoch.store(epoch, Ordering::Release);
+        self.seq.store(seq, Ordering::Release);
+    }
+}
+
+impl<Store> Clone for Follower<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            leader: self.leader.clone(),
+            position: Arc::clone(&self.position),
+            tls: self.tls.clone(),
+            token: self.token.clone(),
+        }
+    }
+}
+
+impl<Store: Storage> Follower<Store> {
+    pub fn new(service: Service<Store>, leader: impl Into<String>) -> Self {
+        Self {
+            service,
+            leader: leader.into(),
+            position: Default::default(),
+            tls: None,
+            token: None,
+        }
+    }
+
+    /// 使用 TLS 连接 leader
+    pub fn tls(mut self, connector: TlsClientConnector) -> Self {
+        self.tls = Some(connector);
+        self
+    }
+
+    /// leader 开启认证时使用的 token，对应的用户需要对 "*" 有 admin 权限
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// 已经应用的 leader 的 epoch
+    pub fn epoch(&self) -> u64 {
+        self.position.epoch.load(Ordering::Acquire)
+    }
+
+    /// 已经应用的修改的序号
+    pub fn seq(&self) -> u64 {
+        self.position.seq.load(Ordering::Acquire)
+    }
+
+    /// 启动后台任务从 leader 复制数据
+    /// 连接断开后按指数退避重连，并从已经应用的位置继续复制
+    pub fn spawn(&self) -> JoinHandle<()> {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                match this.sync(&mut backoff).await {
+                    Ok(()) => info!("Leader {} closed the connection", this.leader),
+                    Err(e) => warn!("Failed to replicate from {}: {}", this.leader, e),
+                }
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        })
+    }
+
+    // 连接 leader，一直复制到连接断开
+    async fn sync(&self, backoff: &mut Duration) -> Result<(), KvError> {
+        let stream = TcpStream::connect(&self.leader).await?;
+        match &self.tls {
+            Some(connector) => {
+                self.replicate(connector.connect(stream).await?, backoff)
+                    .await
+            }
+            None => self.replicate(stream, backoff).await,
+        }
+    }
+
+    async fn replicate<S>(&self, stream: S, backoff: &mut Duration) -> Result<(), KvError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut client = ProstClientStream::new(stream);
+        if let Some(token) = &self.token {
+            let res = client.execute(CommandRequest::new_auth(token)).await?;
+            if res.status != 200 {
+                return Err(KvError::ServerError(res.status, res.message));
+            }
+        }
+        let cmd = CommandRequest::new_replicate(self.epoch(), self.seq());
+        let mut stream = client.execute_stream(cmd).await?;
+        info!(
+            "Replicating from {} at {}:{}",
+            self.leader,
+            self.epoch(),
+            self.seq()
+        );
+        *backoff = MIN_BACKOFF;
+
+        let mut in_snapshot = false;
+        while let Some(res) = stream.next().await {
+            let res = res?;
+            if res.status != 200 {
+                return Err(KvError::Internal(res.message));
+            }
+            let position = Arc::clone(&self.position);
+            in_snapshot = self
+                .service
+                .with_store(move |store| apply(store, &position, res.logs, in_snapshot))
+                .await??;
+        }
+        Ok(())
+    }
+}
+
+// 应用 leader 发过来的数据，返回是否还在接收 snapshot
+fn apply(
+    store: &impl Storage,
+    position: &Position,
+    logs: Vec<ReplicationLog>,
+    mut in_snapshot: bool,
+) -> Result<bool, KvError> {
+    for log in logs {
+        if log.snapshot && !in_snapshot {
+            // 新的 snapshot：清空本地数据，在 snapshot 结束之前断线的话需要重新同步
+            info!("Loading snapshot {}:{}", log.epoch, log.seq);
+            position.set(0, 0);
+            clear(store)?;
+            in_snapshot = true;
+        }
+
+        let Some(mut cmd) = log.command else {
+            // snapshot 结束
+            position.set(log.epoch, log.seq);
+            in_snapshot = false;
+            continue;
+        };
+
+        if !log.snapshot {
+            let expected = position.seq.load(Ordering::Acquire) + 1;
+            if log.epoch != position.epoch.load(Ordering::Acquire) || log.seq != expected {
+                return Err(KvError::Internal(format!(
+                    "Expect log {} but got {}:{}",
+                    expected, log.epoch, log.seq
+                )));
+            }
+        }
+
+        // leader 执行命令之后已经过去了一段时间，ttl 要扣掉这段时间
+        rebase_ttl(&mut cmd, now_millis().saturating_sub(log.timestamp));
+        // 命令在 leader 上执行成功了，这里失败说明数据已经不一致，只能重新同步 snapshot
+        let res = dispatch(cmd, store);
+        if !(200..300).contains(&res.status) {
+            position.set(0, 0);
+            return Err(KvError::Internal(format!(
+                "Failed to apply log {}:{}: {}",
+                log.epoch, log.seq, res.message
+            )));
+        }
+        if !log.snapshot {
+            position.set(log.epoch, log.seq);
+        }
+    }
+    Ok(in_snapshot)
+}
+
+// 把命令里相对的 ttl 减去 elapsed 毫秒，至少保留 1ms，这样在 leader 上已经过期的 key 在这里也会过期
+// 需要 leader 和 follower 的时钟基本一致
+fn rebase_ttl(cmd: &mut CommandRequest, elapsed: u64) {
+    let rebase = |ttl: &mut u64| {
+        if *ttl > 0 {
+            *ttl = ttl.saturating_sub(elapsed).max(1);
+        }
+    };
+    match &mut cmd.request_data {
+        Some(RequestData::Hset(v)) => rebase(&mut v.ttl),
+        Some(RequestData::Hmset(v)) => rebase(&mut v.ttl),
+        Some(RequestData::Hexpire(v)) => rebase(&mut v.ttl),
+        Some(RequestData::Batch(v)) => v
+            .commands
+            .iter_mut()
+            .for_each(|cmd| rebase_ttl(cmd, elapsed)),
+        _ => {}
+    }
+}
+
+fn clear(store: &impl Storage) -> Result<(), KvError> {
+    for table in store.tables()? {
+        for pair in store.get_all(&table)? {
+            store.del(&table, &pair.key)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(seq: u64, cmd: CommandRequest, timestamp: u64) -> ReplicationLog {
+        ReplicationLog {
+            epoch: 1,
+            seq,
+            snapshot: false,
+            command: Some(cmd),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn apply_should_count_down_ttl_from_leader_time() {
+        let store = MemTable::new();
+        let position = Position::default();
+        position.set(1, 0);
+
+        // leader 4 秒前写入了 10 秒后过期的 key，以及 1 秒后过期的 key
+        let ago = now_millis() - 4000;
+        let ttl = Duration::from_secs(10);
+        let logs = vec![
+            log(
+                1,
+                CommandRequest::new_hset_with_ttl("t1", "k1", "v1".into(), ttl),
+                ago,
+            ),
+            log(
+                2,
+                CommandRequest::new_hset_with_ttl("t1", "k2", "v2".into(), ttl / 10),
+                ago,
+            ),
+        ];
+        apply(&store, &position, logs, false).unwrap();
+
+        let remaining = store.ttl("t1", "k1").unwrap().unwrap().unwrap();
+        assert!(remaining <= Duration::from_secs(6) && remaining > Duration::from_secs(5));
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(store.get("t1", "k2"), Ok(None));
+        assert_eq!(position.seq.load(Ordering::Acquire), 2);
+    }
+
+    #[test]
+    fn apply_should_fail_and_reset_position_on_divergence() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let position = Position::default();
+        position.set(1, 0);
+
+        // leader 上 k1 是整数，follower 上不是，说明数据已经不一致
+        let logs = vec![log(
+            1,
+            CommandRequest::new_hincrby("t1", "k1", 1),
+            now_millis(),
+        )];
+        assert!(apply(&store, &position, logs, false).is_err());
+        assert_eq!(position.epoch.load(Ordering::Acquire), 0);
+        assert_eq!(position.seq.load(Ordering::Acquire), 0);
+    }
+}
@@ -0,0 +1,291 @@
+use futures::{stream, StreamExt};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, info, warn};
+
+use crate::{
+    dispatch, storage::now_millis, CommandRequest, CommandResponse, KvError, Replicate,
+    ReplicationLog, Storage, StreamingResponse,
+};
+
+/// leader 默认保留的最近的修改数量，follower 落后更多时只能重新同步 snapshot
+pub const DEFAULT_BACKLOG: usize = 10_000;
+
+// snapshot 里每个 HMSET 最多包含的 kvpair 数量
+const SNAPSHOT_BATCH: usize = 128;
+
+// 需要先发给 follower 的数据，以及接收之后的修改的 receiver
+type Subscription = (
+    Vec<Arc<ReplicationLog>>,
+    broadcast::Receiver<Arc<ReplicationLog>>,
+);
+
+/// 把修改按顺序复制给 follower 的 leader
+pub struct Leader {
+    epoch: u64,
+    state: Mutex<State>,
+    tx: broadcast::Sender<Arc<ReplicationLog>>,
+}
+
+// 最新的序号，以及最近的修改
+struct State {
+    seq: u64,
+    backlog: VecDeque<Arc<ReplicationLog>>,
+    capacity: usize,
+}
+
+impl State {
+    fn push(&mut self, log: Arc<ReplicationLog>) {
+        if self.backlog.len() == self.capacity {
+            self.backlog.pop_front();
+        }
+        self.backlog.push_back(log);
+    }
+
+    // seq 之后的所有修改，backlog 里已经没有的话返回 None
+    fn since(&self, seq: u64) -> Option<Vec<Arc<ReplicationLog>>> {
+        if seq == self.seq {
+            return Some(Vec::new());
+        }
+        let first = self.backlog.front()?.seq;
+        if first > seq + 1 {
+            return None;
+        }
+        let skip = (seq + 1 - first) as usize;
+        Some(self.backlog.iter().skip(skip).cloned().collect())
+    }
+}
+
+impl Default for Leader {
+    fn default() -> Self {
+        Self::new(DEFAULT_BACKLOG)
+    }
+}
+
+impl Leader {
+    /// 创建 leader，backlog 是保留的最近的修改数量
+    pub fn new(backlog: usize) -> Self {
+        // epoch 使用启动的时间，leader 重启之后 follower 需要重新同步 snapshot
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64);
+        let capacity = backlog.max(1);
+        let (tx, _) = broadcast::channel(capacity);
+        Self {
+            epoch,
+            state: Mutex::new(State {
+                seq: 0,
+                backlog: VecDeque::new(),
+                capacity,
+            }),
+            tx,
+        }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// 最新的修改的序号
+    pub fn seq(&self) -> u64 {
+        self.state().seq
+    }
+
+    // 修改在锁里执行并分配序号，这样 follower 按序号重放的结果和 leader 一致
+    pub(crate) fn execute(&self, cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+        let mut state = self.state();
+        let res = dispatch(cmd.clone(), store);
+        // 失败的命令没有修改数据，不需要复制
+        if (200..300).contains(&res.status) {
+            state.seq += 1;
+            let log = Arc::new(self.log(state.seq, false, Some(cmd)));
+            state.push(log.clone());
+            // 没有 follower 时发送会失败，忽略即可
+            let _ = self.tx.send(log);
+        }
+        res
+    }
+
+    // follower 请求复制时，在锁里准备需要补发的数据并订阅之后的修改，这样不会漏掉或者重复任何修改
+    pub(crate) fn subscribe(
+        &self,
+        req: Replicate,
+        store: &impl Storage,
+    ) -> Result<Subscription, KvError> {
+        let state = self.state();
+        let rx = self.tx.subscribe();
+        if req.epoch == self.epoch && req.seq <= state.seq {
+            if let Some(logs) = state.since(req.seq) {
+                debug!(
+                    "Follower resumes from {}, {} logs behind",
+                    req.seq,
+                    logs.len()
+                );
+                return Ok((logs, rx));
+            }
+        }
+
+        info!(
+            "Follower at {}:{} needs a snapshot of {}:{}",
+            req.epoch, req.seq, self.epoch, state.seq
+        );
+        Ok((self.snapshot(state.seq, store)?, rx))
+    }
+
+    // 把 store 里的数据转换成 HMSET/HSET 命令，最后一条没有命令的 log 表示 snapshot 结束
+    fn snapshot(
+        &self,
+        seq: u64,
+        store: &impl Storage,
+    ) -> Result<Vec<Arc<ReplicationLog>>, KvError> {
+        let mut commands = Vec::new();
+        for table in store.tables()? {
+            let mut pairs = Vec::new();
+            for pair in store.get_all(&table)? {
+                match store.ttl(&table, &pair.key)? {
+                    Some(None) => pairs.push(pair),
+                    // 带过期时间的 key 单独用 HSET 写入，不到 1ms 就过期的 key 直接跳过
+                    Some(Some(ttl)) if ttl.as_millis() > 0 => {
+                        let value = pair.value.unwrap_or_default();
+                        commands.push(CommandRequest::new_hset_with_ttl(
+                            &table, pair.key, value, ttl,
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+            for chunk in pairs.chunks(SNAPSHOT_BATCH) {
+                commands.push(CommandRequest::new_hmset(&table, chunk.to_vec()));
+            }
+        }
+
+        let mut logs: Vec<_> = commands
+            .into_iter()
+            .map(|cmd| Arc::new(self.log(seq, true, Some(cmd))))
+            .collect();
+        logs.push(Arc::new(self.log(seq, true, None)));
+        Ok(logs)
+    }
+
+    fn log(&self, seq: u64, snapshot: bool, command: Option<CommandRequest>) -> ReplicationLog {
+        ReplicationLog {
+            epoch: self.epoch,
+            seq,
+            snapshot,
+            command,
+            timestamp: now_millis(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// 先发送补发的数据，再发送之后的修改
+pub(crate) fn into_stream(
+    logs: Vec<Arc<ReplicationLog>>,
+    rx: broadcast::Receiver<Arc<ReplicationLog>>,
+) -> StreamingResponse {
+    let live = stream::unfold(Some(rx), |rx| async move {
+        let mut rx = rx?;
+        match rx.recv().await {
+            Ok(log) => Some((Ok(log), Some(rx))),
+            // follower 太慢，结束 stream 让它重新连接，从 backlog 或者 snapshot 恢复
+            Err(RecvError::Lagged(n)) => {
+                warn!("Follower lagged behind by {} logs", n);
+                let e = KvError::Internal(format!("Follower lagged behind by {} logs", n));
+                Some((Err(e), None))
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+
+    let res = stream::iter(logs).map(Ok).chain(live).map(|v| {
+        Arc::new(match v {
+            Ok(log) => CommandResponse {
+                status: 200,
+                logs: vec![(*log).clone()],
+                ..Default::default()
+            },
+            Err(e) => e.into(),
+        })
+    });
+    Box::pin(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+    use std::time::Duration;
+
+    #[test]
+    fn leader_should_only_replicate_successful_mutations() {
+        let leader = Leader::default();
+        let store = MemTable::new();
+        leader.execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        leader.execute(CommandRequest::new_hincrby("t1", "k1", 1), &store);
+        leader.execute(CommandRequest::new_hdel("t1", "k1"), &store);
+        assert_eq!(leader.seq(), 2);
+
+        let (logs, _) = leader
+            .subscribe(
+                Replicate {
+                    epoch: leader.epoch(),
+                    seq: 1,
+                },
+                &store,
+            )
+            .unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].seq, 2);
+        assert!(!logs[0].snapshot);
+        assert_eq!(logs[0].command, Some(CommandRequest::new_hdel("t1", "k1")));
+    }
+
+    #[test]
+    fn leader_should_send_snapshot_when_backlog_is_not_enough() {
+        let leader = Leader::new(2);
+        let store = MemTable::new();
+        for i in 0..3 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+            leader.execute(cmd, &store);
+        }
+        let cmd =
+            CommandRequest::new_hset_with_ttl("t2", "k1", "v1".into(), Duration::from_secs(100));
+        leader.execute(cmd, &store);
+        assert_eq!(leader.seq(), 4);
+
+        // backlog 里还有 3 和 4
+        let req = Replicate {
+            epoch: leader.epoch(),
+            seq: 2,
+        };
+        let (logs, _) = leader.subscribe(req, &store).unwrap();
+        assert_eq!(logs.iter().map(|v| v.seq).collect::<Vec<_>>(), vec![3, 4]);
+
+        // seq 太旧或者 epoch 不一致都需要 snapshot
+        let reqs = [
+            Replicate {
+                epoch: leader.epoch(),
+                seq: 1,
+            },
+            Replicate { epoch: 0, seq: 0 },
+            Replicate {
+                epoch: leader.epoch() + 1,
+                seq: 4,
+            },
+        ];
+        for req in reqs {
+            let (logs, _) = leader.subscribe(req, &store).unwrap();
+            assert!(logs.iter().all(|v| v.snapshot && v.seq == 4));
+            assert_eq!(logs.len(), 3);
+            assert_eq!(logs[2].command, None);
+        }
+    }
+}
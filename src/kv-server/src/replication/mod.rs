@@ -0,0 +1,213 @@
+mod follower;
+mod leader;
+
+pub use follower::{Follower, ReadOnly};
+pub(crate) use leader::into_stream;
+pub use leader::{Leader, DEFAULT_BACKLOG};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_error, assert_res_ok, Acl, CommandRequest, CommandResponse, MemTable,
+        Permission, ProstServerStream, Service, ServiceInner,
+    };
+    use futures::StreamExt;
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::{net::TcpListener, task::JoinHandle, time};
+
+    #[tokio::test]
+    async fn follower_should_replicate_snapshot_and_new_mutations() {
+        let leader: Service = ServiceInner::new(MemTable::new())
+            .leader(Leader::default())
+            .into();
+        let ttl = Duration::from_secs(100);
+        execute(&leader, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        execute(
+            &leader,
+            CommandRequest::new_hset_with_ttl("t1", "k2", "v2".into(), ttl),
+        )
+        .await;
+        execute(&leader, CommandRequest::new_hincrby("t2", "counter", 5)).await;
+        let (addr, _) = start_leader(leader.clone()).await;
+
+        let service: Service = ServiceInner::new(MemTable::new()).read_only().into();
+        let follower = Follower::new(service.clone(), addr.to_string());
+        follower.spawn();
+        wait_for(&follower, 3).await;
+
+        let res = execute(&service, CommandRequest::new_hget("t1", "k1")).await;
+        assert_res_ok(res, &["v1".into()], &[]);
+        let res = execute(&service, CommandRequest::new_httl("t1", "k2")).await;
+        let remaining: i64 = res.values[0].clone().try_into().unwrap();
+        assert!(remaining > 90_000);
+
+        // 之后的修改按顺序复制过来，失败的命令不会复制
+        execute(&leader, CommandRequest::new_hdel("t1", "k1")).await;
+        execute(&leader, CommandRequest::new_hincrby("t1", "k2", 1)).await;
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hincrby("t2", "counter", 1),
+                CommandRequest::new_hset("t3", "k1", true.into()),
+            ],
+            true,
+        );
+        execute(&leader, cmd).await;
+        wait_for(&follower, 5).await;
+
+        let res = execute(&service, CommandRequest::new_hget("t1", "k1")).await;
+        assert_res_error(res, 404, "Not found");
+        let res = execute(&service, CommandRequest::new_hget("t2", "counter")).await;
+        assert_res_ok(res, &[6.into()], &[]);
+        let res = execute(&service, CommandRequest::new_hget("t3", "k1")).await;
+        assert_res_ok(res, &[true.into()], &[]);
+
+        // follower 是只读的
+        let res = execute(&service, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        assert_res_error(res, 403, "read-only");
+    }
+
+    #[tokio::test]
+    async fn follower_should_resume_after_reconnect() {
+        let leader: Service = ServiceInner::new(MemTable::new())
+            .leader(Leader::default())
+            .into();
+        let (addr, conns) = start_leader(leader.clone()).await;
+
+        // 不设置只读，这样可以写入一个 leader 上没有的 key，用来判断 follower 有没有重新同步 snapshot
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let follower = Follower::new(service.clone(), addr.to_string());
+        follower.spawn();
+        execute(&leader, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        wait_for(&follower, 1).await;
+        execute(
+            &service,
+            CommandRequest::new_hset("local", "k1", "v1".into()),
+        )
+        .await;
+
+        disconnect(&conns);
+        execute(&leader, CommandRequest::new_hset("t1", "k2", "v2".into())).await;
+        execute(&leader, CommandRequest::new_hset("t1", "k3", "v3".into())).await;
+        wait_for(&follower, 3).await;
+
+        let res = execute(&service, CommandRequest::new_hgetall("t1")).await;
+        assert_eq!(res.pairs.len(), 3);
+        let res = execute(&service, CommandRequest::new_hget("local", "k1")).await;
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn follower_should_reload_snapshot_when_too_far_behind() {
+        let leader: Service = ServiceInner::new(MemTable::new())
+            .leader(Leader::new(1))
+            .into();
+        let (addr, conns) = start_leader(leader.clone()).await;
+
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let follower = Follower::new(service.clone(), addr.to_string());
+        follower.spawn();
+        execute(&leader, CommandRequest::new_hset("t1", "k1", "v1".into())).await;
+        wait_for(&follower, 1).await;
+        execute(
+            &service,
+            CommandRequest::new_hset("local", "k1", "v1".into()),
+        )
+        .await;
+
+        disconnect(&conns);
+        for i in 2..5 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+            execute(&leader, cmd).await;
+        }
+        wait_for(&follower, 4).await;
+
+        let res = execute(&service, CommandRequest::new_hgetall("t1")).await;
+        assert_eq!(res.pairs.len(), 4);
+        let res = execute(&service, CommandRequest::new_hget("local", "k1")).await;
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn follower_should_authenticate_to_leader_with_acl() {
+        let mut acl = Acl::default();
+        acl.add_user("replica", "secret", [("*".into(), Permission::Admin)]);
+        let leader: Service = ServiceInner::new(MemTable::new())
+            .leader(Leader::default())
+            .acl(acl)
+            .into();
+        let (addr, _) = start_leader(leader.clone()).await;
+
+        // 没有 token 的 follower 复制不了
+        let service: Service = ServiceInner::new(MemTable::new()).read_only().into();
+        let anonymous = Follower::new(service, addr.to_string());
+        anonymous.spawn();
+
+        let service: Service = ServiceInner::new(MemTable::new()).read_only().into();
+        let follower = Follower::new(service.clone(), addr.to_string()).token("secret");
+        follower.spawn();
+        wait_for(&follower, 0).await;
+
+        let mut session = leader.session(Some("secret")).unwrap();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = leader.execute_in(cmd, &mut session).next().await.unwrap();
+        assert_eq!(res.status, 200);
+        wait_for(&follower, 1).await;
+        let res = execute(&service, CommandRequest::new_hget("t1", "k1")).await;
+        assert_res_ok(res, &["v1".into()], &[]);
+        assert_eq!(anonymous.epoch(), 0);
+    }
+
+    #[tokio::test]
+    async fn replicate_without_leader_should_fail() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = execute(&service, CommandRequest::new_replicate(0, 0)).await;
+        assert_res_error(res, 400, "Replication is not enabled");
+    }
+
+    type Connections = Arc<Mutex<Vec<JoinHandle<()>>>>;
+
+    // 在 localhost 上启动 leader，返回地址和所有连接的任务
+    async fn start_leader(service: Service) -> (SocketAddr, Connections) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let conns = Connections::default();
+        let handles = conns.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service.clone();
+                let handle = tokio::spawn(async move {
+                    let _ = ProstServerStream::new(stream, service).process().await;
+                });
+                handles.lock().unwrap().push(handle);
+            }
+        });
+        (addr, conns)
+    }
+
+    // 断开 leader 上所有的连接，模拟网络故障
+    fn disconnect(conns: &Connections) {
+        for handle in conns.lock().unwrap().drain(..) {
+            handle.abort();
+        }
+    }
+
+    async fn wait_for(follower: &Follower, seq: u64) {
+        let wait = async {
+            while follower.epoch() == 0 || follower.seq() != seq {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(5), wait).await.unwrap();
+    }
+
+    async fn execute(service: &Service, cmd: CommandRequest) -> CommandResponse {
+        let res = service.execute(cmd).next().await.unwrap();
+        (*res).clone()
+    }
+}
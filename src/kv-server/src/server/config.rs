@@ -0,0 +1,393 @@
+use serde::Deserialize;
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+use crate::{KvError, SyncPolicy, WalOptions, DEFAULT_MAX_IN_FLIGHT, MAX_FRAME};
+
+/// kvs 的配置，对应 TOML 配置文件，没有写的字段使用缺省值
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub general: GeneralConfig,
+    pub storage: StorageConfig,
+    /// 不配置的话不使用 TLS
+    pub tls: Option<TlsConfig>,
+    pub log: LogConfig,
+    pub limits: LimitsConfig,
+    /// 不配置的话不提供 metrics 接口
+    pub metrics: Option<MetricsConfig>,
+    /// 不配置的话不提供 Redis 协议的接口
+    pub resp: Option<RespConfig>,
+    /// 不配置的话不提供 HTTP/JSON 网关
+    pub gateway: Option<GatewayConfig>,
+    /// 不配置的话不提供 gRPC 服务
+    pub grpc: Option<GrpcConfig>,
+    /// 不配置的话不需要认证，所有客户端都可以访问所有的 table
+    pub auth: Option<AuthConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneralConfig {
+    /// 监听的地址
+    pub addr: String,
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:9527".into(),
+        }
+    }
+}
+
+/// 存储后端
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StorageConfig {
+    /// MemTable，配置了 path 的话把数据持久化到这个目录下的 WAL 和 snapshot
+    Memory {
+        path: Option<PathBuf>,
+        #[serde(default)]
+        sync: SyncMode,
+        /// fsync 的间隔，只对 sync = "interval" 有效
+        #[serde(default = "default_sync_interval_ms")]
+        sync_interval_ms: u64,
+        /// 写 snapshot 的间隔，0 表示不自动写
+        #[serde(default = "default_snapshot_interval_secs")]
+        snapshot_interval_secs: u64,
+    },
+    /// sled 数据库
+    Sled { path: PathBuf },
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self::Memory {
+            path: None,
+            sync: SyncMode::default(),
+            sync_interval_ms: default_sync_interval_ms(),
+            snapshot_interval_secs: default_snapshot_interval_secs(),
+        }
+    }
+}
+
+/// WAL 落盘的方式，见 SyncPolicy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    Always,
+    #[default]
+    Interval,
+    Never,
+}
+
+fn default_sync_interval_ms() -> u64 {
+    1000
+}
+
+fn default_snapshot_interval_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// 服务器证书
+    pub cert: PathBuf,
+    /// 服务器证书的私钥
+    pub key: PathBuf,
+    /// 配置了 CA 的话要求客户端提供由它签发的证书
+    pub ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// 日志级别，和 RUST_LOG 的语法一样，比如 "info" 或者 "kv_server=debug"
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// 提供 GET /metrics 的 HTTP 地址
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RespConfig {
+    /// RESP2 协议监听的地址，和 prost 协议共享同一份数据
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    /// HTTP/JSON 网关监听的地址
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// gRPC 服务监听的地址
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// ACL 文件，格式见 Acl
+    pub acl: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// 最多同时处理的连接数，所有协议共享，超过的连接会收到 429 后被关闭
+    /// 同时被拒绝的连接太多，或者是 gRPC 的连接时直接关闭
+    pub max_connections: usize,
+    /// 一个连接最多排队等待执行的请求数，超过的请求返回 429
+    pub max_in_flight: usize,
+    /// 每个客户端 IP 每秒最多的请求数，0 表示不限制，HTTP 网关和 gRPC 也受限制
+    pub requests_per_sec: u32,
+    /// 令牌桶最多攒下的请求数，0 表示和 requests_per_sec 一样
+    pub burst: u32,
+    /// 一个请求 frame（RESP 是一个命令）的最大字节数，超过的请求返回 413
+    pub max_frame: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            requests_per_sec: 0,
+            burst: 0,
+            max_frame: MAX_FRAME,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// 从 TOML 文件加载配置并检查
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| KvError::ConfigError(format!("cannot read {}: {}", path.display(), e)))?;
+        Self::from_toml(&content)
+    }
+
+    /// 解析 TOML 格式的配置并检查
+    pub fn from_toml(content: &str) -> Result<Self, KvError> {
+        let config: Self =
+            toml::from_str(content).map_err(|e| KvError::ConfigError(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// 检查配置是否可用，文件类的配置只检查文件是否存在
+    pub fn validate(&self) -> Result<(), KvError> {
+        let error = |msg: String| Err(KvError::ConfigError(msg));
+
+        let addrs = [
+            ("listen", Some(&self.general.addr)),
+            ("metrics", self.metrics.as_ref().map(|v| &v.addr)),
+            ("resp", self.resp.as_ref().map(|v| &v.addr)),
+            ("gateway", self.gateway.as_ref().map(|v| &v.addr)),
+            ("grpc", self.grpc.as_ref().map(|v| &v.addr)),
+        ];
+        for (name, addr) in addrs {
+            match addr {
+                Some(addr) if addr.parse::<SocketAddr>().is_err() => {
+                    return error(format!("invalid {} address {:?}", name, addr))
+                }
+                _ => {}
+            }
+        }
+
+        match &self.storage {
+            StorageConfig::Memory {
+                sync: SyncMode::Interval,
+                sync_interval_ms: 0,
+                ..
+            } => return error("storage.sync_interval_ms must be positive".into()),
+            StorageConfig::Sled { path } if path.as_os_str().is_empty() => {
+                return error("storage.path is required for sled".into())
+            }
+            _ => {}
+        }
+
+        if let Some(tls) = &self.tls {
+            let files = [("cert", Some(&tls.cert)), ("key", Some(&tls.key))];
+            for (name, file) in files.into_iter().chain([("ca", tls.ca.as_ref())]) {
+                match file {
+                    Some(file) if !file.is_file() => {
+                        return error(format!("tls.{} {} is not a file", name, file.display()))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(auth) = &self.auth {
+            if !auth.acl.is_file() {
+                return error(format!("auth.acl {} is not a file", auth.acl.display()));
+            }
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            return error(format!("invalid log level {:?}: {}", self.log.level, e));
+        }
+
+        if self.limits.max_connections == 0 {
+            return error("limits.max_connections must be positive".into());
+        }
+        if self.limits.max_in_flight == 0 {
+            return error("limits.max_in_flight must be positive".into());
+        }
+        if self.limits.max_frame == 0 || self.limits.max_frame > MAX_FRAME {
+            return error(format!("limits.max_frame must be in 1..={}", MAX_FRAME));
+        }
+        Ok(())
+    }
+}
+
+impl StorageConfig {
+    // MemTable 持久化的配置
+    pub(super) fn wal_options(&self) -> WalOptions {
+        let mut options = WalOptions::default();
+        if let StorageConfig::Memory {
+            sync,
+            sync_interval_ms,
+            snapshot_interval_secs,
+            ..
+        } = self
+        {
+            options.sync = match sync {
+                SyncMode::Always => SyncPolicy::Always,
+                SyncMode::Interval => {
+                    SyncPolicy::Interval(Duration::from_millis(*sync_interval_ms))
+                }
+                SyncMode::Never => SyncPolicy::Never,
+            };
+            options.snapshot_interval =
+                (*snapshot_interval_secs > 0).then(|| Duration::from_secs(*snapshot_interval_secs));
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_should_be_valid() {
+        let config = ServerConfig::from_toml(include_str!("../../fixtures/kvs.toml")).unwrap();
+        assert_eq!(config.general.addr, "0.0.0.0:9527");
+        assert_eq!(config.log.level, "info");
+        assert_eq!(config.limits.max_connections, 1024);
+        assert_eq!(
+            config.metrics,
+            Some(MetricsConfig {
+                addr: "127.0.0.1:9528".into()
+            })
+        );
+        assert_eq!(
+            config.storage.wal_options(),
+            WalOptions {
+                sync: SyncPolicy::Interval(Duration::from_secs(1)),
+                snapshot_interval: Some(Duration::from_secs(300)),
+            }
+        );
+    }
+
+    #[test]
+    fn empty_config_should_use_defaults() {
+        let config = ServerConfig::from_toml("").unwrap();
+        assert_eq!(config, ServerConfig::default());
+    }
+
+    #[test]
+    fn storage_config_should_be_parsed() {
+        let config = ServerConfig::from_toml(
+            r#"
+            [storage]
+            type = "sled"
+            path = "/tmp/kvs"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.storage,
+            StorageConfig::Sled {
+                path: "/tmp/kvs".into()
+            }
+        );
+
+        let config = ServerConfig::from_toml(
+            r#"
+            [storage]
+            type = "memory"
+            path = "/tmp/kvs"
+            sync = "always"
+            snapshot_interval_secs = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.storage.wal_options(),
+            WalOptions {
+                sync: SyncPolicy::Always,
+                snapshot_interval: None,
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_config_should_be_rejected() {
+        let cases = [
+            "[general]\naddr = \"localhost\"",
+            "[general]\nport = 9527",
+            "[storage]\ntype = \"rocksdb\"",
+            "[storage]\ntype = \"sled\"",
+            "[storage]\ntype = \"memory\"\nsync_interval_ms = 0",
+            "[tls]\ncert = \"/no/such/cert\"\nkey = \"/no/such/key\"",
+            "[log]\nlevel = \"kv_server=loud\"",
+            "[limits]\nmax_connections = 0",
+            "[limits]\nmax_in_flight = 0",
+            "[limits]\nmax_frame = 0",
+            "[limits]\nmax_frame = 1073741824",
+            "[metrics]\naddr = \"9528\"",
+            "[auth]\nacl = \"/no/such/acl\"",
+            "[resp]\naddr = \"localhost\"",
+            "[gateway]\naddr = \"localhost\"",
+            "[grpc]\naddr = \"localhost\"",
+        ];
+        for case in cases {
+            let result = ServerConfig::from_toml(case);
+            assert!(
+                matches!(result, Err(KvError::ConfigError(_))),
+                "{}: {:?}",
+                case,
+                result
+            );
+        }
+    }
+}
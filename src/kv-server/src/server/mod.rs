@@ -0,0 +1,664 @@
+mod config;
+
+pub use config::{
+    AuthConfig, GatewayConfig, GeneralConfig, GrpcConfig, LimitsConfig, LogConfig, MetricsConfig,
+    RespConfig, ServerConfig, StorageConfig, SyncMode, TlsConfig,
+};
+
+use bytes::BytesMut;
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+    time,
+};
+use tokio_util::codec::Encoder;
+use tracing::{info, warn};
+
+use crate::{
+    refuse, serve_gateway, serve_grpc, serve_metrics, Acl, ClientLimiter, CommandResponse,
+    ConnectionGuard, FrameCoder, Incoming, KvError, MemTable, Metrics, ProstServerStream,
+    RateLimiter, RespCodec, RespServerStream, RespValue, Service, ServiceInner, SledDb, Storage,
+    TlsServerAcceptor, HANDSHAKE_TIMEOUT, MAX_REJECTING,
+};
+
+// 清理过期 key 的间隔
+const REAPER_PERIOD: Duration = Duration::from_secs(1);
+
+/// 按 ServerConfig 运行的 KV Server
+pub struct Server {
+    config: ServerConfig,
+    listener: TcpListener,
+    acceptor: Option<TlsServerAcceptor>,
+    metrics_listener: Option<TcpListener>,
+    resp_listener: Option<TcpListener>,
+    gateway_listener: Option<TcpListener>,
+    grpc_listener: Option<TcpListener>,
+    acl: Option<Acl>,
+}
+
+// 一个连接的限制
+struct Limits {
+    config: Arc<LimitsConfig>,
+    client: Option<ClientLimiter>,
+}
+
+// 连接使用的协议
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    Prost,
+    Resp,
+}
+
+impl Server {
+    /// 检查配置，加载 TLS 证书并绑定监听的地址
+    pub async fn bind(config: ServerConfig) -> Result<Self, KvError> {
+        config.validate()?;
+        let acceptor = match &config.tls {
+            Some(tls) => Some(TlsServerAcceptor::from_files(
+                &tls.cert,
+                &tls.key,
+                tls.ca.as_deref(),
+            )?),
+            None => None,
+        };
+        let resp_listener = bind_optional(config.resp.as_ref().map(|v| &v.addr)).await?;
+        let gateway_listener = bind_optional(config.gateway.as_ref().map(|v| &v.addr)).await?;
+        let grpc_listener = bind_optional(config.grpc.as_ref().map(|v| &v.addr)).await?;
+        let acl = match &config.auth {
+            Some(auth) => Some(Acl::load(&auth.acl)?),
+            None => None,
+        };
+        let listener = TcpListener::bind(&config.general.addr).await?;
+        let metrics_listener = bind_optional(config.metrics.as_ref().map(|v| &v.addr)).await?;
+        Ok(Self {
+            config,
+            listener,
+            acceptor,
+            metrics_listener,
+            resp_listener,
+            gateway_listener,
+            grpc_listener,
+            acl,
+        })
+    }
+
+    /// 实际监听的地址，配置的端口是 0 时可以用它拿到系统分配的端口
+    pub fn local_addr(&self) -> Result<SocketAddr, KvError> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// metrics 接口实际监听的地址
+    pub fn metrics_addr(&self) -> Result<Option<SocketAddr>, KvError> {
+        listener_addr(&self.metrics_listener)
+    }
+
+    /// RESP2 接口实际监听的地址
+    pub fn resp_addr(&self) -> Result<Option<SocketAddr>, KvError> {
+        listener_addr(&self.resp_listener)
+    }
+
+    /// HTTP/JSON 网关实际监听的地址
+    pub fn gateway_addr(&self) -> Result<Option<SocketAddr>, KvError> {
+        listener_addr(&self.gateway_listener)
+    }
+
+    /// gRPC 服务实际监听的地址
+    pub fn grpc_addr(&self) -> Result<Option<SocketAddr>, KvError> {
+        listener_addr(&self.grpc_listener)
+    }
+
+    /// 打开存储并处理连接，直到 shutdown 完成
+    /// 退出前会关闭所有连接并关闭存储，持久化的数据会在这时落盘
+    pub async fn run(self, shutdown: impl Future<Output = ()>) -> Result<(), KvError> {
+        match &self.config.storage {
+            StorageConfig::Memory { path: None, .. } => self.serve(MemTable::new(), shutdown).await,
+            StorageConfig::Memory {
+                path: Some(path), ..
+            } => {
+                let store = MemTable::open(path, self.config.storage.wal_options())?;
+                self.serve(store, shutdown).await
+            }
+            StorageConfig::Sled { path } => {
+                let store = SledDb::new(path)?;
+                self.serve(store, shutdown).await
+            }
+        }
+    }
+
+    async fn serve<Store: Storage>(
+        mut self,
+        store: Store,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), KvError> {
+        let metrics = Arc::new(Metrics::new());
+        let mut inner = ServiceInner::new(store).metrics(Arc::clone(&metrics));
+        if let Some(acl) = self.acl.take() {
+            inner = inner.acl(acl);
+        }
+        let service: Service<Store> = inner.into();
+        service.spawn_reaper(REAPER_PERIOD);
+        info!("Listening on {}", self.local_addr()?);
+        if let Some(addr) = self.resp_addr()? {
+            info!("Listening on {} for RESP", addr);
+        }
+
+        let limits = Arc::new(self.config.limits.clone());
+        let limiter = match limits.requests_per_sec {
+            0 => None,
+            rate => Some(Arc::new(RateLimiter::new(rate, limits.burst))),
+        };
+        let permits = Arc::new(Semaphore::new(limits.max_connections));
+
+        // metrics、网关和 gRPC 在后台运行，退出时直接停止
+        // 网关和 gRPC 也受限速的限制，并且和其它协议共享最大连接数
+        let acceptor = self.acceptor.clone();
+        let incoming = |listener| {
+            let mut incoming = Incoming::new(listener).max_connections(Arc::clone(&permits));
+            if let Some(acceptor) = acceptor.clone() {
+                incoming = incoming.tls(acceptor);
+            }
+            if let Some(limiter) = limiter.clone() {
+                incoming = incoming.rate_limit(limiter);
+            }
+            incoming
+        };
+        let mut tasks = Vec::new();
+        if let Some(listener) = self.metrics_listener.take() {
+            let metrics = Arc::clone(&metrics);
+            tasks.push(tokio::spawn(serve_metrics(
+                listener,
+                metrics,
+                service.clone(),
+            )));
+        }
+        if let Some(listener) = self.gateway_listener.take() {
+            let (incoming, service) = (incoming(listener), service.clone());
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = serve_gateway(incoming, service).await {
+                    warn!("HTTP gateway stopped: {}", e);
+                }
+            }));
+        }
+        if let Some(listener) = self.grpc_listener.take() {
+            let (incoming, service) = (incoming(listener), service.clone());
+            tasks.push(tokio::spawn(async move {
+                if let Err(e) = serve_grpc(incoming, service).await {
+                    warn!("gRPC server stopped: {}", e);
+                }
+            }));
+        }
+
+        let rejecting = Arc::new(Semaphore::new(MAX_REJECTING));
+        let mut conns = JoinSet::new();
+        tokio::pin!(shutdown);
+        loop {
+            let (res, protocol) = tokio::select! {
+                res = self.listener.accept() => (res, Protocol::Prost),
+                res = accept(self.resp_listener.as_ref()) => (res, Protocol::Resp),
+                // 回收已经结束的连接
+                Some(_) = conns.join_next(), if !conns.is_empty() => continue,
+                _ = &mut shutdown => break,
+            };
+            let (stream, addr) = match res {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let acceptor = self.acceptor.clone();
+            let Ok(permit) = Arc::clone(&permits).try_acquire_owned() else {
+                warn!("Too many connections, rejected {}", addr);
+                // 回复完就结束，不需要在关闭服务器时等待
+                if let Ok(permit) = Arc::clone(&rejecting).try_acquire_owned() {
+                    tokio::spawn(reject(stream, addr, protocol, acceptor, permit));
+                }
+                continue;
+            };
+            let guard = metrics.connection();
+            let limits = Limits {
+                config: Arc::clone(&limits),
+                client: limiter.as_ref().map(|v| v.client(addr.ip())),
+            };
+            let conn = handle(
+                stream,
+                addr,
+                protocol,
+                acceptor,
+                service.clone(),
+                limits,
+                (permit, guard),
+            );
+            conns.spawn(conn);
+        }
+
+        info!("Shutting down, closing {} connections", conns.len());
+        conns.shutdown().await;
+        for task in tasks {
+            task.abort();
+        }
+        Ok(())
+    }
+}
+
+// 只绑定配置了的地址
+async fn bind_optional(addr: Option<&String>) -> Result<Option<TcpListener>, KvError> {
+    match addr {
+        Some(addr) => Ok(Some(TcpListener::bind(addr).await?)),
+        None => Ok(None),
+    }
+}
+
+fn listener_addr(listener: &Option<TcpListener>) -> Result<Option<SocketAddr>, KvError> {
+    match listener {
+        Some(listener) => Ok(Some(listener.local_addr()?)),
+        None => Ok(None),
+    }
+}
+
+// 没有配置的 listener 永远不会 accept 到连接
+async fn accept(listener: Option<&TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+// 处理一个连接，连接结束后释放 permit，并减少 metrics 里的连接数
+async fn handle<Store: Storage>(
+    stream: TcpStream,
+    addr: SocketAddr,
+    protocol: Protocol,
+    acceptor: Option<TlsServerAcceptor>,
+    service: Service<Store>,
+    limits: Limits,
+    _guard: (OwnedSemaphorePermit, ConnectionGuard),
+) {
+    info!("Client {} connected with {:?}", addr, protocol);
+    let result = match acceptor {
+        // 握手太慢的连接直接关闭，释放占用的 permit
+        Some(acceptor) => match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => process(stream, protocol, service, limits).await,
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(KvError::Timeout("TLS handshake".into())),
+        },
+        None => process(stream, protocol, service, limits).await,
+    };
+    match result {
+        Ok(()) => info!("Client {} disconnected", addr),
+        Err(e) => warn!("Client {} disconnected with error: {}", addr, e),
+    }
+}
+
+async fn process<S, Store>(
+    stream: S,
+    protocol: Protocol,
+    service: Service<Store>,
+    limits: Limits,
+) -> Result<(), KvError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: Storage,
+{
+    match protocol {
+        Protocol::Prost => {
+            let mut stream = ProstServerStream::new(stream, service)
+                .max_in_flight(limits.config.max_in_flight)
+                .max_frame(limits.config.max_frame);
+            if let Some(client) = limits.client {
+                stream = stream.rate_limit(client);
+            }
+            stream.process().await
+        }
+        Protocol::Resp => {
+            let mut stream =
+                RespServerStream::new(stream, service).max_frame(limits.config.max_frame);
+            if let Some(client) = limits.client {
+                stream = stream.rate_limit(client);
+            }
+            stream.process().await
+        }
+    }
+}
+
+// 超过连接数限制时，回复 429 之后关闭连接，结束后释放 permit
+async fn reject(
+    stream: TcpStream,
+    addr: SocketAddr,
+    protocol: Protocol,
+    acceptor: Option<TlsServerAcceptor>,
+    permit: OwnedSemaphorePermit,
+) {
+    let e = KvError::RateLimited("max number of connections reached".into());
+    let mut buf = BytesMut::new();
+    let encoded = match protocol {
+        Protocol::Prost => CommandResponse::from(e).encode_frame(&mut buf),
+        Protocol::Resp => {
+            RespCodec::default().encode(RespValue::Error(format!("ERR {}", e)), &mut buf)
+        }
+    };
+    match encoded {
+        Ok(()) => refuse(stream, addr, acceptor, buf.freeze(), permit).await,
+        Err(e) => warn!("Failed to reject {}: {}", addr, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kv_service_client::KvServiceClient, ClientOptions, CommandRequest, KvClient,
+        ProstClientStream, TlsClientConnector, Value,
+    };
+    use tempfile::tempdir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+        task::JoinHandle,
+    };
+    use tonic::transport::{Endpoint, Uri};
+    use tower::service_fn;
+
+    #[tokio::test]
+    async fn server_should_work() {
+        let (addr, stop, server) = start(ServerConfig::default()).await;
+        let client = KvClient::connect(addr.to_string()).await.unwrap();
+        assert_eq!(client.hset("t1", "k1", "v1").await, Ok(None));
+        assert_eq!(
+            client.hget::<String>("t1", "k1").await,
+            Ok(Some("v1".into()))
+        );
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn server_should_serve_metrics() {
+        let config = ServerConfig::from_toml(
+            "[general]\naddr = \"127.0.0.1:0\"\n[metrics]\naddr = \"127.0.0.1:0\"",
+        )
+        .unwrap();
+        let server = Server::bind(config).await.unwrap();
+        let (addr, metrics_addr) = (server.local_addr().unwrap(), server.metrics_addr().unwrap());
+        tokio::spawn(server.run(std::future::pending()));
+
+        let client = KvClient::connect(addr.to_string()).await.unwrap();
+        client.hset("t1", "k1", "v1").await.unwrap();
+
+        let mut stream = TcpStream::connect(metrics_addr.unwrap()).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        for line in [
+            r#"kv_commands_total{command="hset",status="200"} 1"#,
+            r#"kv_table_keys{table="t1"} 1"#,
+            "kv_connections 1",
+        ] {
+            assert!(res.contains(line), "{} not in\n{}", line, res);
+        }
+    }
+
+    #[tokio::test]
+    async fn persistent_server_should_keep_data_after_restart() {
+        let dir = tempdir().unwrap();
+        let config = ServerConfig::from_toml(&format!(
+            "[storage]\ntype = \"memory\"\npath = {:?}",
+            dir.path()
+        ))
+        .unwrap();
+
+        let (addr, stop, server) = start(config.clone()).await;
+        let client = KvClient::connect(addr.to_string()).await.unwrap();
+        client.hset("t1", "k1", 42i64).await.unwrap();
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        let (addr, _stop, _server) = start(config).await;
+        let client = KvClient::connect(addr.to_string()).await.unwrap();
+        assert_eq!(client.hget::<i64>("t1", "k1").await, Ok(Some(42)));
+    }
+
+    #[tokio::test]
+    async fn server_should_limit_connections() {
+        let mut config = ServerConfig::default();
+        config.limits.max_connections = 1;
+        let (addr, _stop, _server) = start(config).await;
+
+        let mut first = ProstClientStream::new(TcpStream::connect(addr).await.unwrap());
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        assert_eq!(first.execute(cmd.clone()).await.unwrap().status, 404);
+
+        // 第二个连接收到 429 之后被关闭
+        let mut second = ProstClientStream::new(TcpStream::connect(addr).await.unwrap());
+        assert_eq!(second.execute(cmd.clone()).await.unwrap().status, 429);
+        assert!(second.execute(cmd.clone()).await.is_err());
+
+        // 第一个连接关闭之后可以建立新的连接
+        drop(first);
+        let mut options = ClientOptions::new(addr.to_string());
+        options.min_backoff = Duration::from_millis(10);
+        let client = KvClient::new(options);
+        assert_eq!(client.hget::<String>("t1", "k1").await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn server_should_use_tls() {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let config = ServerConfig::from_toml(&format!(
+            "[general]\naddr = \"127.0.0.1:0\"\n[tls]\ncert = \"{0}/server.cert\"\nkey = \"{0}/server.key\"",
+            fixtures
+        ))
+        .unwrap();
+        let (addr, _stop, _server) = start(config).await;
+
+        let mut options = ClientOptions::new(addr.to_string());
+        let ca = format!("{}/ca.cert", fixtures);
+        let connector =
+            TlsClientConnector::from_files("kvserver.acme.inc", None, Some(ca.as_ref())).unwrap();
+        options.tls = Some(connector);
+        let client = KvClient::new(options);
+        assert_eq!(client.hset("t1", "k1", "v1").await, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn server_should_require_auth() {
+        let acl = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/acl.toml");
+        let config = ServerConfig::from_toml(&format!("[auth]\nacl = {:?}", acl)).unwrap();
+        let (addr, _stop, _server) = start(config).await;
+
+        let client = KvClient::connect(addr.to_string()).await.unwrap();
+        let e = client.hset("orders", "k1", "v1").await.unwrap_err();
+        assert!(matches!(e, KvError::ServerError(403, _)), "{:?}", e);
+
+        let mut options = ClientOptions::new(addr.to_string());
+        options.token = Some("app-secret".into());
+        let client = KvClient::new(options);
+        assert_eq!(client.hset("orders", "k1", "v1").await, Ok(None));
+        let e = client.hset("users", "u1", "v1").await.unwrap_err();
+        assert!(matches!(e, KvError::ServerError(403, _)), "{:?}", e);
+        assert_eq!(client.hget::<String>("users", "u1").await, Ok(None));
+
+        let mut options = ClientOptions::new(addr.to_string());
+        options.token = Some("wrong".into());
+        let e = KvClient::new(options).hget::<String>("t1", "k1").await;
+        assert!(matches!(e, Err(KvError::ServerError(403, _))), "{:?}", e);
+    }
+
+    #[tokio::test]
+    async fn server_should_serve_resp() {
+        let config = ServerConfig::from_toml(
+            "[general]\naddr = \"127.0.0.1:0\"\n[resp]\naddr = \"127.0.0.1:0\"",
+        )
+        .unwrap();
+        let server = Server::bind(config).await.unwrap();
+        let (addr, resp_addr) = (server.local_addr().unwrap(), server.resp_addr().unwrap());
+        tokio::spawn(server.run(std::future::pending()));
+
+        let client = KvClient::connect(addr.to_string()).await.unwrap();
+        client.hset("t1", "k1", "v1").await.unwrap();
+
+        // 两个协议访问的是同一份数据
+        let mut stream = TcpStream::connect(resp_addr.unwrap()).await.unwrap();
+        stream
+            .write_all(b"*3\r\n$4\r\nHGET\r\n$2\r\nt1\r\n$2\r\nk1\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        let mut res = Vec::new();
+        stream.read_to_end(&mut res).await.unwrap();
+        assert_eq!(res, b"$2\r\nv1\r\n+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn server_should_limit_resp_command_size() {
+        let config = ServerConfig::from_toml(
+            "[general]\naddr = \"127.0.0.1:0\"\n[resp]\naddr = \"127.0.0.1:0\"\n[limits]\nmax_frame = 1024",
+        )
+        .unwrap();
+        let server = Server::bind(config).await.unwrap();
+        let resp_addr = server.resp_addr().unwrap().unwrap();
+        tokio::spawn(server.run(std::future::pending()));
+
+        let mut stream = TcpStream::connect(resp_addr).await.unwrap();
+        stream
+            .write_all(b"*4\r\n$4\r\nHSET\r\n$2\r\nt1\r\n$2\r\nk1\r\n$2000\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("-ERR Frame is too large"), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn server_should_serve_gateway() {
+        let config = ServerConfig::from_toml(
+            "[general]\naddr = \"127.0.0.1:0\"\n[gateway]\naddr = \"127.0.0.1:0\"",
+        )
+        .unwrap();
+        let server = Server::bind(config).await.unwrap();
+        let (addr, gateway_addr) = (server.local_addr().unwrap(), server.gateway_addr().unwrap());
+        tokio::spawn(server.run(std::future::pending()));
+
+        let client = KvClient::connect(addr.to_string()).await.unwrap();
+        client.hset("t1", "k1", 42i64).await.unwrap();
+
+        let mut stream = TcpStream::connect(gateway_addr.unwrap()).await.unwrap();
+        stream
+            .write_all(
+                b"GET /tables/t1/keys/k1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK"), "{}", res);
+        assert!(res.contains(r#""values":[42]"#), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn server_should_serve_grpc() {
+        let config = ServerConfig::from_toml(
+            "[general]\naddr = \"127.0.0.1:0\"\n[grpc]\naddr = \"127.0.0.1:0\"",
+        )
+        .unwrap();
+        let server = Server::bind(config).await.unwrap();
+        let (addr, grpc_addr) = (server.local_addr().unwrap(), server.grpc_addr().unwrap());
+        tokio::spawn(server.run(std::future::pending()));
+
+        let client = KvClient::connect(addr.to_string()).await.unwrap();
+        client.hset("t1", "k1", "v1").await.unwrap();
+
+        let url = format!("http://{}", grpc_addr.unwrap());
+        let mut grpc = KvServiceClient::connect(url).await.unwrap();
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = grpc.execute(cmd).await.unwrap().into_inner();
+        assert_eq!(res.values, [Value::from("v1")]);
+    }
+
+    #[tokio::test]
+    async fn gateway_and_grpc_should_use_tls() {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+        let config = ServerConfig::from_toml(&format!(
+            "[general]\naddr = \"127.0.0.1:0\"\n[gateway]\naddr = \"127.0.0.1:0\"\n[grpc]\naddr = \"127.0.0.1:0\"\n[tls]\ncert = \"{0}/server.cert\"\nkey = \"{0}/server.key\"",
+            fixtures
+        ))
+        .unwrap();
+        let server = Server::bind(config).await.unwrap();
+        let gateway_addr = server.gateway_addr().unwrap().unwrap();
+        let grpc_addr = server.grpc_addr().unwrap().unwrap();
+        tokio::spawn(server.run(std::future::pending()));
+        let ca = format!("{}/ca.cert", fixtures);
+        let connector = |alpn: &str| {
+            let mut connector =
+                TlsClientConnector::from_files("kvserver.acme.inc", None, Some(ca.as_ref()))
+                    .unwrap();
+            let mut config = (*connector.config).clone();
+            config.alpn_protocols = vec![Vec::from(alpn)];
+            connector.config = Arc::new(config);
+            connector
+        };
+
+        // 不使用 TLS 的请求收不到回复
+        let mut stream = TcpStream::connect(gateway_addr).await.unwrap();
+        stream
+            .write_all(b"GET /tables/t1/keys/k1 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = Vec::new();
+        let _ = stream.read_to_end(&mut res).await;
+        assert!(!res.starts_with(b"HTTP/1.1"));
+
+        let stream = TcpStream::connect(gateway_addr).await.unwrap();
+        let mut stream = connector("http/1.1").connect(stream).await.unwrap();
+        stream
+            .write_all(
+                b"GET /tables/t1/keys/k1 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut res = String::new();
+        let _ = stream.read_to_string(&mut res).await;
+        assert!(res.starts_with("HTTP/1.1 404"), "{}", res);
+
+        let connector = connector("h2");
+        let channel = Endpoint::from_static("http://kvserver.acme.inc")
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let connector = connector.clone();
+                async move {
+                    connector
+                        .connect(TcpStream::connect(grpc_addr).await?)
+                        .await
+                }
+            }))
+            .await
+            .unwrap();
+        let mut grpc = KvServiceClient::new(channel);
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = grpc.execute(cmd).await.unwrap().into_inner();
+        assert_eq!(res.status, 200);
+    }
+
+    async fn start(
+        mut config: ServerConfig,
+    ) -> (
+        SocketAddr,
+        oneshot::Sender<()>,
+        JoinHandle<Result<(), KvError>>,
+    ) {
+        config.general.addr = "127.0.0.1:0".into();
+        let server = Server::bind(config).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::spawn(server.run(async {
+            let _ = rx.await;
+        }));
+        (addr, tx, handle)
+    }
+}
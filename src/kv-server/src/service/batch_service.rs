@@ -0,0 +1,290 @@
+use std::time::Duration;
+
+use super::command_service::CommandService;
+use crate::command_request::RequestData;
+use crate::storage::{incr_float_value, incr_value};
+use crate::*;
+
+impl CommandService for Batch {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let nested = self
+            .commands
+            .iter()
+            .any(|cmd| matches!(cmd.request_data, Some(RequestData::Batch(_))));
+        if nested {
+            return KvError::InvalidCommand("Batch cannot be nested".into()).into();
+        }
+
+        match self.atomic {
+            true => execute_atomic(self.commands, store),
+            // 非原子的 batch 逐个执行，某个命令失败不影响其它命令
+            false => self
+                .commands
+                .into_iter()
+                .map(|cmd| dispatch(cmd, store))
+                .collect::<Vec<_>>()
+                .into(),
+        }
+    }
+}
+
+// 所有命令在一个事务里执行，任何一个命令出错，整个 batch 都会回滚
+// 读不到 key（404）以及 HSETNX/HCAS 的条件不满足不算出错，返回的结果和单独执行时一样
+fn execute_atomic(commands: Vec<CommandRequest>, store: &impl Storage) -> CommandResponse {
+    let mut tables = Vec::with_capacity(commands.len());
+    for cmd in &commands {
+        match table_of(cmd) {
+            Some(table) => tables.push(table),
+            None => {
+                let msg = format!("Command is not supported in atomic batch: {:?}", cmd);
+                return KvError::InvalidCommand(msg).into();
+            }
+        }
+    }
+
+    let mut responses = Vec::with_capacity(commands.len());
+    let mut failed = None;
+    let result = store.transaction(&tables, &mut |tx| {
+        // 事务可能因为冲突被重新执行，先清掉上一次的结果
+        responses.clear();
+        failed = None;
+        for (i, cmd) in commands.iter().enumerate() {
+            match execute_in_transaction(cmd.clone(), tx) {
+                Ok(res) => responses.push(res),
+                Err(e) => {
+                    failed = Some((i, CommandResponse::from(e)));
+                    return Err(KvError::Internal("Batch aborted".into()));
+                }
+            }
+        }
+        Ok(())
+    });
+
+    match (result, failed) {
+        (Ok(()), _) => responses.into(),
+        (Err(_), Some((i, mut res))) => {
+            res.message = format!("Batch aborted at command {}: {}", i, res.message);
+            res
+        }
+        (Err(e), None) => e.into(),
+    }
+}
+
+// 原子 batch 支持的命令，返回命令访问的 table
+fn table_of(cmd: &CommandRequest) -> Option<&str> {
+    let table = match cmd.request_data.as_ref()? {
+        RequestData::Hget(v) => &v.table,
+        RequestData::Hmget(v) => &v.table,
+        RequestData::Hset(v) => &v.table,
+        RequestData::Hmset(v) => &v.table,
+        RequestData::Hdel(v) => &v.table,
+        RequestData::Hmdel(v) => &v.table,
+        RequestData::Hexist(v) => &v.table,
+        RequestData::Hmexist(v) => &v.table,
+        RequestData::Hincrby(v) => &v.table,
+        RequestData::Hincrbyfloat(v) => &v.table,
+        RequestData::Hsetnx(v) => &v.table,
+        RequestData::Hcas(v) => &v.table,
+        _ => return None,
+    };
+    Some(table)
+}
+
+// 和 CommandService 的语义一样，只是所有读写都在事务里，返回 Err 的命令会让整个 batch 回滚
+fn execute_in_transaction(
+    cmd: CommandRequest,
+    tx: &mut dyn Transaction,
+) -> Result<CommandResponse, KvError> {
+    let res = match cmd.request_data {
+        Some(RequestData::Hget(v)) => match tx.get(&v.table, &v.key)? {
+            Some(value) => value.into(),
+            None => KvError::NotFound(v.table, v.key).into(),
+        },
+        Some(RequestData::Hmget(v)) => v
+            .keys
+            .iter()
+            .map(|key| tx.get(&v.table, key).map(|v| v.unwrap_or_default()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into(),
+        Some(RequestData::Hset(v)) => match v.pair {
+            Some(pair) => {
+                let value = pair.value.unwrap_or_default();
+                tx.set(&v.table, &pair.key, value, ttl(v.ttl))?
+                    .unwrap_or_default()
+                    .into()
+            }
+            None => Value::default().into(),
+        },
+        Some(RequestData::Hmset(v)) => v
+            .pairs
+            .into_iter()
+            .map(|pair| {
+                let value = pair.value.unwrap_or_default();
+                tx.set(&v.table, &pair.key, value, ttl(v.ttl))
+                    .map(|v| v.unwrap_or_default())
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .into(),
+        Some(RequestData::Hdel(v)) => tx.del(&v.table, &v.key)?.unwrap_or_default().into(),
+        Some(RequestData::Hmdel(v)) => v
+            .keys
+            .iter()
+            .map(|key| tx.del(&v.table, key).map(|v| v.unwrap_or_default()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into(),
+        Some(RequestData::Hexist(v)) => Value::from(tx.get(&v.table, &v.key)?.is_some()).into(),
+        Some(RequestData::Hmexist(v)) => v
+            .keys
+            .iter()
+            .map(|key| tx.get(&v.table, key).map(|v| Value::from(v.is_some())))
+            .collect::<Result<Vec<_>, _>>()?
+            .into(),
+        Some(RequestData::Hincrby(v)) => {
+            let new = incr_value(tx.get(&v.table, &v.key)?, v.delta)?;
+            tx.update(&v.table, &v.key, new.into())?;
+            Value::from(new).into()
+        }
+        Some(RequestData::Hincrbyfloat(v)) => {
+            let new = incr_float_value(tx.get(&v.table, &v.key)?, v.delta)?;
+            tx.update(&v.table, &v.key, new.into())?;
+            Value::from(new).into()
+        }
+        Some(RequestData::Hsetnx(v)) => match v.pair {
+            Some(pair) if tx.get(&v.table, &pair.key)?.is_none() => {
+                tx.set(&v.table, &pair.key, pair.value.unwrap_or_default(), None)?;
+                Value::from(true).into()
+            }
+            _ => Value::from(false).into(),
+        },
+        Some(RequestData::Hcas(v)) => {
+            let swapped = tx.get(&v.table, &v.key)? == v.expected;
+            if swapped {
+                tx.update(&v.table, &v.key, v.new.unwrap_or_default())?;
+            }
+            Value::from(swapped).into()
+        }
+        _ => {
+            let msg = "Command is not supported in atomic batch".into();
+            return Err(KvError::InvalidCommand(msg));
+        }
+    };
+    Ok(res)
+}
+
+// ttl 为 0 表示永不过期
+fn ttl(ttl: u64) -> Option<Duration> {
+    (ttl > 0).then(|| Duration::from_millis(ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok};
+    use tempfile::tempdir;
+
+    #[test]
+    fn batch_should_run_every_command() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hset("t1", "k1", "v1".into()),
+                CommandRequest::new_hget("t1", "k2"),
+                CommandRequest::new_hincrby("t2", "counter", 2),
+                CommandRequest::new_hget("t1", "k1"),
+            ],
+            false,
+        );
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.responses.len(), 4);
+        assert_res_ok(res.responses[0].clone(), &[Value::default()], &[]);
+        // 非原子的 batch 里某个命令失败，不影响其它命令
+        assert_res_error(res.responses[1].clone(), 404, "Not found");
+        assert_res_ok(res.responses[2].clone(), &[2.into()], &[]);
+        assert_res_ok(res.responses[3].clone(), &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn atomic_batch_should_work_with_memtable() {
+        test_atomic_batch(&MemTable::new());
+    }
+
+    #[test]
+    fn atomic_batch_should_work_with_sleddb() {
+        let dir = tempdir().unwrap();
+        test_atomic_batch(&SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn batch_with_unsupported_commands_should_fail() {
+        let store = MemTable::new();
+        let nested = CommandRequest::new_batch(vec![], true);
+        let cmd = CommandRequest::new_batch(vec![nested], false);
+        assert_res_error(dispatch(cmd, &store), 400, "nested");
+
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hset("t1", "k1", "v1".into()),
+                CommandRequest::new_hgetall("t1"),
+            ],
+            true,
+        );
+        assert_res_error(dispatch(cmd, &store), 400, "not supported");
+        // 校验失败时什么都不会执行
+        assert_eq!(store.get("t1", "k1"), Ok(None));
+    }
+
+    fn test_atomic_batch(store: &impl Storage) {
+        store.set("account", "alice".into(), 100.into()).unwrap();
+
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hincrby("account", "alice", -30),
+                CommandRequest::new_hincrby("account", "bob", 30),
+                CommandRequest::new_hset("log", "1", "alice -> bob: 30".into()),
+                CommandRequest::new_hmget("account", vec!["alice".into(), "bob".into()]),
+            ],
+            true,
+        );
+        let res = dispatch(cmd, store);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.responses.len(), 4);
+        assert_res_ok(res.responses[0].clone(), &[70.into()], &[]);
+        assert_res_ok(res.responses[1].clone(), &[30.into()], &[]);
+        assert_res_ok(res.responses[3].clone(), &[70.into(), 30.into()], &[]);
+
+        // 读不到 key 和条件不满足时和单独执行一样返回结果，不会回滚
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hget("account", "carol"),
+                CommandRequest::new_hsetnx("account", "bob", 0.into()),
+                CommandRequest::new_hcas("account", "bob", Some(0.into()), 30.into()),
+                CommandRequest::new_hset("log", "2", "carol: none".into()),
+            ],
+            true,
+        );
+        let res = dispatch(cmd, store);
+        assert_eq!(res.status, 200);
+        assert_res_error(res.responses[0].clone(), 404, "Not found");
+        assert_res_ok(res.responses[1].clone(), &[false.into()], &[]);
+        assert_res_ok(res.responses[2].clone(), &[false.into()], &[]);
+        assert_eq!(store.get("account", "bob"), Ok(Some(30.into())));
+        assert_eq!(store.get("log", "2"), Ok(Some("carol: none".into())));
+
+        // 命令出错时，整个 batch 回滚
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hincrby("account", "alice", -30),
+                CommandRequest::new_hsetnx("account", "carol", 10.into()),
+                CommandRequest::new_hdel("log", "2"),
+                CommandRequest::new_hincrby("log", "1", 1),
+            ],
+            true,
+        );
+        let res = dispatch(cmd, store);
+        assert_res_error(res, 400, "Batch aborted at command 3");
+        assert_eq!(store.get("account", "alice"), Ok(Some(70.into())));
+        assert_eq!(store.get("account", "carol"), Ok(None));
+        assert_eq!(store.get("log", "2"), Ok(Some("carol: none".into())));
+    }
+}
@@ -1,12 +1,12 @@
 // 命令接口
 
-use crate::command_request::RequestData;
-use crate::storage::MemTable;
-use crate::KvError;
-use crate::Storage;
-use crate::{CommandRequest, CommandResponse};
-use crate::{Hget, Hgetall, Hset, Kvpair, Value};
-use std::sync::Arc;
+use crate::*;
+use std::time::Duration;
+
+// HSCAN 没有指定 limit 时每页返回的数量
+const DEFAULT_SCAN_LIMIT: usize = 100;
+// HSCAN 每页最多返回的数量
+const MAX_SCAN_LIMIT: usize = 10_000;
 
 // 执行然后返回响应
 pub trait CommandService {
@@ -23,6 +23,20 @@ impl CommandService for Hget {
     }
 }
 
+impl CommandService for Hmget {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| match store.get(&self.table, key) {
+                Ok(Some(v)) => Ok(v),
+                Ok(None) => Ok(Value::default()),
+                Err(e) => Err(e),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(|e| e.into(), |v| v.into())
+    }
+}
+
 impl CommandService for Hgetall {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         match store.get_all(&self.table) {
@@ -35,7 +49,13 @@ impl CommandService for Hgetall {
 impl CommandService for Hset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         match self.pair {
-            Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
+            Some(v) => match set(
+                store,
+                &self.table,
+                v.key,
+                v.value.unwrap_or_default(),
+                self.ttl,
+            ) {
                 Ok(Some(v)) => v.into(),
                 Ok(None) => Value::default().into(),
                 Err(e) => e.into(),
@@ -45,11 +65,179 @@ impl CommandService for Hset {
     }
 }
 
-#[cfg(test)]
+// 和 Hset 一样，每个 key 返回之前的值，之前没有值则返回 Value::default()
+impl CommandService for Hmset {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let table = self.table;
+        self.pairs
+            .into_iter()
+            .map(|pair| {
+                set(
+                    store,
+                    &table,
+                    pair.key,
+                    pair.value.unwrap_or_default(),
+                    self.ttl,
+                )
+                .map(|v| v.unwrap_or_default())
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(|e| e.into(), |v| v.into())
+    }
+}
+
+impl CommandService for Hdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.del(&self.table, &self.key) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmdel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| store.del(&self.table, key).map(|v| v.unwrap_or_default()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(|e| e.into(), |v| v.into())
+    }
+}
+
+impl CommandService for Hexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.contains(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
 
+impl CommandService for Hmexist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| store.contains(&self.table, key).map(Value::from))
+            .collect::<Result<Vec<_>, _>>()
+            .map_or_else(|e| e.into(), |v| v.into())
+    }
+}
+
+impl CommandService for Hexpire {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.expire(&self.table, &self.key, Duration::from_millis(self.ttl)) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// 返回剩余的毫秒数，没有过期时间则返回 -1
+impl CommandService for Httl {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.ttl(&self.table, &self.key) {
+            Ok(Some(Some(ttl))) => Value::from(ttl.as_millis() as i64).into(),
+            Ok(Some(None)) => Value::from(-1).into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hpersist {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.persist(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrby {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr(&self.table, self.key, self.delta) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrbyfloat {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match store.incr_float(&self.table, self.key, self.delta) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hsetnx {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        match self.pair {
+            Some(v) => match store.set_nx(&self.table, v.key, v.value.unwrap_or_default()) {
+                Ok(v) => Value::from(v).into(),
+                Err(e) => e.into(),
+            },
+            None => Value::from(false).into(),
+        }
+    }
+}
+
+impl CommandService for Hcas {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let new = self.new.unwrap_or_default();
+        match store.compare_and_swap(&self.table, self.key, self.expected, new) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// 下一页的 cursor 放在 CommandResponse.cursor 里，没有更多数据时为空
+impl CommandService for Hscan {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let limit = match self.limit as usize {
+            0 => DEFAULT_SCAN_LIMIT,
+            n => n.min(MAX_SCAN_LIMIT),
+        };
+        // cursor 就是下一页的第一个 key，相当于新的起点
+        let range = ScanRange {
+            start: self.start.max(self.cursor),
+            end: self.end,
+            prefix: self.prefix,
+        };
+        match store.scan(&self.table, &range, limit) {
+            Ok((pairs, cursor)) => {
+                let mut res: CommandResponse = pairs.into();
+                res.cursor = cursor.unwrap_or_default();
+                res
+            }
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// ttl 为 0 表示永不过期
+fn set(
+    store: &impl Storage,
+    table: &str,
+    key: String,
+    value: Value,
+    ttl: u64,
+) -> Result<Option<Value>, KvError> {
+    match ttl {
+        0 => store.set(table, key, value),
+        ttl => store.set_with_ttl(table, key, value, Duration::from_millis(ttl)),
+    }
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{command_request::RequestData, storage::MemTable};
+    use crate::service::{assert_res_error, assert_res_ok, dispatch};
+    use tempfile::tempdir;
 
     #[test]
     fn hset_should_work() {
@@ -102,26 +290,288 @@ mod tests {
         ];
         assert_res_ok(res, &[], pairs);
     }
-    // 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET
-    fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
-        match cmd.request_data.unwrap() {
-            RequestData::Hget(v) => v.execute(store),
-            RequestData::Hgetall(v) => v.execute(store),
-            RequestData::Hset(v) => v.execute(store),
-            _ => todo!(),
-        }
-    } // 测试成功返回的结果
-    fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-        res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        assert_eq!(res.status, 200);
-        assert_eq!(res.message, "");
-        assert_eq!(res.values, values);
-        assert_eq!(res.pairs, pairs);
-    } // 测试失败返回的结果
-    fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
-        assert_eq!(res.status, code);
-        assert!(res.message.contains(msg));
-        assert_eq!(res.values, &[]);
-        assert_eq!(res.pairs, &[]);
+    #[test]
+    fn hset_with_ttl_should_expire() {
+        let store = MemTable::new();
+        let ttl = Duration::from_millis(50);
+        let cmd = CommandRequest::new_hset_with_ttl("session", "u1", "token".into(), ttl);
+        dispatch(cmd, &store);
+        let pairs = vec![Kvpair::new("u2", 1.into()), Kvpair::new("u3", 2.into())];
+        dispatch(
+            CommandRequest::new_hmset_with_ttl("session", pairs, ttl),
+            &store,
+        );
+
+        let res = dispatch(CommandRequest::new_httl("session", "u1"), &store);
+        let remain: i64 = res.values[0].clone().try_into().unwrap();
+        assert!(remain > 0 && remain <= 50);
+        let res = dispatch(CommandRequest::new_hget("session", "u3"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+
+        std::thread::sleep(ttl * 2);
+        let res = dispatch(CommandRequest::new_hget("session", "u1"), &store);
+        assert_res_error(res, 404, "Not found");
+        let res = dispatch(CommandRequest::new_httl("session", "u2"), &store);
+        assert_res_error(res, 404, "Not found");
+        let res = dispatch(CommandRequest::new_hgetall("session"), &store);
+        assert_res_ok(res, &[], &[]);
+    }
+
+    #[test]
+    fn hincrby_with_non_integer_should_return_400() {
+        let store = MemTable::new();
+        dispatch(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        let res = dispatch(CommandRequest::new_hincrby("t1", "k1", 1), &store);
+        assert_res_error(res, 400, "not an integer");
+
+        dispatch(
+            CommandRequest::new_hset("t1", "k2", i64::MAX.into()),
+            &store,
+        );
+        let res = dispatch(CommandRequest::new_hincrby("t1", "k2", 1), &store);
+        assert_res_error(res, 400, "overflow");
+    }
+
+    #[test]
+    fn hscan_should_paginate() {
+        let store = MemTable::new();
+        for i in 0..5 {
+            let cmd = CommandRequest::new_hset("t1", format!("user:{}", i), i.into());
+            dispatch(cmd, &store);
+        }
+        dispatch(CommandRequest::new_hset("t1", "admin", 0.into()), &store);
+
+        let cmd = CommandRequest::new_hscan("t1", "", "", "user:", 2, "");
+        let res = dispatch(cmd, &store);
+        let pairs = &[
+            Kvpair::new("user:0", 0.into()),
+            Kvpair::new("user:1", 1.into()),
+        ];
+        assert_eq!(res.cursor, "user:2");
+        assert_res_ok(res, &[], pairs);
+
+        let cmd = CommandRequest::new_hscan("t1", "", "", "user:", 2, "user:2");
+        let res = dispatch(cmd, &store);
+        let pairs = &[
+            Kvpair::new("user:2", 2.into()),
+            Kvpair::new("user:3", 3.into()),
+        ];
+        assert_eq!(res.cursor, "user:4");
+        assert_res_ok(res, &[], pairs);
+
+        let cmd = CommandRequest::new_hscan("t1", "", "", "user:", 2, "user:4");
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.cursor, "");
+        assert_res_ok(res, &[], &[Kvpair::new("user:4", 4.into())]);
+
+        // limit 为 0 时使用默认值
+        let cmd = CommandRequest::new_hscan("t1", "", "user:1", "", 0, "");
+        let res = dispatch(cmd, &store);
+        let pairs = &[
+            Kvpair::new("admin", 0.into()),
+            Kvpair::new("user:0", 0.into()),
+        ];
+        assert_res_ok(res, &[], pairs);
+    }
+
+    #[test]
+    fn commands_should_work_with_memtable() {
+        run_cases(&MemTable::new());
+    }
+
+    #[test]
+    fn commands_should_work_with_sleddb() {
+        let dir = tempdir().unwrap();
+        run_cases(&SledDb::new(dir).unwrap());
+    }
+
+    // 表驱动测试：先执行 setup 里的命令，再执行 cmd，检查返回的 values
+    fn run_cases(store: &impl Storage) {
+        let cases: Vec<(&str, Vec<CommandRequest>, CommandRequest, Vec<Value>)> = vec![
+            (
+                "hmget",
+                vec![
+                    CommandRequest::new_hset("hmget", "u1", 10.into()),
+                    CommandRequest::new_hset("hmget", "u2", 8.into()),
+                ],
+                CommandRequest::new_hmget("hmget", keys(&["u1", "u2", "u3"])),
+                vec![10.into(), 8.into(), Value::default()],
+            ),
+            (
+                "hmset",
+                vec![CommandRequest::new_hset("hmset", "k1", "v1".into())],
+                CommandRequest::new_hmset(
+                    "hmset",
+                    vec![
+                        Kvpair::new("k1", "v2".into()),
+                        Kvpair::new("k2", "v3".into()),
+                    ],
+                ),
+                vec!["v1".into(), Value::default()],
+            ),
+            (
+                "hdel",
+                vec![CommandRequest::new_hset("hdel", "k1", "v1".into())],
+                CommandRequest::new_hdel("hdel", "k1"),
+                vec!["v1".into()],
+            ),
+            (
+                "hdel non-exist key",
+                vec![],
+                CommandRequest::new_hdel("hdel", "k2"),
+                vec![Value::default()],
+            ),
+            (
+                "hmdel",
+                vec![
+                    CommandRequest::new_hset("hmdel", "k1", "v1".into()),
+                    CommandRequest::new_hset("hmdel", "k2", "v2".into()),
+                ],
+                CommandRequest::new_hmdel("hmdel", keys(&["k1", "k3"])),
+                vec!["v1".into(), Value::default()],
+            ),
+            (
+                "hmdel removed keys",
+                vec![],
+                CommandRequest::new_hmexist("hmdel", keys(&["k1", "k2"])),
+                vec![false.into(), true.into()],
+            ),
+            (
+                "hexist",
+                vec![CommandRequest::new_hset("hexist", "k1", "v1".into())],
+                CommandRequest::new_hexist("hexist", "k1"),
+                vec![true.into()],
+            ),
+            (
+                "hexist non-exist key",
+                vec![],
+                CommandRequest::new_hexist("hexist", "k2"),
+                vec![false.into()],
+            ),
+            (
+                "hmexist",
+                vec![CommandRequest::new_hset("hmexist", "k1", "v1".into())],
+                CommandRequest::new_hmexist("hmexist", keys(&["k1", "k2"])),
+                vec![true.into(), false.into()],
+            ),
+            (
+                "httl without ttl",
+                vec![CommandRequest::new_hset("httl", "k1", "v1".into())],
+                CommandRequest::new_httl("httl", "k1"),
+                vec![(-1).into()],
+            ),
+            (
+                "hexpire",
+                vec![],
+                CommandRequest::new_hexpire("httl", "k1", Duration::from_secs(100)),
+                vec![true.into()],
+            ),
+            (
+                "hexpire non-exist key",
+                vec![],
+                CommandRequest::new_hexpire("httl", "k2", Duration::from_secs(100)),
+                vec![false.into()],
+            ),
+            (
+                "hpersist",
+                vec![],
+                CommandRequest::new_hpersist("httl", "k1"),
+                vec![true.into()],
+            ),
+            (
+                "hpersist without ttl",
+                vec![],
+                CommandRequest::new_hpersist("httl", "k1"),
+                vec![false.into()],
+            ),
+            (
+                "hset clears ttl",
+                vec![
+                    CommandRequest::new_hset_with_ttl(
+                        "httl",
+                        "k3",
+                        "v1".into(),
+                        Duration::from_secs(100),
+                    ),
+                    CommandRequest::new_hset("httl", "k3", "v2".into()),
+                ],
+                CommandRequest::new_httl("httl", "k3"),
+                vec![(-1).into()],
+            ),
+            (
+                "hincrby non-exist key",
+                vec![],
+                CommandRequest::new_hincrby("hincrby", "k1", 5),
+                vec![5.into()],
+            ),
+            (
+                "hincrby",
+                vec![],
+                CommandRequest::new_hincrby("hincrby", "k1", -7),
+                vec![(-2).into()],
+            ),
+            (
+                "hincrbyfloat on integer",
+                vec![],
+                CommandRequest::new_hincrbyfloat("hincrby", "k1", 0.5),
+                vec![(-1.5).into()],
+            ),
+            (
+                "hsetnx",
+                vec![],
+                CommandRequest::new_hsetnx("hsetnx", "k1", "v1".into()),
+                vec![true.into()],
+            ),
+            (
+                "hsetnx existing key",
+                vec![],
+                CommandRequest::new_hsetnx("hsetnx", "k1", "v2".into()),
+                vec![false.into()],
+            ),
+            (
+                "hsetnx keeps old value",
+                vec![],
+                CommandRequest::new_hget("hsetnx", "k1"),
+                vec!["v1".into()],
+            ),
+            (
+                "hcas",
+                vec![],
+                CommandRequest::new_hcas("hsetnx", "k1", Some("v1".into()), "v2".into()),
+                vec![true.into()],
+            ),
+            (
+                "hcas with stale value",
+                vec![],
+                CommandRequest::new_hcas("hsetnx", "k1", Some("v1".into()), "v3".into()),
+                vec![false.into()],
+            ),
+            (
+                "hcas non-exist key",
+                vec![],
+                CommandRequest::new_hcas("hsetnx", "k2", None, "v1".into()),
+                vec![true.into()],
+            ),
+            (
+                "hcas expects non-exist key",
+                vec![],
+                CommandRequest::new_hcas("hsetnx", "k1", None, "v1".into()),
+                vec![false.into()],
+            ),
+        ];
+
+        for (name, setup, cmd, values) in cases {
+            for c in setup {
+                let res = dispatch(c, store);
+                assert_eq!(res.status, 200, "setup of case `{}` failed", name);
+            }
+            let res = dispatch(cmd, store);
+            assert_eq!(res.values, values, "case `{}` failed", name);
+            assert_res_ok(res, &values, &[]);
+        }
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
     }
 }
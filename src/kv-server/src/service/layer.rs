@@ -0,0 +1,136 @@
+use crate::{CommandRequest, CommandResponse};
+
+/// 中间件，可以拦截请求、改写响应
+/// 多个 Layer 按注册顺序处理请求，按相反的顺序处理响应
+pub trait Layer: Send + Sync + 'static {
+    /// 处理请求，可以修改请求内容
+    /// 返回 Some(response) 时请求不再往下执行，直接把 response 返回给客户端
+    fn on_request(&self, _cmd: &mut CommandRequest) -> Option<CommandResponse> {
+        None
+    }
+
+    /// 处理即将返回给客户端的 response
+    fn on_response(&self, _res: &mut CommandResponse) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        assert_res_error, assert_res_ok, command_request::RequestData, KvError, MemTable, Service,
+        ServiceInner, Value,
+    };
+    use futures::StreamExt;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    // 拒绝访问某个 table 的 Layer
+    struct DenyTable(&'static str);
+
+    impl Layer for DenyTable {
+        fn on_request(&self, cmd: &mut CommandRequest) -> Option<CommandResponse> {
+            match &cmd.request_data {
+                Some(RequestData::Hget(v)) if v.table == self.0 => {
+                    Some(KvError::InvalidCommand(format!("table {} is denied", v.table)).into())
+                }
+                Some(RequestData::Hset(v)) if v.table == self.0 => {
+                    Some(KvError::InvalidCommand(format!("table {} is denied", v.table)).into())
+                }
+                _ => None,
+            }
+        }
+    }
+
+    // 记录 Layer 的调用顺序
+    struct Trace(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Layer for Trace {
+        fn on_request(&self, _cmd: &mut CommandRequest) -> Option<CommandResponse> {
+            self.1.lock().unwrap().push(format!("req {}", self.0));
+            None
+        }
+
+        fn on_response(&self, res: &mut CommandResponse) {
+            self.1.lock().unwrap().push(format!("res {}", self.0));
+            res.message.push_str(self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn layer_should_short_circuit_request() {
+        let executed = Arc::new(AtomicUsize::new(0));
+        let counter = executed.clone();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(DenyTable("secret"))
+            .fn_executed(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .into();
+
+        let cmd = CommandRequest::new_hset("secret", "k1", "v1".into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_error((*res).clone(), 400, "table secret is denied");
+
+        // 被拦截的请求不会执行，数据也没有写进去
+        assert_eq!(executed.load(Ordering::SeqCst), 0);
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_error((*res).clone(), 404, "Not found");
+        assert_eq!(executed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn layers_should_wrap_request_and_response_in_order() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(Trace("a", trace.clone()))
+            .layer(Trace("b", trace.clone()))
+            .into();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.message, "ba");
+        assert_eq!(res.values, vec![Value::default()]);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            vec!["req a", "req b", "res b", "res a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn short_circuit_response_should_pass_outer_layers_only() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let service: Service = ServiceInner::new(MemTable::new())
+            .layer(Trace("a", trace.clone()))
+            .layer(DenyTable("secret"))
+            .layer(Trace("b", trace.clone()))
+            .into();
+
+        let cmd = CommandRequest::new_hget("secret", "k1");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, 400);
+        assert!(res.message.ends_with('a'));
+        assert_eq!(*trace.lock().unwrap(), vec!["req a", "res a"]);
+    }
+
+    #[tokio::test]
+    async fn layer_should_rewrite_topic_responses() {
+        struct Tag;
+        impl Layer for Tag {
+            fn on_response(&self, res: &mut CommandResponse) {
+                res.values.push("tagged".into());
+            }
+        }
+
+        let service: Service = ServiceInner::new(MemTable::new()).layer(Tag).into();
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"));
+        sub.next().await.unwrap();
+
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        service.execute(cmd).next().await.unwrap();
+        let data = sub.next().await.unwrap();
+        assert_res_ok((*data).clone(), &["hello".into(), "tagged".into()], &[]);
+    }
+}
@@ -1,82 +1,397 @@
+mod batch_service;
 mod command_service;
+mod layer;
+mod topic;
+mod topic_service;
 use crate::command_request::RequestData;
+use crate::replication::{self, Leader, ReadOnly};
 use crate::storage::MemTable;
-use crate::storage::Storage;
+use crate::storage::{ChangeFeed, Storage, Watched};
+use crate::Acl;
+use crate::Auth;
 use crate::CommandRequest;
 use crate::CommandResponse;
 use crate::KvError;
+use crate::Metrics;
+use crate::Replicate;
+use crate::Session;
+use crate::Value;
+use crate::Watch;
 use command_service::*;
-use http::StatusCode;
+use futures::{stream, Future, StreamExt};
+pub use layer::Layer;
 use std::sync::Arc;
-use std::thread;
-use tracing::debug;
-use tracing::info;
+use std::time::{Duration, Instant};
+use tokio::{task, time};
+pub use topic::{Broadcaster, Topic};
+pub use topic_service::{StreamingResponse, TopicService};
+use tracing::{debug, warn};
 /// Service 数据结构
 pub struct Service<Store = MemTable> {
     inner: Arc<ServiceInner<Store>>,
+    broadcaster: Arc<Broadcaster>,
 }
 
 impl<Store> Clone for Service<Store> {
     fn clone(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
+            broadcaster: Arc::clone(&self.broadcaster),
         }
     }
 }
 
+/// 事件回调，可以捕获外部状态（比如 metrics 或 logger）
+pub type Hook<Arg> = Box<dyn Fn(&Arg) + Send + Sync>;
+/// 可以修改参数的事件回调
+pub type HookMut<Arg> = Box<dyn Fn(&mut Arg) + Send + Sync>;
+
+/// 一个命令执行完成的信息，用于统计
+/// 返回 stream 的命令（比如 Subscribe）在第一个 response 返回时算作完成
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    /// 命令的名字，见 CommandRequest::name
+    pub command: &'static str,
+    pub status: u32,
+    /// 出错时 KvError 的类型，见 KvError::kind
+    pub error: String,
+    /// 从收到请求到 response 准备好的时间
+    pub elapsed: Duration,
+}
+
 /// Service 内部数据结构
 pub struct ServiceInner<Store> {
-    store: Store,
-    on_received: Vec<fn(&CommandRequest)>,
-    on_executed: Vec<fn(&CommandResponse)>,
-    on_before_send: Vec<fn(&mut CommandResponse)>,
-    on_after_send: Vec<fn()>,
+    // 所有的修改都会发布到 ChangeFeed，用于 Watch
+    store: Watched<Store>,
+    layers: Vec<Box<dyn Layer>>,
+    on_received: Vec<Hook<CommandRequest>>,
+    on_executed: Vec<Hook<CommandResponse>>,
+    on_before_send: Vec<HookMut<CommandResponse>>,
+    on_after_send: Vec<Box<dyn Fn() + Send + Sync>>,
+    on_completed: Vec<Hook<Completion>>,
+    leader: Option<Leader>,
+    acl: Option<Acl>,
 }
 
 impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
-            store,
+            store: Watched::new(store, Arc::new(ChangeFeed::default())),
+            layers: Vec::new(),
             on_received: Vec::new(),
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
             on_after_send: Vec::new(),
+            on_completed: Vec::new(),
+            leader: None,
+            acl: None,
         }
     }
 
-    pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
-        self.on_received.push(f);
+    /// 作为 leader 把修改复制给 follower
+    pub fn leader(mut self, leader: Leader) -> Self {
+        self.leader = Some(leader);
+        self
+    }
+
+    /// 要求连接先用 AUTH 认证，之后按 ACL 检查每个命令的权限
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
         self
     }
 
-    pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
-        self.on_executed.push(f);
+    /// 把每个命令的执行情况记录到 metrics
+    pub fn metrics(self, metrics: Arc<Metrics>) -> Self {
+        self.fn_completed(move |completion| metrics.record(completion))
+    }
+
+    /// 拒绝所有修改数据的命令，用于 follower
+    pub fn read_only(self) -> Self {
+        self.layer(ReadOnly)
+    }
+
+    /// 添加一个中间件，先添加的 layer 先处理请求、后处理响应
+    pub fn layer(mut self, layer: impl Layer) -> Self {
+        self.layers.push(Box::new(layer));
         self
     }
 
-    pub fn fn_before_send(mut self, f: fn(&mut CommandResponse)) -> Self {
-        self.on_before_send.push(f);
+    pub fn fn_received(mut self, f: impl Fn(&CommandRequest) + Send + Sync + 'static) -> Self {
+        self.on_received.push(Box::new(f));
         self
     }
 
-    pub fn fn_after_send(mut self, f: fn()) -> Self {
-        self.on_after_send.push(f);
+    pub fn fn_executed(mut self, f: impl Fn(&CommandResponse) + Send + Sync + 'static) -> Self {
+        self.on_executed.push(Box::new(f));
+        self
+    }
+
+    pub fn fn_before_send(
+        mut self,
+        f: impl Fn(&mut CommandResponse) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_before_send.push(Box::new(f));
+        self
+    }
+
+    pub fn fn_after_send(mut self, f: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_after_send.push(Box::new(f));
+        self
+    }
+
+    /// 命令执行完成、response 经过所有 layer 之后调用
+    pub fn fn_completed(mut self, f: impl Fn(&Completion) + Send + Sync + 'static) -> Self {
+        self.on_completed.push(Box::new(f));
         self
     }
 }
 
-impl<Store: Storage> Service<Store> {
-    pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
-        debug!("Got request: {:?}", cmd);
-        self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd, &self.inner.store);
-        debug!("Executed response: {:?}", res);
-        self.inner.on_executed.notify(&res);
-        self.inner.on_before_send.notify(&mut res);
-        if !self.inner.on_before_send.is_empty() {
+impl<Store: Storage> ServiceInner<Store> {
+    // leader 上的修改需要分配序号并复制给 follower
+    fn dispatch(&self, cmd: CommandRequest) -> CommandResponse {
+        match &self.leader {
+            Some(leader) if cmd.is_mutating() => leader.execute(cmd, &self.store),
+            _ => dispatch(cmd, &self.store),
+        }
+    }
+}
+
+impl<Store> ServiceInner<Store> {
+    // 认证成功后把用户记录到 session 里，返回用户名
+    fn authenticate(&self, param: &Auth, session: &mut Session) -> Result<Value, KvError> {
+        let Some(acl) = &self.acl else {
+            return Err(KvError::InvalidCommand(
+                "Authentication is not enabled".into(),
+            ));
+        };
+        let principal = acl.authenticate(&param.token)?;
+        let name = Value::from(principal.name.as_str());
+        session.set_principal(principal);
+        Ok(name)
+    }
+
+    fn authorize(&self, cmd: &CommandRequest, session: &Session) -> Result<(), KvError> {
+        match &self.acl {
+            Some(acl) => acl.check(session.principal().as_deref(), cmd),
+            None => Ok(()),
+        }
+    }
+
+    // response 按相反的顺序经过 layers，最后交给 on_before_send
+    fn process_response(&self, layers: &[Box<dyn Layer>], res: &mut CommandResponse) {
+        for layer in layers.iter().rev() {
+            layer.on_response(res);
+        }
+        self.on_before_send.notify(res);
+        if !layers.is_empty() || !self.on_before_send.is_empty() {
             debug!("Modified response: {:?}", res);
         }
-        res
+    }
+
+    fn complete(&self, command: &'static str, start: Instant, res: &CommandResponse) {
+        if self.on_completed.is_empty() {
+            return;
+        }
+        self.on_completed.notify(&Completion {
+            command,
+            status: res.status,
+            error: res.error.clone(),
+            elapsed: start.elapsed(),
+        });
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 执行命令，返回 response stream
+    /// 普通命令的 stream 里只有一个 response，Subscribe 会一直返回发布到主题的数据
+    /// 访问 Storage 可能阻塞（比如 sled 的磁盘 I/O），所以放在 blocking 线程池里执行
+    /// 没有连接状态，配置了 ACL 时命令都会因为没有认证被拒绝，这时应该使用 execute_in
+    pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
+        self.execute_in(cmd, &mut Session::default())
+    }
+
+    /// 在连接的 session 里执行命令，AUTH 会修改 session
+    pub fn execute_in(&self, mut cmd: CommandRequest, session: &mut Session) -> StreamingResponse {
+        debug!("Got request: {:?}", cmd.redacted());
+        let (command, start) = (cmd.name(), Instant::now());
+        // hook 拿不到 AUTH 的 token
+        match &cmd.request_data {
+            Some(RequestData::Auth(_)) => self
+                .inner
+                .on_received
+                .notify(&CommandRequest::new_auth("***")),
+            _ => self.inner.on_received.notify(&cmd),
+        }
+
+        // 认证和权限检查在所有 layer 之前
+        let denied = match &cmd.request_data {
+            Some(RequestData::Auth(param)) => Some(
+                self.inner
+                    .authenticate(param, session)
+                    .map_or_else(|e| e.into(), |v| v.into()),
+            ),
+            _ => self.inner.authorize(&cmd, session).err().map(Into::into),
+        };
+        if let Some(mut res) = denied {
+            self.inner.process_response(&[], &mut res);
+            self.inner.complete(command, start, &res);
+            return Box::pin(stream::once(async { Arc::new(res) }));
+        }
+
+        // 任何一个 layer 都可以直接返回 response，后面的 layer 和命令都不再执行
+        for (i, layer) in self.inner.layers.iter().enumerate() {
+            if let Some(mut res) = layer.on_request(&mut cmd) {
+                debug!("Request is short-circuited: {:?}", res);
+                self.inner
+                    .process_response(&self.inner.layers[..i], &mut res);
+                self.inner.complete(command, start, &res);
+                return Box::pin(stream::once(async { Arc::new(res) }));
+            }
+        }
+
+        if let Some(RequestData::Replicate(param)) = &cmd.request_data {
+            let res = self.replicate(param.clone());
+            return self.process_stream(res, command, start);
+        }
+        if let Some(RequestData::Watch(param)) = cmd.request_data {
+            let res = self.watch(param);
+            return self.process_stream(res, command, start);
+        }
+        if is_topic_command(&cmd) {
+            let res = dispatch_stream(cmd, Arc::clone(&self.broadcaster), session.id());
+            return self.process_stream(res, command, start);
+        }
+
+        let inner = Arc::clone(&self.inner);
+        Box::pin(stream::once(async move {
+            let store = Arc::clone(&inner);
+            let mut res = task::spawn_blocking(move || store.dispatch(cmd))
+                .await
+                .unwrap_or_else(|e| KvError::Internal(e.to_string()).into());
+
+            debug!("Executed response: {:?}", res);
+            inner.on_executed.notify(&res);
+            inner.process_response(&inner.layers, &mut res);
+            inner.complete(command, start, &res);
+            Arc::new(res)
+        }))
+    }
+
+    /// 用 token 认证一个新的 session，用于 HTTP、gRPC 这类每个请求单独认证的接口
+    /// 没有 token 时返回没有认证的 session
+    pub fn session(&self, token: Option<&str>) -> Result<Session, KvError> {
+        let mut session = Session::default();
+        if let Some(token) = token {
+            let auth = Auth {
+                token: token.into(),
+            };
+            self.inner.authenticate(&auth, &mut session)?;
+        }
+        Ok(session)
+    }
+
+    /// 当前订阅的数量
+    pub fn subscription_count(&self) -> usize {
+        self.broadcaster.subscription_count()
+    }
+
+    /// 在 blocking 线程池里访问 Storage
+    pub(crate) fn with_store<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Watched<Store>) -> T + Send + 'static,
+    ) -> impl Future<Output = Result<T, KvError>> + Send + 'static {
+        let inner = Arc::clone(&self.inner);
+        async move {
+            task::spawn_blocking(move || f(&inner.store))
+                .await
+                .map_err(|e| KvError::Internal(e.to_string()))
+        }
+    }
+
+    // follower 请求复制数据：leader 先在 blocking 线程池里准备需要补发的数据，之后推送新的修改
+    fn replicate(&self, param: Replicate) -> StreamingResponse {
+        let inner = Arc::clone(&self.inner);
+        let prepare = self.with_store(move |store| match &inner.leader {
+            Some(leader) => leader.subscribe(param, store),
+            None => Err(KvError::InvalidCommand("Replication is not enabled".into())),
+        });
+        Box::pin(stream::once(prepare).flat_map(|result| match result {
+            Ok(Ok((logs, rx))) => replication::into_stream(logs, rx),
+            Ok(Err(e)) | Err(e) => {
+                let res = Arc::new(CommandResponse::from(e));
+                Box::pin(stream::once(async { res }))
+            }
+        }))
+    }
+
+    // 第一个 response 是 watcher 的 id，之后是修改事件
+    // stream 被 drop（比如客户端断开，即使表上一直没有修改）时马上删除 watcher
+    fn watch(&self, param: Watch) -> StreamingResponse {
+        if param.table.is_empty() {
+            let res = CommandResponse::from(KvError::InvalidCommand("Watch needs a table".into()));
+            return Box::pin(stream::once(async { Arc::new(res) }));
+        }
+        let watcher = self.inner.store.feed().watch(param.table, param.key_prefix);
+        let first = Arc::new(CommandResponse::from(Value::from(watcher.id as i64)));
+        let events = watcher.map(|event| Arc::new(CommandResponse::from(event)));
+        Box::pin(stream::once(async { first }).chain(events))
+    }
+
+    // 推送给订阅者的数据是共享的，需要改写时复制一份
+    fn process_stream(
+        &self,
+        res: StreamingResponse,
+        command: &'static str,
+        start: Instant,
+    ) -> StreamingResponse {
+        let inner = Arc::clone(&self.inner);
+        let modify = !inner.layers.is_empty() || !inner.on_before_send.is_empty();
+        if !modify && inner.on_completed.is_empty() {
+            return res;
+        }
+        let mut start = Some(start);
+        Box::pin(res.map(move |res| {
+            let res = match modify {
+                true => {
+                    let mut res = Arc::unwrap_or_clone(res);
+                    inner.process_response(&inner.layers, &mut res);
+                    Arc::new(res)
+                }
+                false => res,
+            };
+            if let Some(start) = start.take() {
+                inner.complete(command, start, &res);
+            }
+            res
+        }))
+    }
+
+    /// 启动后台任务，定期清理过期的 key，所有的 Service 都被 drop 之后任务自动退出
+    pub fn spawn_reaper(&self, period: Duration) -> task::JoinHandle<()> {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut interval = time::interval(period);
+            interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                match task::spawn_blocking(move || inner.store.purge_expired()).await {
+                    Ok(Ok(purged)) if purged.is_empty() => {}
+                    Ok(Ok(purged)) => debug!("Purged {} expired keys", purged.len()),
+                    Ok(Err(e)) => warn!("Failed to purge expired keys: {:?}", e),
+                    Err(e) => warn!("Reaper task failed: {:?}", e),
+                }
+            }
+        })
+    }
+
+    /// response 成功写入网络后由网络层调用
+    pub fn notify_after_send(&self) {
+        for f in &self.inner.on_after_send {
+            f()
+        }
     }
 }
 
@@ -84,6 +399,7 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     fn from(inner: ServiceInner<Store>) -> Self {
         Self {
             inner: Arc::new(inner),
+            broadcaster: Default::default(),
         }
     }
 }
@@ -98,7 +414,7 @@ pub trait NotifyMut<Arg> {
     fn notify(&self, arg: &mut Arg);
 }
 
-impl<Arg> Notify<Arg> for Vec<fn(&Arg)> {
+impl<Arg> Notify<Arg> for Vec<Hook<Arg>> {
     #[inline]
     fn notify(&self, arg: &Arg) {
         for f in self {
@@ -107,7 +423,7 @@ impl<Arg> Notify<Arg> for Vec<fn(&Arg)> {
     }
 }
 
-impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
+impl<Arg> NotifyMut<Arg> for Vec<HookMut<Arg>> {
     #[inline]
     fn notify(&self, arg: &mut Arg) {
         for f in self {
@@ -116,44 +432,173 @@ impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
     }
 }
 
-// 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET
+// 从 Request 中得到 Response
 pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
     match cmd.request_data {
         Some(RequestData::Hget(param)) => param.execute(store),
+        Some(RequestData::Hmget(param)) => param.execute(store),
         Some(RequestData::Hgetall(param)) => param.execute(store),
         Some(RequestData::Hset(param)) => param.execute(store),
+        Some(RequestData::Hmset(param)) => param.execute(store),
+        Some(RequestData::Hdel(param)) => param.execute(store),
+        Some(RequestData::Hmdel(param)) => param.execute(store),
+        Some(RequestData::Hexist(param)) => param.execute(store),
+        Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Hexpire(param)) => param.execute(store),
+        Some(RequestData::Httl(param)) => param.execute(store),
+        Some(RequestData::Hpersist(param)) => param.execute(store),
+        Some(RequestData::Hincrby(param)) => param.execute(store),
+        Some(RequestData::Hincrbyfloat(param)) => param.execute(store),
+        Some(RequestData::Hsetnx(param)) => param.execute(store),
+        Some(RequestData::Hcas(param)) => param.execute(store),
+        Some(RequestData::Hscan(param)) => param.execute(store),
+        Some(RequestData::Batch(param)) => param.execute(store),
+        // 主题和复制相关的命令会返回 stream，由 Service::execute 处理
+        Some(_) => KvError::InvalidCommand("Streaming command cannot be dispatched".into()).into(),
         None => KvError::InvalidCommand("Request has no data".into()).into(),
-        _ => KvError::Internal("Not implemented".into()).into(),
     }
 }
 
+// 从 Request 中得到 Response stream，处理 SUBSCRIBE/UNSUBSCRIBE/PUBLISH，owner 是 session id
+pub fn dispatch_stream(
+    cmd: CommandRequest,
+    topic: impl Topic + Clone,
+    owner: u64,
+) -> StreamingResponse {
+    match cmd.request_data {
+        Some(RequestData::Subscribe(param)) => param.execute(topic, owner),
+        Some(RequestData::Unsubscribe(param)) => param.execute(topic, owner),
+        Some(RequestData::Publish(param)) => param.execute(topic, owner),
+        // 如果走到这里，就是代码逻辑的问题，直接返回错误
+        _ => {
+            let res: CommandResponse = KvError::InvalidCommand("Not a topic command".into()).into();
+            Box::pin(stream::once(async { Arc::new(res) }))
+        }
+    }
+}
+
+fn is_topic_command(cmd: &CommandRequest) -> bool {
+    matches!(
+        cmd.request_data,
+        Some(RequestData::Subscribe(_))
+            | Some(RequestData::Unsubscribe(_))
+            | Some(RequestData::Publish(_))
+    )
+}
+
+#[cfg(test)]
+use crate::Kvpair;
+
+// 测试成功返回的结果
+#[cfg(test)]
+pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
+    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(res.pairs, pairs);
+}
+
+// 测试失败返回的结果
+#[cfg(test)]
+pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.pairs, &[]);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{storage::MemTable, Value};
+    use crate::{storage::MemTable, Permission, ScanRange, Transaction, Value};
+    use futures::StreamExt;
+    use http::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    use tokio::time;
+    use tracing::info;
 
-    #[test]
-    fn service_should_works() {
+    #[tokio::test]
+    async fn service_should_works() {
         // 我们需要一个 service 结构至少包含 Storage
         let service: Service = ServiceInner::new(MemTable::default()).into();
 
         // service 可以运行在多线程环境下，它的 clone 应该是轻量级的
         let cloned = service.clone();
 
-        // 创建一个线程，在 table t1 中写入 k1, v1
-        let handle = thread::spawn(move || {
-            let res = cloned.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-            assert_res_ok(res, &[Value::default()], &[]);
+        // 创建一个任务，在 table t1 中写入 k1, v1
+        let handle = tokio::spawn(async move {
+            let mut res = cloned.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+            let data = res.next().await.unwrap();
+            assert_res_ok((*data).clone(), &[Value::default()], &[]);
         });
-        handle.join().unwrap();
+        handle.await.unwrap();
+
+        // 在当前任务下读取 table t1 的 k1，应该返回 v1
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        let data = res.next().await.unwrap();
+        assert_res_ok((*data).clone(), &["v1".into()], &[]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn blocking_storage_should_not_block_executor() {
+        let service: Service<SlowStore> = ServiceInner::new(SlowStore(MemTable::new())).into();
+
+        // 单线程 runtime 下，如果 Storage 阻塞了 executor，ticker 要等 get 结束后才能跑完
+        let start = Instant::now();
+        let ticker = async {
+            time::sleep(Duration::from_millis(10)).await;
+            start.elapsed()
+        };
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        let (data, elapsed) = tokio::join!(res.next(), ticker);
+
+        assert!(elapsed < SLOW_STORE_DELAY);
+        assert!(start.elapsed() >= SLOW_STORE_DELAY);
+        assert_res_error((*data.unwrap()).clone(), 404, "Not found");
+        assert!(res.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn service_should_handle_topic_commands() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"));
+        let id = sub.next().await.unwrap();
+        assert_eq!(id.status, 200);
+
+        let mut res = service.execute(CommandRequest::new_publish("lobby", vec![1.into()]));
+        assert_res_ok((*res.next().await.unwrap()).clone(), &[], &[]);
+        assert!(res.next().await.is_none());
+
+        let data = sub.next().await.unwrap();
+        assert_res_ok((*data).clone(), &[1.into()], &[]);
+    }
 
-        // 在当前线程下读取 table t1 的 k1，应该返回 v1
-        let res = service.execute(CommandRequest::new_hget("t1", "k1"));
-        assert_res_ok(res, &["v1".into()], &[]);
+    #[tokio::test]
+    async fn reaper_should_purge_expired_keys() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let ttl = Duration::from_millis(20);
+        for i in 0..10 {
+            let cmd = CommandRequest::new_hset_with_ttl("t1", format!("k{}", i), i.into(), ttl);
+            service.execute(cmd).next().await.unwrap();
+        }
+
+        let handle = service.spawn_reaper(Duration::from_millis(10));
+        time::sleep(ttl * 5).await;
+        // 过期的 key 已经被后台任务清理掉了
+        assert_eq!(service.inner.store.purge_expired(), Ok(vec![]));
+
+        drop(service);
+        time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
     }
 
-    #[test]
-    fn event_registration_should_work() {
+    #[tokio::test]
+    async fn event_registration_should_work() {
         fn b(cmd: &CommandRequest) {
             info!("Got {:?}", cmd);
         }
@@ -175,31 +620,243 @@ mod tests {
             .fn_after_send(e)
             .into();
 
-        let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-        // assert_eq!(res.status, StatusCode::CREATED.as_u16() as _);
+        let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        let res = res.next().await.unwrap();
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
         assert_eq!(res.values, vec![Value::default()]);
     }
-}
 
-#[cfg(test)]
-use crate::{Kvpair, Value};
+    #[tokio::test]
+    async fn hooks_should_capture_state() {
+        let received = Arc::new(AtomicUsize::new(0));
+        let sent = Arc::new(AtomicUsize::new(0));
+        let (r, s) = (received.clone(), sent.clone());
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_received(move |_| {
+                r.fetch_add(1, Ordering::SeqCst);
+            })
+            .fn_after_send(move || {
+                s.fetch_add(1, Ordering::SeqCst);
+            })
+            .into();
 
-// 测试成功返回的结果
-#[cfg(test)]
-pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    assert_eq!(res.status, 200);
-    assert_eq!(res.message, "");
-    assert_eq!(res.values, values);
-    assert_eq!(res.pairs, pairs);
-}
+        for _ in 0..3 {
+            let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+            res.next().await.unwrap();
+        }
+        assert_eq!(received.load(Ordering::SeqCst), 3);
 
-// 测试失败返回的结果
-#[cfg(test)]
-pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
-    assert_eq!(res.status, code);
-    assert!(res.message.contains(msg));
-    assert_eq!(res.values, &[]);
-    assert_eq!(res.pairs, &[]);
+        // on_after_send 由网络层在写成功后调用
+        assert_eq!(sent.load(Ordering::SeqCst), 0);
+        service.notify_after_send();
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn completion_hook_should_see_every_command() {
+        let completed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let c = completed.clone();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .read_only()
+            .fn_completed(move |v| c.lock().unwrap().push(v.clone()))
+            .into();
+
+        let cmds = [
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_subscribe("lobby"),
+        ];
+        for cmd in cmds {
+            service.execute(cmd).next().await.unwrap();
+        }
+
+        let completed = completed.lock().unwrap();
+        let summary: Vec<_> = completed
+            .iter()
+            .map(|v| (v.command, v.status, v.error.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("hget", 404, "NotFound"),
+                ("hset", 403, "ReadOnly"),
+                ("subscribe", 200, ""),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn session_should_remember_authenticated_principal() {
+        let mut acl = Acl::default();
+        acl.add_user("app", "secret", [("orders:*".into(), Permission::Write)]);
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let r = received.clone();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .acl(acl)
+            .fn_received(move |cmd: &CommandRequest| r.lock().unwrap().push(cmd.clone()))
+            .into();
+        let mut session = Session::default();
+        let mut execute = |cmd| {
+            let mut res = service.execute_in(cmd, &mut session);
+            async move { res.next().await.unwrap() }
+        };
+
+        let res = execute(CommandRequest::new_hget("orders:1", "k1")).await;
+        assert_res_error(Arc::unwrap_or_clone(res), 403, "authentication required");
+        let res = execute(CommandRequest::new_auth("bad")).await;
+        assert_res_error(Arc::unwrap_or_clone(res), 403, "invalid token");
+
+        let res = execute(CommandRequest::new_auth("secret")).await;
+        assert_res_ok(Arc::unwrap_or_clone(res), &["app".into()], &[]);
+        let res = execute(CommandRequest::new_hset("orders:1", "k1", "v1".into())).await;
+        assert_eq!(res.status, 200);
+        let res = execute(CommandRequest::new_hget("users", "u1")).await;
+        assert_res_error(
+            Arc::unwrap_or_clone(res),
+            403,
+            "app has no Read permission on users",
+        );
+        assert_eq!(session.principal().unwrap().name, "app");
+
+        // hook 看到的 AUTH 不带 token
+        let received = received.lock().unwrap();
+        assert_eq!(received[2], CommandRequest::new_auth("***"));
+    }
+
+    #[tokio::test]
+    async fn watch_should_stream_changes() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let mut watch = service.execute(CommandRequest::new_watch("t1", "k"));
+        let id = watch.next().await.unwrap();
+        assert_eq!(id.status, 200);
+        assert_eq!(id.values.len(), 1);
+
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hset("t1", "k1", "v1".into()),
+                CommandRequest::new_hset("t1", "other", "v1".into()),
+                CommandRequest::new_hincrby("t1", "k2", 2),
+            ],
+            true,
+        );
+        service.execute(cmd).next().await.unwrap();
+        service
+            .execute(CommandRequest::new_hdel("t1", "k1"))
+            .next()
+            .await
+            .unwrap();
+
+        let events: Vec<_> = watch
+            .take(3)
+            .map(|res| res.events[0].clone())
+            .collect()
+            .await;
+        let changes: Vec<_> = events
+            .into_iter()
+            .map(|e| (e.seq, e.key, e.old, e.new))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (1, "k1".into(), None, Some("v1".into())),
+                (3, "k2".into(), None, Some(2.into())),
+                (4, "k1".into(), Some("v1".into()), None),
+            ]
+        );
+
+        let res = service
+            .execute(CommandRequest::new_watch("", ""))
+            .next()
+            .await;
+        assert_res_error(
+            Arc::unwrap_or_clone(res.unwrap()),
+            400,
+            "Watch needs a table",
+        );
+    }
+
+    const SLOW_STORE_DELAY: Duration = Duration::from_millis(200);
+
+    // get 会阻塞一段时间的 Storage，用来模拟慢速的磁盘 I/O
+    struct SlowStore(MemTable);
+
+    impl Storage for SlowStore {
+        fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            std::thread::sleep(SLOW_STORE_DELAY);
+            self.0.get(table, key)
+        }
+        fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+            self.0.set(table, key, value)
+        }
+        fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+            self.0.contains(table, key)
+        }
+        fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.0.del(table, key)
+        }
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            self.0.get_all(table)
+        }
+        fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+            self.0.get_iter(table)
+        }
+        fn set_with_ttl(
+            &self,
+            table: &str,
+            key: String,
+            value: Value,
+            ttl: Duration,
+        ) -> Result<Option<Value>, KvError> {
+            self.0.set_with_ttl(table, key, value, ttl)
+        }
+        fn scan(
+            &self,
+            table: &str,
+            range: &ScanRange,
+            limit: usize,
+        ) -> Result<(Vec<Kvpair>, Option<String>), KvError> {
+            self.0.scan(table, range, limit)
+        }
+        fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+            self.0.expire(table, key, ttl)
+        }
+        fn ttl(&self, table: &str, key: &str) -> Result<Option<Option<Duration>>, KvError> {
+            self.0.ttl(table, key)
+        }
+        fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+            self.0.persist(table, key)
+        }
+        fn purge_expired(&self) -> Result<Vec<(String, String, Value)>, KvError> {
+            self.0.purge_expired()
+        }
+        fn incr(&self, table: &str, key: String, delta: i64) -> Result<i64, KvError> {
+            self.0.incr(table, key, delta)
+        }
+        fn incr_float(&self, table: &str, key: String, delta: f64) -> Result<f64, KvError> {
+            self.0.incr_float(table, key, delta)
+        }
+        fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+            self.0.set_nx(table, key, value)
+        }
+        fn compare_and_swap(
+            &self,
+            table: &str,
+            key: String,
+            expected: Option<Value>,
+            new: Value,
+        ) -> Result<bool, KvError> {
+            self.0.compare_and_swap(table, key, expected, new)
+        }
+        fn tables(&self) -> Result<Vec<String>, KvError> {
+            self.0.tables()
+        }
+        fn transaction(
+            &self,
+            tables: &[&str],
+            f: &mut dyn FnMut(&mut dyn Transaction) -> Result<(), KvError>,
+        ) -> Result<(), KvError> {
+            self.0.transaction(tables, f)
+        }
+    }
 }
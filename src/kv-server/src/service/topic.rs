@@ -0,0 +1,185 @@
+use dashmap::{DashMap, DashSet};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, info, warn};
+
+use crate::{CommandResponse, KvError};
+
+/// topic 里最多缓存的数据，订阅者消费太慢时新数据会被丢弃
+const BROADCAST_CAPACITY: usize = 128;
+
+/// 下一个 subscription 的 id
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// 获取下一个 subscription id
+fn get_next_subscription_id() -> u32 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub trait Topic: Send + Sync + 'static {
+    /// 订阅某个主题，返回 subscription id 和接收数据的 channel，owner 是订阅者的 session id
+    fn subscribe(self, name: String, owner: u64) -> (u32, mpsc::Receiver<Arc<CommandResponse>>);
+    /// 取消对主题的订阅，只有 owner 和订阅时一样才能取消
+    fn unsubscribe(self, name: String, id: u32, owner: u64) -> Result<u32, KvError>;
+    /// 往主题里发布一个数据
+    fn publish(self, name: String, value: Arc<CommandResponse>);
+}
+
+/// 用于主题发布和订阅的数据结构
+#[derive(Default)]
+pub struct Broadcaster {
+    /// 所有的主题列表
+    topics: DashMap<String, DashSet<u32>>,
+    /// 所有的订阅列表
+    subscriptions: DashMap<u32, Subscription>,
+}
+
+struct Subscription {
+    owner: u64,
+    tx: mpsc::Sender<Arc<CommandResponse>>,
+}
+
+impl Topic for Arc<Broadcaster> {
+    fn subscribe(self, name: String, owner: u64) -> (u32, mpsc::Receiver<Arc<CommandResponse>>) {
+        let id = {
+            let entry = self.topics.entry(name.clone()).or_default();
+            let id = get_next_subscription_id();
+            entry.value().insert(id);
+            id
+        };
+
+        // 生成一个 mpsc channel
+        let (tx, rx) = mpsc::channel(BROADCAST_CAPACITY);
+        self.subscriptions.insert(id, Subscription { owner, tx });
+        debug!("Subscription {} is added to topic {}", id, name);
+
+        (id, rx)
+    }
+
+    fn unsubscribe(self, name: String, id: u32, owner: u64) -> Result<u32, KvError> {
+        // 别人的订阅和不存在的订阅一样处理，不暴露订阅是否存在
+        let owned = self.subscriptions.get(&id).map(|v| v.owner) == Some(owner)
+            && self.topics.get(&name).is_some_and(|v| v.contains(&id));
+        match owned {
+            true => self.remove_subscription(name.clone(), id),
+            false => None,
+        }
+        .ok_or_else(|| KvError::NotFound(format!("topic: {}", name), id.to_string()))
+    }
+
+    fn publish(self, name: String, value: Arc<CommandResponse>) {
+        let Some(ids) = self.topics.get(&name).map(|topic| topic.value().clone()) else {
+            return;
+        };
+
+        for id in ids.into_iter() {
+            let Some(tx) = self.subscriptions.get(&id).map(|v| v.tx.clone()) else {
+                continue;
+            };
+            match tx.try_send(value.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("Subscription {} is too slow, drop data for it", id);
+                }
+                // 订阅者已经不在了，顺手清理掉
+                Err(TrySendError::Closed(_)) => {
+                    info!("Subscription {} is closed, remove it", id);
+                    self.remove_subscription(name.clone(), id);
+                }
+            }
+        }
+    }
+}
+
+impl Broadcaster {
+    pub fn remove_subscription(&self, name: String, id: u32) -> Option<u32> {
+        if let Some(v) = self.topics.get_mut(&name) {
+            // 在 topics 表里找到 topic 的 subscription id，删除
+            v.remove(&id);
+
+            // 如果这个 topic 为空，则也删除 topic
+            if v.is_empty() {
+                info!("Topic: {:?} is deleted", &name);
+                drop(v);
+                self.topics.remove_if(&name, |_, v| v.is_empty());
+            }
+        }
+
+        debug!("Subscription {} is removed!", id);
+        // 在 subscription 表中同样删除
+        self.subscriptions.remove(&id).map(|(id, _)| id)
+    }
+
+    /// 当前订阅的数量
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_ok, Value};
+
+    #[tokio::test]
+    async fn pub_sub_should_work() {
+        let b = Arc::new(Broadcaster::default());
+        let lobby = "lobby".to_string();
+
+        // subscribe
+        let (id1, mut stream1) = b.clone().subscribe(lobby.clone(), 1);
+        let (id2, mut stream2) = b.clone().subscribe(lobby.clone(), 2);
+        assert_ne!(id1, id2);
+
+        // publish
+        let v: Value = "hello".into();
+        b.clone().publish(lobby.clone(), Arc::new(v.clone().into()));
+
+        // subscribers 应该能收到 publish 的数据
+        let res1 = stream1.recv().await.unwrap();
+        let res2 = stream2.recv().await.unwrap();
+        assert_eq!(res1, res2);
+        assert_res_ok((*res1).clone(), &[v], &[]);
+
+        // 只有订阅者自己能取消订阅
+        assert!(b.clone().unsubscribe(lobby.clone(), id1, 2).is_err());
+
+        // 如果 subscriber 取消订阅，则收不到新数据
+        let result = b.clone().unsubscribe(lobby.clone(), id1, 1).unwrap();
+        assert_eq!(result, id1);
+
+        // publish
+        let v: Value = "world".into();
+        b.clone().publish(lobby.clone(), Arc::new(v.clone().into()));
+
+        assert!(stream1.recv().await.is_none());
+        let res2 = stream2.recv().await.unwrap();
+        assert_res_ok((*res2).clone(), &[v], &[]);
+
+        // 取消不存在的订阅应该报错
+        assert!(b.clone().unsubscribe(lobby, id1, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn dropped_subscriber_should_be_removed_on_publish() {
+        let b = Arc::new(Broadcaster::default());
+        let (_, stream1) = b.clone().subscribe("t".into(), 1);
+        let (_, mut stream2) = b.clone().subscribe("t".into(), 1);
+        assert_eq!(b.subscription_count(), 2);
+
+        drop(stream1);
+        b.clone()
+            .publish("t".into(), Arc::new(Value::from(1).into()));
+        assert_eq!(b.subscription_count(), 1);
+        assert!(stream2.recv().await.is_some());
+
+        drop(stream2);
+        b.clone()
+            .publish("t".into(), Arc::new(Value::from(2).into()));
+        assert_eq!(b.subscription_count(), 0);
+        assert!(b.topics.is_empty());
+    }
+}
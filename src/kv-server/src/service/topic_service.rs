@@ -0,0 +1,168 @@
+use futures::{stream, Stream, StreamExt};
+use std::{pin::Pin, sync::Arc};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{CommandResponse, Publish, Subscribe, Topic, Unsubscribe, Value};
+
+/// 返回多个 CommandResponse 的 stream
+pub type StreamingResponse = Pin<Box<dyn Stream<Item = Arc<CommandResponse>> + Send>>;
+
+pub trait TopicService {
+    /// 处理 Command，返回 Response，owner 是发起命令的 session id
+    fn execute<T>(self, topic: T, owner: u64) -> StreamingResponse
+    where
+        T: Topic + Clone;
+}
+
+impl TopicService for Subscribe {
+    fn execute<T>(self, topic: T, owner: u64) -> StreamingResponse
+    where
+        T: Topic + Clone,
+    {
+        let (id, rx) = topic.clone().subscribe(self.topic.clone(), owner);
+
+        // 第一个 response 告诉客户端 subscription id
+        let first = Arc::new(CommandResponse::from(Value::from(id as i64)));
+
+        // stream 被 drop（比如客户端断开）时自动取消订阅
+        let guard = SubscriptionGuard {
+            topic: Some(topic),
+            name: self.topic,
+            id,
+            owner,
+        };
+        let data = ReceiverStream::new(rx).map(move |res| {
+            let _ = &guard;
+            res
+        });
+        Box::pin(stream::once(async { first }).chain(data))
+    }
+}
+
+impl TopicService for Unsubscribe {
+    fn execute<T>(self, topic: T, owner: u64) -> StreamingResponse
+    where
+        T: Topic + Clone,
+    {
+        let res = match topic.unsubscribe(self.topic, self.id, owner) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        };
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
+}
+
+impl TopicService for Publish {
+    fn execute<T>(self, topic: T, _owner: u64) -> StreamingResponse
+    where
+        T: Topic + Clone,
+    {
+        topic.publish(self.topic, Arc::new(self.values.into()));
+        Box::pin(stream::once(async { Arc::new(CommandResponse::ok()) }))
+    }
+}
+
+struct SubscriptionGuard<T: Topic> {
+    topic: Option<T>,
+    name: String,
+    id: u32,
+    owner: u64,
+}
+
+impl<T: Topic> Drop for SubscriptionGuard<T> {
+    fn drop(&mut self) {
+        if let Some(topic) = self.topic.take() {
+            // 已经主动取消订阅的话，这里会返回 NotFound，忽略即可
+            let _ = topic.unsubscribe(std::mem::take(&mut self.name), self.id, self.owner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok, dispatch_stream, Broadcaster, CommandRequest};
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn dispatch_publish_should_work() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        let mut res = dispatch_stream(cmd, topic, 1);
+        let data = res.next().await.unwrap();
+        assert_res_ok((*data).clone(), &[], &[]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_subscribe_should_work() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_subscribe("lobby");
+        let mut res = dispatch_stream(cmd, topic.clone(), 1);
+        let id = get_id(&mut res).await;
+        assert!(id > 0);
+
+        let cmd = CommandRequest::new_publish("lobby", vec!["hello".into()]);
+        dispatch_stream(cmd, topic, 1).next().await.unwrap();
+        let data = res.next().await.unwrap();
+        assert_res_ok((*data).clone(), &["hello".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_subscribe_abnormal_quit_should_be_removed_on_drop() {
+        let topic = Arc::new(Broadcaster::default());
+        let id = {
+            let cmd = CommandRequest::new_subscribe("lobby");
+            let mut res = dispatch_stream(cmd, topic.clone(), 1);
+            let id = get_id(&mut res).await;
+            drop(res);
+            id as u32
+        };
+
+        // stream 被 drop 后订阅已经被清理，再取消订阅会报错
+        let cmd = CommandRequest::new_unsubscribe("lobby", id);
+        let mut res = dispatch_stream(cmd, topic.clone(), 1);
+        let data = res.next().await.unwrap();
+        assert_res_error((*data).clone(), 404, "Not found");
+        assert_eq!(topic.subscription_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn dispatch_unsubscribe_should_work() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_subscribe("lobby");
+        let mut res = dispatch_stream(cmd, topic.clone(), 1);
+        let id = get_id(&mut res).await;
+
+        // 其它 session 不能取消订阅
+        let cmd = CommandRequest::new_unsubscribe("lobby", id as _);
+        let mut res1 = dispatch_stream(cmd.clone(), topic.clone(), 2);
+        let data = res1.next().await.unwrap();
+        assert_res_error((*data).clone(), 404, "Not found");
+
+        let mut res1 = dispatch_stream(cmd, topic, 1);
+        let data = res1.next().await.unwrap();
+        assert_res_ok((*data).clone(), &[], &[]);
+
+        // 取消订阅后，订阅的 stream 结束
+        let next = time::timeout(Duration::from_secs(1), res.next()).await;
+        assert_eq!(next, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn dispatch_unsubscribe_random_id_should_error() {
+        let topic = Arc::new(Broadcaster::default());
+        let cmd = CommandRequest::new_unsubscribe("lobby", 9527);
+        let mut res = dispatch_stream(cmd, topic, 1);
+        let data = res.next().await.unwrap();
+        assert_res_error((*data).clone(), 404, "Not found");
+    }
+
+    async fn get_id(res: &mut StreamingResponse) -> i64 {
+        let id: i64 = res.next().await.unwrap().values[0]
+            .clone()
+            .try_into()
+            .unwrap();
+        id
+    }
+}
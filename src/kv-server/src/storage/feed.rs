@@ -0,0 +1,600 @@
+use dashmap::DashMap;
+use futures::Stream;
+use std::{
+    hash::{BuildHasher, RandomState},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, warn};
+
+use super::ScanRange;
+use crate::{ChangeEvent, KvError, Kvpair, Storage, Transaction, Value};
+
+/// 每个 watcher 最多缓存的事件，watcher 处理得太慢时之后的事件会被丢掉
+pub const DEFAULT_WATCH_BUFFER: usize = 1024;
+// 修改按 table 名字的 hash 分到这些锁上
+const LOCKS: usize = 16;
+
+/// 把修改事件分发给 watcher，每个 watcher 只收到它监听的 table 和前缀的事件
+pub struct ChangeFeed {
+    // 最新的序号
+    seq: AtomicU64,
+    // 同一个 table 的修改和发布事件都在它的锁里，保证事件的顺序和修改的顺序一致
+    // watcher 只监听一个 table，所以它收到的序号依然是递增的
+    locks: [Mutex<()>; LOCKS],
+    hasher: RandomState,
+    watchers: DashMap<u32, Entry>,
+    next_id: AtomicU32,
+    buffer: usize,
+}
+
+struct Entry {
+    table: String,
+    key_prefix: String,
+    tx: mpsc::Sender<ChangeEvent>,
+    // 因为缓存满了丢掉的事件数量，补发 OVERFLOW 之后清零
+    dropped: Arc<Mutex<u64>>,
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self::new(DEFAULT_WATCH_BUFFER)
+    }
+}
+
+impl ChangeFeed {
+    /// 创建 feed，buffer 是每个 watcher 最多缓存的事件数量
+    pub fn new(buffer: usize) -> Self {
+        Self {
+            seq: AtomicU64::new(0),
+            locks: Default::default(),
+            hasher: RandomState::new(),
+            watchers: DashMap::new(),
+            next_id: AtomicU32::new(1),
+            buffer: buffer.max(1),
+        }
+    }
+
+    /// 监听 table 里 key 以 key_prefix 开头的修改，Watcher 被 drop 时停止监听
+    pub fn watch(
+        self: &Arc<Self>,
+        table: impl Into<String>,
+        key_prefix: impl Into<String>,
+    ) -> Watcher {
+        let (tx, rx) = mpsc::channel(self.buffer);
+        let dropped = Arc::new(Mutex::new(0));
+        let entry = Entry {
+            table: table.into(),
+            key_prefix: key_prefix.into(),
+            tx,
+            dropped: Arc::clone(&dropped),
+        };
+        // 在锁里注册，正在进行的修改（比如事务）的事件要么全部收到，要么都收不到
+        let _locks = self.lock([entry.table.as_str()]);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        debug!("Watcher {} is added to {}", id, entry.table);
+        self.watchers.insert(id, entry);
+        Watcher {
+            id,
+            rx,
+            dropped,
+            feed: Arc::clone(self),
+        }
+    }
+
+    /// 当前 watcher 的数量
+    pub fn watcher_count(&self) -> usize {
+        self.watchers.len()
+    }
+
+    /// 最新的事件序号
+    pub fn last_seq(&self) -> u64 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    // 拿到要修改的 table 的锁，没有 watcher 时返回 None，这时修改不需要记录事件，也不需要拿锁
+    fn guard(&self, tables: &[&str]) -> Option<FeedGuard<'_>> {
+        if self.watchers.is_empty() {
+            return None;
+        }
+        Some(FeedGuard {
+            feed: self,
+            _locks: self.lock(tables.iter().copied()),
+        })
+    }
+
+    // 拿到所有的锁，用于可能修改任何 table 的操作
+    fn guard_all(&self) -> Option<FeedGuard<'_>> {
+        if self.watchers.is_empty() {
+            return None;
+        }
+        Some(FeedGuard {
+            feed: self,
+            _locks: self.locks.iter().map(lock).collect(),
+        })
+    }
+
+    // 按顺序拿锁，这样同时拿多个锁也不会死锁
+    fn lock<'t>(&self, tables: impl IntoIterator<Item = &'t str>) -> Vec<MutexGuard<'_, ()>> {
+        let mut indexes: Vec<_> = tables
+            .into_iter()
+            .map(|t| self.hasher.hash_one(t) as usize % LOCKS)
+            .collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        indexes.into_iter().map(|i| lock(&self.locks[i])).collect()
+    }
+}
+
+fn lock(mutex: &Mutex<()>) -> MutexGuard<'_, ()> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// 持有修改的 table 的锁，在锁里完成修改并发布事件
+struct FeedGuard<'a> {
+    feed: &'a ChangeFeed,
+    _locks: Vec<MutexGuard<'a, ()>>,
+}
+
+impl FeedGuard<'_> {
+    fn publish(&mut self, mut event: ChangeEvent) {
+        event.seq = self.feed.seq.fetch_add(1, Ordering::AcqRel) + 1;
+
+        let mut closed = Vec::new();
+        for entry in self.feed.watchers.iter() {
+            if entry.table != event.table || !event.key.starts_with(&entry.key_prefix) {
+                continue;
+            }
+            if !entry.send(event.clone()) {
+                closed.push(*entry.key());
+            }
+        }
+        // watcher 已经不在了，顺手清理掉
+        for id in closed {
+            debug!("Watcher {} is closed, remove it", id);
+            self.feed.watchers.remove(&id);
+        }
+    }
+}
+
+impl Entry {
+    // 之前丢过事件的话先补发 OVERFLOW，缓存满了就继续丢，返回 watcher 是否还在
+    fn send(&self, event: ChangeEvent) -> bool {
+        let mut dropped = self.dropped.lock().unwrap_or_else(PoisonError::into_inner);
+        if *dropped > 0 {
+            match self.tx.try_send(ChangeEvent::overflow(*dropped)) {
+                Ok(()) => *dropped = 0,
+                Err(TrySendError::Full(_)) => {
+                    *dropped += 1;
+                    return true;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+        }
+        match self.tx.try_send(event) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                if *dropped == 0 {
+                    warn!("Watcher on {} is too slow, drop events for it", self.table);
+                }
+                *dropped += 1;
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// 修改事件的 stream，缓存里的事件都处理完之后，如果之前丢过事件，会先收到一个 OVERFLOW
+pub struct Watcher {
+    pub id: u32,
+    rx: mpsc::Receiver<ChangeEvent>,
+    dropped: Arc<Mutex<u64>>,
+    feed: Arc<ChangeFeed>,
+}
+
+impl Stream for Watcher {
+    type Item = ChangeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Pending => {
+                // 缓存已经空了，丢掉的事件之后也不会再补发，这里直接通知
+                let mut dropped = self.dropped.lock().unwrap_or_else(PoisonError::into_inner);
+                match std::mem::take(&mut *dropped) {
+                    0 => Poll::Pending,
+                    n => Poll::Ready(Some(ChangeEvent::overflow(n))),
+                }
+            }
+            ready => ready,
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        debug!("Watcher {} is removed", self.id);
+        self.feed.watchers.remove(&self.id);
+    }
+}
+
+/// 把每次修改发布到 ChangeFeed 的 Storage
+/// 过期时间的修改不会改变值，不产生事件；过期的 key 被 purge_expired 清理时发布 DELETE
+/// 在清理之前又写入同一个 key 的话，不会有 DELETE，写入的事件里 old 为空
+pub struct Watched<S> {
+    store: S,
+    feed: Arc<ChangeFeed>,
+}
+
+impl<S: Storage> Watched<S> {
+    pub fn new(store: S, feed: Arc<ChangeFeed>) -> Self {
+        Self { store, feed }
+    }
+
+    pub fn feed(&self) -> &Arc<ChangeFeed> {
+        &self.feed
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+}
+
+impl<S: Storage> Storage for Watched<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.store.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let Some(mut guard) = self.feed.guard(&[table]) else {
+            return self.store.set(table, key, value);
+        };
+        let old = self.store.set(table, key.clone(), value.clone())?;
+        guard.publish(ChangeEvent::new(table, key, old.clone(), Some(value)));
+        Ok(old)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let Some(mut guard) = self.feed.guard(&[table]) else {
+            return self.store.set_with_ttl(table, key, value, ttl);
+        };
+        let old = self
+            .store
+            .set_with_ttl(table, key.clone(), value.clone(), ttl)?;
+        guard.publish(ChangeEvent::new(table, key, old.clone(), Some(value)));
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let Some(mut guard) = self.feed.guard(&[table]) else {
+            return self.store.del(table, key);
+        };
+        let old = self.store.del(table, key)?;
+        if old.is_some() {
+            guard.publish(ChangeEvent::new(table, key, old.clone(), None));
+        }
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.store.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.store.get_iter(table)
+    }
+
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        self.store.tables()
+    }
+
+    fn count(&self, table: &str) -> Result<usize, KvError> {
+        self.store.count(table)
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        range: &ScanRange,
+        limit: usize,
+    ) -> Result<(Vec<Kvpair>, Option<String>), KvError> {
+        self.store.scan(table, range, limit)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.store.expire(table, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Option<Duration>>, KvError> {
+        self.store.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.store.persist(table, key)
+    }
+
+    fn purge_expired(&self) -> Result<Vec<(String, String, Value)>, KvError> {
+        let Some(mut guard) = self.feed.guard_all() else {
+            return self.store.purge_expired();
+        };
+        let purged = self.store.purge_expired()?;
+        for (table, key, old) in &purged {
+            guard.publish(ChangeEvent::new(table, key, Some(old.clone()), None));
+        }
+        Ok(purged)
+    }
+
+    // 在锁里先读出旧值，锁里的修改不会交错，所以旧值是准确的
+    fn incr(&self, table: &str, key: String, delta: i64) -> Result<i64, KvError> {
+        let Some(mut guard) = self.feed.guard(&[table]) else {
+            return self.store.incr(table, key, delta);
+        };
+        let old = self.store.get(table, &key)?;
+        let new = self.store.incr(table, key.clone(), delta)?;
+        guard.publish(ChangeEvent::new(table, key, old, Some(new.into())));
+        Ok(new)
+    }
+
+    fn incr_float(&self, table: &str, key: String, delta: f64) -> Result<f64, KvError> {
+        let Some(mut guard) = self.feed.guard(&[table]) else {
+            return self.store.incr_float(table, key, delta);
+        };
+        let old = self.store.get(table, &key)?;
+        let new = self.store.incr_float(table, key.clone(), delta)?;
+        guard.publish(ChangeEvent::new(table, key, old, Some(new.into())));
+        Ok(new)
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        let Some(mut guard) = self.feed.guard(&[table]) else {
+            return self.store.set_nx(table, key, value);
+        };
+        let written = self.store.set_nx(table, key.clone(), value.clone())?;
+        if written {
+            guard.publish(ChangeEvent::new(table, key, None, Some(value)));
+        }
+        Ok(written)
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        new: Value,
+    ) -> Result<bool, KvError> {
+        let Some(mut guard) = self.feed.guard(&[table]) else {
+            return self.store.compare_and_swap(table, key, expected, new);
+        };
+        let written =
+            self.store
+                .compare_and_swap(table, key.clone(), expected.clone(), new.clone())?;
+        if written {
+            guard.publish(ChangeEvent::new(table, key, expected, Some(new)));
+        }
+        Ok(written)
+    }
+
+    // f 可能被重复执行，只发布最后一次成功提交的修改
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&mut dyn Transaction) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        let Some(mut guard) = self.feed.guard(tables) else {
+            return self.store.transaction(tables, f);
+        };
+        let mut events = Vec::new();
+        self.store.transaction(tables, &mut |tx| {
+            events.clear();
+            f(&mut Recorder {
+                tx,
+                events: &mut events,
+            })
+        })?;
+        for event in events {
+            guard.publish(event);
+        }
+        Ok(())
+    }
+}
+
+// 记录事务里的修改，事务提交之后再发布
+struct Recorder<'a> {
+    tx: &'a mut dyn Transaction,
+    events: &'a mut Vec<ChangeEvent>,
+}
+
+impl Transaction for Recorder<'_> {
+    fn get(&mut self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.tx.get(table, key)
+    }
+
+    fn set(
+        &mut self,
+        table: &str,
+        key: &str,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<Option<Value>, KvError> {
+        let old = self.tx.set(table, key, value.clone(), ttl)?;
+        let event = ChangeEvent::new(table, key, old.clone(), Some(value));
+        self.events.push(event);
+        Ok(old)
+    }
+
+    fn update(&mut self, table: &str, key: &str, value: Value) -> Result<Option<Value>, KvError> {
+        let old = self.tx.update(table, key, value.clone())?;
+        let event = ChangeEvent::new(table, key, old.clone(), Some(value));
+        self.events.push(event);
+        Ok(old)
+    }
+
+    fn del(&mut self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.tx.del(table, key)?;
+        if old.is_some() {
+            self.events
+                .push(ChangeEvent::new(table, key, old.clone(), None));
+        }
+        Ok(old)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChangeKind, MemTable};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn watched_store_should_publish_changes() {
+        let feed = Arc::new(ChangeFeed::default());
+        let store = Watched::new(MemTable::new(), Arc::clone(&feed));
+        // 没有 watcher 时不记录事件
+        store.set("t1", "k0".into(), 0.into()).unwrap();
+        let mut watcher = feed.watch("t1", "");
+
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        store.incr("t1", "k0".into(), 2).unwrap();
+        store.del("t1", "k1").unwrap();
+        store.del("t1", "k1").unwrap();
+        assert!(!store.set_nx("t1", "k0".into(), 1.into()).unwrap());
+
+        let expected = [
+            (1, ChangeKind::Set, "k1", None, Some("v1".into())),
+            (
+                2,
+                ChangeKind::Set,
+                "k1",
+                Some("v1".into()),
+                Some("v2".into()),
+            ),
+            (3, ChangeKind::Set, "k0", Some(0.into()), Some(2.into())),
+            (4, ChangeKind::Delete, "k1", Some("v2".into()), None),
+        ];
+        for (seq, kind, key, old, new) in expected {
+            let event = watcher.next().await.unwrap();
+            assert_eq!(event.seq, seq);
+            assert_eq!(event.kind, kind as i32);
+            assert_eq!((event.key.as_str(), event.old, event.new), (key, old, new));
+        }
+
+        // 事务提交后才发布，回滚的修改不会发布
+        store
+            .transaction(&["t1"], &mut |tx| {
+                tx.set("t1", "k2", 1.into(), None)?;
+                tx.del("t1", "k0")?;
+                Ok(())
+            })
+            .unwrap();
+        let _ = store.transaction(&["t1"], &mut |tx| {
+            tx.set("t1", "k3", 1.into(), None)?;
+            Err(KvError::Internal("abort".into()))
+        });
+        store.set("t1", "k4".into(), 1.into()).unwrap();
+        let keys: Vec<_> = watcher.by_ref().take(3).map(|e| e.key).collect().await;
+        assert_eq!(keys, ["k2", "k0", "k4"]);
+    }
+
+    #[tokio::test]
+    async fn watcher_should_only_receive_matching_keys() {
+        let feed = Arc::new(ChangeFeed::default());
+        let store = Watched::new(MemTable::new(), Arc::clone(&feed));
+        let mut watcher = feed.watch("orders", "2021:");
+
+        store.set("orders", "2020:1".into(), 1.into()).unwrap();
+        store.set("users", "2021:1".into(), 1.into()).unwrap();
+        store.set("orders", "2021:1".into(), 1.into()).unwrap();
+        let event = watcher.next().await.unwrap();
+        assert_eq!(
+            (event.table.as_str(), event.key.as_str()),
+            ("orders", "2021:1")
+        );
+        assert_eq!(event.seq, 3);
+
+        drop(watcher);
+        assert_eq!(feed.watcher_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn purged_keys_should_publish_delete() {
+        let feed = Arc::new(ChangeFeed::default());
+        let store = Watched::new(MemTable::new(), Arc::clone(&feed));
+        let mut watcher = feed.watch("t1", "");
+        let ttl = Duration::from_millis(10);
+        store
+            .set_with_ttl("t1", "k1".into(), 1.into(), ttl)
+            .unwrap();
+        store.set("t1", "k2".into(), 2.into()).unwrap();
+        store.expire("t1", "k2", ttl).unwrap();
+        store
+            .set_with_ttl("t2", "k1".into(), 1.into(), ttl)
+            .unwrap();
+        std::thread::sleep(ttl * 2);
+
+        // 过期的 key 不能被 del 删掉，只会在清理时发布一次 DELETE
+        assert_eq!(store.del("t1", "k1"), Ok(None));
+        assert_eq!(store.purge_expired().unwrap().len(), 3);
+        let events: Vec<_> = watcher.by_ref().take(4).collect().await;
+        let mut deleted: Vec<_> = events[2..]
+            .iter()
+            .map(|e| (e.kind, e.key.as_str(), e.old.clone(), e.new.clone()))
+            .collect();
+        deleted.sort_by(|a, b| a.1.cmp(b.1));
+        let delete = ChangeKind::Delete as i32;
+        assert_eq!(
+            deleted,
+            [
+                (delete, "k1", Some(1.into()), None),
+                (delete, "k2", Some(2.into()), None)
+            ]
+        );
+        assert_eq!(feed.last_seq(), 6);
+    }
+
+    #[tokio::test]
+    async fn slow_watcher_should_receive_overflow() {
+        let feed = Arc::new(ChangeFeed::new(2));
+        let store = Watched::new(MemTable::new(), Arc::clone(&feed));
+        let mut watcher = feed.watch("t1", "");
+
+        for i in 0..3 {
+            store.set("t1", format!("k{}", i), i.into()).unwrap();
+        }
+        let seqs: Vec<_> = watcher.by_ref().take(2).map(|e| e.seq).collect().await;
+        assert_eq!(seqs, [1, 2]);
+
+        // 丢过事件之后，新的事件前面会先补发 OVERFLOW
+        store.set("t1", "k3".into(), 3.into()).unwrap();
+        let events: Vec<_> = watcher.by_ref().take(2).collect().await;
+        assert_eq!(events[0].kind, ChangeKind::Overflow as i32);
+        assert_eq!(events[0].dropped, 1);
+        assert_eq!(events[1].seq, 4);
+
+        // 没有新的事件时，缓存里的事件处理完之后也会收到 OVERFLOW
+        for i in 4..7 {
+            store.set("t1", format!("k{}", i), i.into()).unwrap();
+        }
+        let events: Vec<_> = watcher.take(3).collect().await;
+        assert_eq!((events[0].seq, events[1].seq), (5, 6));
+        assert_eq!(events[2].kind, ChangeKind::Overflow as i32);
+        assert_eq!(events[2].dropped, 1);
+    }
+}
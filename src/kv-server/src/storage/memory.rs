@@ -1,10 +1,229 @@
-use super::StorageIter;
-use crate::{KvError, Kvpair, Storage, Value};
-use dashmap::{mapref::one::Ref, DashMap};
+use super::wal::{Mutation, Wal};
+use super::{
+    expire_at, incr_float_value, incr_value, now_millis, paginate, remaining, ScanRange,
+    SyncPolicy, WalOptions,
+};
+use crate::{KvError, Kvpair, Storage, Transaction, Value};
+use dashmap::DashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::{BuildHasher, RandomState},
+    iter,
+    ops::Bound,
+    path::Path,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::warn;
 
+// get_iter 每次从 table 里取出的 kvpair 数量
+const ITER_BATCH: usize = 128;
+// snapshot 里每条记录最多包含的 kvpair 数量
+const SNAPSHOT_BATCH: usize = 1024;
+// 每个 table 分成的 shard 数量
+const SHARDS: usize = 16;
+
+type Tables = DashMap<String, Arc<Table>>;
+type Shard = BTreeMap<String, Entry>;
+
+/// clone 出来的 MemTable 和原来的共享数据
 #[derive(Debug, Clone, Default)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: Arc<Tables>,
+    // 持久化的 MemTable 会把修改写进 WAL
+    wal: Option<Arc<Wal>>,
+}
+
+// 一个 table 按 key 的 hash 分成多个 shard，每个 shard 是一个按 key 排序的 BTreeMap
+// 修改一个 key 只锁住它所在的 shard，需要按顺序遍历时合并所有 shard
+#[derive(Debug, Default)]
+struct Table {
+    shards: [RwLock<Shard>; SHARDS],
+    hasher: RandomState,
+}
+
+// 存在 table 里的值，带上过期的时间点（unix 时间戳，毫秒）
+#[derive(Debug, Clone)]
+struct Entry {
+    value: Value,
+    expire_at: Option<u64>,
+}
+
+impl Entry {
+    fn new(value: Value, expire_at: Option<u64>) -> Self {
+        Self { value, expire_at }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expire_at, Some(t) if t <= now)
+    }
+
+    // 没有过期的话返回 value
+    fn into_value(self, now: u64) -> Option<Value> {
+        (!self.is_expired(now)).then_some(self.value)
+    }
+
+    fn to_mutation(&self, table: &str, key: &str) -> Mutation {
+        Mutation::put(table, key, self.value.clone(), self.expire_at)
+    }
+}
+
+impl Table {
+    fn index(&self, key: &str) -> usize {
+        self.hasher.hash_one(key) as usize % SHARDS
+    }
+
+    // 持有锁的线程 panic 时数据本身没有损坏，继续使用即可
+    fn read(&self, key: &str) -> RwLockReadGuard<'_, Shard> {
+        read(&self.shards[self.index(key)])
+    }
+
+    fn write(&self, key: &str) -> RwLockWriteGuard<'_, Shard> {
+        write(&self.shards[self.index(key)])
+    }
+
+    // 按顺序拿所有 shard 的锁，这样同时拿多个 shard 也不会死锁
+    fn read_all(&self) -> Vec<RwLockReadGuard<'_, Shard>> {
+        self.shards.iter().map(read).collect()
+    }
+
+    fn write_all(&self) -> Vec<RwLockWriteGuard<'_, Shard>> {
+        self.shards.iter().map(write).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.read_all().iter().map(|v| v.len()).sum()
+    }
+
+    // 按 key 的顺序返回一页数据，以及下一页的 cursor
+    // 每个 shard 最多取出一页，合并排序之后再分页
+    fn scan(&self, range: &ScanRange, limit: usize) -> (Vec<Kvpair>, Option<String>) {
+        let shards = self.read_all();
+        let now = now_millis();
+        let mut pairs: Vec<_> = shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .range::<str, _>((Bound::Included(range.lower()), Bound::Unbounded))
+                    .take_while(|(k, _)| range.contains(k))
+                    .filter(|(_, v)| !v.is_expired(now))
+                    .take(limit.saturating_add(1))
+            })
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        let iter = pairs
+            .into_iter()
+            .map(|(k, v)| Ok(Kvpair::new(k, v.value.clone())));
+        // 内存里的数据不会出错
+        paginate(iter, limit).unwrap_or_default()
+    }
+}
+
+fn read(shard: &RwLock<Shard>) -> RwLockReadGuard<'_, Shard> {
+    shard.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write(shard: &RwLock<Shard>) -> RwLockWriteGuard<'_, Shard> {
+    shard.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+// 事务里锁住的 table
+struct LockedTable<'a> {
+    table: &'a Table,
+    shards: Vec<RwLockWriteGuard<'a, Shard>>,
+}
+
+// 事务拿着所有用到的 table 的写锁，修改前的 entry 记在 undo 里，失败时按相反的顺序恢复
+// 成功时所有的修改作为一条记录写进 WAL
+struct MemTransaction<'a> {
+    tables: HashMap<String, LockedTable<'a>>,
+    undo: Vec<(String, String, Option<Entry>)>,
+    mutations: Vec<Mutation>,
+    now: u64,
+}
+
+impl MemTransaction<'_> {
+    // key 所在的 shard
+    fn shard(&mut self, name: &str, key: &str) -> Result<&mut Shard, KvError> {
+        let locked = self.tables.get_mut(name).ok_or_else(|| {
+            KvError::Internal(format!("Table {} is not in the transaction", name))
+        })?;
+        let index = locked.table.index(key);
+        Ok(&mut locked.shards[index])
+    }
+
+    // 写入 entry，记下之前的 entry 用于回滚，返回之前没有过期的值
+    fn put(
+        &mut self,
+        table: &str,
+        key: &str,
+        entry: Option<Entry>,
+    ) -> Result<Option<Value>, KvError> {
+        let old = match entry {
+            Some(entry) => {
+                self.mutations.push(entry.to_mutation(table, key));
+                self.shard(table, key)?.insert(key.into(), entry)
+            }
+            None => {
+                self.mutations.push(Mutation::delete(table, key));
+                self.shard(table, key)?.remove(key)
+            }
+        };
+        self.undo.push((table.into(), key.into(), old.clone()));
+        Ok(old.and_then(|v| v.into_value(self.now)))
+    }
+
+    fn rollback(&mut self) {
+        while let Some((table, key, old)) = self.undo.pop() {
+            if let Ok(shard) = self.shard(&table, &key) {
+                match old {
+                    Some(entry) => shard.insert(key, entry),
+                    None => shard.remove(&key),
+                };
+            }
+        }
+    }
+}
+
+impl Transaction for MemTransaction<'_> {
+    fn get(&mut self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let now = self.now;
+        let entry = self.shard(table, key)?.get(key).cloned();
+        Ok(entry.and_then(|v| v.into_value(now)))
+    }
+
+    fn set(
+        &mut self,
+        table: &str,
+        key: &str,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<Option<Value>, KvError> {
+        self.put(table, key, Some(Entry::new(value, ttl.map(expire_at))))
+    }
+
+    fn update(&mut self, table: &str, key: &str, value: Value) -> Result<Option<Value>, KvError> {
+        let now = self.now;
+        let expire_at = self
+            .shard(table, key)?
+            .get(key)
+            .filter(|v| !v.is_expired(now))
+            .and_then(|v| v.expire_at);
+        self.put(table, key, Some(Entry::new(value, expire_at)))
+    }
+
+    fn del(&mut self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let now = self.now;
+        if self
+            .shard(table, key)?
+            .get(key)
+            .is_none_or(|v| v.is_expired(now))
+        {
+            return Ok(None);
+        }
+        self.put(table, key, None)
+    }
 }
 
 impl MemTable {
@@ -13,46 +232,385 @@ impl MemTable {
         Self::default()
     }
 
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    /// 打开持久化的 MemTable：启动时从 path 下的 snapshot 和 WAL 恢复数据，之后的修改都会写进 WAL
+    pub fn open(path: impl AsRef<Path>, options: WalOptions) -> Result<Self, KvError> {
+        let mut store = Self::default();
+        let wal = Wal::open(path.as_ref(), options.sync, |m| store.apply(m))?;
+        store.wal = Some(Arc::new(wal));
+        store.spawn_maintenance(&options)?;
+        Ok(store)
+    }
+
+    /// 把当前的数据写成 snapshot，并删除已经包含在 snapshot 里的 WAL
+    pub fn snapshot(&self) -> Result<(), KvError> {
+        match &self.wal {
+            Some(wal) => write_snapshot(&self.tables, wal),
+            None => Err(KvError::Internal("MemTable is not persistent".into())),
+        }
+    }
+
+    /// 把 WAL 里还没落盘的数据 fsync 到磁盘
+    pub fn sync(&self) -> Result<(), KvError> {
+        match &self.wal {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        }
+    }
+
+    // 后台线程定期 fsync 和写 snapshot，MemTable 被 drop 之后线程自动退出
+    fn spawn_maintenance(&self, options: &WalOptions) -> Result<(), KvError> {
+        let sync_interval = match options.sync {
+            SyncPolicy::Interval(interval) => Some(interval),
+            _ => None,
+        };
+        let snapshot_interval = options.snapshot_interval;
+        let (Some(wal), Some(tick)) = (
+            &self.wal,
+            sync_interval.into_iter().chain(snapshot_interval).min(),
+        ) else {
+            return Ok(());
+        };
+
+        let wal = Arc::downgrade(wal);
+        let tables = Arc::downgrade(&self.tables);
+        thread::Builder::new()
+            .name("memtable-wal".into())
+            .spawn(move || {
+                let mut last_snapshot = Instant::now();
+                loop {
+                    thread::sleep(tick);
+                    let (Some(wal), Some(tables)) = (wal.upgrade(), tables.upgrade()) else {
+                        break;
+                    };
+                    if sync_interval.is_some() {
+                        if let Err(e) = wal.sync() {
+                            warn!("Failed to sync WAL: {}", e);
+                        }
+                    }
+                    match snapshot_interval {
+                        Some(interval) if last_snapshot.elapsed() >= interval && wal.changed() => {
+                            last_snapshot = Instant::now();
+                            if let Err(e) = write_snapshot(&tables, &wal) {
+                                warn!("Failed to write snapshot: {}", e);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    // 重放 WAL 里的一次修改
+    fn apply(&self, m: Mutation) {
+        let table = self.get_or_create_table(&m.table);
+        let mut table = table.write(&m.key);
+        match m.deleted {
+            true => table.remove(&m.key),
+            false => {
+                let expire_at = (m.expire_at > 0).then_some(m.expire_at);
+                table.insert(m.key, Entry::new(m.value.unwrap_or_default(), expire_at))
+            }
+        };
+    }
+
+    // 持久化的 MemTable 先写 WAL 再修改内存
+    // 调用时要拿着 key 所在 shard 的写锁，这样 WAL 里同一个 key 的修改顺序和内存里是一致的
+    fn log(&self, mutations: impl FnOnce() -> Vec<Mutation>) -> Result<(), KvError> {
+        match &self.wal {
+            Some(wal) => wal.append(mutations()),
+            None => Ok(()),
+        }
+    }
+
+    fn get_or_create_table(&self, name: &str) -> Arc<Table> {
         match self.tables.get(name) {
-            Some(table) => table,
-            None => {
-                let entry = self.tables.entry(name.into()).or_default();
-                entry.downgrade()
+            Some(table) => table.value().clone(),
+            None => self.tables.entry(name.into()).or_default().value().clone(),
+        }
+    }
+
+    // 读取一个没有过期的 entry，过期的 entry 会被顺手删除
+    fn get_entry(&self, table: &str, key: &str) -> Option<Entry> {
+        let table = self.get_or_create_table(table);
+        let now = now_millis();
+        let entry = table.read(key).get(key).cloned()?;
+        if entry.is_expired(now) {
+            let mut table = table.write(key);
+            if table.get(key).is_some_and(|v| v.is_expired(now)) {
+                table.remove(key);
             }
+            return None;
+        }
+        Some(entry)
+    }
+
+    // 在 key 所在 shard 的写锁里完成读-改-写：f 拿到当前没过期的值，返回要写入的新值和结果
+    fn update<T>(
+        &self,
+        name: &str,
+        key: String,
+        f: impl FnOnce(Option<Value>) -> Result<(Option<Value>, T), KvError>,
+    ) -> Result<T, KvError> {
+        let table = self.get_or_create_table(name);
+        let mut table = table.write(&key);
+        let now = now_millis();
+        let old = table.get(&key).filter(|v| !v.is_expired(now));
+        // 之前不存在或者已经过期的 key，新值没有过期时间
+        let expire_at = old.and_then(|v| v.expire_at);
+        let (new, result) = f(old.map(|v| v.value.clone()))?;
+        if let Some(v) = new {
+            let entry = Entry::new(v, expire_at);
+            self.log(|| vec![entry.to_mutation(name, &key)])?;
+            table.insert(key, entry);
         }
+        Ok(result)
+    }
+
+    fn insert(&self, name: &str, key: String, entry: Entry) -> Result<Option<Value>, KvError> {
+        let table = self.get_or_create_table(name);
+        let mut table = table.write(&key);
+        self.log(|| vec![entry.to_mutation(name, &key)])?;
+        let old = table.insert(key, entry);
+        Ok(old.and_then(|v| v.into_value(now_millis())))
+    }
+
+    // 修改一个没有过期的 entry，返回 f 的结果，f 返回 false 时不会修改
+    fn modify(
+        &self,
+        name: &str,
+        key: &str,
+        f: impl FnOnce(&mut Entry) -> bool,
+    ) -> Result<bool, KvError> {
+        let table = self.get_or_create_table(name);
+        let mut table = table.write(key);
+        let mut entry = match table.get(key) {
+            Some(v) if !v.is_expired(now_millis()) => v.clone(),
+            _ => return Ok(false),
+        };
+        if !f(&mut entry) {
+            return Ok(false);
+        }
+        self.log(|| vec![entry.to_mutation(name, key)])?;
+        table.insert(key.into(), entry);
+        Ok(true)
     }
 }
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.get(key).map(|v| v.value().clone()))
+        Ok(self.get_entry(table, key).map(|v| v.value))
     }
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.insert(key, value))
+        self.insert(table, key, Entry::new(value, None))
     } // 返回前值
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.insert(table, key, Entry::new(value, Some(expire_at(ttl))))
+    }
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.contains_key(key))
+        Ok(self.get_entry(table, key).is_some())
     }
-    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-        Ok(table.remove(key).map(|(_k, v)| v))
+    fn del(&self, name: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let table = self.get_or_create_table(name);
+        let mut table = table.write(key);
+        // 过期的 key 留给 purge_expired 清理
+        if table.get(key).is_none_or(|v| v.is_expired(now_millis())) {
+            return Ok(None);
+        }
+        self.log(|| vec![Mutation::delete(name, key)])?;
+        Ok(table.remove(key).map(|v| v.value))
     }
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
         let table = self.get_or_create_table(table);
-        Ok(table
+        let now = now_millis();
+        let mut pairs: Vec<_> = table
+            .read_all()
             .iter()
-            .map(|v| Kvpair::new(v.key(), v.value().clone()))
-            .collect())
+            .flat_map(|shard| {
+                shard
+                    .iter()
+                    .filter(|(_, v)| !v.is_expired(now))
+                    .map(|(k, v)| Kvpair::new(k, v.value.clone()))
+            })
+            .collect();
+        pairs.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Ok(pairs)
     }
+    // 按页从 table 里取数据，不需要复制整个 table，也不会一直拿着锁
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter());
+        let table = self.get_or_create_table(table);
+        let mut cursor = Some(String::new());
+        let mut page = Vec::new().into_iter();
+        let iter = iter::from_fn(move || loop {
+            if let Some(pair) = page.next() {
+                return Some(pair);
+            }
+            let range = ScanRange {
+                start: cursor.take()?,
+                ..Default::default()
+            };
+            let (pairs, next) = table.scan(&range, ITER_BATCH);
+            page = pairs.into_iter();
+            cursor = next;
+        });
         Ok(Box::new(iter))
     }
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables: Vec<_> = self.tables.iter().map(|t| t.key().clone()).collect();
+        tables.sort_unstable();
+        Ok(tables)
+    }
+    fn count(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.tables.get(table).map_or(0, |t| t.len()))
+    }
+    fn scan(
+        &self,
+        table: &str,
+        range: &ScanRange,
+        limit: usize,
+    ) -> Result<(Vec<Kvpair>, Option<String>), KvError> {
+        Ok(self.get_or_create_table(table).scan(range, limit))
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.modify(table, key, |v| {
+            v.expire_at = Some(expire_at(ttl));
+            true
+        })
+    }
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Option<Duration>>, KvError> {
+        Ok(self
+            .get_entry(table, key)
+            .map(|v| v.expire_at.map(remaining)))
+    }
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.modify(table, key, |v| v.expire_at.take().is_some())
+    }
+    // 过期的数据不需要写 WAL，重放之后它们依然是过期的
+    fn purge_expired(&self) -> Result<Vec<(String, String, Value)>, KvError> {
+        let now = now_millis();
+        let tables: Vec<_> = self
+            .tables
+            .iter()
+            .map(|t| (t.key().clone(), t.value().clone()))
+            .collect();
+        let mut purged = Vec::new();
+        for (name, table) in &tables {
+            for shard in &table.shards {
+                write(shard).retain(|k, v| {
+                    if !v.is_expired(now) {
+                        return true;
+                    }
+                    purged.push((name.clone(), k.clone(), v.value.clone()));
+                    false
+                });
+            }
+        }
+        Ok(purged)
+    }
+
+    fn incr(&self, table: &str, key: String, delta: i64) -> Result<i64, KvError> {
+        self.update(table, key, |old| {
+            let new = incr_value(old, delta)?;
+            Ok((Some(new.into()), new))
+        })
+    }
+    fn incr_float(&self, table: &str, key: String, delta: f64) -> Result<f64, KvError> {
+        self.update(table, key, |old| {
+            let new = incr_float_value(old, delta)?;
+            Ok((Some(new.into()), new))
+        })
+    }
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.update(table, key, |old| match old {
+            Some(_) => Ok((None, false)),
+            None => Ok((Some(value), true)),
+        })
+    }
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        new: Value,
+    ) -> Result<bool, KvError> {
+        self.update(table, key, |old| match old == expected {
+            true => Ok((Some(new), true)),
+            false => Ok((None, false)),
+        })
+    }
+
+    // 按 table 名字的顺序拿所有 shard 的写锁，这样多个事务同时执行也不会死锁
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&mut dyn Transaction) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        let mut names = tables.to_vec();
+        names.sort_unstable();
+        names.dedup();
+        let locked: Vec<_> = names
+            .into_iter()
+            .map(|name| (name, self.get_or_create_table(name)))
+            .collect();
+
+        let mut tx = MemTransaction {
+            tables: locked
+                .iter()
+                .map(|(name, table)| {
+                    let shards = table.write_all();
+                    (name.to_string(), LockedTable { table, shards })
+                })
+                .collect(),
+            undo: Vec::new(),
+            mutations: Vec::new(),
+            now: now_millis(),
+        };
+        let mut result = f(&mut tx);
+        if result.is_ok() {
+            let mutations = std::mem::take(&mut tx.mutations);
+            result = self.log(|| mutations);
+        }
+        if result.is_err() {
+            tx.rollback();
+        }
+        result
+    }
+}
+
+// 先切换 WAL 再复制数据，每个 shard 只在复制的时候拿读锁
+fn write_snapshot(tables: &Tables, wal: &Wal) -> Result<(), KvError> {
+    wal.snapshot(|writer| {
+        let now = now_millis();
+        let tables: Vec<_> = tables
+            .iter()
+            .map(|t| (t.key().clone(), t.value().clone()))
+            .collect();
+        for (name, table) in tables {
+            for shard in &table.shards {
+                let mutations: Vec<_> = read(shard)
+                    .iter()
+                    .filter(|(_, v)| !v.is_expired(now))
+                    .map(|(k, v)| v.to_mutation(&name, k))
+                    .collect();
+                let mut mutations = mutations.into_iter();
+                loop {
+                    let batch: Vec<_> = mutations.by_ref().take(SNAPSHOT_BATCH).collect();
+                    if batch.is_empty() {
+                        break;
+                    }
+                    writer.write(batch)?;
+                }
+            }
+        }
+        Ok(())
+    })
 }
 
 impl From<(String, Value)> for Kvpair {
@@ -62,9 +620,24 @@ impl From<(String, Value)> for Kvpair {
 }
 
 #[cfg(test)]
-
 mod tests {
     use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    const MANUAL: WalOptions = WalOptions {
+        sync: SyncPolicy::Always,
+        snapshot_interval: None,
+    };
+
+    fn wal_files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|v| v.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        files
+    }
 
     #[test]
     fn get_or_create_table_should_work() {
@@ -75,8 +648,127 @@ mod tests {
     }
 
     #[test]
-    fn memtable_should_work() {
+    fn cloned_memtable_should_share_data() {
         let store = MemTable::new();
-        // test_get_iter(store);
+        let cloned = store.clone();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(cloned.get("t1", "k1"), Ok(Some("v1".into())));
+    }
+
+    #[test]
+    fn get_iter_should_fetch_in_batches() {
+        let store = MemTable::new();
+        let n = ITER_BATCH * 2 + 3;
+        for i in 0..n {
+            store
+                .set("t1", format!("k{:04}", i), (i as i64).into())
+                .unwrap();
+        }
+
+        let keys: Vec<_> = store.get_iter("t1").unwrap().map(|v| v.key).collect();
+        assert_eq!(keys.len(), n);
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn snapshot_should_compact_wal() {
+        let dir = tempdir().unwrap();
+        {
+            let store = MemTable::open(dir.path(), MANUAL).unwrap();
+            for i in 0..SNAPSHOT_BATCH + 10 {
+                store
+                    .set("t1", format!("k{}", i), (i as i64).into())
+                    .unwrap();
+            }
+            store.del("t1", "k0").unwrap();
+            store.snapshot().unwrap();
+
+            // 旧的 WAL 被删掉，只剩下 snapshot 和新的 WAL
+            let files = wal_files(dir.path());
+            assert_eq!(files.len(), 2);
+            assert!(files[0].ends_with(".snap"));
+            assert!(files[1].ends_with(".log"));
+
+            store.set("t1", "k0".into(), "new".into()).unwrap();
+            store.set("t2", "k1".into(), "v1".into()).unwrap();
+        }
+
+        let store = MemTable::open(dir.path(), MANUAL).unwrap();
+        assert_eq!(store.get_all("t1").unwrap().len(), SNAPSHOT_BATCH + 10);
+        assert_eq!(store.get("t1", "k0"), Ok(Some("new".into())));
+        assert_eq!(store.get("t2", "k1"), Ok(Some("v1".into())));
+    }
+
+    #[test]
+    fn corrupted_wal_tail_should_be_truncated() {
+        let dir = tempdir().unwrap();
+        {
+            let store = MemTable::open(dir.path(), MANUAL).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+        }
+
+        // 模拟写到一半时进程崩溃：最后一条记录不完整
+        let files = wal_files(dir.path());
+        let path = dir.path().join(&files[0]);
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 3]).unwrap();
+
+        let store = MemTable::open(dir.path(), MANUAL).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+        assert_eq!(store.get("t1", "k2"), Ok(None));
+        let len = fs::metadata(&path).unwrap().len() as usize;
+        assert!(len < data.len() - 3);
+
+        // 截断之后可以继续写入和恢复
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        drop(store);
+        let store = MemTable::open(dir.path(), MANUAL).unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+        assert_eq!(store.get("t1", "k3"), Ok(Some("v3".into())));
+    }
+
+    #[test]
+    fn corrupted_wal_before_last_segment_should_fail() {
+        let dir = tempdir().unwrap();
+        // 每次打开都会写一个新的 WAL 文件
+        for key in ["k1", "k2"] {
+            let store = MemTable::open(dir.path(), MANUAL).unwrap();
+            store.set("t1", key.into(), "v".into()).unwrap();
+        }
+        let files = wal_files(dir.path());
+        assert_eq!(files.len(), 2);
+
+        // 第一个文件的最后一条记录损坏了
+        let path = dir.path().join(&files[0]);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        let result = MemTable::open(dir.path(), MANUAL);
+        assert!(matches!(result, Err(KvError::Internal(_))));
+    }
+
+    #[test]
+    fn snapshot_should_be_written_periodically() {
+        let dir = tempdir().unwrap();
+        let options = WalOptions {
+            sync: SyncPolicy::Interval(Duration::from_millis(10)),
+            snapshot_interval: Some(Duration::from_millis(50)),
+        };
+        let store = MemTable::open(dir.path(), options).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+
+        let start = Instant::now();
+        while !wal_files(dir.path()).iter().any(|v| v.ends_with(".snap")) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn snapshot_without_wal_should_fail() {
+        assert!(MemTable::new().snapshot().is_err());
     }
 }
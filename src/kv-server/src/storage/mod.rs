@@ -1,11 +1,78 @@
+mod feed;
 mod memory;
 mod sleddb;
+#[allow(clippy::module_inception)]
 mod storage;
+mod wal;
 
-use crate::pb::abi::Kvpair;
+use crate::pb::abi::{value, Kvpair, Value};
+use crate::KvError;
+pub use feed::{ChangeFeed, Watched, Watcher, DEFAULT_WATCH_BUFFER};
 pub use memory::MemTable;
 pub use sleddb::*;
 pub use storage::*;
+pub use wal::{SyncPolicy, WalOptions};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// 过期时间用 unix 时间戳（毫秒）保存，这样进程重启后依然有效
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+// 根据 ttl 计算过期的时间点
+fn expire_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+// 根据过期的时间点计算剩余的 ttl
+fn remaining(expire_at: u64) -> Duration {
+    Duration::from_millis(expire_at.saturating_sub(now_millis()))
+}
+
+// 整数自增，key 不存在时从 0 开始
+pub(crate) fn incr_value(old: Option<Value>, delta: i64) -> Result<i64, KvError> {
+    let old = match old {
+        Some(v) => v
+            .try_into()
+            .map_err(|_| KvError::InvalidCommand("value is not an integer".into()))?,
+        None => 0i64,
+    };
+    old.checked_add(delta)
+        .ok_or_else(|| KvError::InvalidCommand("increment would overflow".into()))
+}
+
+// 浮点数自增，整数也可以当作浮点数处理
+pub(crate) fn incr_float_value(old: Option<Value>, delta: f64) -> Result<f64, KvError> {
+    let old = match old.and_then(|v| v.value) {
+        Some(value::Value::Float(f)) => f,
+        Some(value::Value::Integer(i)) => i as f64,
+        None => 0.0,
+        Some(_) => return Err(KvError::InvalidCommand("value is not a float".into())),
+    };
+    let new = old + delta;
+    if !new.is_finite() {
+        return Err(KvError::InvalidCommand(
+            "increment would produce NaN or Infinity".into(),
+        ));
+    }
+    Ok(new)
+}
+
+// 从按 key 排序的数据里取出一页，多取一个，它的 key 就是下一页的 cursor
+fn paginate(
+    iter: impl Iterator<Item = Result<Kvpair, KvError>>,
+    limit: usize,
+) -> Result<(Vec<Kvpair>, Option<String>), KvError> {
+    let mut pairs = iter.take(limit + 1).collect::<Result<Vec<_>, _>>()?;
+    let cursor = match pairs.len() > limit {
+        true => pairs.pop().map(|v| v.key),
+        false => None,
+    };
+    Ok((pairs, cursor))
+}
 
 pub struct StorageIter<T> {
     data: T,
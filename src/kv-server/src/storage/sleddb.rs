@@ -1,15 +1,41 @@
-use sled::{Db, IVec};
-use std::{convert::TryInto, path::Path, str};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, TransactionError, Transactional, TransactionalTree,
+        UnabortableTransactionError,
+    },
+    Db, IVec, Tree,
+};
+use std::{
+    cell::RefCell, collections::HashSet, convert::TryInto, fmt::Display, path::Path, str,
+    time::Duration,
+};
 
-use crate::storage::StorageIter;
-use crate::{KvError, Kvpair, Storage, Value};
+use crate::storage::{
+    expire_at, incr_float_value, incr_value, now_millis, paginate, remaining, ScanRange,
+    StorageIter,
+};
+use crate::{KvError, Kvpair, Storage, Transaction, Value};
+
+// 保存 key 过期时间的 tree，key 和数据的 key 一样，value 是过期的时间点
+const TTL_TREE: &str = "__ttl__";
+
+type TxResult<T> = Result<T, ConflictableTransactionError<sled::Error>>;
 
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb {
+    db: Db,
+    ttl: Tree,
+}
 
 impl SledDb {
-    pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, KvError> {
+        let path = path.as_ref();
+        let open_error = |e: sled::Error| {
+            KvError::StorageError("open", path.display().to_string(), "".into(), e.to_string())
+        };
+        let db = sled::open(path).map_err(open_error)?;
+        let ttl = db.open_tree(TTL_TREE).map_err(open_error)?;
+        Ok(Self { db, ttl })
     }
 
     // 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
@@ -22,6 +48,198 @@ impl SledDb {
     fn get_table_prefix(table: &str) -> String {
         format!("{}:", table)
     }
+
+    // 同时写入数据和过期时间，返回之前没有过期的值
+    fn insert(&self, name: &str, data: Vec<u8>, expire_at: Option<u64>) -> TxResult<Option<IVec>> {
+        let result = (&*self.db, &self.ttl).transaction(|(db, ttl)| {
+            let old = db.insert(name.as_bytes(), data.as_slice())?;
+            let old_ttl = match expire_at {
+                Some(t) => ttl.insert(name.as_bytes(), &t.to_be_bytes())?,
+                None => ttl.remove(name.as_bytes())?,
+            };
+            Ok(old.filter(|_| !is_expired(old_ttl.as_ref())))
+        });
+        result.map_err(flatten)
+    }
+
+    // 如果 key 已经过期，就把它删除并返回过期前的值
+    // 在事务里检查，避免删掉刚刚被重新写入的值
+    fn remove_if_expired(&self, name: &[u8]) -> TxResult<Option<IVec>> {
+        let result = (&*self.db, &self.ttl).transaction(|(db, ttl)| {
+            if !is_expired(ttl.get(name)?.as_ref()) {
+                return Ok(None);
+            }
+            ttl.remove(name)?;
+            Ok(db.remove(name)?)
+        });
+        result.map_err(flatten)
+    }
+
+    // 过期的 key 当作不存在，留给 purge_expired 删除
+    fn is_expired(&self, name: &str) -> sled::Result<bool> {
+        Ok(is_expired(self.ttl.get(name.as_bytes())?.as_ref()))
+    }
+
+    // 在事务里完成读-改-写：f 拿到当前没过期的值，返回要写入的新值和结果
+    // 数据和过期时间一起读写，如果中间有其它写入（包括修改过期时间），sled 会重试整个事务
+    fn update<T>(
+        &self,
+        cmd: &'static str,
+        table: &str,
+        key: &str,
+        f: impl FnMut(Option<Value>) -> Result<(Option<Value>, T), KvError>,
+    ) -> Result<T, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let f = RefCell::new(f);
+        let result = (&*self.db, &self.ttl).transaction(|(db, ttl)| {
+            let expired = is_expired(ttl.get(name.as_bytes())?.as_ref());
+            let old = match expired {
+                true => None,
+                false => db.get(name.as_bytes())?,
+            };
+            let value = flip(old.map(|v| v.as_ref().try_into())).map_err(abort)?;
+            let (new, result) = (f.borrow_mut())(value).map_err(abort)?;
+            let Some(new) = new else {
+                return Ok(result);
+            };
+
+            let data: Vec<u8> = new.try_into().map_err(abort)?;
+            // 已经过期的 key 当作新 key，不再保留之前的过期时间
+            if expired {
+                ttl.remove(name.as_bytes())?;
+            }
+            db.insert(name.as_bytes(), data)?;
+            Ok(result)
+        });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => storage_error(cmd, table, key)(e),
+        })
+    }
+
+    // table 里所有过期的 key
+    fn expired_keys(&self, prefix: &str) -> Result<HashSet<IVec>, sled::Error> {
+        let now = now_millis();
+        self.ttl
+            .scan_prefix(prefix)
+            .filter_map(|v| match v {
+                Ok((k, t)) if decode_expire_at(&t) <= now => Some(Ok(k)),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect()
+    }
+}
+
+// 过期的时间点用 big endian 的 u64 保存，数据损坏时当作永不过期
+fn decode_expire_at(v: &[u8]) -> u64 {
+    v.try_into().map_or(u64::MAX, u64::from_be_bytes)
+}
+
+fn is_expired(expire_at: Option<&IVec>) -> bool {
+    expire_at.is_some_and(|t| decode_expire_at(t) <= now_millis())
+}
+
+// 在事务里遇到 KvError 时放弃整个事务
+fn abort(e: KvError) -> ConflictableTransactionError<KvError> {
+    ConflictableTransactionError::Abort(e)
+}
+
+// 事务里只会出现 sled 本身的错误
+fn flatten(e: TransactionError<sled::Error>) -> ConflictableTransactionError<sled::Error> {
+    match e {
+        TransactionError::Abort(e) | TransactionError::Storage(e) => {
+            ConflictableTransactionError::Storage(e)
+        }
+    }
+}
+
+// sled 事务里的操作，数据和过期时间在两个 tree 里，一起读写
+struct SledTransaction<'a> {
+    db: &'a TransactionalTree,
+    ttl: &'a TransactionalTree,
+    // 遇到冲突时记下来，返回给 sled 让它重试整个事务
+    error: Option<UnabortableTransactionError>,
+}
+
+impl SledTransaction<'_> {
+    fn check<T>(
+        &mut self,
+        cmd: &'static str,
+        table: &str,
+        key: &str,
+        result: Result<T, UnabortableTransactionError>,
+    ) -> Result<T, KvError> {
+        result.map_err(|e| {
+            let err = KvError::StorageError(cmd, table.into(), key.into(), e.to_string());
+            self.error.get_or_insert(e);
+            err
+        })
+    }
+}
+
+impl Transaction for SledTransaction<'_> {
+    fn get(&mut self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let ttl = self.check("get", table, key, self.ttl.get(name.as_bytes()))?;
+        if is_expired(ttl.as_ref()) {
+            return Ok(None);
+        }
+        let old = self.check("get", table, key, self.db.get(name.as_bytes()))?;
+        flip(old.map(|v| v.as_ref().try_into()))
+    }
+
+    fn set(
+        &mut self,
+        table: &str,
+        key: &str,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let data: Vec<u8> = value.try_into()?;
+        let old = self.check("set", table, key, self.db.insert(name.as_bytes(), data))?;
+        let old_ttl = match ttl {
+            Some(ttl) => {
+                let expire_at = expire_at(ttl).to_be_bytes();
+                self.check(
+                    "set",
+                    table,
+                    key,
+                    self.ttl.insert(name.as_bytes(), &expire_at),
+                )?
+            }
+            None => self.check("set", table, key, self.ttl.remove(name.as_bytes()))?,
+        };
+        flip(
+            old.filter(|_| !is_expired(old_ttl.as_ref()))
+                .map(|v| v.as_ref().try_into()),
+        )
+    }
+
+    fn update(&mut self, table: &str, key: &str, value: Value) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let data: Vec<u8> = value.try_into()?;
+        let old_ttl = self.check("update", table, key, self.ttl.get(name.as_bytes()))?;
+        // 已经过期的 key 当作新 key，不再保留之前的过期时间
+        let expired = is_expired(old_ttl.as_ref());
+        if expired {
+            self.check("update", table, key, self.ttl.remove(name.as_bytes()))?;
+        }
+        let old = self.check("update", table, key, self.db.insert(name.as_bytes(), data))?;
+        flip(old.filter(|_| !expired).map(|v| v.as_ref().try_into()))
+    }
+
+    fn del(&mut self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let old_ttl = self.check("del", table, key, self.ttl.get(name.as_bytes()))?;
+        if is_expired(old_ttl.as_ref()) {
+            return Ok(None);
+        }
+        self.check("del", table, key, self.ttl.remove(name.as_bytes()))?;
+        let old = self.check("del", table, key, self.db.remove(name.as_bytes()))?;
+        flip(old.map(|v| v.as_ref().try_into()))
+    }
 }
 
 /// 把 Option<Result<T, E>> flip 成 Result<Option<T>, E>
@@ -30,86 +248,332 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
     x.map_or(Ok(None), |v| v.map(Some))
 }
 
+/// 把 sled 的错误转换成带上下文的 KvError::StorageError
+fn storage_error<'a, E: Display>(
+    cmd: &'static str,
+    table: &'a str,
+    key: &'a str,
+) -> impl FnOnce(E) -> KvError + 'a {
+    move |e| KvError::StorageError(cmd, table.into(), key.into(), e.to_string())
+}
+
 impl Storage for SledDb {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes());
-        if let x = self.0.get(name.as_bytes()) {
-            flip(Some(Ok(Value {
-                value: Some(crate::value::Value::Bool(true)),
-            })))
-        } else {
-            Err(KvError::InvalidCommand("invalid command".to_string()))
+        if self
+            .is_expired(&name)
+            .map_err(storage_error("get", table, key))?
+        {
+            return Ok(None);
         }
+        let result = self
+            .db
+            .get(name.as_bytes())
+            .map_err(storage_error("get", table, key))?
+            .map(|v| v.as_ref().try_into());
+        flip(result)
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, &key);
         let data: Vec<u8> = value.try_into()?;
 
-        let result = self.0.get(name.as_bytes());
-        if let x = self.0.get(name.as_bytes()) {
-            flip(Some(Ok(Value {
-                value: Some(crate::value::Value::Bool(true)),
-            })))
-        } else {
-            Err(KvError::InvalidCommand("invalid command".to_string()))
-        }
+        let result = self
+            .insert(&name, data, None)
+            .map_err(storage_error("set", table, &key))?
+            .map(|v| v.as_ref().try_into());
+        flip(result)
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, &key);
-        match self.0.contains_key(name) {
-            Ok(value) => Ok(value),
-            Err(err) => Err(KvError::NotFound(
-                "Invalid key".to_string(),
-                "Invalid key".to_string(),
-            )),
+        let data: Vec<u8> = value.try_into()?;
+
+        let result = self
+            .insert(&name, data, Some(expire_at(ttl)))
+            .map_err(storage_error("set", table, &key))?
+            .map(|v| v.as_ref().try_into());
+        flip(result)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        if self
+            .is_expired(&name)
+            .map_err(storage_error("contains", table, key))?
+        {
+            return Ok(false);
         }
+        self.db
+            .contains_key(name)
+            .map_err(storage_error("contains", table, key))
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+        let name = SledDb::get_full_key(table, key);
 
-        let result = self.0.get(name.as_bytes());
-        if let x = self.0.get(name.as_bytes()) {
-            flip(Some(Ok(Value {
-                value: Some(crate::value::Value::Bool(true)),
-            })))
-        } else {
-            Err(KvError::InvalidCommand("invalid command".to_string()))
-        }
+        let result: Result<_, TransactionError<sled::Error>> =
+            (&*self.db, &self.ttl).transaction(|(db, ttl)| {
+                // 过期的 key 留给 purge_expired 清理
+                if is_expired(ttl.get(name.as_bytes())?.as_ref()) {
+                    return Ok(None);
+                }
+                ttl.remove(name.as_bytes())?;
+                Ok(db.remove(name.as_bytes())?)
+            });
+        let result = result
+            .map_err(storage_error("del", table, key))?
+            .map(|v| v.as_ref().try_into());
+        flip(result)
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
         let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
-
-        Ok(result)
+        let expired = self
+            .expired_keys(&prefix)
+            .map_err(storage_error("get_all", table, ""))?;
+        self.db
+            .scan_prefix(prefix)
+            .filter(|v| !matches!(v, Ok((k, _)) if expired.contains(k)))
+            .map(|v| {
+                let (k, v) = v.map_err(storage_error("get_all", table, ""))?;
+                Ok(Kvpair::new(
+                    ivec_to_key(k.as_ref())?,
+                    v.as_ref().try_into()?,
+                ))
+            })
+            .collect()
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
         let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
-        Ok(Box::new(iter))
+        let expired = self
+            .expired_keys(&prefix)
+            .map_err(storage_error("get_iter", table, ""))?;
+        let iter = self
+            .db
+            .scan_prefix(prefix)
+            .filter(move |v| !matches!(v, Ok((k, _)) if expired.contains(k)));
+        Ok(Box::new(StorageIter::new(iter)))
+    }
+
+    // key 的格式是 table:key，找到一个 table 之后直接跳到下一个 table 的位置
+    fn tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = Vec::new();
+        let mut start = Vec::new();
+        while let Some(v) = self.db.range(start.as_slice()..).next() {
+            let (k, _) = v.map_err(storage_error("tables", "", ""))?;
+            let Some(pos) = k.iter().position(|c| *c == b':') else {
+                start = [&k[..], &[0]].concat();
+                continue;
+            };
+            tables.push(String::from_utf8_lossy(&k[..pos]).into_owned());
+            // ';' 是 ':' 之后的下一个字符
+            start = [&k[..pos], b";"].concat();
+        }
+        // "t10:" 在 "t1:" 前面，需要重新按名字排序
+        tables.sort_unstable();
+        Ok(tables)
+    }
+
+    // sled 里的 key 本身就是有序的，直接从 range 的起点开始遍历
+    fn scan(
+        &self,
+        table: &str,
+        range: &ScanRange,
+        limit: usize,
+    ) -> Result<(Vec<Kvpair>, Option<String>), KvError> {
+        let prefix = SledDb::get_table_prefix(table);
+        let lower = SledDb::get_full_key(table, range.lower());
+        let iter = self
+            .db
+            .range(lower.as_bytes()..)
+            .map(|v| v.map_err(storage_error("scan", table, "")))
+            .take_while(|v| match v {
+                Ok((k, _)) => {
+                    k.starts_with(prefix.as_bytes())
+                        && ivec_to_key(k).map_or(true, |k| range.contains(k))
+                }
+                Err(_) => true,
+            })
+            .filter_map(|v| {
+                let (k, v) = match v {
+                    Ok(v) => v,
+                    Err(e) => return Some(Err(e)),
+                };
+                match self.ttl.get(&k) {
+                    Ok(t) if is_expired(t.as_ref()) => None,
+                    Ok(_) => Some(
+                        ivec_to_key(&k).and_then(|k| Ok(Kvpair::new(k, v.as_ref().try_into()?))),
+                    ),
+                    Err(e) => Some(Err(storage_error("scan", table, "")(e))),
+                }
+            });
+        paginate(iter, limit)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key);
+        let expire_at = expire_at(ttl);
+
+        let result: Result<_, TransactionError<sled::Error>> =
+            (&*self.db, &self.ttl).transaction(|(db, ttl)| {
+                if db.get(name.as_bytes())?.is_none()
+                    || is_expired(ttl.get(name.as_bytes())?.as_ref())
+                {
+                    return Ok(false);
+                }
+                ttl.insert(name.as_bytes(), &expire_at.to_be_bytes())?;
+                Ok(true)
+            });
+        result.map_err(storage_error("expire", table, key))
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Option<Duration>>, KvError> {
+        if !self.contains(table, key)? {
+            return Ok(None);
+        }
+        let name = SledDb::get_full_key(table, key);
+        let expire_at = self
+            .ttl
+            .get(name)
+            .map_err(storage_error("ttl", table, key))?;
+        Ok(Some(expire_at.map(|t| remaining(decode_expire_at(&t)))))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let name = SledDb::get_full_key(table, key);
+
+        // 已经过期的 key 不能去掉过期时间，否则它会"复活"
+        let result: Result<_, TransactionError<sled::Error>> =
+            self.ttl.transaction(|ttl| match ttl.get(name.as_bytes())? {
+                Some(t) if !is_expired(Some(&t)) => {
+                    ttl.remove(name.as_bytes())?;
+                    Ok(true)
+                }
+                _ => Ok(false),
+            });
+        result.map_err(storage_error("persist", table, key))
+    }
+
+    fn purge_expired(&self) -> Result<Vec<(String, String, Value)>, KvError> {
+        let now = now_millis();
+        let mut purged = Vec::new();
+        for v in self.ttl.iter() {
+            let (k, t) = v.map_err(storage_error("purge_expired", "", ""))?;
+            if decode_expire_at(&t) > now {
+                continue;
+            }
+            let old = self
+                .remove_if_expired(&k)
+                .map_err(storage_error("purge_expired", "", ""))?;
+            if let Some(old) = old {
+                let (table, key) = split_full_key(&k)?;
+                purged.push((table.into(), key.into(), old.as_ref().try_into()?));
+            }
+        }
+        Ok(purged)
+    }
+
+    fn incr(&self, table: &str, key: String, delta: i64) -> Result<i64, KvError> {
+        self.update("incr", table, &key, |old| {
+            let new = incr_value(old, delta)?;
+            Ok((Some(new.into()), new))
+        })
+    }
+
+    fn incr_float(&self, table: &str, key: String, delta: f64) -> Result<f64, KvError> {
+        self.update("incr_float", table, &key, |old| {
+            let new = incr_float_value(old, delta)?;
+            Ok((Some(new.into()), new))
+        })
+    }
+
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError> {
+        self.update("set_nx", table, &key, |old| match old {
+            Some(_) => Ok((None, false)),
+            None => Ok((Some(value.clone()), true)),
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        new: Value,
+    ) -> Result<bool, KvError> {
+        self.update("compare_and_swap", table, &key, |old| {
+            match old == expected {
+                true => Ok((Some(new.clone()), true)),
+                false => Ok((None, false)),
+            }
+        })
+    }
+
+    // sled 的事务本身就能跨 tree，不需要提前知道会用到哪些 table
+    fn transaction(
+        &self,
+        _tables: &[&str],
+        f: &mut dyn FnMut(&mut dyn Transaction) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        // sled 遇到冲突时会重新执行闭包，闭包只能是 Fn
+        let f = RefCell::new(f);
+        let result = (&*self.db, &self.ttl).transaction(|(db, ttl)| {
+            let mut tx = SledTransaction {
+                db,
+                ttl,
+                error: None,
+            };
+            let result = (*f.borrow_mut())(&mut tx);
+            match (tx.error, result) {
+                (Some(e), _) => Err(e.into()),
+                (None, Ok(())) => Ok(()),
+                (None, Err(e)) => Err(ConflictableTransactionError::Abort(e)),
+            }
+        });
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => {
+                KvError::StorageError("transaction", "".into(), "".into(), e.to_string())
+            }
+        })
     }
 }
 
 impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
     fn from(v: Result<(IVec, IVec), sled::Error>) -> Self {
         match v {
-            Ok((k, v)) => match v.as_ref().try_into() {
-                Ok(v) => Kvpair::new(ivec_to_key(k.as_ref()), v),
-                Err(_) => Kvpair::default(),
+            Ok((k, v)) => match (ivec_to_key(k.as_ref()), v.as_ref().try_into()) {
+                (Ok(k), Ok(v)) => Kvpair::new(k, v),
+                _ => Kvpair::default(),
             },
             _ => Kvpair::default(),
         }
     }
 }
 
-fn ivec_to_key(ivec: &[u8]) -> &str {
-    let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(":");
-    iter.next();
-    iter.next().unwrap()
+// full key 是 table:key，key 本身可能也含有 `:`，所以只切第一个
+fn ivec_to_key(ivec: &[u8]) -> Result<&str, KvError> {
+    split_full_key(ivec).map(|(_, key)| key)
+}
+
+fn split_full_key(ivec: &[u8]) -> Result<(&str, &str), KvError> {
+    let s = str::from_utf8(ivec)
+        .map_err(|e| KvError::StorageError("decode_key", "".into(), "".into(), e.to_string()))?;
+    match s.split_once(':') {
+        Some(v) => Ok(v),
+        None => Err(KvError::StorageError(
+            "decode_key",
+            "".into(),
+            s.into(),
+            "missing table prefix".into(),
+        )),
+    }
 }
@@ -3,22 +3,102 @@
 // 注意 storage 需要并发安全访问，所以要用到 Arc以及读写锁 RwLock
 
 // crate代表当前 lib
-use crate::storage::MemTable;
-use crate::storage::SledDb;
 use crate::{KvError, Kvpair, Value};
+use std::time::Duration;
 
 // 定义一个 Storage 约束所有对Storage的操作行为,增删改查
 // 有接口就知道类型有哪些方法可以操作了
 
-pub trait Storage {
+// Service 会把 Storage 放到 blocking 线程池里执行，所以需要 Send + Sync + 'static
+pub trait Storage: Send + Sync + 'static {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>; // 返回前值
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>; // 返回前值，同时清除之前的过期时间
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError>; // 设置值和过期时间，返回前值
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    fn tables(&self) -> Result<Vec<String>, KvError>; // 按名字的顺序返回所有的 table
+    fn count(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.get_iter(table)?.count())
+    } // table 里 key 的数量，用于统计，可能包含已经过期但还没有清理的 key
+    fn scan(
+        &self,
+        table: &str,
+        range: &ScanRange,
+        limit: usize,
+    ) -> Result<(Vec<Kvpair>, Option<String>), KvError>; // 按 key 的顺序返回最多 limit 个 kvpair，以及下一页的 cursor
+
+    // 过期的 key 在读写时都当作不存在，del 也不会删除它，统一由 purge_expired 在后台清理
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError>; // key 不存在返回 false
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Option<Duration>>, KvError>; // key 不存在返回 None，没有过期时间返回 Some(None)
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError>; // 返回之前是否有过期时间
+    fn purge_expired(&self) -> Result<Vec<(String, String, Value)>, KvError>; // 删除所有过期的 key，返回删除的 (table, key, 过期前的值)
+
+    // 原子的读-改-写操作，修改已有的 key 时保留它的过期时间
+    fn incr(&self, table: &str, key: String, delta: i64) -> Result<i64, KvError>; // 返回新的值
+    fn incr_float(&self, table: &str, key: String, delta: f64) -> Result<f64, KvError>; // 返回新的值
+    fn set_nx(&self, table: &str, key: String, value: Value) -> Result<bool, KvError>; // key 不存在才写入，返回是否写入
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: String,
+        expected: Option<Value>,
+        new: Value,
+    ) -> Result<bool, KvError>; // 当前值等于 expected 才写入，返回是否写入
+
+    // 在一个事务里执行 f，tables 是 f 会访问的所有 table
+    // f 返回错误时，事务里所有的修改都会回滚；f 可能会因为冲突被重复执行
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&mut dyn Transaction) -> Result<(), KvError>,
+    ) -> Result<(), KvError>;
+}
+
+/// 事务里可以执行的操作，过期的 key 当作不存在
+pub trait Transaction {
+    fn get(&mut self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
+    fn set(
+        &mut self,
+        table: &str,
+        key: &str,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<Option<Value>, KvError>; // 写入新值和过期时间，返回前值
+    fn update(&mut self, table: &str, key: &str, value: Value) -> Result<Option<Value>, KvError>; // 写入新值，保留之前的过期时间
+    fn del(&mut self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
 }
 
+/// 扫描 table 的范围，空字符串表示不限制
+/// 返回的 cursor 是下一页第一个 key，把它当作 start 就可以继续往下扫描
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanRange {
+    /// 起始的 key（包含）
+    pub start: String,
+    /// 结束的 key（不包含）
+    pub end: String,
+    /// key 的前缀
+    pub prefix: String,
+}
+
+impl ScanRange {
+    /// 扫描的起点，有前缀的话不会早于前缀
+    pub fn lower(&self) -> &str {
+        self.start.as_str().max(self.prefix.as_str())
+    }
+
+    /// key 是否在范围内。从 lower() 开始按顺序遍历时，第一个不在范围内的 key 之后就不用再看了
+    pub fn contains(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix) && (self.end.is_empty() || key < self.end.as_str())
+    }
+}
 
 #[cfg(test)]
 // 单元测试写在 实现之前，是标准的TDD(Test-Driven Deployment)
@@ -27,28 +107,240 @@ mod tests {
     use tempfile::tempdir;
 
     use super::*;
+    use crate::storage::{MemTable, SledDb, SyncPolicy, WalOptions};
+    use std::{path::Path, sync::Arc, thread};
 
     #[test]
     fn sleddb_basic_interface_should_work() {
         let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
+        let store = SledDb::new(dir).unwrap();
         test_basi_interface(store);
     }
 
     #[test]
     fn sleddb_get_all_should_work() {
         let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
+        let store = SledDb::new(dir).unwrap();
         test_get_all(store);
     }
 
     #[test]
     fn sleddb_iter_should_work() {
         let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
+        let store = SledDb::new(dir).unwrap();
         test_get_iter(store);
     }
 
+    #[test]
+    fn memtable_tables_should_work() {
+        test_tables(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_tables_should_work() {
+        let dir = tempdir().unwrap();
+        test_tables(SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn memtable_ttl_should_work() {
+        test_ttl(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_ttl(store);
+    }
+
+    #[test]
+    fn memtable_purge_expired_should_work() {
+        test_purge_expired(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_purge_expired_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir).unwrap();
+        test_purge_expired(store);
+    }
+
+    #[test]
+    fn memtable_atomic_ops_should_work() {
+        test_atomic_ops(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_atomic_ops_should_work() {
+        let dir = tempdir().unwrap();
+        test_atomic_ops(SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn memtable_concurrent_updates_should_not_lose_writes() {
+        test_concurrent_updates(Arc::new(MemTable::new()));
+    }
+
+    #[test]
+    fn sleddb_concurrent_updates_should_not_lose_writes() {
+        let dir = tempdir().unwrap();
+        test_concurrent_updates(Arc::new(SledDb::new(dir).unwrap()));
+    }
+
+    #[test]
+    fn memtable_scan_should_work() {
+        test_scan(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_scan_should_work() {
+        let dir = tempdir().unwrap();
+        test_scan(SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn memtable_transaction_should_work() {
+        test_transaction(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        test_transaction(SledDb::new(dir).unwrap());
+    }
+
+    #[test]
+    fn memtable_transaction_should_be_isolated() {
+        test_transaction_isolation(Arc::new(MemTable::new()));
+    }
+
+    #[test]
+    fn sleddb_transaction_should_be_isolated() {
+        let dir = tempdir().unwrap();
+        test_transaction_isolation(Arc::new(SledDb::new(dir).unwrap()));
+    }
+
+    // sled 的后台线程可能还没退出，文件锁要过一会儿才释放，所以重试几次
+    fn reopen_sleddb(path: &Path) -> SledDb {
+        (0..50)
+            .find_map(|_| match SledDb::new(path) {
+                Ok(store) => Some(store),
+                Err(_) => {
+                    thread::sleep(Duration::from_millis(20));
+                    None
+                }
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn sleddb_ttl_should_persist_after_reopen() {
+        let dir = tempdir().unwrap();
+        {
+            let store = SledDb::new(dir.path()).unwrap();
+            let ttl = Duration::from_secs(100);
+            store
+                .set_with_ttl("t1", "k1".into(), "v1".into(), ttl)
+                .unwrap();
+            store
+                .set_with_ttl("t1", "k2".into(), "v2".into(), SHORT_TTL)
+                .unwrap();
+        }
+
+        thread::sleep(SHORT_TTL * 2);
+        let store = reopen_sleddb(dir.path());
+        let ttl = store.ttl("t1", "k1").unwrap().unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(90));
+        assert_eq!(store.get("t1", "k2"), Ok(None));
+    }
+
+    #[test]
+    fn sleddb_should_persist_after_reopen() {
+        let dir = tempdir().unwrap();
+        {
+            let store = SledDb::new(dir.path()).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k:2".into(), 2.into()).unwrap();
+            store.set("t1", "k3".into(), true.into()).unwrap();
+            assert_eq!(store.del("t1", "k3"), Ok(Some(true.into())));
+        }
+
+        // 模拟进程重启：重新打开同一个目录，数据应该还在
+        let store = reopen_sleddb(dir.path());
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v1".into())));
+        assert_eq!(store.get("t1", "k3"), Ok(None));
+        let mut data = store.get_all("t1").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![Kvpair::new("k1", "v1".into()), Kvpair::new("k:2", 2.into())]
+        );
+    }
+
+    #[test]
+    fn persistent_memtable_should_work() {
+        let dir = tempdir().unwrap();
+        let options = WalOptions {
+            sync: SyncPolicy::Always,
+            snapshot_interval: None,
+        };
+        test_basi_interface(MemTable::open(dir.path().join("basic"), options.clone()).unwrap());
+        test_atomic_ops(MemTable::open(dir.path().join("atomic"), options.clone()).unwrap());
+        test_transaction(MemTable::open(dir.path().join("tx"), options).unwrap());
+    }
+
+    #[test]
+    fn persistent_memtable_should_recover_after_reopen() {
+        let dir = tempdir().unwrap();
+        let options = WalOptions {
+            sync: SyncPolicy::Never,
+            snapshot_interval: None,
+        };
+        {
+            let store = MemTable::open(dir.path(), options.clone()).unwrap();
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.set("t1", "k2".into(), "v2".into()).unwrap();
+            store
+                .set_with_ttl("t1", "k3".into(), 3.into(), Duration::from_secs(100))
+                .unwrap();
+            store
+                .set_with_ttl("t1", "k4".into(), 4.into(), SHORT_TTL)
+                .unwrap();
+            store.del("t1", "k2").unwrap();
+            store.incr("t2", "counter".into(), 5).unwrap();
+            store.persist("t1", "k3").unwrap();
+            store
+                .transaction(&["t1", "t2"], &mut |tx| {
+                    tx.set("t1", "k5", 5.into(), None)?;
+                    tx.update("t2", "counter", 10.into())?;
+                    Ok(())
+                })
+                .unwrap();
+            // 失败的事务不会写进 WAL
+            let result = store.transaction(&["t1"], &mut |tx| {
+                tx.del("t1", "k1")?;
+                Err(KvError::Internal("abort".into()))
+            });
+            assert!(result.is_err());
+        }
+
+        thread::sleep(SHORT_TTL * 2);
+        let store = MemTable::open(dir.path(), options).unwrap();
+        let mut data = store.get_all("t1").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k3", 3.into()),
+                Kvpair::new("k5", 5.into())
+            ]
+        );
+        assert_eq!(store.ttl("t1", "k3"), Ok(Some(None)));
+        assert_eq!(store.get("t2", "counter"), Ok(Some(10.into())));
+    }
+
     // 如果测试函数中内容太多的话，需要收敛到新的函数中
     // 测试驱动开发
     fn test_basi_interface(store: impl Storage) {
@@ -80,6 +372,315 @@ mod tests {
         assert_eq!(Ok(None), store.del("t2", "k"));
     }
 
+    const SHORT_TTL: Duration = Duration::from_millis(50);
+
+    fn test_ttl(store: impl Storage) {
+        // 没有过期时间的 key
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.ttl("t1", "k1"), Ok(Some(None)));
+        assert_eq!(store.ttl("t1", "k2"), Ok(None));
+
+        // 设置过期时间
+        let ttl = Duration::from_secs(100);
+        assert_eq!(store.expire("t1", "k1", ttl), Ok(true));
+        assert_eq!(store.expire("t1", "k2", ttl), Ok(false));
+        let remain = store.ttl("t1", "k1").unwrap().unwrap().unwrap();
+        assert!(remain <= ttl && remain > Duration::from_secs(90));
+
+        // persist 去掉过期时间，再 persist 返回 false
+        assert_eq!(store.persist("t1", "k1"), Ok(true));
+        assert_eq!(store.persist("t1", "k1"), Ok(false));
+        assert_eq!(store.ttl("t1", "k1"), Ok(Some(None)));
+
+        // set 会清除之前的过期时间
+        store.expire("t1", "k1", ttl).unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        assert_eq!(store.ttl("t1", "k1"), Ok(Some(None)));
+
+        // 过期之后读不到了
+        let v = store.set_with_ttl("t1", "k1".into(), "v3".into(), SHORT_TTL);
+        assert_eq!(v, Ok(Some("v2".into())));
+        store
+            .set_with_ttl("t1", "k2".into(), "v".into(), SHORT_TTL)
+            .unwrap();
+        assert_eq!(store.get("t1", "k1"), Ok(Some("v3".into())));
+        thread::sleep(SHORT_TTL * 2);
+        assert_eq!(store.get("t1", "k1"), Ok(None));
+        assert_eq!(store.contains("t1", "k2"), Ok(false));
+        assert_eq!(store.ttl("t1", "k2"), Ok(None));
+        assert_eq!(store.get_all("t1"), Ok(vec![]));
+        assert_eq!(store.get_iter("t1").unwrap().count(), 0);
+
+        // 过期的 key 被覆盖时，不会返回过期的值
+        store
+            .set_with_ttl("t1", "k3".into(), "v".into(), SHORT_TTL)
+            .unwrap();
+        thread::sleep(SHORT_TTL * 2);
+        assert_eq!(store.set("t1", "k3".into(), "v1".into()), Ok(None));
+        assert_eq!(store.get("t1", "k3"), Ok(Some("v1".into())));
+    }
+
+    fn test_purge_expired(store: impl Storage) {
+        let ttl = Duration::from_secs(100);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store
+            .set_with_ttl("t1", "k2".into(), "v2".into(), ttl)
+            .unwrap();
+        store
+            .set_with_ttl("t1", "k3".into(), "v3".into(), SHORT_TTL)
+            .unwrap();
+        store
+            .set_with_ttl("t2", "k1".into(), "v1".into(), SHORT_TTL)
+            .unwrap();
+
+        thread::sleep(SHORT_TTL * 2);
+        let mut purged = store.purge_expired().unwrap();
+        purged.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        assert_eq!(
+            purged,
+            vec![
+                ("t1".into(), "k3".into(), "v3".into()),
+                ("t2".into(), "k1".into(), "v1".into())
+            ]
+        );
+        assert_eq!(store.purge_expired(), Ok(vec![]));
+
+        let mut data = store.get_all("t1").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new("k1", "v1".into()),
+                Kvpair::new("k2", "v2".into())
+            ]
+        );
+    }
+
+    fn test_atomic_ops(store: impl Storage) {
+        assert_eq!(store.incr("t1", "counter".into(), 3), Ok(3));
+        assert_eq!(store.incr("t1", "counter".into(), -1), Ok(2));
+        assert_eq!(store.incr_float("t1", "counter".into(), 0.5), Ok(2.5));
+        assert_eq!(store.get("t1", "counter"), Ok(Some(2.5.into())));
+        assert!(store.incr("t1", "counter".into(), 1).is_err());
+
+        // 修改已有的 key 会保留过期时间
+        let ttl = Duration::from_secs(100);
+        store
+            .set_with_ttl("t1", "k1".into(), 1.into(), ttl)
+            .unwrap();
+        assert_eq!(store.incr("t1", "k1".into(), 1), Ok(2));
+        assert!(store.ttl("t1", "k1").unwrap().unwrap().is_some());
+
+        // 过期的 key 当作不存在，新值没有过期时间
+        store
+            .set_with_ttl("t1", "k2".into(), 10.into(), SHORT_TTL)
+            .unwrap();
+        thread::sleep(SHORT_TTL * 2);
+        assert_eq!(store.incr("t1", "k2".into(), 1), Ok(1));
+        assert_eq!(store.ttl("t1", "k2"), Ok(Some(None)));
+
+        assert_eq!(store.set_nx("t1", "k3".into(), "v1".into()), Ok(true));
+        assert_eq!(store.set_nx("t1", "k3".into(), "v2".into()), Ok(false));
+        assert_eq!(store.get("t1", "k3"), Ok(Some("v1".into())));
+
+        let cas = |expected: Option<Value>, new: Value| {
+            store.compare_and_swap("t1", "k4".into(), expected, new)
+        };
+        assert_eq!(cas(Some("v0".into()), "v1".into()), Ok(false));
+        assert_eq!(cas(None, "v1".into()), Ok(true));
+        assert_eq!(cas(None, "v2".into()), Ok(false));
+        assert_eq!(cas(Some("v1".into()), "v2".into()), Ok(true));
+        assert_eq!(store.get("t1", "k4"), Ok(Some("v2".into())));
+    }
+
+    // 多个线程同时修改同一个 key，每个修改都不能丢
+    fn test_concurrent_updates(store: Arc<impl Storage>) {
+        const THREADS: i64 = 8;
+        const TIMES: i64 = 200;
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    let mut won = 0;
+                    for _ in 0..TIMES {
+                        store.incr("t1", "counter".into(), 1).unwrap();
+                        store.incr_float("t1", "float".into(), 0.5).unwrap();
+
+                        // 用 compare_and_swap 实现的自增，冲突时重试
+                        loop {
+                            let old = store.get("t1", "cas").unwrap();
+                            let n: i64 = old.clone().map_or(0, |v| v.try_into().unwrap());
+                            let cas =
+                                store.compare_and_swap("t1", "cas".into(), old, (n + 1).into());
+                            if cas.unwrap() {
+                                break;
+                            }
+                        }
+                    }
+                    if store.set_nx("t1", "owner".into(), i.into()).unwrap() {
+                        won += 1;
+                    }
+                    won
+                })
+            })
+            .collect();
+        let won: i64 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+
+        let total = THREADS * TIMES;
+        assert_eq!(store.get("t1", "counter"), Ok(Some(total.into())));
+        assert_eq!(
+            store.get("t1", "float"),
+            Ok(Some((total as f64 * 0.5).into()))
+        );
+        assert_eq!(store.get("t1", "cas"), Ok(Some(total.into())));
+        // 只有一个线程能抢到 setnx
+        assert_eq!(won, 1);
+    }
+
+    fn test_scan(store: impl Storage) {
+        for key in ["b", "a", "c:1", "c:2", "c:3", "d", "e"] {
+            store.set("t1", key.into(), key.into()).unwrap();
+        }
+        // 其它 table 的数据不会被扫描到
+        store.set("t0", "z".into(), "z".into()).unwrap();
+        store.set("t2", "a".into(), "a".into()).unwrap();
+        store
+            .set_with_ttl("t1", "c:0".into(), "v".into(), SHORT_TTL)
+            .unwrap();
+        thread::sleep(SHORT_TTL * 2);
+
+        // 返回逗号分隔的 key 和下一页的 cursor，方便比较
+        let scan = |start: &str, end: &str, prefix: &str, limit| {
+            let range = ScanRange {
+                start: start.into(),
+                end: end.into(),
+                prefix: prefix.into(),
+            };
+            let (pairs, cursor) = store.scan("t1", &range, limit).unwrap();
+            let keys: Vec<_> = pairs.into_iter().map(|v| v.key).collect();
+            (keys.join(","), cursor)
+        };
+
+        let all = "a,b,c:1,c:2,c:3,d,e";
+        assert_eq!(scan("", "", "", 100), (all.into(), None));
+        assert_eq!(scan("", "", "", 7), (all.into(), None));
+        assert_eq!(scan("", "", "", 2), ("a,b".into(), Some("c:1".into())));
+        assert_eq!(scan("b", "d", "", 100), ("b,c:1,c:2,c:3".into(), None));
+        assert_eq!(scan("", "", "c:", 100), ("c:1,c:2,c:3".into(), None));
+        assert_eq!(scan("c:2", "", "c:", 1), ("c:2".into(), Some("c:3".into())));
+        assert_eq!(scan("", "c:3", "c:", 100), ("c:1,c:2".into(), None));
+        assert_eq!(scan("f", "", "", 100), ("".into(), None));
+        assert_eq!(scan("", "", "x", 100), ("".into(), None));
+
+        // 用 cursor 一页一页地遍历整个 table
+        let mut keys = Vec::new();
+        let mut cursor = Some(String::new());
+        while let Some(start) = cursor {
+            let range = ScanRange {
+                start,
+                ..Default::default()
+            };
+            let (page, next) = store.scan("t1", &range, 3).unwrap();
+            assert!(page.len() <= 3);
+            keys.extend(page.into_iter().map(|v| v.key));
+            cursor = next;
+        }
+        assert_eq!(keys.join(","), all);
+    }
+
+    fn test_transaction(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let ttl = Duration::from_secs(100);
+        store
+            .set_with_ttl("t1", "k2".into(), 2.into(), ttl)
+            .unwrap();
+
+        // 成功的事务，修改都生效
+        let result = store.transaction(&["t1", "t2"], &mut |tx| {
+            assert_eq!(tx.get("t1", "k1")?, Some("v1".into()));
+            assert_eq!(tx.set("t2", "k1", "v2".into(), None)?, None);
+            assert_eq!(tx.get("t2", "k1")?, Some("v2".into()));
+            assert_eq!(tx.update("t1", "k2", 3.into())?, Some(2.into()));
+            assert_eq!(tx.del("t1", "k1")?, Some("v1".into()));
+            Ok(())
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(store.get("t1", "k1"), Ok(None));
+        assert_eq!(store.get("t2", "k1"), Ok(Some("v2".into())));
+        assert_eq!(store.get("t1", "k2"), Ok(Some(3.into())));
+        // update 保留过期时间
+        assert!(store.ttl("t1", "k2").unwrap().unwrap().is_some());
+
+        // 失败的事务，所有修改都回滚
+        let result = store.transaction(&["t1", "t2"], &mut |tx| {
+            tx.set("t1", "k1", "new".into(), Some(ttl))?;
+            tx.set("t1", "k1", "newer".into(), None)?;
+            tx.update("t1", "k2", 4.into())?;
+            tx.del("t2", "k1")?;
+            Err(KvError::Internal("abort".into()))
+        });
+        assert_eq!(result, Err(KvError::Internal("abort".into())));
+        assert_eq!(store.get("t1", "k1"), Ok(None));
+        assert_eq!(store.get("t2", "k1"), Ok(Some("v2".into())));
+        assert_eq!(store.get("t1", "k2"), Ok(Some(3.into())));
+        assert!(store.ttl("t1", "k2").unwrap().unwrap().is_some());
+    }
+
+    // 两个账户之间不停地转账，其它线程任何时候看到的总额都不变
+    fn test_transaction_isolation(store: Arc<impl Storage>) {
+        store.set("a", "balance".into(), 100.into()).unwrap();
+        store.set("b", "balance".into(), 100.into()).unwrap();
+
+        let transfer = |store: Arc<_>, from: &'static str, to: &'static str| {
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    let result = Storage::transaction(&*store, &[from, to], &mut |tx| {
+                        let a: i64 = tx.get(from, "balance")?.unwrap().try_into()?;
+                        let b: i64 = tx.get(to, "balance")?.unwrap().try_into()?;
+                        tx.update(from, "balance", (a - 1).into())?;
+                        tx.update(to, "balance", (b + 1).into())?;
+                        Ok(())
+                    });
+                    result.unwrap();
+                }
+            })
+        };
+        let handles = vec![
+            transfer(store.clone(), "a", "b"),
+            transfer(store.clone(), "b", "a"),
+        ];
+
+        for _ in 0..100 {
+            let mut total = 0;
+            store
+                .transaction(&["a", "b"], &mut |tx| {
+                    let a: i64 = tx.get("a", "balance")?.unwrap().try_into()?;
+                    let b: i64 = tx.get("b", "balance")?.unwrap().try_into()?;
+                    total = a + b;
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(total, 200);
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(store.get("a", "balance"), Ok(Some(100.into())));
+    }
+
+    fn test_tables(store: impl Storage) {
+        for table in ["t2", "t10", "t1"] {
+            store.set(table, "k1".into(), "v1".into()).unwrap();
+            store.set(table, "k2".into(), "v2".into()).unwrap();
+        }
+        assert_eq!(store.tables().unwrap(), vec!["t1", "t10", "t2"]);
+        store.del("t10", "k1").unwrap();
+        assert_eq!(store.count("t1"), Ok(2));
+        assert_eq!(store.count("t10"), Ok(1));
+        assert_eq!(store.count("t3"), Ok(0));
+    }
+
     fn test_get_all(store: impl Storage) {
         store.set("t2", "k1".into(), "v1".into()).unwrap();
         store.set("t2", "k2".into(), "v2".into()).unwrap();
@@ -0,0 +1,398 @@
+use crate::{KvError, Value};
+use prost::Message;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+const WAL_PREFIX: &str = "wal-";
+const WAL_SUFFIX: &str = ".log";
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_SUFFIX: &str = ".snap";
+const TMP_SUFFIX: &str = ".tmp";
+
+// 每条记录的头：长度（u32）+ crc32（u32），后面是 protobuf 编码的 Record
+const HEADER_LEN: usize = 8;
+
+/// WAL 什么时候调用 fsync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// 每次写入都 fsync，最安全也最慢
+    Always,
+    /// 后台线程定期 fsync，机器掉电时最多丢失这段时间的数据
+    Interval(Duration),
+    /// 交给操作系统决定什么时候落盘
+    Never,
+}
+
+/// MemTable 持久化的配置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalOptions {
+    pub sync: SyncPolicy,
+    /// 每隔多久写一次 snapshot 并清理旧的 WAL，None 表示只能手动调用 MemTable::snapshot
+    pub snapshot_interval: Option<Duration>,
+}
+
+impl Default for WalOptions {
+    fn default() -> Self {
+        Self {
+            sync: SyncPolicy::Interval(Duration::from_secs(1)),
+            snapshot_interval: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+// WAL 和 snapshot 里保存的一次修改
+#[derive(Clone, PartialEq, Message)]
+pub(super) struct Mutation {
+    #[prost(string, tag = "1")]
+    pub table: String,
+    #[prost(string, tag = "2")]
+    pub key: String,
+    #[prost(message, optional, tag = "3")]
+    pub value: Option<Value>,
+    // 过期的时间点（unix 时间戳，毫秒），0 表示不过期
+    #[prost(uint64, tag = "4")]
+    pub expire_at: u64,
+    #[prost(bool, tag = "5")]
+    pub deleted: bool,
+}
+
+impl Mutation {
+    pub(super) fn put(table: &str, key: &str, value: Value, expire_at: Option<u64>) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+            value: Some(value),
+            expire_at: expire_at.unwrap_or_default(),
+            deleted: false,
+        }
+    }
+
+    pub(super) fn delete(table: &str, key: &str) -> Self {
+        Self {
+            table: table.into(),
+            key: key.into(),
+            deleted: true,
+            ..Default::default()
+        }
+    }
+}
+
+// 一条记录里的修改要么全部重放，要么全部丢弃，事务的修改放在同一条记录里
+#[derive(Clone, PartialEq, Message)]
+struct Record {
+    #[prost(message, repeated, tag = "1")]
+    mutations: Vec<Mutation>,
+}
+
+fn encode_record(mutations: Vec<Mutation>) -> Vec<u8> {
+    let data = Record { mutations }.encode_to_vec();
+    let mut buf = Vec::with_capacity(HEADER_LEN + data.len());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+    buf.extend_from_slice(&data);
+    buf
+}
+
+// 按顺序解析 data 里的记录，返回有效数据的长度，之后的数据不完整或者已经损坏
+fn decode_records(data: &[u8], mut f: impl FnMut(Mutation)) -> usize {
+    let mut offset = 0;
+    while let Some(header) = data.get(offset..offset + HEADER_LEN) {
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let start = offset + HEADER_LEN;
+        let Some(body) = data.get(start..start + len) else {
+            break;
+        };
+        if crc32fast::hash(body) != crc {
+            break;
+        }
+        let Ok(record) = Record::decode(body) else {
+            break;
+        };
+        record.mutations.into_iter().for_each(&mut f);
+        offset = start + len;
+    }
+    offset
+}
+
+/// 追加写的 WAL，写 snapshot 时切换到新的文件，之前的文件就可以删掉了
+#[derive(Debug)]
+pub(super) struct Wal {
+    dir: PathBuf,
+    sync: SyncPolicy,
+    segment: Mutex<Segment>,
+    // 同一时间只能有一个 snapshot 在写
+    snapshot_lock: Mutex<()>,
+}
+
+// 正在写的 WAL 文件
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    file: File,
+    len: u64,
+    // 有没有还没 fsync 的数据
+    unsynced: bool,
+    // 上次 snapshot 之后有没有新的写入
+    changed: bool,
+}
+
+/// 写 snapshot 文件
+pub(super) struct SnapshotWriter(BufWriter<File>);
+
+impl SnapshotWriter {
+    pub(super) fn write(&mut self, mutations: Vec<Mutation>) -> Result<(), KvError> {
+        if !mutations.is_empty() {
+            self.0.write_all(&encode_record(mutations))?;
+        }
+        Ok(())
+    }
+}
+
+impl Wal {
+    /// 打开 dir 下的 WAL：先加载最新的 snapshot，再按顺序重放之后的 WAL，最后开始写新的 WAL 文件
+    pub(super) fn open(
+        dir: &Path,
+        sync: SyncPolicy,
+        mut apply: impl FnMut(Mutation),
+    ) -> Result<Self, KvError> {
+        fs::create_dir_all(dir)?;
+        let (snapshots, mut segments) = list_files(dir)?;
+
+        let base = snapshots.iter().max().copied().unwrap_or_default();
+        if !snapshots.is_empty() {
+            // snapshot 是写完 fsync 之后才 rename 的，损坏了说明磁盘有问题，不能继续
+            let path = file_path(dir, SNAPSHOT_PREFIX, base, SNAPSHOT_SUFFIX);
+            let data = fs::read(&path)?;
+            if decode_records(&data, &mut apply) != data.len() {
+                return Err(KvError::Internal(format!(
+                    "Snapshot {} is corrupted",
+                    path.display()
+                )));
+            }
+        }
+
+        segments.retain(|id| *id >= base);
+        segments.sort_unstable();
+        for (i, id) in segments.iter().enumerate() {
+            let path = file_path(dir, WAL_PREFIX, *id, WAL_SUFFIX);
+            let data = fs::read(&path)?;
+            let valid = decode_records(&data, &mut apply);
+            // 切换文件之前会 fsync，之前的文件损坏了说明磁盘有问题，跳过它会恢复出从来没有过的状态
+            if valid < data.len() && i + 1 < segments.len() {
+                return Err(KvError::Internal(format!(
+                    "WAL {} is corrupted at offset {}",
+                    path.display(),
+                    valid
+                )));
+            }
+            // 进程崩溃时最后一个文件的最后一条记录可能只写了一半，截掉即可
+            if valid < data.len() {
+                warn!(
+                    "WAL {} is corrupted at offset {}, truncate {} bytes",
+                    path.display(),
+                    valid,
+                    data.len() - valid
+                );
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(valid as u64)?;
+                file.sync_all()?;
+            }
+        }
+        remove_before(dir, base)?;
+
+        let id = segments.last().map_or(base, |id| id + 1);
+        info!("WAL in {} is replayed, start writing {}", dir.display(), id);
+        Ok(Self {
+            dir: dir.into(),
+            sync,
+            segment: Mutex::new(Segment::create(dir, id)?),
+            snapshot_lock: Mutex::new(()),
+        })
+    }
+
+    /// 把一组修改作为一条记录写入 WAL
+    pub(super) fn append(&self, mutations: Vec<Mutation>) -> Result<(), KvError> {
+        if mutations.is_empty() {
+            return Ok(());
+        }
+        let buf = encode_record(mutations);
+        let mut segment = self.segment();
+        if let Err(e) = segment.file.write_all(&buf) {
+            // 去掉写了一半的数据，不然之后的记录在重放时都会被当作损坏的数据
+            let _ = segment.file.set_len(segment.len);
+            return Err(e.into());
+        }
+        segment.len += buf.len() as u64;
+        segment.changed = true;
+        match self.sync {
+            SyncPolicy::Always => segment.file.sync_data()?,
+            _ => segment.unsynced = true,
+        }
+        Ok(())
+    }
+
+    /// fsync 还没落盘的数据
+    pub(super) fn sync(&self) -> Result<(), KvError> {
+        let mut segment = self.segment();
+        if segment.unsynced {
+            segment.file.sync_data()?;
+            segment.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// 上次 snapshot 之后有没有新的写入
+    pub(super) fn changed(&self) -> bool {
+        self.segment().changed
+    }
+
+    /// 写 snapshot：先切换到新的 WAL 文件，再把 dump 写出的数据存成 snapshot，最后删掉旧的 WAL
+    /// 切换之后的修改也可能出现在 snapshot 里，重放时再执行一遍，结果是一样的
+    pub(super) fn snapshot(
+        &self,
+        dump: impl FnOnce(&mut SnapshotWriter) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        let _guard = self
+            .snapshot_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let id = self.rotate()?;
+
+        let path = file_path(&self.dir, SNAPSHOT_PREFIX, id, SNAPSHOT_SUFFIX);
+        let tmp = path.with_extension(&TMP_SUFFIX[1..]);
+        let mut writer = SnapshotWriter(BufWriter::new(File::create(&tmp)?));
+        dump(&mut writer)?;
+        let file = writer.0.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir)?;
+
+        remove_before(&self.dir, id)?;
+        info!("Snapshot {} is written", path.display());
+        Ok(())
+    }
+
+    // 切换到新的 WAL 文件，返回新文件的 id
+    fn rotate(&self) -> Result<u64, KvError> {
+        let mut segment = self.segment();
+        segment.file.sync_data()?;
+        *segment = Segment::create(&self.dir, segment.id + 1)?;
+        Ok(segment.id)
+    }
+
+    fn segment(&self) -> MutexGuard<'_, Segment> {
+        self.segment.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!("Failed to sync WAL: {}", e);
+        }
+    }
+}
+
+impl Segment {
+    fn create(dir: &Path, id: u64) -> Result<Self, KvError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path(dir, WAL_PREFIX, id, WAL_SUFFIX))?;
+        sync_dir(dir)?;
+        Ok(Self {
+            id,
+            len: file.metadata()?.len(),
+            file,
+            unsynced: false,
+            changed: false,
+        })
+    }
+}
+
+fn file_path(dir: &Path, prefix: &str, id: u64, suffix: &str) -> PathBuf {
+    dir.join(format!("{}{:020}{}", prefix, id, suffix))
+}
+
+fn parse_id(name: &str, prefix: &str, suffix: &str) -> Option<u64> {
+    name.strip_prefix(prefix)?
+        .strip_suffix(suffix)?
+        .parse()
+        .ok()
+}
+
+// 返回 dir 下所有 snapshot 和 WAL 文件的 id，顺手删掉没写完的 snapshot
+fn list_files(dir: &Path) -> Result<(Vec<u64>, Vec<u64>), KvError> {
+    let mut snapshots = Vec::new();
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|v| v.to_str()) else {
+            continue;
+        };
+        if let Some(id) = parse_id(name, SNAPSHOT_PREFIX, SNAPSHOT_SUFFIX) {
+            snapshots.push(id);
+        } else if let Some(id) = parse_id(name, WAL_PREFIX, WAL_SUFFIX) {
+            segments.push(id);
+        } else if name.ends_with(TMP_SUFFIX) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok((snapshots, segments))
+}
+
+// 删除 id 之前的 snapshot 和 WAL，它们的数据都已经在 id 对应的 snapshot 里了
+fn remove_before(dir: &Path, id: u64) -> Result<(), KvError> {
+    let (snapshots, segments) = list_files(dir)?;
+    for old in snapshots.into_iter().filter(|v| *v < id) {
+        fs::remove_file(file_path(dir, SNAPSHOT_PREFIX, old, SNAPSHOT_SUFFIX))?;
+    }
+    for old in segments.into_iter().filter(|v| *v < id) {
+        fs::remove_file(file_path(dir, WAL_PREFIX, old, WAL_SUFFIX))?;
+    }
+    Ok(())
+}
+
+// 文件的创建和 rename 需要 fsync 目录才能保证落盘
+fn sync_dir(dir: &Path) -> Result<(), KvError> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_records_should_stop_at_corrupted_data() {
+        let mut data = encode_record(vec![Mutation::put("t1", "k1", "v1".into(), None)]);
+        let valid = data.len();
+        data.extend(encode_record(vec![
+            Mutation::put("t1", "k2", "v2".into(), Some(42)),
+            Mutation::delete("t1", "k1"),
+        ]));
+
+        let mut mutations = Vec::new();
+        assert_eq!(decode_records(&data, |m| mutations.push(m)), data.len());
+        assert_eq!(mutations.len(), 3);
+        assert_eq!(mutations[1].expire_at, 42);
+        assert!(mutations[2].deleted);
+
+        // 第二条记录被改坏了，只能读出第一条
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        let mut mutations = Vec::new();
+        assert_eq!(decode_records(&data, |m| mutations.push(m)), valid);
+        assert_eq!(mutations.len(), 1);
+
+        // 不完整的记录同样会被丢弃
+        assert_eq!(decode_records(&data[..valid + 3], |_| {}), valid);
+    }
+}